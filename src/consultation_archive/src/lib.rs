@@ -0,0 +1,174 @@
+//! Archive canister for consultations closed long ago. The main canister
+//! (`icp_rust_boilerplate_backend`) moves them here and is the only
+//! principal allowed to read or write them; it applies organization scoping
+//! before serving archived records to anyone else.
+
+#[macro_use]
+extern crate serde;
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const MAX_LIST_LIMIT: usize = 100;
+
+// Mirrors `LegalConsultation` of the main canister.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegalConsultation {
+    id: u64,
+    advisor_id: u64,
+    details: String,
+    created_at: u64,
+    closed_at: Option<u64>,
+    is_completed: bool,
+    status: ConsultationStatus,
+    client: Option<Principal>,
+    practice_area: Option<String>,
+    organization_id: Option<u64>,
+    reference: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum ConsultationStatus {
+    Open,
+    Completed,
+    Cancelled,
+    Pending,
+    Declined,
+}
+
+impl Storable for LegalConsultation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegalConsultation {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// The main canister that owns this archive.
+#[derive(Default)]
+struct Owner(Option<Principal>);
+
+impl Storable for Owner {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            self.0
+                .map(|owner| owner.as_slice().to_vec())
+                .unwrap_or_default(),
+        )
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Owner((!bytes.is_empty()).then(|| Principal::from_slice(&bytes)))
+    }
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    Unauthorized { msg: String },
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static OWNER: RefCell<Cell<Owner, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), Owner::default())
+            .expect("Cannot create owner")
+    );
+
+    static ARCHIVED_CONSULTATIONS: RefCell<StableBTreeMap<u64, LegalConsultation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+}
+
+#[ic_cdk::init]
+fn init(owner: Principal) {
+    OWNER
+        .with(|cell| cell.borrow_mut().set(Owner(Some(owner))))
+        .expect("Cannot set owner");
+}
+
+/// Stores consultations moved out of the main canister, replacing earlier
+/// copies.
+#[ic_cdk::update]
+fn archive_consultations(consultations: Vec<LegalConsultation>) -> Result<(), Error> {
+    ensure_owner()?;
+
+    ARCHIVED_CONSULTATIONS.with(|service| {
+        let mut archive = service.borrow_mut();
+        for consultation in consultations {
+            archive.insert(consultation.id, consultation);
+        }
+    });
+    Ok(())
+}
+
+/// Permanently deletes archived consultations, e.g. once their retention
+/// period has ended.
+#[ic_cdk::update]
+fn delete_consultations(ids: Vec<u64>) -> Result<(), Error> {
+    ensure_owner()?;
+
+    ARCHIVED_CONSULTATIONS.with(|service| {
+        let mut archive = service.borrow_mut();
+        for id in ids {
+            archive.remove(&id);
+        }
+    });
+    Ok(())
+}
+
+/// The archived consultations among `ids`; unknown ids are skipped.
+#[ic_cdk::query]
+fn get_consultations(ids: Vec<u64>) -> Result<Vec<LegalConsultation>, Error> {
+    ensure_owner()?;
+
+    Ok(ARCHIVED_CONSULTATIONS.with(|service| {
+        let archive = service.borrow();
+        ids.iter().filter_map(|id| archive.get(id)).collect()
+    }))
+}
+
+/// Pages through the archive in id order, starting after `start_after`.
+#[ic_cdk::query]
+fn list_consultations(
+    start_after: Option<u64>,
+    limit: u32,
+) -> Result<Vec<LegalConsultation>, Error> {
+    ensure_owner()?;
+
+    let start = start_after.map_or(0, |id| id.saturating_add(1));
+    Ok(ARCHIVED_CONSULTATIONS.with(|service| {
+        service
+            .borrow()
+            .range(start..)
+            .take((limit as usize).min(MAX_LIST_LIMIT))
+            .map(|(_, consultation)| consultation)
+            .collect()
+    }))
+}
+
+fn ensure_owner() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if OWNER.with(|cell| cell.borrow().get().0) == Some(caller) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("Principal {} does not own this archive", caller),
+        })
+    }
+}
+
+ic_cdk::export_candid!();
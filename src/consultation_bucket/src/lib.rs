@@ -0,0 +1,292 @@
+//! Bucket canister holding a shard of the consultation records and document
+//! contents. Buckets are created and fed by the index canister
+//! (`icp_rust_boilerplate_backend`),
+//! which is the only principal allowed to read or write them: access rules
+//! such as organization scoping live in the index.
+
+#[macro_use]
+extern crate serde;
+use candid::Principal;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::{borrow::Cow, cell::RefCell};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const MAX_LIST_LIMIT: usize = 1000;
+// Matches the chunk size of the index canister's document uploads.
+const MAX_CHUNK_BYTES: u32 = 256 * 1024;
+
+/// A candid-encoded record, opaque to the bucket.
+struct Record(Vec<u8>);
+
+impl Storable for Record {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Record(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Record {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Orders the chunks of a document together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    document_id: u64,
+    index: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(12);
+        bytes.extend_from_slice(&self.document_id.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        ChunkKey {
+            document_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            index: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = 12;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+struct Chunk(Vec<u8>);
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Chunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = MAX_CHUNK_BYTES;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// The index canister that owns this bucket.
+#[derive(Default)]
+struct Owner(Option<Principal>);
+
+impl Storable for Owner {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            self.0
+                .map(|owner| owner.as_slice().to_vec())
+                .unwrap_or_default(),
+        )
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Owner((!bytes.is_empty()).then(|| Principal::from_slice(&bytes)))
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BucketStats {
+    records: u64,
+    chunks: u64,
+    stable_memory_bytes: u64,
+}
+
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    Unauthorized { msg: String },
+    InvalidInput { msg: String },
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static OWNER: RefCell<Cell<Owner, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), Owner::default())
+            .expect("Cannot create owner")
+    );
+
+    static RECORDS: RefCell<StableBTreeMap<u64, Record, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static CHUNKS: RefCell<StableBTreeMap<ChunkKey, Chunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+}
+
+#[ic_cdk::init]
+fn init(owner: Principal) {
+    OWNER
+        .with(|cell| cell.borrow_mut().set(Owner(Some(owner))))
+        .expect("Cannot set owner");
+}
+
+/// Inserts or replaces records, keyed by their id in the index canister.
+#[ic_cdk::update]
+fn put_records(records: Vec<(u64, ByteBuf)>) -> Result<(), Error> {
+    ensure_owner()?;
+
+    if let Some((id, _)) = records
+        .iter()
+        .find(|(_, bytes)| bytes.len() > Record::MAX_SIZE as usize)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("Record with id={} exceeds {} bytes", id, Record::MAX_SIZE),
+        });
+    }
+    RECORDS.with(|service| {
+        let mut records_map = service.borrow_mut();
+        for (id, bytes) in records {
+            records_map.insert(id, Record(bytes.into_vec()));
+        }
+    });
+    Ok(())
+}
+
+/// Deletes the records, and the chunks of the documents, with the given ids.
+#[ic_cdk::update]
+fn delete_records(ids: Vec<u64>) -> Result<(), Error> {
+    ensure_owner()?;
+
+    RECORDS.with(|service| {
+        let mut records_map = service.borrow_mut();
+        for id in &ids {
+            records_map.remove(id);
+        }
+    });
+    CHUNKS.with(|service| {
+        let mut chunks = service.borrow_mut();
+        for id in ids {
+            let keys: Vec<ChunkKey> = chunks
+                .range(
+                    ChunkKey {
+                        document_id: id,
+                        index: 0,
+                    }..,
+                )
+                .take_while(|(key, _)| key.document_id == id)
+                .map(|(key, _)| key)
+                .collect();
+            for key in &keys {
+                chunks.remove(key);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn put_chunk(document_id: u64, index: u32, data: ByteBuf) -> Result<(), Error> {
+    ensure_owner()?;
+
+    if data.len() > MAX_CHUNK_BYTES as usize {
+        return Err(Error::InvalidInput {
+            msg: format!("Chunks are at most {} bytes", MAX_CHUNK_BYTES),
+        });
+    }
+    CHUNKS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(ChunkKey { document_id, index }, Chunk(data.into_vec()))
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_chunk(document_id: u64, index: u32) -> Result<Option<ByteBuf>, Error> {
+    ensure_owner()?;
+
+    Ok(CHUNKS.with(|service| {
+        service
+            .borrow()
+            .get(&ChunkKey { document_id, index })
+            .map(|chunk| ByteBuf::from(chunk.0))
+    }))
+}
+
+#[ic_cdk::query]
+fn get_record(id: u64) -> Result<Option<ByteBuf>, Error> {
+    ensure_owner()?;
+
+    Ok(RECORDS.with(|service| {
+        service
+            .borrow()
+            .get(&id)
+            .map(|record| ByteBuf::from(record.0))
+    }))
+}
+
+/// The records with the given ids that exist, at most `MAX_LIST_LIMIT`.
+#[ic_cdk::query]
+fn get_records(ids: Vec<u64>) -> Result<Vec<(u64, ByteBuf)>, Error> {
+    ensure_owner()?;
+
+    if ids.len() > MAX_LIST_LIMIT {
+        return Err(Error::InvalidInput {
+            msg: format!("At most {} records can be read at once", MAX_LIST_LIMIT),
+        });
+    }
+    Ok(RECORDS.with(|service| {
+        let records = service.borrow();
+        ids.into_iter()
+            .filter_map(|id| records.get(&id).map(|record| (id, ByteBuf::from(record.0))))
+            .collect()
+    }))
+}
+
+/// Pages through the records in id order, starting after `start_after`.
+#[ic_cdk::query]
+fn list_records(start_after: Option<u64>, limit: u32) -> Result<Vec<(u64, ByteBuf)>, Error> {
+    ensure_owner()?;
+
+    let start = start_after.map_or(0, |id| id.saturating_add(1));
+    Ok(RECORDS.with(|service| {
+        service
+            .borrow()
+            .range(start..)
+            .take((limit as usize).min(MAX_LIST_LIMIT))
+            .map(|(id, record)| (id, ByteBuf::from(record.0)))
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn bucket_stats() -> BucketStats {
+    BucketStats {
+        records: RECORDS.with(|service| service.borrow().len()),
+        chunks: CHUNKS.with(|service| service.borrow().len()),
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * 65536,
+    }
+}
+
+fn ensure_owner() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if OWNER.with(|cell| cell.borrow().get().0) == Some(caller) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("Principal {} does not own this bucket", caller),
+        })
+    }
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,398 @@
+use crate::{
+    _get_legal_consultation, all_legal_consultations, backup, billing, changes,
+    evict_legal_consultation, organizations, participants, payments, remove_dependent_records,
+    retention, search, sharding, ChangeEntity, Error, LegalConsultation, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// Consultations moved or purged per run; the rest waits for the next one.
+const ARCHIVE_BATCH: usize = 100;
+const SNIPPET_CHARS: usize = 160;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ArchiveConfig {
+    archive_canister: Option<Principal>,
+    // Consultations closed for longer are archived; archiving is off when
+    // unset.
+    archive_after_days: Option<u32>,
+}
+
+impl Storable for ArchiveConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Redirect left behind for an archived consultation, with what is needed
+/// to scope, search and eventually purge it without asking the archive.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ArchiveStub {
+    id: u64,
+    archive: Principal,
+    organization_id: Option<u64>,
+    client: Option<Principal>,
+    advisor_id: u64,
+    closed_at: u64,
+    practice_area: Option<String>,
+    snippet: String,
+    archived_at: u64,
+}
+
+impl Storable for ArchiveStub {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ArchiveStub {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ARCHIVE_CONFIG: RefCell<Cell<ArchiveConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+            ArchiveConfig::default(),
+        )
+        .expect("Cannot create archive config")
+    );
+
+    static ARCHIVE_STUBS: RefCell<StableBTreeMap<u64, ArchiveStub, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static ARCHIVING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+#[ic_cdk::update]
+fn configure_archive(config: ArchiveConfig) -> Result<ArchiveConfig, Error> {
+    ensure_controller()?;
+
+    ARCHIVE_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("Cannot update archive config");
+    Ok(config)
+}
+
+#[ic_cdk::query]
+fn get_archive_config() -> ArchiveConfig {
+    ARCHIVE_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn get_archive_stub(id: u64) -> Result<ArchiveStub, Error> {
+    stub_visible_to(&id, &ic_cdk::caller()).ok_or_else(|| archived_not_found(id))
+}
+
+/// Reads an archived consultation through to the archive canister.
+#[ic_cdk::query(composite = true)]
+async fn get_archived_legal_consultation(id: u64) -> Result<LegalConsultation, Error> {
+    let stub = stub_visible_to(&id, &ic_cdk::caller()).ok_or_else(|| archived_not_found(id))?;
+
+    fetch(stub.archive, vec![id])
+        .await?
+        .pop()
+        .ok_or_else(|| archived_not_found(id))
+}
+
+/// Archives eligible consultations now instead of waiting for the daily
+/// timer. Returns the number of archived consultations.
+#[ic_cdk::update]
+async fn run_archival() -> Result<u64, Error> {
+    ensure_controller()?;
+
+    if ARCHIVING.with(|archiving| archiving.replace(true)) {
+        return Ok(0);
+    }
+    let result = archive_closed().await;
+    ARCHIVING.with(|archiving| *archiving.borrow_mut() = false);
+    result
+}
+
+/// Daily run that archives closed consultations and purges archived ones
+/// whose retention period has ended.
+pub(crate) fn start_archive_timer() {
+    ic_cdk_timers::set_timer_interval(ARCHIVE_INTERVAL, || {
+        ic_cdk::spawn(async {
+            if ARCHIVING.with(|archiving| archiving.replace(true)) {
+                return;
+            }
+            let _ = archive_closed().await;
+            let _ = purge_expired().await;
+            ARCHIVING.with(|archiving| *archiving.borrow_mut() = false);
+        })
+    });
+}
+
+/// The archive canister holding the consultation, if it was archived and
+/// `principal` may see it.
+pub(crate) fn archived_in(id: &u64, principal: &Principal) -> Option<Principal> {
+    stub_visible_to(id, principal).map(|stub| stub.archive)
+}
+
+/// Whether the consultation was archived within the staff member's scope.
+pub(crate) fn is_archived_in_scope(id: &u64, staff: &Principal) -> bool {
+    _get_stub(id).is_some_and(|stub| organizations::can_access(staff, stub.organization_id))
+}
+
+pub(crate) fn archived_organization(id: &u64) -> Option<u64> {
+    _get_stub(id).and_then(|stub| stub.organization_id)
+}
+
+/// Snippet of an archived consultation for search results, if the caller
+/// could read the consultation (see `can_view_consultation`).
+pub(crate) fn search_snippet(id: &u64, caller: &Principal) -> Option<String> {
+    let stub = _get_stub(id)?;
+    let allowed = stub.client.as_ref() == Some(caller)
+        || participants::role_of(stub.id, stub.advisor_id, caller).is_some()
+        || ic_cdk::api::is_controller(caller);
+    allowed.then_some(stub.snippet)
+}
+
+/// Archived consultations of the client principal, limited to those
+/// `scope` may access when given.
+pub(crate) async fn client_consultations(
+    client: &Principal,
+    scope: Option<&Principal>,
+) -> Result<Vec<LegalConsultation>, Error> {
+    let mut by_archive: BTreeMap<Principal, Vec<u64>> = BTreeMap::new();
+    ARCHIVE_STUBS.with(|service| {
+        for (id, stub) in service.borrow().iter() {
+            if stub.client.as_ref() == Some(client)
+                && scope.is_none_or(|staff| organizations::can_access(staff, stub.organization_id))
+            {
+                by_archive.entry(stub.archive).or_default().push(id);
+            }
+        }
+    });
+
+    let mut consultations = Vec::new();
+    for (archive, ids) in by_archive {
+        consultations.extend(fetch(archive, ids).await?);
+    }
+    Ok(consultations)
+}
+
+/// Replaces archived consultations with the given versions and refreshes
+/// their stubs.
+pub(crate) async fn rewrite(consultations: Vec<LegalConsultation>) -> Result<(), Error> {
+    let mut by_archive: BTreeMap<Principal, Vec<LegalConsultation>> = BTreeMap::new();
+    for consultation in consultations {
+        if let Some(stub) = _get_stub(&consultation.id) {
+            by_archive
+                .entry(stub.archive)
+                .or_default()
+                .push(consultation);
+        }
+    }
+
+    for (archive, consultations) in by_archive {
+        call_archive(archive, "archive_consultations", &consultations).await?;
+        for consultation in &consultations {
+            if let Some(stub) = _get_stub(&consultation.id) {
+                insert_stub(consultation, stub.archive, stub.archived_at);
+                search::index_document(
+                    search::SearchDocKind::ArchivedConsultation,
+                    consultation.id,
+                    &consultation.details,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves consultations closed for longer than the configured period to the
+/// archive canister. Consultations under legal hold, with an unsettled
+/// escrow or with unbilled work stay in place.
+async fn archive_closed() -> Result<u64, Error> {
+    let config = get_archive_config();
+    let (archive, after_days) = match (config.archive_canister, config.archive_after_days) {
+        (Some(archive), Some(after_days)) => (archive, after_days),
+        _ => return Ok(0),
+    };
+    let cutoff = time().saturating_sub(after_days as u64 * NANOS_PER_DAY);
+
+    let eligible: Vec<LegalConsultation> = all_legal_consultations()
+        .await?
+        .into_iter()
+        .filter(|consultation| {
+            consultation
+                .closed_at
+                .is_some_and(|closed_at| closed_at <= cutoff)
+                && !retention::is_on_hold(consultation.id)
+                && !payments::has_unsettled_escrow(consultation.id)
+                && !billing::has_unbilled_entries(consultation.id)
+        })
+        .take(ARCHIVE_BATCH)
+        .collect();
+    if eligible.is_empty() {
+        return Ok(0);
+    }
+    call_archive(archive, "archive_consultations", &eligible).await?;
+    let ids: Vec<u64> = eligible
+        .iter()
+        .map(|consultation| consultation.id)
+        .collect();
+    sharding::load_consultations(&ids).await?;
+
+    // Consultations changed while the calls were in flight are sent again on
+    // the next run.
+    let mut archived = 0;
+    for consultation in &eligible {
+        let unchanged = _get_legal_consultation(&consultation.id)
+            .is_some_and(|current| Encode!(&current).ok() == Encode!(consultation).ok());
+        if unchanged {
+            insert_stub(consultation, archive, time());
+            evict_legal_consultation(consultation.id);
+            search::remove_document(search::SearchDocKind::Consultation, consultation.id);
+            search::index_document(
+                search::SearchDocKind::ArchivedConsultation,
+                consultation.id,
+                &consultation.details,
+            );
+            archived += 1;
+        }
+    }
+    Ok(archived)
+}
+
+/// Deletes archived consultations whose retention period has ended,
+/// skipping any under legal hold.
+async fn purge_expired() -> Result<u64, Error> {
+    let mut by_archive: BTreeMap<Principal, Vec<u64>> = BTreeMap::new();
+    ARCHIVE_STUBS.with(|service| {
+        for (id, stub) in service
+            .borrow()
+            .iter()
+            .filter(|(id, stub)| {
+                retention::is_expired(stub.closed_at, stub.practice_area.as_ref())
+                    && !retention::is_on_hold(*id)
+            })
+            .take(ARCHIVE_BATCH)
+        {
+            by_archive.entry(stub.archive).or_default().push(id);
+        }
+    });
+
+    let mut purged = 0;
+    for (archive, ids) in by_archive {
+        call_archive(archive, "delete_consultations", &ids).await?;
+        for id in ids {
+            ARCHIVE_STUBS.with(|service| service.borrow_mut().remove(&id));
+            remove_dependent_records(id);
+            search::remove_document(search::SearchDocKind::ArchivedConsultation, id);
+            changes::record_delete(ChangeEntity::Consultation, id);
+            purged += 1;
+        }
+    }
+    Ok(purged)
+}
+
+async fn fetch(archive: Principal, ids: Vec<u64>) -> Result<Vec<LegalConsultation>, Error> {
+    let (result,): (Result<Vec<LegalConsultation>, Error>,) =
+        ic_cdk::call(archive, "get_consultations", (ids,))
+            .await
+            .map_err(|(code, msg)| archive_call_failed("get_consultations", code, msg))?;
+    result
+}
+
+async fn call_archive<T: candid::CandidType>(
+    archive: Principal,
+    method: &str,
+    arg: &T,
+) -> Result<(), Error> {
+    let (result,): (Result<(), Error>,) = ic_cdk::call(archive, method, (arg,))
+        .await
+        .map_err(|(code, msg)| archive_call_failed(method, code, msg))?;
+    result
+}
+
+fn insert_stub(consultation: &LegalConsultation, archive: Principal, archived_at: u64) {
+    let stub = ArchiveStub {
+        id: consultation.id,
+        archive,
+        organization_id: consultation.organization_id,
+        client: consultation.client,
+        advisor_id: consultation.advisor_id,
+        closed_at: consultation.closed_at.unwrap_or(archived_at),
+        practice_area: consultation.practice_area.clone(),
+        snippet: consultation.details.chars().take(SNIPPET_CHARS).collect(),
+        archived_at,
+    };
+    ARCHIVE_STUBS.with(|service| service.borrow_mut().insert(stub.id, stub));
+}
+
+/// Re-indexes archived consultations from their stubs; only the snippet is
+/// searchable for them afterwards.
+pub(crate) fn reindex_stubs() -> u64 {
+    let stubs: Vec<ArchiveStub> =
+        ARCHIVE_STUBS.with(|service| service.borrow().iter().map(|(_, stub)| stub).collect());
+    for stub in &stubs {
+        search::index_document(
+            search::SearchDocKind::ArchivedConsultation,
+            stub.id,
+            &stub.snippet,
+        );
+    }
+    stubs.len() as u64
+}
+
+fn stub_visible_to(id: &u64, principal: &Principal) -> Option<ArchiveStub> {
+    _get_stub(id).filter(|stub| {
+        stub.client.as_ref() == Some(principal)
+            || organizations::can_access(principal, stub.organization_id)
+    })
+}
+
+fn _get_stub(id: &u64) -> Option<ArchiveStub> {
+    ARCHIVE_STUBS.with(|service| service.borrow().get(id))
+}
+
+fn archived_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Archived legal consultation with id={} not found", id),
+    }
+}
+
+fn archive_call_failed(method: &str, code: RejectionCode, msg: String) -> Error {
+    Error::CallFailed {
+        msg: format!("Call to {} failed ({:?}): {}", method, code, msg),
+    }
+}
+
+fn ensure_controller() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers can manage the archive".to_string(),
+        })
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::cell_source("archive_config", &ARCHIVE_CONFIG),
+        backup::map_source("archive_stubs", &ARCHIVE_STUBS),
+    ]
+}
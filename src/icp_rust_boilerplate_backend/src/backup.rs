@@ -0,0 +1,606 @@
+use crate::{
+    archive, billing, changes, custom_fields, documents, forms, handoffs, http, intake, matters,
+    notes, organizations, participants, payments, privacy, quotes, retention, search, sharding,
+    tasks, trash, trust, workflows, Error, Memory,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::thread::LocalKey;
+use std::{borrow::Cow, cell::RefCell};
+
+// Bump whenever a backed-up type or table changes shape.
+const BACKUP_VERSION: u32 = 7;
+// Keeps each chunk well inside the ingress and response size limits.
+const CHUNK_SIZE: usize = 1024 * 1024;
+// Entries collected or restored per call, to stay inside the instruction
+// limit however large the tables grow.
+const BATCH_SIZE: usize = 5_000;
+// Chunks hashed per call while a restore is verified.
+const VERIFY_BATCH: u32 = 64;
+
+// Raw key/value bytes of stored entries.
+type Entries = Vec<(ByteBuf, ByteBuf)>;
+// Up to `limit` entries after the given key.
+type ReadEntries = Box<dyn Fn(Option<&[u8]>, usize) -> Entries>;
+
+/// One stable structure holding primary data, read and written in batches.
+pub(crate) struct TableSource {
+    name: &'static str,
+    cell: bool,
+    is_empty: Box<dyn Fn() -> bool>,
+    // Cells are a single entry with an empty key.
+    read: ReadEntries,
+    write: Box<dyn Fn(&Entries)>,
+}
+
+/// Leads a backup: the tables it holds and the buckets whose records it
+/// does not copy.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BackupHeader {
+    version: u32,
+    tables: Vec<String>,
+    buckets: Vec<Principal>,
+}
+
+/// Entries of one table, in key order. A table spans as many batches as
+/// it needs.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BackupBatch {
+    table: String,
+    entries: Entries,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackupManifest {
+    version: u32,
+    created_at: u64,
+    total_size: u64,
+    checksum: ByteBuf,
+    chunk_checksums: Vec<ByteBuf>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BackupChunk {
+    index: u32,
+    data: ByteBuf,
+}
+
+enum BackupState {
+    Collecting {
+        created_at: u64,
+        table: usize,
+        after: Option<Vec<u8>>,
+        bytes: Vec<u8>,
+        hasher: Sha256,
+        chunk_checksums: Vec<ByteBuf>,
+    },
+    Done(BackupManifest, Vec<u8>),
+}
+
+enum RestoreStage {
+    Uploading,
+    Verifying {
+        hasher: Sha256,
+        next_chunk: u32,
+    },
+    Writing {
+        bytes: Vec<u8>,
+        offset: usize,
+        entries: u64,
+    },
+}
+
+struct PendingRestore {
+    manifest: BackupManifest,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    stage: RestoreStage,
+}
+
+thread_local! {
+    // Heap only: a backup is taken and downloaded before an upgrade, and a
+    // restore is uploaded and committed after one.
+    static BACKUP: RefCell<Option<BackupState>> = const { RefCell::new(None) };
+
+    static PENDING_RESTORE: RefCell<Option<PendingRestore>> = const { RefCell::new(None) };
+}
+
+/// Starts a backup of every stable structure holding primary data and
+/// collects its first batch. Derived indexes are left out and rebuilt on
+/// restore. Consultations and documents already moved to buckets stay
+/// there; the backup holds the bucket directory and a restore checks that
+/// the buckets still answer to the canister.
+///
+/// Returns the manifest once every table is collected; until then call
+/// `continue_backup`. Writes made in between are only included if they
+/// land after the table's collected part, so take backups while the
+/// canister is quiet.
+#[ic_cdk::update]
+fn create_backup() -> Result<Option<BackupManifest>, Error> {
+    ensure_controller()?;
+
+    let header = BackupHeader {
+        version: BACKUP_VERSION,
+        tables: all_tables()
+            .iter()
+            .map(|table| table.name.to_string())
+            .collect(),
+        buckets: sharding::bucket_canisters(),
+    };
+    let mut bytes = Vec::new();
+    append_frame(
+        &mut bytes,
+        Encode!(&header).expect("Cannot encode backup header"),
+    );
+    BACKUP.with(|backup| {
+        *backup.borrow_mut() = Some(BackupState::Collecting {
+            created_at: time(),
+            table: 0,
+            after: None,
+            bytes,
+            hasher: Sha256::new(),
+            chunk_checksums: Vec::new(),
+        })
+    });
+    continue_backup()
+}
+
+/// Collects the next batch of the backup in progress.
+#[ic_cdk::update]
+fn continue_backup() -> Result<Option<BackupManifest>, Error> {
+    ensure_controller()?;
+
+    BACKUP.with(|backup| {
+        let mut backup = backup.borrow_mut();
+        match backup.as_mut() {
+            Some(BackupState::Collecting {
+                created_at,
+                table,
+                after,
+                bytes,
+                hasher,
+                chunk_checksums,
+            }) => {
+                let tables = all_tables();
+                let mut collected = 0;
+                while collected < BATCH_SIZE && *table < tables.len() {
+                    let source = &tables[*table];
+                    let limit = BATCH_SIZE - collected;
+                    let entries = (source.read)(after.as_deref(), limit);
+                    if entries.len() < limit {
+                        *table += 1;
+                        *after = None;
+                    } else {
+                        *after = entries.last().map(|(key, _)| key.to_vec());
+                    }
+                    collected += entries.len();
+                    if !entries.is_empty() {
+                        let batch = BackupBatch {
+                            table: source.name.to_string(),
+                            entries,
+                        };
+                        append_frame(bytes, Encode!(&batch).expect("Cannot encode backup"));
+                    }
+                }
+
+                // Hashes the chunks completed by this batch, and the last one
+                // once every table is collected.
+                let done = *table == tables.len();
+                let hashed = chunk_checksums.len() * CHUNK_SIZE;
+                for chunk in bytes[hashed..].chunks(CHUNK_SIZE) {
+                    if chunk.len() == CHUNK_SIZE || done {
+                        hasher.update(chunk);
+                        chunk_checksums.push(checksum(chunk));
+                    }
+                }
+                if !done {
+                    return Ok(None);
+                }
+
+                let manifest = BackupManifest {
+                    version: BACKUP_VERSION,
+                    created_at: *created_at,
+                    total_size: bytes.len() as u64,
+                    checksum: ByteBuf::from(hasher.clone().finalize().to_vec()),
+                    chunk_checksums: std::mem::take(chunk_checksums),
+                };
+                *backup = Some(BackupState::Done(manifest.clone(), std::mem::take(bytes)));
+                Ok(Some(manifest))
+            }
+            Some(BackupState::Done(manifest, _)) => Ok(Some(manifest.clone())),
+            None => Err(Error::NotFound {
+                msg: "No backup has been created".to_string(),
+            }),
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_backup_chunk(index: u32) -> Result<BackupChunk, Error> {
+    ensure_controller()?;
+
+    BACKUP.with(|backup| match backup.borrow().as_ref() {
+        Some(BackupState::Done(_, bytes)) => match bytes.chunks(CHUNK_SIZE).nth(index as usize) {
+            Some(data) => Ok(BackupChunk {
+                index,
+                data: ByteBuf::from(data.to_vec()),
+            }),
+            None => Err(Error::NotFound {
+                msg: format!("Backup chunk {} not found", index),
+            }),
+        },
+        Some(BackupState::Collecting { .. }) => Err(Error::InvalidInput {
+            msg: "The backup is still being collected".to_string(),
+        }),
+        None => Err(Error::NotFound {
+            msg: "No backup has been created".to_string(),
+        }),
+    })
+}
+
+/// Starts a restore from the given manifest. Only allowed while the canister
+/// holds no records.
+#[ic_cdk::update]
+fn begin_restore(manifest: BackupManifest) -> Result<(), Error> {
+    ensure_controller()?;
+    ensure_empty()?;
+
+    if manifest.version != BACKUP_VERSION {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Backup version {} is not supported, expected {}",
+                manifest.version, BACKUP_VERSION
+            ),
+        });
+    }
+    let expected_chunks = (manifest.total_size as usize).div_ceil(CHUNK_SIZE);
+    if manifest.chunk_checksums.len() != expected_chunks {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Backup of {} bytes must have {} chunks, manifest lists {}",
+                manifest.total_size,
+                expected_chunks,
+                manifest.chunk_checksums.len()
+            ),
+        });
+    }
+
+    PENDING_RESTORE.with(|pending| {
+        *pending.borrow_mut() = Some(PendingRestore {
+            manifest,
+            chunks: BTreeMap::new(),
+            stage: RestoreStage::Uploading,
+        })
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn upload_restore_chunk(chunk: BackupChunk) -> Result<(), Error> {
+    ensure_controller()?;
+
+    PENDING_RESTORE.with(|pending| match pending.borrow_mut().as_mut() {
+        Some(restore) => {
+            if !matches!(restore.stage, RestoreStage::Uploading) {
+                return Err(Error::InvalidInput {
+                    msg: "The restore is already being committed".to_string(),
+                });
+            }
+            match restore.manifest.chunk_checksums.get(chunk.index as usize) {
+                Some(expected) => {
+                    if checksum(&chunk.data) != *expected {
+                        return Err(Error::InvalidInput {
+                            msg: format!("Checksum mismatch for backup chunk {}", chunk.index),
+                        });
+                    }
+                    restore.chunks.insert(chunk.index, chunk.data.into_vec());
+                    Ok(())
+                }
+                None => Err(Error::InvalidInput {
+                    msg: format!("Backup has no chunk {}", chunk.index),
+                }),
+            }
+        }
+        None => Err(Error::NotFound {
+            msg: "No restore in progress".to_string(),
+        }),
+    })
+}
+
+/// Verifies the uploaded backup as a whole and only then writes it to
+/// stable memory, a batch per call. Returns the number of restored entries
+/// once the last batch is written; until then call it again.
+#[ic_cdk::update]
+async fn commit_restore() -> Result<Option<u64>, Error> {
+    ensure_controller()?;
+
+    let mut restore = match PENDING_RESTORE.with(|pending| pending.borrow_mut().take()) {
+        Some(restore) => restore,
+        None => {
+            return Err(Error::NotFound {
+                msg: "No restore in progress".to_string(),
+            })
+        }
+    };
+    let result = commit_batch(&mut restore).await;
+    // Kept until the last batch is written, or to retry after an error
+    // that left stable memory untouched.
+    let finished = matches!(result, Ok(Some(_)))
+        || matches!(restore.stage, RestoreStage::Writing { .. }) && result.is_err();
+    if !finished {
+        PENDING_RESTORE.with(|pending| *pending.borrow_mut() = Some(restore));
+    }
+    result
+}
+
+async fn commit_batch(restore: &mut PendingRestore) -> Result<Option<u64>, Error> {
+    if let RestoreStage::Uploading = restore.stage {
+        ensure_empty()?;
+        let chunk_count = restore.manifest.chunk_checksums.len() as u32;
+        if let Some(missing) = (0..chunk_count).find(|index| !restore.chunks.contains_key(index)) {
+            return Err(Error::InvalidInput {
+                msg: format!("Backup chunk {} has not been uploaded", missing),
+            });
+        }
+        restore.stage = RestoreStage::Verifying {
+            hasher: Sha256::new(),
+            next_chunk: 0,
+        };
+    }
+
+    if let RestoreStage::Verifying { hasher, next_chunk } = &mut restore.stage {
+        let chunk_count = restore.manifest.chunk_checksums.len() as u32;
+        let last = chunk_count.min(*next_chunk + VERIFY_BATCH);
+        for index in *next_chunk..last {
+            hasher.update(&restore.chunks[&index]);
+        }
+        *next_chunk = last;
+        if last < chunk_count {
+            return Ok(None);
+        }
+
+        if ByteBuf::from(hasher.clone().finalize().to_vec()) != restore.manifest.checksum {
+            restore.stage = RestoreStage::Uploading;
+            return Err(Error::InvalidInput {
+                msg: "Backup checksum mismatch".to_string(),
+            });
+        }
+        let bytes: Vec<u8> = std::mem::take(&mut restore.chunks)
+            .into_values()
+            .flatten()
+            .collect();
+        let (header, offset) = match read_frame(&bytes, 0)
+            .and_then(|(frame, offset)| Some((Decode!(frame, BackupHeader).ok()?, offset)))
+        {
+            Some(header) => header,
+            None => {
+                return Err(Error::InvalidInput {
+                    msg: "Cannot decode the backup header".to_string(),
+                })
+            }
+        };
+        check_header(&header)?;
+        // Checked before anything is written: the buckets are not copied and
+        // only answer to the canister that created them.
+        sharding::ensure_buckets_owned(&header.buckets).await?;
+        ensure_empty()?;
+        restore.stage = RestoreStage::Writing {
+            bytes,
+            offset,
+            entries: 0,
+        };
+    }
+
+    if let RestoreStage::Writing {
+        bytes,
+        offset,
+        entries,
+    } = &mut restore.stage
+    {
+        let tables = all_tables();
+        let mut written = 0;
+        while written < BATCH_SIZE && *offset < bytes.len() {
+            let batch = read_frame(bytes, *offset)
+                .and_then(|(frame, next)| Some((Decode!(frame, BackupBatch).ok()?, next)));
+            let (batch, next) = match batch {
+                Some(batch) => batch,
+                None => {
+                    return Err(Error::InvalidInput {
+                        msg: format!("Cannot decode the backup at byte {}", offset),
+                    })
+                }
+            };
+            let source = match tables.iter().find(|table| table.name == batch.table) {
+                Some(source) => source,
+                None => {
+                    return Err(Error::InvalidInput {
+                        msg: format!("Backup table {} is not in the schema", batch.table),
+                    })
+                }
+            };
+            (source.write)(&batch.entries);
+            if !source.cell {
+                *entries += batch.entries.len() as u64;
+            }
+            written += batch.entries.len();
+            *offset = next;
+        }
+        if *offset < bytes.len() {
+            return Ok(None);
+        }
+
+        let entries = *entries;
+        search::rebuild_search_indexes().await?;
+        http::certify_responses();
+        return Ok(Some(entries));
+    }
+    Ok(None)
+}
+
+fn check_header(header: &BackupHeader) -> Result<(), Error> {
+    if header.version != BACKUP_VERSION {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Backup version {} is not supported, expected {}",
+                header.version, BACKUP_VERSION
+            ),
+        });
+    }
+
+    let mut expected: Vec<String> = all_tables()
+        .iter()
+        .map(|table| table.name.to_string())
+        .collect();
+    let mut found = header.tables.clone();
+    expected.sort();
+    found.sort();
+    if expected != found {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Backup tables {:?} do not match the schema {:?}",
+                found, expected
+            ),
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn map_source<K, V>(
+    name: &'static str,
+    map: &'static LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+) -> TableSource
+where
+    K: BoundedStorable + Ord + Clone + 'static,
+    V: BoundedStorable + 'static,
+{
+    TableSource {
+        name,
+        cell: false,
+        is_empty: Box::new(move || map.with(|map| map.borrow().is_empty())),
+        read: Box::new(move |after, limit| {
+            map.with(|map| {
+                let map = map.borrow();
+                let entries = match after {
+                    Some(key) => map.range((
+                        Bound::Excluded(K::from_bytes(Cow::Borrowed(key))),
+                        Bound::Unbounded,
+                    )),
+                    None => map.iter(),
+                };
+                entries
+                    .take(limit)
+                    .map(|(key, value)| {
+                        (
+                            ByteBuf::from(key.to_bytes().into_owned()),
+                            ByteBuf::from(value.to_bytes().into_owned()),
+                        )
+                    })
+                    .collect()
+            })
+        }),
+        write: Box::new(move |entries| {
+            map.with(|map| {
+                let mut map = map.borrow_mut();
+                for (key, value) in entries {
+                    map.insert(
+                        K::from_bytes(Cow::Borrowed(key)),
+                        V::from_bytes(Cow::Borrowed(value)),
+                    );
+                }
+            })
+        }),
+    }
+}
+
+pub(crate) fn cell_source<T: Storable + 'static>(
+    name: &'static str,
+    cell: &'static LocalKey<RefCell<Cell<T, Memory>>>,
+) -> TableSource {
+    TableSource {
+        name,
+        cell: true,
+        // A cell always holds a value; only its default is restored over.
+        is_empty: Box::new(|| true),
+        read: Box::new(move |after, _| match after {
+            Some(_) => Vec::new(),
+            None => cell.with(|cell| {
+                vec![(
+                    ByteBuf::new(),
+                    ByteBuf::from(cell.borrow().get().to_bytes().into_owned()),
+                )]
+            }),
+        }),
+        write: Box::new(move |entries| {
+            if let Some((_, value)) = entries.first() {
+                cell.with(|cell| cell.borrow_mut().set(T::from_bytes(Cow::Borrowed(value))))
+                    .unwrap_or_else(|_| panic!("Cannot restore {}", name));
+            }
+        }),
+    }
+}
+
+fn all_tables() -> Vec<TableSource> {
+    let mut tables = crate::backup_tables();
+    tables.extend(payments::backup_tables());
+    tables.extend(trust::backup_tables());
+    tables.extend(billing::backup_tables());
+    tables.extend(changes::backup_tables());
+    tables.extend(privacy::backup_tables());
+    tables.extend(retention::backup_tables());
+    tables.extend(trash::backup_tables());
+    tables.extend(organizations::backup_tables());
+    tables.extend(archive::backup_tables());
+    tables.extend(matters::backup_tables());
+    tables.extend(participants::backup_tables());
+    tables.extend(handoffs::backup_tables());
+    tables.extend(intake::backup_tables());
+    tables.extend(forms::backup_tables());
+    tables.extend(custom_fields::backup_tables());
+    tables.extend(workflows::backup_tables());
+    tables.extend(tasks::backup_tables());
+    tables.extend(notes::backup_tables());
+    tables.extend(quotes::backup_tables());
+    tables.extend(documents::backup_tables());
+    tables.extend(sharding::backup_tables());
+    tables
+}
+
+fn ensure_empty() -> Result<(), Error> {
+    if all_tables().iter().any(|table| !(table.is_empty)()) {
+        return Err(Error::InvalidInput {
+            msg: "Backups can only be restored into an empty canister".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Frames are a big-endian u32 length followed by a candid value.
+fn append_frame(bytes: &mut Vec<u8>, frame: Vec<u8>) {
+    bytes.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&frame);
+}
+
+fn read_frame(bytes: &[u8], offset: usize) -> Option<(&[u8], usize)> {
+    let start = offset.checked_add(4)?;
+    let len = u32::from_be_bytes(bytes.get(offset..start)?.try_into().ok()?) as usize;
+    let end = start.checked_add(len)?;
+    Some((bytes.get(start..end)?, end))
+}
+
+fn ensure_controller() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers can back up or restore the canister".to_string(),
+        })
+    }
+}
+
+fn checksum(bytes: &[u8]) -> ByteBuf {
+    ByteBuf::from(Sha256::digest(bytes).to_vec())
+}
@@ -0,0 +1,996 @@
+use crate::{
+    _get_legal_advisor, _get_legal_consultation, archive, backup, changes, ensure_firm_staff,
+    firm_legal_client, firm_legal_consultation, next_id, organizations, quotes,
+    scoped_legal_advisor, sharding, ChangeEntity, Error, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// UTBMS litigation (L) and counseling (C) task codes.
+const UTBMS_TASK_CODES: &[&str] = &[
+    "L110", "L120", "L130", "L140", "L150", "L160", "L190", "L210", "L220", "L230", "L240", "L250",
+    "L260", "L290", "L310", "L320", "L330", "L340", "L350", "L390", "L410", "L420", "L430", "L440",
+    "L450", "L460", "L470", "L480", "L490", "L510", "L520", "L530", "C100", "C200", "C300", "C400",
+];
+
+const UTBMS_ACTIVITY_CODES: &[&str] = &[
+    "A101", "A102", "A103", "A104", "A105", "A106", "A107", "A108", "A109", "A110", "A111",
+];
+
+const UTBMS_EXPENSE_CODES: &[&str] = &[
+    "E101", "E102", "E103", "E104", "E105", "E106", "E107", "E108", "E109", "E110", "E111", "E112",
+    "E113", "E114", "E115", "E116", "E117", "E118", "E119", "E120", "E121", "E122", "E123", "E124",
+];
+
+const LEDES_1998B_FIELDS: &[&str] = &[
+    "INVOICE_DATE",
+    "INVOICE_NUMBER",
+    "CLIENT_ID",
+    "LAW_FIRM_MATTER_ID",
+    "INVOICE_TOTAL",
+    "BILLING_START_DATE",
+    "BILLING_END_DATE",
+    "INVOICE_DESCRIPTION",
+    "LINE_ITEM_NUMBER",
+    "EXP/FEE/INV_ADJ_TYPE",
+    "LINE_ITEM_NUMBER_OF_UNITS",
+    "LINE_ITEM_ADJUSTMENT_AMOUNT",
+    "LINE_ITEM_TOTAL",
+    "LINE_ITEM_DATE",
+    "LINE_ITEM_TASK_CODE",
+    "LINE_ITEM_EXPENSE_CODE",
+    "LINE_ITEM_ACTIVITY_CODE",
+    "TIMEKEEPER_ID",
+    "LINE_ITEM_DESCRIPTION",
+    "LAW_FIRM_ID",
+    "LINE_ITEM_UNIT_COST",
+    "TIMEKEEPER_NAME",
+    "TIMEKEEPER_CLASSIFICATION",
+    "CLIENT_MATTER_ID",
+];
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+// Keep invoices and entries inside their storage bounds.
+const MAX_INVOICE_ENTRIES: usize = 256;
+const MAX_INVOICE_DESCRIPTION_BYTES: usize = 1024;
+const MAX_ENTRY_DESCRIPTION_BYTES: usize = 512;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct BillingConfig {
+    law_firm_id: String,
+}
+
+impl Storable for BillingConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// LEDES timekeeper classifications.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum TimekeeperClassification {
+    Partner,
+    Associate,
+    OfCounsel,
+    Paralegal,
+    LegalAssistant,
+    Other,
+}
+
+impl TimekeeperClassification {
+    fn code(self) -> &'static str {
+        match self {
+            TimekeeperClassification::Partner => "PT",
+            TimekeeperClassification::Associate => "AS",
+            TimekeeperClassification::OfCounsel => "OC",
+            TimekeeperClassification::Paralegal => "PL",
+            TimekeeperClassification::LegalAssistant => "LA",
+            TimekeeperClassification::Other => "OT",
+        }
+    }
+}
+
+impl Storable for TimekeeperClassification {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TimekeeperClassification {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum BillingEntryKind {
+    Fee,
+    Expense,
+}
+
+/// A time (fee) or expense entry. Units are kept in hundredths (hours for
+/// fees, quantity for expenses) and money in minor currency units.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BillingEntry {
+    id: u64,
+    consultation_id: u64,
+    kind: BillingEntryKind,
+    timekeeper_id: u64,
+    date: u64,
+    units: u64,
+    unit_cost: u64,
+    total: u64,
+    task_code: Option<String>,
+    activity_code: Option<String>,
+    expense_code: Option<String>,
+    description: String,
+    invoice_id: Option<u64>,
+    created_at: u64,
+}
+
+impl Storable for BillingEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BillingEntry {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Invoice {
+    id: u64,
+    consultation_id: u64,
+    client_id: u64,
+    invoice_date: u64,
+    billing_start: u64,
+    billing_end: u64,
+    description: String,
+    entry_ids: Vec<u64>,
+    total: u64,
+    // Set once the client has settled the invoice.
+    paid_at: Option<u64>,
+}
+
+impl Storable for Invoice {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Invoice {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LedesValidation {
+    valid: bool,
+    errors: Vec<String>,
+}
+
+thread_local! {
+    static BILLING_ENTRIES: RefCell<StableBTreeMap<u64, BillingEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static INVOICES: RefCell<StableBTreeMap<u64, Invoice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static BILLING_CONFIG: RefCell<Cell<BillingConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+            BillingConfig::default(),
+        )
+        .expect("Cannot create billing config")
+    );
+
+    // Keyed by advisor id; unclassified timekeepers are exported as "OT".
+    static TIMEKEEPER_CLASSIFICATIONS: RefCell<StableBTreeMap<u64, TimekeeperClassification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+}
+
+#[ic_cdk::update]
+fn configure_billing(law_firm_id: String) -> Result<BillingConfig, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can configure billing".to_string(),
+        });
+    }
+
+    let config = BillingConfig { law_firm_id };
+    BILLING_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("Cannot update billing config");
+    Ok(config)
+}
+
+#[ic_cdk::query]
+fn get_billing_config() -> BillingConfig {
+    BILLING_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+/// Sets or clears how the advisor is classified as a timekeeper in LEDES
+/// exports.
+#[ic_cdk::update]
+fn set_timekeeper_classification(
+    advisor_id: u64,
+    classification: Option<TimekeeperClassification>,
+) -> Result<(), Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+    if scoped_legal_advisor(&advisor_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", advisor_id),
+        });
+    }
+
+    TIMEKEEPER_CLASSIFICATIONS.with(|service| {
+        let mut classifications = service.borrow_mut();
+        match classification {
+            Some(classification) => classifications.insert(advisor_id, classification),
+            None => classifications.remove(&advisor_id),
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_timekeeper_classification(advisor_id: u64) -> Result<TimekeeperClassification, Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+    if scoped_legal_advisor(&advisor_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", advisor_id),
+        });
+    }
+
+    Ok(classification_of(advisor_id))
+}
+
+/// Records billable time; `hours` are in hundredths of an hour and `rate` is
+/// in minor currency units per hour.
+#[ic_cdk::update]
+#[allow(clippy::too_many_arguments)]
+async fn record_time_entry(
+    consultation_id: u64,
+    timekeeper_id: u64,
+    date: u64,
+    hours: u64,
+    rate: u64,
+    task_code: String,
+    activity_code: String,
+    description: String,
+) -> Result<BillingEntry, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let entry = BillingEntry {
+        id: 0,
+        consultation_id,
+        kind: BillingEntryKind::Fee,
+        timekeeper_id,
+        date,
+        units: hours,
+        unit_cost: rate,
+        total: line_total(hours, rate).ok_or_else(total_overflow)?,
+        task_code: Some(task_code),
+        activity_code: Some(activity_code),
+        expense_code: None,
+        description,
+        invoice_id: None,
+        created_at: time(),
+    };
+    insert_new_entry(entry)
+}
+
+/// Records an expense; `quantity` is in hundredths of a unit.
+#[ic_cdk::update]
+async fn record_expense_entry(
+    consultation_id: u64,
+    timekeeper_id: u64,
+    date: u64,
+    quantity: u64,
+    unit_cost: u64,
+    expense_code: String,
+    description: String,
+) -> Result<BillingEntry, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let entry = BillingEntry {
+        id: 0,
+        consultation_id,
+        kind: BillingEntryKind::Expense,
+        timekeeper_id,
+        date,
+        units: quantity,
+        unit_cost,
+        total: line_total(quantity, unit_cost).ok_or_else(total_overflow)?,
+        task_code: None,
+        activity_code: None,
+        expense_code: Some(expense_code),
+        description,
+        invoice_id: None,
+        created_at: time(),
+    };
+    insert_new_entry(entry)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn list_billing_entries(consultation_id: u64) -> Result<Vec<BillingEntry>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    ensure_firm_staff(&ic_cdk::caller())?;
+    ensure_consultation_in_scope(consultation_id)?;
+
+    Ok(entries_for_consultation(consultation_id))
+}
+
+/// Bills every unbilled entry of the consultation dated within the period.
+#[ic_cdk::update]
+async fn create_invoice(
+    consultation_id: u64,
+    client_id: u64,
+    billing_start: u64,
+    billing_end: u64,
+    description: String,
+) -> Result<Invoice, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    if firm_legal_consultation(&consultation_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", consultation_id),
+        });
+    }
+    if firm_legal_client(&client_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal client with id={} not found", client_id),
+        });
+    }
+    if billing_start > billing_end {
+        return Err(Error::InvalidInput {
+            msg: "Billing period starts after it ends".to_string(),
+        });
+    }
+    if description.len() > MAX_INVOICE_DESCRIPTION_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Invoice descriptions are at most {} bytes",
+                MAX_INVOICE_DESCRIPTION_BYTES
+            ),
+        });
+    }
+
+    let entries: Vec<BillingEntry> = BILLING_ENTRIES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| {
+                entry.consultation_id == consultation_id
+                    && entry.invoice_id.is_none()
+                    && (billing_start..=billing_end).contains(&entry.date)
+            })
+            .collect()
+    });
+    if entries.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} has no unbilled entries in the period",
+                consultation_id
+            ),
+        });
+    }
+    if entries.len() > MAX_INVOICE_ENTRIES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "The period has {} unbilled entries; an invoice bills at most {}, so invoice a shorter period",
+                entries.len(),
+                MAX_INVOICE_ENTRIES
+            ),
+        });
+    }
+
+    let total = entries
+        .iter()
+        .try_fold(0u64, |total, entry| total.checked_add(entry.total))
+        .ok_or_else(total_overflow)?;
+    let invoice = Invoice {
+        id: next_id(),
+        consultation_id,
+        client_id,
+        invoice_date: time(),
+        billing_start,
+        billing_end,
+        description,
+        entry_ids: entries.iter().map(|entry| entry.id).collect(),
+        total,
+        paid_at: None,
+    };
+
+    for mut entry in entries {
+        entry.invoice_id = Some(invoice.id);
+        do_insert_billing_entry(&entry);
+    }
+    do_insert_invoice(&invoice);
+    Ok(invoice)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_invoice(id: u64) -> Result<Invoice, Error> {
+    load_invoice_consultation(id).await?;
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    match scoped_invoice(&id) {
+        Some(invoice) => Ok(invoice),
+        None => Err(invoice_not_found(id)),
+    }
+}
+
+/// Records that the client has paid the invoice in full.
+#[ic_cdk::update]
+async fn record_invoice_payment(invoice_id: u64) -> Result<Invoice, Error> {
+    load_invoice_consultation(invoice_id).await?;
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    match scoped_invoice(&invoice_id) {
+        Some(invoice) if invoice.paid_at.is_some() => Err(Error::InvalidInput {
+            msg: format!("Invoice with id={} is already paid", invoice_id),
+        }),
+        Some(mut invoice) => {
+            invoice.paid_at = Some(time());
+            do_insert_invoice(&invoice);
+            Ok(invoice)
+        }
+        None => Err(invoice_not_found(invoice_id)),
+    }
+}
+
+/// Checks an invoice against the UTBMS code sets and its own totals.
+#[ic_cdk::query(composite = true)]
+async fn validate_ledes_invoice(invoice_id: u64) -> Result<LedesValidation, Error> {
+    load_invoice_consultation(invoice_id).await?;
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    match scoped_invoice(&invoice_id) {
+        Some(invoice) => {
+            let errors = invoice_errors(&invoice);
+            Ok(LedesValidation {
+                valid: errors.is_empty(),
+                errors,
+            })
+        }
+        None => Err(invoice_not_found(invoice_id)),
+    }
+}
+
+/// Renders an invoice as a LEDES 1998B file.
+#[ic_cdk::query(composite = true)]
+async fn export_invoice_ledes(invoice_id: u64) -> Result<String, Error> {
+    load_invoice_consultation(invoice_id).await?;
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    match scoped_invoice(&invoice_id) {
+        Some(invoice) => render_ledes(&[invoice]),
+        None => Err(invoice_not_found(invoice_id)),
+    }
+}
+
+/// Renders every invoice whose billing period overlaps `[start, end]` as a
+/// LEDES 1998B file. Invoices are exported whole so each one's line items
+/// add up to its INVOICE_TOTAL.
+#[ic_cdk::query(composite = true)]
+async fn export_ledes_range(start: u64, end: u64) -> Result<String, Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    let overlapping = || {
+        INVOICES.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, invoice)| invoice)
+                .filter(|invoice| invoice.billing_start <= end && invoice.billing_end >= start)
+                .collect::<Vec<Invoice>>()
+        })
+    };
+    let consultation_ids: Vec<u64> = overlapping()
+        .iter()
+        .map(|invoice| invoice.consultation_id)
+        .collect();
+    sharding::load_consultations(&consultation_ids).await?;
+
+    let invoices: Vec<Invoice> = overlapping()
+        .into_iter()
+        .filter(|invoice| consultation_in_scope(invoice.consultation_id))
+        .collect();
+    render_ledes(&invoices)
+}
+
+fn insert_new_entry(mut entry: BillingEntry) -> Result<BillingEntry, Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    if firm_legal_consultation(&entry.consultation_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!(
+                "Legal consultation with id={} not found",
+                entry.consultation_id
+            ),
+        });
+    }
+    if scoped_legal_advisor(&entry.timekeeper_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", entry.timekeeper_id),
+        });
+    }
+    let errors = entry_errors(&entry);
+    if !errors.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: errors.join("; "),
+        });
+    }
+    quotes::ensure_fee_agreement(entry.consultation_id)?;
+    if entry.kind == BillingEntryKind::Fee {
+        ensure_within_fee_cap(&entry)?;
+    }
+
+    entry.id = next_id();
+    do_insert_billing_entry(&entry);
+    Ok(entry)
+}
+
+/// Keeps fees on a consultation within the flat fee or cap the client
+/// accepted.
+fn ensure_within_fee_cap(entry: &BillingEntry) -> Result<(), Error> {
+    let cap = match quotes::fee_cap(entry.consultation_id) {
+        Some(cap) => cap,
+        None => return Ok(()),
+    };
+    let billed: u64 = entries_for_consultation(entry.consultation_id)
+        .iter()
+        .filter(|billed| billed.kind == BillingEntryKind::Fee)
+        .map(|billed| billed.total)
+        .sum();
+    if billed.saturating_add(entry.total) > cap {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Fees on legal consultation with id={} would exceed the accepted limit of {}",
+                entry.consultation_id, cap
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Billing records stay with the consultation's organization, also once it
+/// is archived; controllers also reach records whose consultation has since
+/// been deleted.
+fn consultation_in_scope(consultation_id: u64) -> bool {
+    let caller = ic_cdk::caller();
+    ic_cdk::api::is_controller(&caller)
+        || firm_legal_consultation(&consultation_id).is_some()
+        || archive::archived_in(&consultation_id, &caller).is_some()
+}
+
+fn ensure_consultation_in_scope(consultation_id: u64) -> Result<(), Error> {
+    if consultation_in_scope(consultation_id) {
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", consultation_id),
+        })
+    }
+}
+
+fn scoped_invoice(id: &u64) -> Option<Invoice> {
+    _get_invoice(id).filter(|invoice| consultation_in_scope(invoice.consultation_id))
+}
+
+/// The organization's own law firm id, falling back to the canister-wide one.
+fn law_firm_id(invoice: &Invoice) -> String {
+    let organization_id = _get_legal_consultation(&invoice.consultation_id)
+        .and_then(|consultation| consultation.organization_id)
+        .or_else(|| archive::archived_organization(&invoice.consultation_id));
+    organizations::law_firm_id(organization_id).unwrap_or_else(|| get_billing_config().law_firm_id)
+}
+
+fn entry_errors(entry: &BillingEntry) -> Vec<String> {
+    let mut errors = Vec::new();
+    // New entries are validated before they are given an id.
+    let name = if entry.id == 0 {
+        "The entry".to_string()
+    } else {
+        format!("Entry {}", entry.id)
+    };
+    let code_valid = |code: &Option<String>, codes: &[&str]| {
+        code.as_deref().is_some_and(|code| codes.contains(&code))
+    };
+
+    match entry.kind {
+        BillingEntryKind::Fee => {
+            if !code_valid(&entry.task_code, UTBMS_TASK_CODES) {
+                errors.push(format!(
+                    "{} has an invalid UTBMS task code {:?}",
+                    name, entry.task_code
+                ));
+            }
+            if !code_valid(&entry.activity_code, UTBMS_ACTIVITY_CODES) {
+                errors.push(format!(
+                    "{} has an invalid UTBMS activity code {:?}",
+                    name, entry.activity_code
+                ));
+            }
+        }
+        BillingEntryKind::Expense => {
+            if !code_valid(&entry.expense_code, UTBMS_EXPENSE_CODES) {
+                errors.push(format!(
+                    "{} has an invalid UTBMS expense code {:?}",
+                    name, entry.expense_code
+                ));
+            }
+        }
+    }
+    if entry.units == 0 {
+        errors.push(format!("{} has no units", name));
+    }
+    if entry.description.len() > MAX_ENTRY_DESCRIPTION_BYTES {
+        errors.push(format!(
+            "{} description is longer than {} bytes",
+            name, MAX_ENTRY_DESCRIPTION_BYTES
+        ));
+    }
+    if line_total(entry.units, entry.unit_cost) != Some(entry.total) {
+        errors.push(format!(
+            "{} total {} does not match units x unit cost",
+            name,
+            format_decimal(entry.total)
+        ));
+    }
+    errors
+}
+
+fn invoice_errors(invoice: &Invoice) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if law_firm_id(invoice).is_empty() {
+        errors.push("Law firm id is not configured".to_string());
+    }
+    if invoice.billing_start > invoice.billing_end {
+        errors.push(format!(
+            "Invoice {} billing period starts after it ends",
+            invoice.id
+        ));
+    }
+
+    let mut line_sum: u64 = 0;
+    for entry_id in &invoice.entry_ids {
+        match _get_billing_entry(entry_id) {
+            Some(entry) => {
+                errors.extend(entry_errors(&entry));
+                if entry.invoice_id != Some(invoice.id) {
+                    errors.push(format!(
+                        "Entry {} is not billed on invoice {}",
+                        entry.id, invoice.id
+                    ));
+                }
+                if !(invoice.billing_start..=invoice.billing_end).contains(&entry.date) {
+                    errors.push(format!(
+                        "Entry {} is dated outside the billing period",
+                        entry.id
+                    ));
+                }
+                line_sum = line_sum.saturating_add(entry.total);
+            }
+            None => errors.push(format!("Entry {} not found", entry_id)),
+        }
+    }
+    if line_sum != invoice.total {
+        errors.push(format!(
+            "Invoice {} total {} does not match line items {}",
+            invoice.id,
+            format_decimal(invoice.total),
+            format_decimal(line_sum)
+        ));
+    }
+    errors
+}
+
+fn render_ledes(invoices: &[Invoice]) -> Result<String, Error> {
+    let errors: Vec<String> = invoices.iter().flat_map(invoice_errors).collect();
+    if !errors.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: errors.join("; "),
+        });
+    }
+
+    let mut ledes = String::from("LEDES1998B[]\n");
+    ledes.push_str(&LEDES_1998B_FIELDS.join("|"));
+    ledes.push_str("[]\n");
+
+    for invoice in invoices {
+        let law_firm_id = law_firm_id(invoice);
+        let entries = invoice.entry_ids.iter().filter_map(_get_billing_entry);
+
+        for (line, entry) in entries.enumerate() {
+            let (timekeeper_name, timekeeper_classification) =
+                match _get_legal_advisor(&entry.timekeeper_id) {
+                    Some(advisor) => (
+                        advisor.name,
+                        classification_of(entry.timekeeper_id).code().to_string(),
+                    ),
+                    None => (String::new(), String::new()),
+                };
+            let fields = [
+                ledes_date(invoice.invoice_date),
+                invoice.id.to_string(),
+                invoice.client_id.to_string(),
+                invoice.consultation_id.to_string(),
+                format_decimal(invoice.total),
+                ledes_date(invoice.billing_start),
+                ledes_date(invoice.billing_end),
+                sanitize(&invoice.description),
+                (line + 1).to_string(),
+                match entry.kind {
+                    BillingEntryKind::Fee => "F".to_string(),
+                    BillingEntryKind::Expense => "E".to_string(),
+                },
+                format_decimal(entry.units),
+                format_decimal(0),
+                format_decimal(entry.total),
+                ledes_date(entry.date),
+                entry.task_code.clone().unwrap_or_default(),
+                entry.expense_code.clone().unwrap_or_default(),
+                entry.activity_code.clone().unwrap_or_default(),
+                entry.timekeeper_id.to_string(),
+                sanitize(&entry.description),
+                sanitize(&law_firm_id),
+                format_decimal(entry.unit_cost),
+                sanitize(&timekeeper_name),
+                timekeeper_classification,
+                String::new(),
+            ];
+            ledes.push_str(&fields.join("|"));
+            ledes.push_str("[]\n");
+        }
+    }
+
+    Ok(ledes)
+}
+
+/// Amount of `units` hundredths at `unit_cost`, rounded to the nearest minor
+/// unit, or `None` if it does not fit into a `u64`.
+fn line_total(units: u64, unit_cost: u64) -> Option<u64> {
+    u64::try_from((units as u128 * unit_cost as u128 + 50) / 100).ok()
+}
+
+fn total_overflow() -> Error {
+    Error::InvalidInput {
+        msg: "Billing total is too large".to_string(),
+    }
+}
+
+fn format_decimal(hundredths: u64) -> String {
+    format!("{}.{:02}", hundredths / 100, hundredths % 100)
+}
+
+/// Formats a nanosecond timestamp as a LEDES `YYYYMMDD` date.
+fn ledes_date(timestamp: u64) -> String {
+    // Civil-from-days conversion for the proleptic Gregorian calendar.
+    let days = (timestamp / NANOS_PER_DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// Strips characters that would break the pipe-delimited LEDES layout.
+fn sanitize(text: &str) -> String {
+    text.replace("[]", "")
+        .replace(['|', '\n', '\r'], " ")
+        .trim()
+        .to_string()
+}
+
+fn invoice_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Invoice with id={} not found", id),
+    }
+}
+
+pub(crate) fn has_unbilled_entries(consultation_id: u64) -> bool {
+    BILLING_ENTRIES.with(|service| {
+        service.borrow().iter().any(|(_, entry)| {
+            entry.consultation_id == consultation_id && entry.invoice_id.is_none()
+        })
+    })
+}
+
+pub(crate) fn all_invoices_paid(consultation_id: u64) -> bool {
+    INVOICES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, invoice)| invoice.consultation_id == consultation_id)
+            .all(|(_, invoice)| invoice.paid_at.is_some())
+    })
+}
+
+pub(crate) fn entries_for_consultation(consultation_id: u64) -> Vec<BillingEntry> {
+    BILLING_ENTRIES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.consultation_id == consultation_id)
+            .collect()
+    })
+}
+
+pub(crate) fn invoices_for_client(client_id: u64) -> Vec<Invoice> {
+    INVOICES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, invoice)| invoice)
+            .filter(|invoice| invoice.client_id == client_id)
+            .collect()
+    })
+}
+
+fn do_insert_billing_entry(entry: &BillingEntry) {
+    BILLING_ENTRIES.with(|service| service.borrow_mut().insert(entry.id, entry.clone()));
+    changes::record_upsert(ChangeEntity::BillingEntry, entry.id, entry);
+}
+
+fn do_insert_invoice(invoice: &Invoice) {
+    INVOICES.with(|service| service.borrow_mut().insert(invoice.id, invoice.clone()));
+    changes::record_upsert(ChangeEntity::Invoice, invoice.id, invoice);
+}
+
+fn classification_of(advisor_id: u64) -> TimekeeperClassification {
+    TIMEKEEPER_CLASSIFICATIONS
+        .with(|service| service.borrow().get(&advisor_id))
+        .unwrap_or(TimekeeperClassification::Other)
+}
+
+fn _get_billing_entry(id: &u64) -> Option<BillingEntry> {
+    BILLING_ENTRIES.with(|service| service.borrow().get(id))
+}
+
+/// Loads the consultation of the invoice, if there is one, for the checks
+/// and exports that read it.
+async fn load_invoice_consultation(id: u64) -> Result<(), Error> {
+    let consultation_ids: Vec<u64> = _get_invoice(&id)
+        .map(|invoice| invoice.consultation_id)
+        .into_iter()
+        .collect();
+    sharding::load_consultations(&consultation_ids).await
+}
+
+fn _get_invoice(id: &u64) -> Option<Invoice> {
+    INVOICES.with(|service| service.borrow().get(id))
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("billing_entries", &BILLING_ENTRIES),
+        backup::map_source("invoices", &INVOICES),
+        backup::cell_source("billing_config", &BILLING_CONFIG),
+        backup::map_source("timekeeper_classifications", &TIMEKEEPER_CLASSIFICATIONS),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ledes_dates_are_civil_dates() {
+        assert_eq!(ledes_date(0), "19700101");
+        // 2000-02-29, a leap day in a century leap year.
+        assert_eq!(ledes_date(11_016 * NANOS_PER_DAY), "20000229");
+        assert_eq!(ledes_date(11_017 * NANOS_PER_DAY), "20000301");
+        // 2024-12-31, the last second of the day.
+        assert_eq!(
+            ledes_date(20_088 * NANOS_PER_DAY + NANOS_PER_DAY - 1),
+            "20241231"
+        );
+        assert_eq!(ledes_date(20_089 * NANOS_PER_DAY), "20250101");
+    }
+
+    #[test]
+    fn amounts_are_formatted_with_two_decimals() {
+        assert_eq!(format_decimal(0), "0.00");
+        assert_eq!(format_decimal(5), "0.05");
+        assert_eq!(format_decimal(123_456), "1234.56");
+    }
+
+    #[test]
+    fn line_totals_round_to_the_nearest_minor_unit() {
+        // 1.5 hours at 20000 minor units an hour.
+        assert_eq!(line_total(150, 20_000), Some(30_000));
+        // 0.33 units at 5 minor units is 1.65, rounded up.
+        assert_eq!(line_total(33, 5), Some(2));
+        assert_eq!(line_total(u64::MAX, u64::MAX), None);
+    }
+
+    #[test]
+    fn entries_without_an_id_are_named_in_errors() {
+        let mut entry = BillingEntry {
+            id: 0,
+            consultation_id: 1,
+            kind: BillingEntryKind::Fee,
+            timekeeper_id: 2,
+            date: 0,
+            units: 0,
+            unit_cost: 20_000,
+            total: 0,
+            task_code: Some("L110".to_string()),
+            activity_code: Some("A999".to_string()),
+            expense_code: None,
+            description: String::new(),
+            invoice_id: None,
+            created_at: 0,
+        };
+        assert_eq!(
+            entry_errors(&entry),
+            vec![
+                "The entry has an invalid UTBMS activity code Some(\"A999\")",
+                "The entry has no units",
+            ]
+        );
+
+        entry.id = 7;
+        entry.activity_code = Some("A101".to_string());
+        assert_eq!(entry_errors(&entry), vec!["Entry 7 has no units"]);
+    }
+
+    #[test]
+    fn unclassified_timekeepers_are_exported_as_other() {
+        assert_eq!(classification_of(2).code(), "OT");
+        TIMEKEEPER_CLASSIFICATIONS.with(|service| {
+            service
+                .borrow_mut()
+                .insert(2, TimekeeperClassification::Partner)
+        });
+        assert_eq!(classification_of(2).code(), "PT");
+    }
+
+    #[test]
+    fn sanitize_removes_ledes_delimiters() {
+        assert_eq!(
+            sanitize(" Review | draft[]\nlease\r "),
+            "Review   draft lease"
+        );
+    }
+}
@@ -0,0 +1,507 @@
+use crate::{backup, next_id, Error, Memory, MEMORY_MANAGER};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const DEFAULT_CHANGES_LIMIT: usize = 100;
+const MAX_CHANGES_LIMIT: usize = 1000;
+// Bounds the work done by a single acknowledgement; the rest is compacted
+// by later ones.
+const COMPACTION_BATCH: usize = 1000;
+
+/// The kinds of record published in the change feed. Two stores are left
+/// out: note revisions, whose text must never reach the feed (each edit is
+/// published as an upsert of its note instead), and settings such as the
+/// payment, billing, retention, intake, archive and sharding configs, which
+/// consumers read with their getters.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ChangeEntity {
+    Consultation,
+    Advisor,
+    AdvisorRating,
+    Client,
+    Escrow,
+    TrustJournalEntry,
+    BillingEntry,
+    Invoice,
+    LegalHold,
+    Organization,
+    Matter,
+    Participant,
+    Handoff,
+    IntakeRequest,
+    IntakeForm,
+    IntakeAnswers,
+    CustomField,
+    CustomFieldValues,
+    Workflow,
+    ConsultationWorkflow,
+    Task,
+    TaskTemplate,
+    Note,
+    Quote,
+    FeeAgreement,
+    Document,
+    MatterEvent,
+    StageChange,
+    WorkflowNotice,
+    LeadTransfer,
+    PrivacyRequest,
+    TrashEntry,
+    // Keyed by principal rather than id.
+    OrganizationMember,
+    StaffRole,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum ChangeOp {
+    Upsert,
+    Delete,
+    // The record moved to an archive canister and is only kept as a stub.
+    Archive,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ChangeRecord {
+    seq: u64,
+    entity: ChangeEntity,
+    // Zero for records keyed by `principal` instead.
+    id: u64,
+    principal: Option<Principal>,
+    op: ChangeOp,
+    // JSON of the new value; absent for deletions and redacted records.
+    value: Option<String>,
+    recorded_at: u64,
+}
+
+impl Storable for ChangeRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChangeRecord {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ChangeConsumer {
+    id: u64,
+    name: String,
+    principal: Principal,
+    acknowledged_seq: u64,
+    registered_at: u64,
+}
+
+impl Storable for ChangeConsumer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ChangeConsumer {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ChangePage {
+    changes: Vec<ChangeRecord>,
+    last_seq: u64,
+    has_more: bool,
+}
+
+/// Orders the changes of each record together, for redaction.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ChangeIndexKey {
+    entity: u8,
+    id: u64,
+    seq: u64,
+}
+
+impl ChangeIndexKey {
+    fn of(record: &ChangeRecord) -> Self {
+        ChangeIndexKey {
+            entity: record.entity as u8,
+            id: record.id,
+            seq: record.seq,
+        }
+    }
+}
+
+impl Storable for ChangeIndexKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(17);
+        bytes.push(self.entity);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        ChangeIndexKey {
+            entity: bytes[0],
+            id: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+            seq: u64::from_be_bytes(bytes[9..17].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ChangeIndexKey {
+    const MAX_SIZE: u32 = 17;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static CHANGE_LOG: RefCell<StableBTreeMap<u64, ChangeRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // Last sequence number handed out; never reused, even after compaction.
+    static CHANGE_SEQUENCE: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), 0)
+            .expect("Cannot create change sequence")
+    );
+
+    static CHANGE_CONSUMERS: RefCell<StableBTreeMap<u64, ChangeConsumer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // The changes of each record, by entity and id.
+    static CHANGE_INDEX: RefCell<StableBTreeMap<ChangeIndexKey, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+}
+
+/// Pages through the change log, returning records with a sequence number
+/// greater than `seq`.
+#[ic_cdk::query]
+fn changes_since(seq: u64, limit: Option<u32>) -> Result<ChangePage, Error> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) && consumer_for(&caller).is_none() {
+        return Err(Error::Unauthorized {
+            msg: "Only registered change consumers can read the change log".to_string(),
+        });
+    }
+
+    let first_retained = first_retained_seq();
+    if seq.saturating_add(1) < first_retained {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Changes up to seq={} have been compacted",
+                first_retained - 1
+            ),
+        });
+    }
+
+    let limit = limit
+        .map(|limit| (limit as usize).min(MAX_CHANGES_LIMIT))
+        .unwrap_or(DEFAULT_CHANGES_LIMIT);
+    let changes: Vec<ChangeRecord> = CHANGE_LOG.with(|service| {
+        service
+            .borrow()
+            .range(seq.saturating_add(1)..)
+            .take(limit)
+            .map(|(_, record)| record)
+            .collect()
+    });
+    let last_seq = changes.last().map_or(seq, |record| record.seq);
+
+    Ok(ChangePage {
+        has_more: last_seq < current_seq(),
+        changes,
+        last_seq,
+    })
+}
+
+/// Registers a principal that reads the change log. Entries are only
+/// compacted once every registered consumer has acknowledged them; while
+/// no consumer is registered nothing is kept.
+#[ic_cdk::update]
+fn register_change_consumer(name: String, principal: Principal) -> Result<ChangeConsumer, Error> {
+    ensure_controller()?;
+
+    if consumer_for(&principal).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("Principal {} is already a change consumer", principal),
+        });
+    }
+
+    let consumer = ChangeConsumer {
+        id: next_id(),
+        name,
+        principal,
+        // Everything still in the log is unacknowledged by a new consumer.
+        acknowledged_seq: first_retained_seq() - 1,
+        registered_at: time(),
+    };
+    do_insert_change_consumer(&consumer);
+    Ok(consumer)
+}
+
+#[ic_cdk::update]
+fn remove_change_consumer(id: u64) -> Result<(), Error> {
+    ensure_controller()?;
+
+    if CHANGE_CONSUMERS
+        .with(|service| service.borrow_mut().remove(&id))
+        .is_some()
+    {
+        compact_change_log();
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Change consumer with id={} not found", id),
+        })
+    }
+}
+
+#[ic_cdk::query]
+fn list_change_consumers() -> Result<Vec<ChangeConsumer>, Error> {
+    ensure_controller()?;
+
+    Ok(CHANGE_CONSUMERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, consumer)| consumer)
+            .collect()
+    }))
+}
+
+/// Records that the calling consumer has processed every change up to and
+/// including `seq`, then compacts what all consumers have acknowledged.
+#[ic_cdk::update]
+fn acknowledge_changes(seq: u64) -> Result<ChangeConsumer, Error> {
+    let caller = ic_cdk::caller();
+    if let Some(mut consumer) = consumer_for(&caller) {
+        if seq > current_seq() {
+            return Err(Error::InvalidInput {
+                msg: format!("Change with seq={} has not been recorded yet", seq),
+            });
+        }
+        if seq < consumer.acknowledged_seq {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Changes up to seq={} are already acknowledged",
+                    consumer.acknowledged_seq
+                ),
+            });
+        }
+
+        consumer.acknowledged_seq = seq;
+        do_insert_change_consumer(&consumer);
+        compact_change_log();
+        Ok(consumer)
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("Principal {} is not a change consumer", caller),
+        })
+    }
+}
+
+pub(crate) fn record_upsert<T: serde::Serialize>(entity: ChangeEntity, id: u64, value: &T) {
+    append(
+        entity,
+        id,
+        ChangeOp::Upsert,
+        Some(serde_json::to_string(value).expect("Cannot serialize change")),
+    );
+}
+
+pub(crate) fn record_delete(entity: ChangeEntity, id: u64) {
+    append(entity, id, ChangeOp::Delete, None);
+}
+
+pub(crate) fn record_principal_upsert<T: serde::Serialize>(
+    entity: ChangeEntity,
+    principal: Principal,
+    value: &T,
+) {
+    append_record(
+        entity,
+        0,
+        Some(principal),
+        ChangeOp::Upsert,
+        Some(serde_json::to_string(value).expect("Cannot serialize change")),
+    );
+}
+
+pub(crate) fn record_principal_delete(entity: ChangeEntity, principal: Principal) {
+    append_record(entity, 0, Some(principal), ChangeOp::Delete, None);
+}
+
+pub(crate) fn record_archive(entity: ChangeEntity, id: u64) {
+    append(entity, id, ChangeOp::Archive, None);
+}
+
+/// Drops the recorded values of every change to the record, for when the
+/// values themselves must no longer be held.
+pub(crate) fn redact_history(entity: ChangeEntity, id: u64) {
+    let first = ChangeIndexKey {
+        entity: entity as u8,
+        id,
+        seq: 0,
+    };
+    let last = ChangeIndexKey {
+        seq: u64::MAX,
+        ..first.clone()
+    };
+    let seqs: Vec<u64> = CHANGE_INDEX.with(|service| {
+        service
+            .borrow()
+            .range(first..=last)
+            .map(|(key, _)| key.seq)
+            .collect()
+    });
+    CHANGE_LOG.with(|service| {
+        let mut log = service.borrow_mut();
+        for seq in seqs {
+            if let Some(mut record) = log.get(&seq).filter(|record| record.value.is_some()) {
+                record.value = None;
+                log.insert(seq, record);
+            }
+        }
+    });
+}
+
+/// Indexes a change log recorded before the index existed.
+pub(crate) fn index_change_log() {
+    if CHANGE_INDEX.with(|service| !service.borrow().is_empty()) {
+        return;
+    }
+    CHANGE_LOG.with(|log| {
+        CHANGE_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (_, record) in log.borrow().iter() {
+                index.insert(ChangeIndexKey::of(&record), ());
+            }
+        })
+    });
+}
+
+fn append(entity: ChangeEntity, id: u64, op: ChangeOp, value: Option<String>) {
+    append_record(entity, id, None, op, value);
+}
+
+fn append_record(
+    entity: ChangeEntity,
+    id: u64,
+    principal: Option<Principal>,
+    op: ChangeOp,
+    value: Option<String>,
+) {
+    let seq = CHANGE_SEQUENCE
+        .with(|cell| {
+            let next = *cell.borrow().get() + 1;
+            cell.borrow_mut().set(next)
+        })
+        .expect("Cannot increment change sequence")
+        + 1;
+    if CHANGE_CONSUMERS.with(|service| service.borrow().is_empty()) {
+        // Nobody will read it; drop what earlier consumers left instead.
+        compact_change_log();
+        return;
+    }
+
+    let record = ChangeRecord {
+        seq,
+        entity,
+        id,
+        principal,
+        op,
+        value,
+        recorded_at: time(),
+    };
+    CHANGE_INDEX.with(|service| service.borrow_mut().insert(ChangeIndexKey::of(&record), ()));
+    CHANGE_LOG.with(|service| service.borrow_mut().insert(seq, record));
+}
+
+/// Drops changes every consumer has acknowledged, or all of them when no
+/// consumer is registered.
+fn compact_change_log() {
+    let acknowledged = CHANGE_CONSUMERS
+        .with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, consumer)| consumer.acknowledged_seq)
+                .min()
+        })
+        .unwrap_or_else(current_seq);
+
+    CHANGE_LOG.with(|service| {
+        let mut log = service.borrow_mut();
+        let compacted: Vec<ChangeRecord> = log
+            .range(..=acknowledged)
+            .take(COMPACTION_BATCH)
+            .map(|(_, record)| record)
+            .collect();
+        for record in compacted {
+            log.remove(&record.seq);
+            CHANGE_INDEX.with(|index| index.borrow_mut().remove(&ChangeIndexKey::of(&record)));
+        }
+    });
+}
+
+fn current_seq() -> u64 {
+    CHANGE_SEQUENCE.with(|cell| *cell.borrow().get())
+}
+
+fn first_retained_seq() -> u64 {
+    CHANGE_LOG.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .next()
+            .map_or(current_seq() + 1, |(seq, _)| seq)
+    })
+}
+
+fn consumer_for(principal: &Principal) -> Option<ChangeConsumer> {
+    CHANGE_CONSUMERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, consumer)| consumer)
+            .find(|consumer| consumer.principal == *principal)
+    })
+}
+
+fn do_insert_change_consumer(consumer: &ChangeConsumer) {
+    CHANGE_CONSUMERS.with(|service| service.borrow_mut().insert(consumer.id, consumer.clone()));
+}
+
+fn ensure_controller() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers can manage change consumers".to_string(),
+        })
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("change_log", &CHANGE_LOG),
+        backup::cell_source("change_sequence", &CHANGE_SEQUENCE),
+        backup::map_source("change_consumers", &CHANGE_CONSUMERS),
+        // Kept so that a restore need not index the whole log at once.
+        backup::map_source("change_index", &CHANGE_INDEX),
+    ]
+}
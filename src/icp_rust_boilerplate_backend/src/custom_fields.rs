@@ -0,0 +1,600 @@
+use crate::{
+    advisors_visible_to, all_legal_clients, backup, changes, consultations_visible_to,
+    ensure_firm_staff, firm_legal_client, firm_legal_consultation,
+    forms::{FieldFilter, FieldValue},
+    next_id, organizations, scoped_legal_advisor, sharding, ChangeEntity, Error, LegalAdvisor,
+    LegalClient, LegalConsultation, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_KEY_BYTES: usize = 64;
+const MAX_LABEL_BYTES: usize = 256;
+const MAX_TEXT_BYTES: u32 = 1024;
+const MAX_ENUM_OPTIONS: usize = 50;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum CustomFieldEntity {
+    Consultation,
+    Advisor,
+    Client,
+}
+
+/// The value type of a custom field together with its validation rules.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum CustomFieldType {
+    Text { max_length: u32 },
+    Number { min: Option<f64>, max: Option<f64> },
+    Date,
+    Enum(Vec<String>),
+    Boolean,
+}
+
+/// A firm-defined attribute of consultations, advisors or clients. Retired
+/// fields keep their values but accept no new ones.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CustomField {
+    id: u64,
+    entity: CustomFieldEntity,
+    key: String,
+    label: String,
+    field_type: CustomFieldType,
+    organization_id: Option<u64>,
+    created_at: u64,
+    retired: bool,
+}
+
+impl Storable for CustomField {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CustomField {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StoredValue {
+    value: FieldValue,
+    updated_by: Principal,
+    updated_at: u64,
+}
+
+impl Storable for StoredValue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StoredValue {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Orders the custom field values of an entity together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CustomValueKey {
+    entity_id: u64,
+    field_id: u64,
+}
+
+impl Storable for CustomValueKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.entity_id.to_be_bytes());
+        bytes.extend_from_slice(&self.field_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        CustomValueKey {
+            entity_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            field_id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for CustomValueKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct CustomFieldValue {
+    key: String,
+    value: FieldValue,
+    updated_by: Principal,
+    updated_at: u64,
+}
+
+thread_local! {
+    static CUSTOM_FIELDS: RefCell<StableBTreeMap<u64, CustomField, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    static CUSTOM_FIELD_VALUES: RefCell<StableBTreeMap<CustomValueKey, StoredValue, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+}
+
+/// Registers a custom field for the caller's organization. Keys are unique
+/// per entity and organization. Controllers and organization admins only.
+#[ic_cdk::update]
+fn define_custom_field(
+    entity: CustomFieldEntity,
+    key: String,
+    label: String,
+    field_type: CustomFieldType,
+) -> Result<CustomField, Error> {
+    ensure_admin()?;
+    if key.is_empty()
+        || key.len() > MAX_KEY_BYTES
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Custom field keys are 1 to {} lowercase letters, digits or underscores",
+                MAX_KEY_BYTES
+            ),
+        });
+    }
+    if label.trim().is_empty() || label.len() > MAX_LABEL_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Custom field labels are 1 to {} bytes", MAX_LABEL_BYTES),
+        });
+    }
+    match &field_type {
+        CustomFieldType::Text { max_length }
+            if *max_length == 0 || *max_length > MAX_TEXT_BYTES =>
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("Text fields hold 1 to {} bytes", MAX_TEXT_BYTES),
+            })
+        }
+        CustomFieldType::Number {
+            min: Some(min),
+            max: Some(max),
+        } if min > max => {
+            return Err(Error::InvalidInput {
+                msg: "The minimum of a number field exceeds its maximum".to_string(),
+            })
+        }
+        CustomFieldType::Enum(options)
+            if options.is_empty() || options.len() > MAX_ENUM_OPTIONS =>
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("Enum fields have 1 to {} options", MAX_ENUM_OPTIONS),
+            })
+        }
+        _ => {}
+    }
+
+    let organization_id = organizations::organization_of(&ic_cdk::caller());
+    if field_by_key(entity, organization_id, &key).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("Custom field {} is already defined", key),
+        });
+    }
+    let field = CustomField {
+        id: next_id(),
+        entity,
+        key,
+        label,
+        field_type,
+        organization_id,
+        created_at: time(),
+        retired: false,
+    };
+    do_insert_custom_field(&field);
+    Ok(field)
+}
+
+#[ic_cdk::update]
+fn retire_custom_field(id: u64) -> Result<CustomField, Error> {
+    ensure_admin()?;
+    let mut field = CUSTOM_FIELDS
+        .with(|service| service.borrow().get(&id))
+        .filter(|field| organizations::can_access(&ic_cdk::caller(), field.organization_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Custom field with id={} not found", id),
+        })?;
+
+    field.retired = true;
+    do_insert_custom_field(&field);
+    Ok(field)
+}
+
+#[ic_cdk::query]
+fn list_custom_fields(entity: Option<CustomFieldEntity>) -> Result<Vec<CustomField>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    Ok(CUSTOM_FIELDS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, field)| field)
+            .filter(|field| {
+                organizations::can_access(&caller, field.organization_id)
+                    && entity.is_none_or(|entity| field.entity == entity)
+            })
+            .collect()
+    }))
+}
+
+/// Sets a custom field of a consultation, advisor or client after checking
+/// the value against the field's type and rules.
+#[ic_cdk::update]
+async fn set_custom_field_value(
+    entity: CustomFieldEntity,
+    entity_id: u64,
+    key: String,
+    value: FieldValue,
+) -> Result<CustomFieldValue, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    let organization_id = entity_organization(entity, entity_id).await?;
+    set_value(entity, entity_id, organization_id, key, value, caller)
+}
+
+/// Validates and stores a custom field value of an entity already known to
+/// belong to `organization_id`.
+pub(crate) fn set_value(
+    entity: CustomFieldEntity,
+    entity_id: u64,
+    organization_id: Option<u64>,
+    key: String,
+    value: FieldValue,
+    by: Principal,
+) -> Result<CustomFieldValue, Error> {
+    let field = field_by_key(entity, organization_id, &key)
+        .filter(|field| !field.retired)
+        .ok_or_else(|| field_not_found(&key))?;
+    validate_value(&field, &value)?;
+
+    let stored = StoredValue {
+        value,
+        updated_by: by,
+        updated_at: time(),
+    };
+    CUSTOM_FIELD_VALUES.with(|service| {
+        service.borrow_mut().insert(
+            CustomValueKey {
+                entity_id,
+                field_id: field.id,
+            },
+            stored.clone(),
+        )
+    });
+    record_values(entity_id);
+    Ok(CustomFieldValue {
+        key,
+        value: stored.value,
+        updated_by: stored.updated_by,
+        updated_at: stored.updated_at,
+    })
+}
+
+#[ic_cdk::update]
+async fn clear_custom_field_value(
+    entity: CustomFieldEntity,
+    entity_id: u64,
+    key: String,
+) -> Result<(), Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+    let organization_id = entity_organization(entity, entity_id).await?;
+    let field = field_by_key(entity, organization_id, &key).ok_or_else(|| field_not_found(&key))?;
+
+    CUSTOM_FIELD_VALUES.with(|service| {
+        service.borrow_mut().remove(&CustomValueKey {
+            entity_id,
+            field_id: field.id,
+        })
+    });
+    record_values(entity_id);
+    Ok(())
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_custom_field_values(
+    entity: CustomFieldEntity,
+    entity_id: u64,
+) -> Result<Vec<CustomFieldValue>, Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+    entity_organization(entity, entity_id).await?;
+    Ok(values_of(entity_id))
+}
+
+/// Consultations in the caller's scope whose custom fields match every
+/// filter.
+#[ic_cdk::query(composite = true)]
+async fn filter_legal_consultations(
+    filters: Vec<FieldFilter>,
+) -> Result<Vec<LegalConsultation>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    Ok(consultations_visible_to(&caller)
+        .await?
+        .into_iter()
+        // Staff of one firm may be the client of another.
+        .filter(|consultation| organizations::can_access(&caller, consultation.organization_id))
+        .filter(|consultation| {
+            matches_all(
+                CustomFieldEntity::Consultation,
+                consultation.id,
+                consultation.organization_id,
+                &filters,
+            )
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+fn filter_legal_advisors(filters: Vec<FieldFilter>) -> Result<Vec<LegalAdvisor>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    Ok(advisors_visible_to(&caller)
+        .into_iter()
+        .filter(|advisor| {
+            matches_all(
+                CustomFieldEntity::Advisor,
+                advisor.id,
+                advisor.organization_id,
+                &filters,
+            )
+        })
+        .collect())
+}
+
+#[ic_cdk::query]
+fn filter_legal_clients(filters: Vec<FieldFilter>) -> Result<Vec<LegalClient>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    Ok(all_legal_clients()
+        .into_iter()
+        .filter(|client| {
+            organizations::can_access(&caller, client.organization_id)
+                && matches_all(
+                    CustomFieldEntity::Client,
+                    client.id,
+                    client.organization_id,
+                    &filters,
+                )
+        })
+        .collect())
+}
+
+/// JSON document mapping each entity id in the caller's scope to its
+/// custom field values, for spreadsheets and reporting tools.
+#[ic_cdk::query]
+fn export_custom_field_values(entity: CustomFieldEntity) -> Result<String, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    let fields: BTreeMap<u64, CustomField> = CUSTOM_FIELDS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, field)| {
+                field.entity == entity && organizations::can_access(&caller, field.organization_id)
+            })
+            .collect()
+    });
+    let mut export: BTreeMap<u64, BTreeMap<String, FieldValue>> = BTreeMap::new();
+    CUSTOM_FIELD_VALUES.with(|service| {
+        for (key, stored) in service.borrow().iter() {
+            if let Some(field) = fields.get(&key.field_id) {
+                export
+                    .entry(key.entity_id)
+                    .or_default()
+                    .insert(field.key.clone(), stored.value);
+            }
+        }
+    });
+    Ok(serde_json::json!(export).to_string())
+}
+
+/// The custom field values of a consultation, advisor or client.
+pub(crate) fn values_of(entity_id: u64) -> Vec<CustomFieldValue> {
+    CUSTOM_FIELD_VALUES.with(|service| {
+        service
+            .borrow()
+            .range(
+                CustomValueKey {
+                    entity_id,
+                    field_id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.entity_id == entity_id)
+            .filter_map(|(key, stored)| {
+                let field = CUSTOM_FIELDS.with(|fields| fields.borrow().get(&key.field_id))?;
+                Some(CustomFieldValue {
+                    key: field.key,
+                    value: stored.value,
+                    updated_by: stored.updated_by,
+                    updated_at: stored.updated_at,
+                })
+            })
+            .collect()
+    })
+}
+
+pub(crate) fn has_value(entity_id: u64, key: &str) -> bool {
+    values_of(entity_id).iter().any(|value| value.key == key)
+}
+
+/// Drops the values of an entity that is erased or permanently deleted.
+pub(crate) fn remove_values(entity_id: u64) {
+    let keys: Vec<CustomValueKey> = CUSTOM_FIELD_VALUES.with(|service| {
+        service
+            .borrow()
+            .range(
+                CustomValueKey {
+                    entity_id,
+                    field_id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.entity_id == entity_id)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    CUSTOM_FIELD_VALUES.with(|service| {
+        let mut values = service.borrow_mut();
+        for key in &keys {
+            values.remove(key);
+        }
+    });
+    if !keys.is_empty() {
+        changes::record_delete(ChangeEntity::CustomFieldValues, entity_id);
+    }
+}
+
+/// Publishes every value the entity now holds as a single change.
+fn record_values(entity_id: u64) {
+    changes::record_upsert(
+        ChangeEntity::CustomFieldValues,
+        entity_id,
+        &values_of(entity_id),
+    );
+}
+
+/// Whether every filter matches a value the entity holds for the field of
+/// that key in the entity's organization.
+fn matches_all(
+    entity: CustomFieldEntity,
+    entity_id: u64,
+    organization_id: Option<u64>,
+    filters: &[FieldFilter],
+) -> bool {
+    filters.iter().all(|filter| {
+        field_by_key(entity, organization_id, filter.key())
+            .and_then(|field| {
+                CUSTOM_FIELD_VALUES.with(|service| {
+                    service.borrow().get(&CustomValueKey {
+                        entity_id,
+                        field_id: field.id,
+                    })
+                })
+            })
+            .is_some_and(|stored| filter.matches(&stored.value))
+    })
+}
+
+fn validate_value(field: &CustomField, value: &FieldValue) -> Result<(), Error> {
+    let valid = match (&field.field_type, value) {
+        (CustomFieldType::Text { max_length }, FieldValue::Text(text)) => {
+            text.len() <= *max_length as usize
+        }
+        (CustomFieldType::Number { min, max }, FieldValue::Number(number)) => {
+            number.is_finite()
+                && min.is_none_or(|min| *number >= min)
+                && max.is_none_or(|max| *number <= max)
+        }
+        (CustomFieldType::Enum(options), FieldValue::Enum(choice)) => options.contains(choice),
+        (CustomFieldType::Date, FieldValue::Date(_))
+        | (CustomFieldType::Boolean, FieldValue::Boolean(_)) => true,
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!(
+                "Value does not satisfy the rules of custom field {}",
+                field.key
+            ),
+        })
+    }
+}
+
+/// The organization of an entity the caller may see.
+async fn entity_organization(
+    entity: CustomFieldEntity,
+    entity_id: u64,
+) -> Result<Option<u64>, Error> {
+    if entity == CustomFieldEntity::Consultation {
+        sharding::load_consultations(&[entity_id]).await?;
+    }
+    let (organization_id, name) = match entity {
+        CustomFieldEntity::Consultation => (
+            firm_legal_consultation(&entity_id).map(|consultation| consultation.organization_id),
+            "Legal consultation",
+        ),
+        CustomFieldEntity::Advisor => (
+            scoped_legal_advisor(&entity_id).map(|advisor| advisor.organization_id),
+            "Legal advisor",
+        ),
+        CustomFieldEntity::Client => (
+            firm_legal_client(&entity_id).map(|client| client.organization_id),
+            "Legal client",
+        ),
+    };
+    organization_id.ok_or_else(|| Error::NotFound {
+        msg: format!("{} with id={} not found", name, entity_id),
+    })
+}
+
+fn field_by_key(
+    entity: CustomFieldEntity,
+    organization_id: Option<u64>,
+    key: &str,
+) -> Option<CustomField> {
+    CUSTOM_FIELDS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, field)| field)
+            .find(|field| {
+                field.entity == entity
+                    && field.organization_id == organization_id
+                    && field.key == key
+            })
+    })
+}
+
+fn do_insert_custom_field(field: &CustomField) {
+    CUSTOM_FIELDS.with(|service| service.borrow_mut().insert(field.id, field.clone()));
+    changes::record_upsert(ChangeEntity::CustomField, field.id, field);
+}
+
+fn field_not_found(key: &str) -> Error {
+    Error::NotFound {
+        msg: format!("Custom field {} not found", key),
+    }
+}
+
+fn ensure_admin() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) || organizations::is_organization_admin(&caller) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers and organization admins can manage custom fields".to_string(),
+        })
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("custom_fields", &CUSTOM_FIELDS),
+        backup::map_source("custom_field_values", &CUSTOM_FIELD_VALUES),
+    ]
+}
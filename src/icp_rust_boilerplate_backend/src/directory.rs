@@ -0,0 +1,87 @@
+use crate::{_get_legal_advisor, all_legal_advisors, computed_rating, LegalAdvisor};
+
+/// Advisors who have not opted out of the public directory.
+pub(crate) fn listed_advisors() -> Vec<LegalAdvisor> {
+    all_legal_advisors()
+        .into_iter()
+        .filter(|advisor| advisor.listed)
+        .collect()
+}
+
+pub(crate) fn render_index() -> String {
+    let advisors = listed_advisors();
+
+    let body = if advisors.is_empty() {
+        "<p>No advisors are listed yet.</p>".to_string()
+    } else {
+        let items: String = advisors
+            .iter()
+            .map(|advisor| {
+                format!(
+                    "<li><a href=\"/directory/{}\">{}</a> &middot; {} &middot; {}</li>",
+                    advisor.id,
+                    escape(&advisor.name),
+                    escape(&advisor.practice_areas.join(", ")),
+                    format_rating(computed_rating(advisor)),
+                )
+            })
+            .collect();
+        format!("<ul>{}</ul>", items)
+    };
+
+    page("Legal advisors", &body)
+}
+
+pub(crate) fn render_profile(id: u64) -> Option<String> {
+    let advisor = _get_legal_advisor(&id).filter(|advisor| advisor.listed)?;
+
+    let practice_areas: String = advisor
+        .practice_areas
+        .iter()
+        .map(|area| format!("<li>{}</li>", escape(area)))
+        .collect();
+    let body = format!(
+        "<dl>\
+         <dt>Credentials</dt><dd>{}</dd>\
+         <dt>Practice areas</dt><dd><ul>{}</ul></dd>\
+         <dt>Rating</dt><dd>{}</dd>\
+         </dl>\
+         <p><a href=\"/directory\">All advisors</a></p>",
+        escape(&advisor.credentials),
+        practice_areas,
+        format_rating(computed_rating(&advisor)),
+    );
+
+    Some(page(&advisor.name, &body))
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\
+         <html lang=\"en\">\
+         <head><meta charset=\"utf-8\"><title>{title}</title></head>\
+         <body><h1>{title}</h1>{body}</body>\
+         </html>",
+        title = escape(title),
+        body = body,
+    )
+}
+
+fn format_rating(rating: f32) -> String {
+    format!("{:.1} / 5", rating)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
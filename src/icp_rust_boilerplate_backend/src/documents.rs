@@ -0,0 +1,390 @@
+use crate::{
+    _get_legal_consultation, archive, backup, can_manage_consultation, can_view_consultation,
+    changes, next_id, scoped_legal_consultation, search, search::SearchDocKind, sharding,
+    ChangeEntity, Error, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::{borrow::Cow, cell::RefCell};
+
+// Every chunk but the last has exactly this size; buckets store up to it.
+const CHUNK_BYTES: u64 = 256 * 1024;
+const MAX_DOCUMENT_BYTES: u64 = 100 * 1024 * 1024;
+const MAX_NAME_BYTES: usize = 255;
+const MAX_CONTENT_TYPE_BYTES: usize = 127;
+
+/// A file attached to a consultation. Its contents are uploaded in chunks
+/// and kept only by the bucket owning the document's id; this canister
+/// holds the metadata.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Document {
+    id: u64,
+    consultation_id: u64,
+    name: String,
+    content_type: String,
+    size: u64,
+    chunk_count: u32,
+    // Chunks are uploaded in order; this many are stored so far.
+    uploaded_chunks: u32,
+    uploaded_by: Principal,
+    created_at: u64,
+    // Set once the last chunk is stored; only then can the document be read.
+    completed_at: Option<u64>,
+}
+
+impl Storable for Document {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Document {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Orders the documents of a consultation together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct DocumentKey {
+    consultation_id: u64,
+    id: u64,
+}
+
+impl Storable for DocumentKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.consultation_id.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        DocumentKey {
+            consultation_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for DocumentKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static DOCUMENTS: RefCell<StableBTreeMap<DocumentKey, Document, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+
+    // The consultation of each document, for lookups by document id.
+    static DOCUMENT_CONSULTATIONS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+}
+
+/// Registers a document on a consultation the caller can reach. The
+/// contents follow through `upload_document_chunk`, in chunks of 256 KiB.
+#[ic_cdk::update]
+async fn create_document(
+    consultation_id: u64,
+    name: String,
+    content_type: String,
+    size: u64,
+) -> Result<Document, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let consultation = _get_legal_consultation(&consultation_id)
+        .filter(|_| is_reachable(consultation_id, &ic_cdk::caller()))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", consultation_id),
+        })?;
+    if !sharding::is_enabled() {
+        return Err(Error::InvalidInput {
+            msg: "Documents are stored in buckets; enable sharding first".to_string(),
+        });
+    }
+    if name.trim().is_empty() || name.len() > MAX_NAME_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Document names are 1 to {} bytes", MAX_NAME_BYTES),
+        });
+    }
+    if content_type.len() > MAX_CONTENT_TYPE_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Content types are at most {} bytes", MAX_CONTENT_TYPE_BYTES),
+        });
+    }
+    if size == 0 || size > MAX_DOCUMENT_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Documents are 1 to {} bytes", MAX_DOCUMENT_BYTES),
+        });
+    }
+
+    let document = Document {
+        id: next_id(),
+        consultation_id: consultation.id,
+        name,
+        content_type,
+        size,
+        chunk_count: size.div_ceil(CHUNK_BYTES) as u32,
+        uploaded_chunks: 0,
+        uploaded_by: ic_cdk::caller(),
+        created_at: time(),
+        completed_at: None,
+    };
+    do_insert_document(&document);
+    search::index_document(SearchDocKind::Document, document.id, &document.name);
+    Ok(document)
+}
+
+/// Stores the next chunk of one of the caller's documents in its bucket.
+#[ic_cdk::update]
+async fn upload_document_chunk(id: u64, index: u32, data: ByteBuf) -> Result<Document, Error> {
+    load_document_consultation(id).await?;
+    let document = uploading_document(id)?;
+    if index != document.uploaded_chunks {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Document with id={} expects chunk {} next",
+                id, document.uploaded_chunks
+            ),
+        });
+    }
+    let expected = chunk_len(&document, index);
+    if data.len() as u64 != expected {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Chunk {} of document with id={} is {} bytes",
+                index, id, expected
+            ),
+        });
+    }
+
+    let bucket = sharding::bucket_of(id).await?;
+    sharding::call_bucket(bucket, "put_chunk", (id, index, data)).await?;
+
+    // The document may have been deleted, or the chunk stored by a
+    // concurrent call, while the bucket was called.
+    load_document_consultation(id).await?;
+    let mut document = uploading_document(id)?;
+    if document.uploaded_chunks == index {
+        document.uploaded_chunks += 1;
+        if document.uploaded_chunks == document.chunk_count {
+            document.completed_at = Some(time());
+        }
+        do_insert_document(&document);
+    }
+    Ok(document)
+}
+
+/// Reads one chunk of a completed document through to its bucket.
+#[ic_cdk::query(composite = true)]
+async fn get_document_chunk(id: u64, index: u32) -> Result<ByteBuf, Error> {
+    load_document_consultation(id).await?;
+    let document = readable_document(id)?;
+    if document.completed_at.is_none() || index >= document.chunk_count {
+        return Err(chunk_not_found(id, index));
+    }
+    let bucket = sharding::locate(id).ok_or_else(|| chunk_not_found(id, index))?;
+
+    let (result,): (Result<Option<ByteBuf>, Error>,) =
+        ic_cdk::call(bucket, "get_chunk", (id, index))
+            .await
+            .map_err(|(code, msg)| sharding::bucket_call_failed("get_chunk", code, msg))?;
+    result?.ok_or_else(|| chunk_not_found(id, index))
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_document(id: u64) -> Result<Document, Error> {
+    load_document_consultation(id).await?;
+    readable_document(id)
+}
+
+/// The documents on a consultation the caller can reach, oldest first.
+#[ic_cdk::query(composite = true)]
+async fn list_consultation_documents(consultation_id: u64) -> Result<Vec<Document>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    if !is_reachable(consultation_id, &ic_cdk::caller()) {
+        return Err(Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", consultation_id),
+        });
+    }
+    Ok(documents_of(consultation_id))
+}
+
+/// Deletes a document. Its uploader and those managing the consultation
+/// may do so; the bucket drops the contents on its next flush.
+#[ic_cdk::update]
+async fn delete_document(id: u64) -> Result<(), Error> {
+    load_document_consultation(id).await?;
+    let caller = ic_cdk::caller();
+    let document = readable_document(id)?;
+    let may_delete = document.uploaded_by == caller
+        || _get_legal_consultation(&document.consultation_id)
+            .is_some_and(|consultation| can_manage_consultation(&consultation, &caller));
+    if !may_delete {
+        return Err(Error::Unauthorized {
+            msg: format!("Caller cannot delete document with id={}", id),
+        });
+    }
+    remove_document(&document);
+    Ok(())
+}
+
+/// The documents of the consultation, for exports.
+pub(crate) fn documents_of(consultation_id: u64) -> Vec<Document> {
+    DOCUMENTS.with(|service| {
+        service
+            .borrow()
+            .range(
+                DocumentKey {
+                    consultation_id,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.consultation_id == consultation_id)
+            .map(|(_, document)| document)
+            .collect()
+    })
+}
+
+/// Drops the documents of a consultation that is permanently deleted or
+/// whose client's data is erased.
+pub(crate) fn remove_consultation(consultation_id: u64) {
+    for document in documents_of(consultation_id) {
+        remove_document(&document);
+    }
+}
+
+fn remove_document(document: &Document) {
+    DOCUMENTS.with(|service| service.borrow_mut().remove(&key_of(document)));
+    DOCUMENT_CONSULTATIONS.with(|service| service.borrow_mut().remove(&document.id));
+    changes::record_delete(ChangeEntity::Document, document.id);
+    search::remove_document(SearchDocKind::Document, document.id);
+    sharding::mark_deleted(document.id);
+}
+
+/// The consultation the document belongs to.
+pub(crate) fn consultation_of(id: u64) -> Option<u64> {
+    DOCUMENT_CONSULTATIONS.with(|service| service.borrow().get(&id))
+}
+
+/// The name of the document if the caller may read it, for search hits.
+pub(crate) fn searchable_name(id: u64) -> Option<String> {
+    readable_document(id).ok().map(|document| document.name)
+}
+
+/// Indexes the names of every document. Returns the number indexed.
+pub(crate) fn reindex_documents() -> u64 {
+    DOCUMENTS.with(|service| {
+        let documents = service.borrow();
+        for (_, document) in documents.iter() {
+            search::index_document(SearchDocKind::Document, document.id, &document.name);
+        }
+        documents.len()
+    })
+}
+
+/// The size of chunk `index`: full except for the last one.
+fn chunk_len(document: &Document, index: u32) -> u64 {
+    let start = u64::from(index) * CHUNK_BYTES;
+    (document.size - start).min(CHUNK_BYTES)
+}
+
+/// Live consultations are reached by their client, organization and
+/// participants, archived ones through their stub.
+fn is_reachable(consultation_id: u64, caller: &Principal) -> bool {
+    scoped_legal_consultation(&consultation_id).is_some()
+        || _get_legal_consultation(&consultation_id)
+            .is_some_and(|consultation| can_view_consultation(&consultation, caller))
+        || archive::archived_in(&consultation_id, caller).is_some()
+}
+
+fn readable_document(id: u64) -> Result<Document, Error> {
+    let caller = ic_cdk::caller();
+    DOCUMENT_CONSULTATIONS
+        .with(|service| service.borrow().get(&id))
+        .filter(|consultation_id| is_reachable(*consultation_id, &caller))
+        .and_then(|consultation_id| {
+            DOCUMENTS.with(|service| {
+                service.borrow().get(&DocumentKey {
+                    consultation_id,
+                    id,
+                })
+            })
+        })
+        .ok_or_else(|| document_not_found(id))
+}
+
+/// Loads the consultation a document belongs to from its bucket.
+async fn load_document_consultation(id: u64) -> Result<(), Error> {
+    let consultation_ids: Vec<u64> = DOCUMENT_CONSULTATIONS
+        .with(|service| service.borrow().get(&id))
+        .into_iter()
+        .collect();
+    sharding::load_consultations(&consultation_ids).await
+}
+
+/// A document the caller is still uploading.
+fn uploading_document(id: u64) -> Result<Document, Error> {
+    let document = readable_document(id)?;
+    if document.uploaded_by != ic_cdk::caller() {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the uploader can add to document with id={}", id),
+        });
+    }
+    if document.completed_at.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!("Document with id={} is already complete", id),
+        });
+    }
+    Ok(document)
+}
+
+fn do_insert_document(document: &Document) {
+    DOCUMENTS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(key_of(document), document.clone())
+    });
+    DOCUMENT_CONSULTATIONS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(document.id, document.consultation_id)
+    });
+    changes::record_upsert(ChangeEntity::Document, document.id, document);
+}
+
+fn key_of(document: &Document) -> DocumentKey {
+    DocumentKey {
+        consultation_id: document.consultation_id,
+        id: document.id,
+    }
+}
+
+fn document_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Document with id={} not found", id),
+    }
+}
+
+fn chunk_not_found(id: u64, index: u32) -> Error {
+    Error::NotFound {
+        msg: format!("Chunk {} of document with id={} not found", index, id),
+    }
+}
+
+/// Only the metadata is backed up; the contents stay in the buckets.
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("documents", &DOCUMENTS),
+        backup::map_source("document_consultations", &DOCUMENT_CONSULTATIONS),
+    ]
+}
@@ -0,0 +1,566 @@
+use crate::{
+    _get_legal_advisor, _get_legal_client, backup, can_view_consultation, changes,
+    ensure_firm_staff, firm_legal_consultation, next_id, open_legal_consultation, organizations,
+    scoped_legal_consultation, sharding, validate_details, ChangeEntity, Error, LegalConsultation,
+    Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Keep forms and their answers within their storage bounds.
+const MAX_FORM_FIELDS: usize = 40;
+const MAX_LABEL_BYTES: usize = 256;
+const MAX_ANSWER_BYTES: usize = 512;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum FormFieldType {
+    Text,
+    Number,
+    // Nanoseconds since the epoch, like every other timestamp.
+    Date,
+    Enum(Vec<String>),
+    Boolean,
+    Party,
+}
+
+/// A person or company involved in the matter, such as a counterparty,
+/// optionally linked to a known client record.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct PartyReference {
+    name: String,
+    client_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) enum FieldValue {
+    Text(String),
+    Number(f64),
+    Date(u64),
+    Enum(String),
+    Boolean(bool),
+    Party(PartyReference),
+}
+
+/// Shows a field only when an earlier field holds the given value.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldCondition {
+    field: String,
+    equals: FieldValue,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FormField {
+    key: String,
+    label: String,
+    field_type: FormFieldType,
+    // Only enforced while the field is visible.
+    required: bool,
+    visible_when: Option<FieldCondition>,
+}
+
+/// One version of the intake questionnaire for a practice area. Redefining
+/// a form adds a version; answers keep pointing at the one they were given
+/// against.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IntakeForm {
+    id: u64,
+    practice_area: String,
+    organization_id: Option<u64>,
+    version: u32,
+    fields: Vec<FormField>,
+    defined_at: u64,
+}
+
+impl Storable for IntakeForm {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IntakeForm {
+    const MAX_SIZE: u32 = 65536;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldAnswer {
+    key: String,
+    value: FieldValue,
+}
+
+/// The questionnaire answers a client gave when initiating a consultation.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IntakeAnswers {
+    consultation_id: u64,
+    form_id: u64,
+    answers: Vec<FieldAnswer>,
+    submitted_at: u64,
+}
+
+impl Storable for IntakeAnswers {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IntakeAnswers {
+    const MAX_SIZE: u32 = 32768;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum FieldComparison {
+    Equals,
+    NotEquals,
+    GreaterThan,
+    LessThan,
+    // Case-insensitive substring match on text values and party names.
+    Contains,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldFilter {
+    key: String,
+    comparison: FieldComparison,
+    value: FieldValue,
+}
+
+impl FieldFilter {
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub(crate) fn matches(&self, value: &FieldValue) -> bool {
+        match self.comparison {
+            FieldComparison::Equals => *value == self.value,
+            FieldComparison::NotEquals => *value != self.value,
+            FieldComparison::GreaterThan | FieldComparison::LessThan => {
+                let ordering = match (value, &self.value) {
+                    (FieldValue::Number(a), FieldValue::Number(b)) => a.partial_cmp(b),
+                    (FieldValue::Date(a), FieldValue::Date(b)) => Some(a.cmp(b)),
+                    _ => None,
+                };
+                match self.comparison {
+                    FieldComparison::GreaterThan => ordering == Some(std::cmp::Ordering::Greater),
+                    _ => ordering == Some(std::cmp::Ordering::Less),
+                }
+            }
+            FieldComparison::Contains => {
+                let haystack = match value {
+                    FieldValue::Text(text) => text,
+                    FieldValue::Party(party) => &party.name,
+                    _ => return false,
+                };
+                match &self.value {
+                    FieldValue::Text(needle) => {
+                        haystack.to_lowercase().contains(&needle.to_lowercase())
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+thread_local! {
+    static INTAKE_FORMS: RefCell<StableBTreeMap<u64, IntakeForm, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    static INTAKE_ANSWERS: RefCell<StableBTreeMap<u64, IntakeAnswers, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+}
+
+/// Defines the next version of the questionnaire for a practice area of
+/// the caller's organization. Controllers and organization admins only.
+#[ic_cdk::update]
+fn define_intake_form(practice_area: String, fields: Vec<FormField>) -> Result<IntakeForm, Error> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) && !organizations::is_organization_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers and organization admins can define intake forms".to_string(),
+        });
+    }
+    if practice_area.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "An intake form needs a practice area".to_string(),
+        });
+    }
+    validate_fields(&fields)?;
+
+    let organization_id = organizations::organization_of(&caller);
+    let version = current_form(organization_id, &practice_area).map_or(1, |form| form.version + 1);
+    let form = IntakeForm {
+        id: next_id(),
+        practice_area,
+        organization_id,
+        version,
+        fields,
+        defined_at: time(),
+    };
+    INTAKE_FORMS.with(|service| service.borrow_mut().insert(form.id, form.clone()));
+    changes::record_upsert(ChangeEntity::IntakeForm, form.id, &form);
+    Ok(form)
+}
+
+/// The current questionnaire clients of the caller's organization fill in
+/// for a practice area.
+#[ic_cdk::query]
+fn get_intake_form(practice_area: String) -> Result<IntakeForm, Error> {
+    current_form(
+        organizations::organization_of(&ic_cdk::caller()),
+        &practice_area,
+    )
+    .ok_or_else(|| Error::NotFound {
+        msg: format!("No intake form for {}", practice_area),
+    })
+}
+
+/// The questionnaire a client fills in to engage the advisor in a practice
+/// area; clients need not belong to the advisor's organization.
+#[ic_cdk::query]
+fn get_advisor_intake_form(advisor_id: u64, practice_area: String) -> Result<IntakeForm, Error> {
+    let advisor = _get_legal_advisor(&advisor_id).ok_or_else(|| Error::NotFound {
+        msg: format!("Legal advisor with id={} not found", advisor_id),
+    })?;
+    current_form(advisor.organization_id, &practice_area).ok_or_else(|| Error::NotFound {
+        msg: format!("No intake form for {}", practice_area),
+    })
+}
+
+/// Every version of every form of the caller's organization, oldest first.
+#[ic_cdk::query]
+fn list_intake_forms() -> Vec<IntakeForm> {
+    let organization_id = organizations::organization_of(&ic_cdk::caller());
+    INTAKE_FORMS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, form)| form)
+            .filter(|form| form.organization_id == organization_id)
+            .collect()
+    })
+}
+
+/// Initiates a consultation in a practice area together with the answers
+/// to its intake form, if one is defined. Nothing is created unless the
+/// answers are valid.
+#[ic_cdk::update]
+fn initiate_consultation_with_intake(
+    advisor_id: u64,
+    details: String,
+    practice_area: String,
+    answers: Vec<FieldAnswer>,
+) -> Result<LegalConsultation, Error> {
+    validate_details(&details)?;
+    // The advisor's organization defines the form, whoever the client is.
+    let organization_id = match _get_legal_advisor(&advisor_id) {
+        Some(advisor) => {
+            let offered = advisor
+                .practice_areas
+                .iter()
+                .any(|offered| offered.eq_ignore_ascii_case(&practice_area));
+            if !offered {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Legal advisor with id={} does not practice {}",
+                        advisor_id, practice_area
+                    ),
+                });
+            }
+            advisor.organization_id
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Legal advisor with id={} not found", advisor_id),
+            })
+        }
+    };
+    let form = current_form(organization_id, &practice_area);
+    match &form {
+        Some(form) => validate_answers(form, &answers)?,
+        None if !answers.is_empty() => {
+            return Err(Error::InvalidInput {
+                msg: format!("No intake form for {}", practice_area),
+            })
+        }
+        None => {}
+    }
+
+    let consultation = open_legal_consultation(advisor_id, details, Some(practice_area))
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!(
+                "Legal advisor with id={} cannot take consultations",
+                advisor_id
+            ),
+        })?;
+    if let Some(form) = form {
+        let record = IntakeAnswers {
+            consultation_id: consultation.id,
+            form_id: form.id,
+            answers,
+            submitted_at: time(),
+        };
+        changes::record_upsert(ChangeEntity::IntakeAnswers, consultation.id, &record);
+        INTAKE_ANSWERS.with(|service| service.borrow_mut().insert(consultation.id, record));
+    }
+    Ok(consultation)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_intake_answers(consultation_id: u64) -> Result<IntakeAnswers, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let not_found = || Error::NotFound {
+        msg: format!(
+            "No intake answers for legal consultation with id={}",
+            consultation_id
+        ),
+    };
+    match scoped_legal_consultation(&consultation_id) {
+        Some(consultation) if can_view_consultation(&consultation, &ic_cdk::caller()) => {
+            answers_for(consultation_id).ok_or_else(not_found)
+        }
+        _ => Err(not_found()),
+    }
+}
+
+/// Consultations in the caller's scope whose intake answers match every
+/// filter. Consultations without answers never match a filter.
+#[ic_cdk::query(composite = true)]
+async fn filter_consultations_by_answers(
+    practice_area: Option<String>,
+    filters: Vec<FieldFilter>,
+) -> Result<Vec<LegalConsultation>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    let matching: Vec<u64> = INTAKE_ANSWERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, record)| {
+                filters.iter().all(|filter| {
+                    record
+                        .answers
+                        .iter()
+                        .find(|answer| answer.key == filter.key)
+                        .is_some_and(|answer| filter.matches(&answer.value))
+                })
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+    sharding::load_consultations(&matching).await?;
+    Ok(matching
+        .iter()
+        .filter_map(firm_legal_consultation)
+        .filter(|consultation| {
+            practice_area.as_ref().is_none_or(|area| {
+                consultation
+                    .practice_area
+                    .as_ref()
+                    .is_some_and(|practice_area| practice_area.eq_ignore_ascii_case(area))
+            })
+        })
+        .collect())
+}
+
+pub(crate) fn answers_for(consultation_id: u64) -> Option<IntakeAnswers> {
+    INTAKE_ANSWERS.with(|service| service.borrow().get(&consultation_id))
+}
+
+pub(crate) fn has_answer(consultation_id: u64, key: &str) -> bool {
+    answers_for(consultation_id)
+        .is_some_and(|record| record.answers.iter().any(|answer| answer.key == key))
+}
+
+/// Drops the answers of a consultation that is erased or permanently
+/// deleted.
+pub(crate) fn remove_answers(consultation_id: u64) {
+    if INTAKE_ANSWERS
+        .with(|service| service.borrow_mut().remove(&consultation_id))
+        .is_some()
+    {
+        changes::record_delete(ChangeEntity::IntakeAnswers, consultation_id);
+    }
+}
+
+fn current_form(organization_id: Option<u64>, practice_area: &str) -> Option<IntakeForm> {
+    INTAKE_FORMS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, form)| form)
+            .filter(|form| {
+                form.organization_id == organization_id
+                    && form.practice_area.eq_ignore_ascii_case(practice_area)
+            })
+            .max_by_key(|form| form.version)
+    })
+}
+
+fn validate_fields(fields: &[FormField]) -> Result<(), Error> {
+    if fields.is_empty() || fields.len() > MAX_FORM_FIELDS {
+        return Err(Error::InvalidInput {
+            msg: format!("An intake form has 1 to {} fields", MAX_FORM_FIELDS),
+        });
+    }
+    for (index, field) in fields.iter().enumerate() {
+        let earlier = &fields[..index];
+        if field.key.trim().is_empty() || field.label.len() > MAX_LABEL_BYTES {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Field {} needs a key and a label of at most {} bytes",
+                    index, MAX_LABEL_BYTES
+                ),
+            });
+        }
+        if earlier.iter().any(|other| other.key == field.key) {
+            return Err(Error::InvalidInput {
+                msg: format!("Field {} is defined twice", field.key),
+            });
+        }
+        if let FormFieldType::Enum(options) = &field.field_type {
+            if options.is_empty() {
+                return Err(Error::InvalidInput {
+                    msg: format!("Field {} needs at least one option", field.key),
+                });
+            }
+        }
+        // Conditions may only look back, so visibility resolves in one pass.
+        if let Some(condition) = &field.visible_when {
+            let valid = earlier.iter().any(|other| {
+                other.key == condition.field && fits(&other.field_type, &condition.equals)
+            });
+            if !valid {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Field {} can only depend on a matching earlier field",
+                        field.key
+                    ),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_answers(form: &IntakeForm, answers: &[FieldAnswer]) -> Result<(), Error> {
+    for answer in answers {
+        if answers
+            .iter()
+            .filter(|other| other.key == answer.key)
+            .count()
+            > 1
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("Field {} is answered twice", answer.key),
+            });
+        }
+        if !form.fields.iter().any(|field| field.key == answer.key) {
+            return Err(Error::InvalidInput {
+                msg: format!("Field {} is not on the intake form", answer.key),
+            });
+        }
+    }
+
+    let mut visible: Vec<&str> = Vec::new();
+    for field in &form.fields {
+        let shown = field.visible_when.as_ref().is_none_or(|condition| {
+            visible.contains(&condition.field.as_str())
+                && answers
+                    .iter()
+                    .any(|answer| answer.key == condition.field && answer.value == condition.equals)
+        });
+        let answer = answers.iter().find(|answer| answer.key == field.key);
+        match answer {
+            Some(_) if !shown => {
+                return Err(Error::InvalidInput {
+                    msg: format!("Field {} does not apply to these answers", field.key),
+                })
+            }
+            Some(answer) => validate_value(field, &answer.value)?,
+            None if shown && field.required => {
+                return Err(Error::InvalidInput {
+                    msg: format!("Field {} is required", field.key),
+                })
+            }
+            None => {}
+        }
+        if shown {
+            visible.push(field.key.as_str());
+        }
+    }
+    Ok(())
+}
+
+fn validate_value(field: &FormField, value: &FieldValue) -> Result<(), Error> {
+    if !fits(&field.field_type, value) {
+        return Err(Error::InvalidInput {
+            msg: format!("Field {} has an answer of the wrong type", field.key),
+        });
+    }
+    match value {
+        FieldValue::Text(text) if text.len() > MAX_ANSWER_BYTES => Err(Error::InvalidInput {
+            msg: format!("Field {} exceeds {} bytes", field.key, MAX_ANSWER_BYTES),
+        }),
+        FieldValue::Number(number) if !number.is_finite() => Err(Error::InvalidInput {
+            msg: format!("Field {} needs a finite number", field.key),
+        }),
+        FieldValue::Party(party)
+            if party.name.trim().is_empty() || party.name.len() > MAX_ANSWER_BYTES =>
+        {
+            Err(Error::InvalidInput {
+                msg: format!("Field {} needs a party name", field.key),
+            })
+        }
+        FieldValue::Party(PartyReference {
+            client_id: Some(client_id),
+            ..
+        }) => {
+            let organization_id = organizations::organization_of(&ic_cdk::caller());
+            match _get_legal_client(client_id) {
+                Some(client) if client.organization_id == organization_id => Ok(()),
+                _ => Err(Error::NotFound {
+                    msg: format!("Legal client with id={} not found", client_id),
+                }),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether a value has the field's type, and for enums is one of its
+/// options.
+fn fits(field_type: &FormFieldType, value: &FieldValue) -> bool {
+    match (field_type, value) {
+        (FormFieldType::Enum(options), FieldValue::Enum(choice)) => options.contains(choice),
+        (FormFieldType::Text, FieldValue::Text(_))
+        | (FormFieldType::Number, FieldValue::Number(_))
+        | (FormFieldType::Date, FieldValue::Date(_))
+        | (FormFieldType::Boolean, FieldValue::Boolean(_))
+        | (FormFieldType::Party, FieldValue::Party(_)) => true,
+        _ => false,
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("intake_forms", &INTAKE_FORMS),
+        backup::map_source("intake_answers", &INTAKE_ANSWERS),
+    ]
+}
@@ -0,0 +1,381 @@
+use crate::{
+    _get_legal_advisor, _get_legal_consultation, backup, can_view_consultation, changes,
+    do_insert_legal_consultation, next_id, organizations, participants, scoped_legal_consultation,
+    sharding, ChangeEntity, ConsultationStatus, Error, LegalConsultation, Memory, ParticipantRole,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Pending proposals lapse back to their sender after a week.
+const HANDOFF_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_NOTES_BYTES: usize = 2048;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum HandoffStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Cancelled,
+    Expired,
+}
+
+/// A proposal to hand a consultation's lead to another advisor, who must
+/// accept it before the assignment changes.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Handoff {
+    id: u64,
+    consultation_id: u64,
+    from_advisor_id: u64,
+    to_advisor_id: u64,
+    proposed_by: Principal,
+    notes: String,
+    // Role the previous lead keeps on the team once the handoff is accepted.
+    keep_previous_as: Option<ParticipantRole>,
+    status: HandoffStatus,
+    proposed_at: u64,
+    expires_at: u64,
+    resolved_at: Option<u64>,
+    decline_reason: Option<String>,
+}
+
+impl Storable for Handoff {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Handoff {
+    const MAX_SIZE: u32 = 5120;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static HANDOFFS: RefCell<StableBTreeMap<u64, Handoff, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+}
+
+/// Proposes handing the consultation to another advisor of its
+/// organization. Only the current lead and admins may propose, and a
+/// consultation has at most one pending proposal.
+#[ic_cdk::update]
+async fn propose_consultation_handoff(
+    consultation_id: u64,
+    to_advisor_id: u64,
+    notes: String,
+    keep_previous_as: Option<ParticipantRole>,
+) -> Result<Handoff, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = scoped_legal_consultation(&consultation_id)
+        .ok_or_else(|| consultation_not_found(consultation_id))?;
+    let is_lead = participants::role_of(consultation.id, consultation.advisor_id, &caller)
+        == Some(ParticipantRole::Lead);
+    if !is_lead && !is_admin(&consultation, &caller) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Only the lead or an admin can hand off legal consultation with id={}",
+                consultation_id
+            ),
+        });
+    }
+    if to_advisor_id == consultation.advisor_id {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal advisor with id={} is already the lead",
+                to_advisor_id
+            ),
+        });
+    }
+    // Requests not yet accepted are routed by intake instead.
+    if matches!(
+        consultation.status,
+        ConsultationStatus::Pending | ConsultationStatus::Declined
+    ) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} has not been accepted by an advisor",
+                consultation_id
+            ),
+        });
+    }
+    if keep_previous_as == Some(ParticipantRole::Lead) {
+        return Err(Error::InvalidInput {
+            msg: "A consultation has a single lead".to_string(),
+        });
+    }
+    if notes.len() > MAX_NOTES_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Handoff notes exceed {} bytes", MAX_NOTES_BYTES),
+        });
+    }
+    match _get_legal_advisor(&to_advisor_id) {
+        Some(advisor) if advisor.organization_id == consultation.organization_id => {
+            if advisor.principal.is_none() {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Legal advisor with id={} has no principal to accept the handoff",
+                        to_advisor_id
+                    ),
+                });
+            }
+        }
+        _ => {
+            return Err(Error::NotFound {
+                msg: format!("Legal advisor with id={} not found", to_advisor_id),
+            })
+        }
+    }
+    if let Some(pending) = handoffs_of(consultation_id)
+        .into_iter()
+        .find(|handoff| handoff.status == HandoffStatus::Pending)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} already has pending handoff {}",
+                consultation_id, pending.id
+            ),
+        });
+    }
+
+    let now = time();
+    let handoff = Handoff {
+        id: next_id(),
+        consultation_id,
+        from_advisor_id: consultation.advisor_id,
+        to_advisor_id,
+        proposed_by: caller,
+        notes,
+        keep_previous_as,
+        status: HandoffStatus::Pending,
+        proposed_at: now,
+        expires_at: now.saturating_add(HANDOFF_TTL),
+        resolved_at: None,
+        decline_reason: None,
+    };
+    do_insert_handoff(&handoff);
+    Ok(handoff)
+}
+
+/// Accepts a pending handoff addressed to the caller, making them the lead.
+#[ic_cdk::update]
+async fn accept_consultation_handoff(id: u64) -> Result<Handoff, Error> {
+    load_handoff_consultation(id).await?;
+    let mut handoff = addressed_handoff(id)?;
+    let mut consultation = match _get_legal_consultation(&handoff.consultation_id) {
+        Some(consultation) if consultation.advisor_id == handoff.from_advisor_id => consultation,
+        // The consultation is gone or has changed hands since the proposal.
+        _ => {
+            resolve(&mut handoff, HandoffStatus::Cancelled);
+            return Err(Error::InvalidInput {
+                msg: format!("Handoff {} no longer applies and was cancelled", id),
+            });
+        }
+    };
+
+    participants::record_lead_transfer(
+        &consultation,
+        handoff.to_advisor_id,
+        handoff.keep_previous_as,
+        ic_cdk::caller(),
+    );
+    consultation.advisor_id = handoff.to_advisor_id;
+    do_insert_legal_consultation(&consultation);
+    resolve(&mut handoff, HandoffStatus::Accepted);
+    Ok(handoff)
+}
+
+/// Declines a pending handoff addressed to the caller; the consultation
+/// stays with its sender.
+#[ic_cdk::update]
+async fn decline_consultation_handoff(id: u64, reason: Option<String>) -> Result<Handoff, Error> {
+    load_handoff_consultation(id).await?;
+    let mut handoff = addressed_handoff(id)?;
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.len() > MAX_NOTES_BYTES)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("Decline reason exceeds {} bytes", MAX_NOTES_BYTES),
+        });
+    }
+
+    handoff.decline_reason = reason;
+    resolve(&mut handoff, HandoffStatus::Declined);
+    Ok(handoff)
+}
+
+/// Withdraws a pending handoff; allowed for whoever proposed it and admins.
+#[ic_cdk::update]
+async fn cancel_consultation_handoff(id: u64) -> Result<Handoff, Error> {
+    load_handoff_consultation(id).await?;
+    let caller = ic_cdk::caller();
+    let mut handoff = pending_handoff(id)?;
+    let allowed = handoff.proposed_by == caller
+        || _get_legal_consultation(&handoff.consultation_id)
+            .is_some_and(|consultation| is_admin(&consultation, &caller));
+    if !allowed {
+        return Err(Error::Unauthorized {
+            msg: format!("Caller cannot cancel handoff {}", id),
+        });
+    }
+
+    resolve(&mut handoff, HandoffStatus::Cancelled);
+    Ok(handoff)
+}
+
+/// Every handoff proposed for the consultation, oldest first.
+#[ic_cdk::query(composite = true)]
+async fn list_consultation_handoffs(consultation_id: u64) -> Result<Vec<Handoff>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    match scoped_legal_consultation(&consultation_id) {
+        Some(consultation) if can_view_consultation(&consultation, &caller) => {
+            Ok(handoffs_of(consultation_id))
+        }
+        _ => Err(consultation_not_found(consultation_id)),
+    }
+}
+
+/// Handoffs the caller's advisor profile sent or received, so senders see
+/// declined and expired proposals come back.
+#[ic_cdk::query]
+fn list_my_handoffs(status: Option<HandoffStatus>) -> Vec<Handoff> {
+    let caller = ic_cdk::caller();
+    let is_caller = |advisor_id: &u64| {
+        _get_legal_advisor(advisor_id)
+            .and_then(|advisor| advisor.principal)
+            .as_ref()
+            == Some(&caller)
+    };
+
+    HANDOFFS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, handoff)| with_expiry(handoff))
+            .filter(|handoff| {
+                (handoff.proposed_by == caller
+                    || is_caller(&handoff.from_advisor_id)
+                    || is_caller(&handoff.to_advisor_id))
+                    && status.is_none_or(|status| handoff.status == status)
+            })
+            .collect()
+    })
+}
+
+pub(crate) fn handoffs_of(consultation_id: u64) -> Vec<Handoff> {
+    HANDOFFS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, handoff)| handoff)
+            .filter(|handoff| handoff.consultation_id == consultation_id)
+            .map(with_expiry)
+            .collect()
+    })
+}
+
+/// Reports lapsed proposals as expired; they are stored as such the next
+/// time they are touched.
+fn with_expiry(mut handoff: Handoff) -> Handoff {
+    if handoff.status == HandoffStatus::Pending && handoff.expires_at <= time() {
+        handoff.status = HandoffStatus::Expired;
+        handoff.resolved_at = Some(handoff.expires_at);
+    }
+    handoff
+}
+
+/// Loads the consultation a handoff belongs to from its bucket.
+async fn load_handoff_consultation(id: u64) -> Result<(), Error> {
+    let consultation_ids: Vec<u64> = HANDOFFS
+        .with(|service| service.borrow().get(&id))
+        .map(|handoff| handoff.consultation_id)
+        .into_iter()
+        .collect();
+    sharding::load_consultations(&consultation_ids).await
+}
+
+fn pending_handoff(id: u64) -> Result<Handoff, Error> {
+    let handoff = HANDOFFS
+        .with(|service| service.borrow().get(&id))
+        .filter(|handoff| {
+            _get_legal_consultation(&handoff.consultation_id).is_none()
+                || scoped_legal_consultation(&handoff.consultation_id).is_some()
+        })
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Handoff {} not found", id),
+        })?;
+    let current = with_expiry(handoff.clone());
+    if current.status != handoff.status {
+        do_insert_handoff(&current);
+    }
+    if current.status != HandoffStatus::Pending {
+        return Err(Error::InvalidInput {
+            msg: format!("Handoff {} is no longer pending", id),
+        });
+    }
+    Ok(current)
+}
+
+/// A pending handoff the caller may answer as its target advisor.
+fn addressed_handoff(id: u64) -> Result<Handoff, Error> {
+    let handoff = pending_handoff(id)?;
+    let target = _get_legal_advisor(&handoff.to_advisor_id).and_then(|advisor| advisor.principal);
+    if target != Some(ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!("Handoff {} is addressed to another advisor", id),
+        });
+    }
+    Ok(handoff)
+}
+
+fn resolve(handoff: &mut Handoff, status: HandoffStatus) {
+    handoff.status = status;
+    handoff.resolved_at = Some(time());
+    do_insert_handoff(handoff);
+}
+
+/// Controllers, and admins of the consultation's organization.
+fn is_admin(consultation: &LegalConsultation, caller: &Principal) -> bool {
+    ic_cdk::api::is_controller(caller)
+        || (organizations::is_organization_admin(caller)
+            && organizations::can_access(caller, consultation.organization_id))
+}
+
+/// Replaces the notes and decline reasons of the consultation's handoffs,
+/// including in the change feed history.
+pub(crate) fn erase_notes(consultation_id: u64, erased: &str) {
+    for mut handoff in handoffs_of(consultation_id) {
+        handoff.notes = erased.to_string();
+        if handoff.decline_reason.is_some() {
+            handoff.decline_reason = Some(erased.to_string());
+        }
+        changes::redact_history(ChangeEntity::Handoff, handoff.id);
+        do_insert_handoff(&handoff);
+    }
+}
+
+fn do_insert_handoff(handoff: &Handoff) {
+    HANDOFFS.with(|service| service.borrow_mut().insert(handoff.id, handoff.clone()));
+    changes::record_upsert(ChangeEntity::Handoff, handoff.id, handoff);
+}
+
+fn consultation_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Legal consultation with id={} not found", id),
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![backup::map_source("handoffs", &HANDOFFS)]
+}
@@ -0,0 +1,323 @@
+use crate::{
+    advisors_visible_to, directory, initiate_legal_consultation, legal_advisor_for,
+    legal_consultation_for, sharding, update_legal_consultation, Error,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use candid::Principal;
+use ic_certification::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+type HeaderField = (String, String);
+
+// Label under which response hashes are certified (response verification v1).
+const CERTIFIED_ASSETS_LABEL: &[u8] = b"http_assets";
+// Advisor and directory responses are certified ahead of time, so they are
+// rendered for the anonymous principal whoever calls: only advisors outside
+// every organization are served that way. Consultations depend on the
+// caller and are always answered by an update call.
+const PUBLIC: Principal = Principal::anonymous();
+
+#[derive(candid::CandidType, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<HeaderField>,
+    body: ByteBuf,
+}
+
+#[derive(candid::CandidType)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<HeaderField>,
+    body: ByteBuf,
+    upgrade: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct NewConsultation {
+    advisor_id: u64,
+    details: String,
+}
+
+#[derive(Deserialize)]
+struct ConsultationPatch {
+    advisor_id: Option<u64>,
+    details: Option<String>,
+    is_completed: Option<bool>,
+}
+
+thread_local! {
+    static CERTIFIED_RESPONSES: RefCell<RbTree<String, Hash>> = RefCell::new(RbTree::default());
+}
+
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    let (path, query) = split_url(&req.url);
+
+    // Only plain GETs of public pages can be answered from the certified
+    // tree; anything else (mutations, filtered listings, consultations) is
+    // re-run as an update call.
+    if req.method != "GET" || !query.is_empty() || path.starts_with("/consultations") {
+        return upgrade_response();
+    }
+
+    let mut response = handle_get(path, "");
+    if response.status_code == 200 {
+        if let Some(header) = certificate_header(path) {
+            response.headers.push(header);
+        }
+    }
+    response
+}
+
+#[ic_cdk::update]
+async fn http_request_update(req: HttpRequest) -> HttpResponse {
+    let (path, query) = split_url(&req.url);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    // Requests through the HTTP gateway arrive as the anonymous principal,
+    // which anyone can act as; consultations need an authenticated call.
+    if segments.first() == Some(&"consultations") && ic_cdk::caller() == Principal::anonymous() {
+        return message_response(401, "Consultations require an authenticated caller");
+    }
+
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["consultations", id]) => match id.parse::<u64>() {
+            Ok(id) => match sharding::load_consultations(&[id]).await {
+                Ok(()) => result_response(legal_consultation_for(id, &ic_cdk::caller())),
+                Err(e) => error_response(e),
+            },
+            Err(_) => not_found_response(),
+        },
+        ("GET", _) => handle_get(path, query),
+        ("POST", ["consultations"]) => match serde_json::from_slice::<NewConsultation>(&req.body) {
+            Ok(new) => match initiate_legal_consultation(new.advisor_id, new.details) {
+                Some(consultation) => json_response(201, &consultation),
+                None => message_response(500, "Cannot create legal consultation"),
+            },
+            Err(e) => message_response(400, &e.to_string()),
+        },
+        ("PATCH", ["consultations", id]) => match id.parse::<u64>() {
+            Ok(id) => match serde_json::from_slice::<ConsultationPatch>(&req.body) {
+                Ok(patch) => match update_legal_consultation(
+                    id,
+                    patch.advisor_id,
+                    patch.details,
+                    patch.is_completed,
+                )
+                .await
+                {
+                    Ok(()) => result_response(legal_consultation_for(id, &ic_cdk::caller())),
+                    Err(e) => error_response(e),
+                },
+                Err(e) => message_response(400, &e.to_string()),
+            },
+            Err(_) => not_found_response(),
+        },
+        _ => not_found_response(),
+    }
+}
+
+fn handle_get(path: &str, query: &str) -> HttpResponse {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["advisors"] => {
+            let mut advisors = advisors_visible_to(&PUBLIC);
+            if let Some(area) = query_param(query, "practice_area") {
+                advisors.retain(|advisor| {
+                    advisor
+                        .practice_areas
+                        .iter()
+                        .any(|practice_area| practice_area.eq_ignore_ascii_case(&area))
+                });
+            }
+            json_response(200, &advisors)
+        }
+        ["advisors", id] => match id.parse::<u64>() {
+            Ok(id) => result_response(legal_advisor_for(id, &PUBLIC)),
+            Err(_) => not_found_response(),
+        },
+        ["directory"] => html_response(200, directory::render_index()),
+        ["directory", id] => match id.parse::<u64>().ok().and_then(directory::render_profile) {
+            Some(page) => html_response(200, page),
+            None => not_found_response(),
+        },
+        _ => not_found_response(),
+    }
+}
+
+/// Rebuilds the certified response tree and publishes its root hash.
+pub(crate) fn certify_responses() {
+    let mut tree = RbTree::default();
+    for path in ["/advisors".to_string(), "/directory".to_string()]
+        .into_iter()
+        .chain(
+            advisors_visible_to(&PUBLIC)
+                .iter()
+                .map(|advisor| format!("/advisors/{}", advisor.id)),
+        )
+        .chain(
+            directory::listed_advisors()
+                .iter()
+                .map(|advisor| format!("/directory/{}", advisor.id)),
+        )
+    {
+        let response = handle_get(&path, "");
+        tree.insert(path, sha256(&response.body));
+    }
+
+    CERTIFIED_RESPONSES.with(|certified| *certified.borrow_mut() = tree);
+    publish_root_hash();
+}
+
+/// Re-certifies the pages that show one advisor.
+///
+/// Must be called after every change to an advisor or its ratings.
+pub(crate) fn certify_advisor(id: u64) {
+    let paths = [
+        "/advisors".to_string(),
+        "/directory".to_string(),
+        format!("/advisors/{}", id),
+        format!("/directory/{}", id),
+    ];
+    CERTIFIED_RESPONSES.with(|certified| {
+        let mut tree = certified.borrow_mut();
+        for path in paths {
+            let response = handle_get(&path, "");
+            if response.status_code == 200 {
+                tree.insert(path, sha256(&response.body));
+            } else {
+                tree.delete(path.as_bytes());
+            }
+        }
+    });
+    publish_root_hash();
+}
+
+fn publish_root_hash() {
+    let root_hash = CERTIFIED_RESPONSES.with(|certified| certified.borrow().root_hash());
+    ic_cdk::api::set_certified_data(&labeled_hash(CERTIFIED_ASSETS_LABEL, &root_hash));
+}
+
+fn certificate_header(path: &str) -> Option<HeaderField> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let witness = CERTIFIED_RESPONSES.with(|certified| certified.borrow().witness(path.as_bytes()));
+    let tree = labeled(CERTIFIED_ASSETS_LABEL, witness);
+
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().ok()?;
+    tree.serialize(&mut serializer).ok()?;
+
+    Some((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            BASE64.encode(certificate),
+            BASE64.encode(serializer.into_inner())
+        ),
+    ))
+}
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+fn split_url(url: &str) -> (&str, &str) {
+    url.split_once('?').unwrap_or((url, ""))
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex_digit(bytes[i + 1]), hex_digit(bytes[i + 2]))
+            {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn result_response<T: Serialize>(result: Result<T, Error>) -> HttpResponse {
+    match result {
+        Ok(value) => json_response(200, &value),
+        Err(e) => error_response(e),
+    }
+}
+
+fn error_response(error: Error) -> HttpResponse {
+    let status_code = match error {
+        Error::NotFound { .. } => 404,
+        Error::Unauthorized { .. } => 403,
+        Error::InvalidInput { .. } => 400,
+        Error::PaymentFailed { .. } => 502,
+        Error::InsufficientFunds { .. } => 409,
+        Error::LegalHold { .. } => 409,
+        Error::CallFailed { .. } => 502,
+    };
+    json_response(status_code, &error)
+}
+
+fn not_found_response() -> HttpResponse {
+    message_response(404, "Not found")
+}
+
+fn message_response(status_code: u16, msg: &str) -> HttpResponse {
+    json_response(status_code, &serde_json::json!({ "msg": msg }))
+}
+
+fn json_response<T: Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: ByteBuf::from(serde_json::to_vec(value).unwrap_or_default()),
+        upgrade: None,
+    }
+}
+
+fn html_response(status_code: u16, html: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/html; charset=utf-8".to_string(),
+        )],
+        body: ByteBuf::from(html.into_bytes()),
+        upgrade: None,
+    }
+}
+
+fn upgrade_response() -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: Vec::new(),
+        body: ByteBuf::new(),
+        upgrade: Some(true),
+    }
+}
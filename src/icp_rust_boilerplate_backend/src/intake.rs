@@ -0,0 +1,430 @@
+use crate::{
+    _get_legal_advisor, _get_legal_consultation, backup, can_view_consultation, changes,
+    do_insert_legal_consultation, organizations, participants, scoped_legal_advisor,
+    scoped_legal_consultation, sharding, ChangeEntity, ConsultationStatus, Error,
+    LegalConsultation, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_REASON_BYTES: usize = 512;
+// Older responses are dropped so a request stays within its storage bound.
+const MAX_RESPONSES: usize = 8;
+
+/// How long advisors have to answer new consultation requests, and who gets
+/// the requests they decline or let expire. Organizations may override
+/// both in their settings.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IntakeConfig {
+    response_window_hours: u32,
+    fallback_advisor_id: Option<u64>,
+}
+
+impl Default for IntakeConfig {
+    fn default() -> Self {
+        IntakeConfig {
+            response_window_hours: 48,
+            fallback_advisor_id: None,
+        }
+    }
+}
+
+impl Storable for IntakeConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum IntakeOutcome {
+    Accepted,
+    Declined,
+    Expired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IntakeResponse {
+    advisor_id: u64,
+    outcome: IntakeOutcome,
+    reason: Option<String>,
+    at: u64,
+}
+
+/// The request behind a consultation: the advisor currently asked to take
+/// it on, their deadline, and how earlier advisors answered.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IntakeRequest {
+    consultation_id: u64,
+    advisor_id: u64,
+    requested_at: u64,
+    respond_by: u64,
+    responses: Vec<IntakeResponse>,
+}
+
+impl Storable for IntakeRequest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IntakeRequest {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static INTAKE_CONFIG: RefCell<Cell<IntakeConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+            IntakeConfig::default(),
+        )
+        .expect("Cannot create intake config")
+    );
+
+    static INTAKE_REQUESTS: RefCell<StableBTreeMap<u64, IntakeRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+}
+
+#[ic_cdk::update]
+fn configure_intake(config: IntakeConfig) -> Result<IntakeConfig, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can configure intake".to_string(),
+        });
+    }
+    if config.response_window_hours == 0 {
+        return Err(Error::InvalidInput {
+            msg: "The response window must be at least one hour".to_string(),
+        });
+    }
+    // The canister-wide settings cover consultations outside organizations.
+    if let Some(advisor_id) = config.fallback_advisor_id {
+        let is_unaffiliated = scoped_legal_advisor(&advisor_id)
+            .is_some_and(|advisor| advisor.organization_id.is_none());
+        if !is_unaffiliated {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Fallback advisor with id={} is not an advisor outside organizations",
+                    advisor_id
+                ),
+            });
+        }
+    }
+
+    INTAKE_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("Cannot update intake config");
+    Ok(config)
+}
+
+#[ic_cdk::query]
+fn get_intake_config() -> IntakeConfig {
+    INTAKE_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_consultation_request(consultation_id: u64) -> Result<IntakeRequest, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    match scoped_legal_consultation(&consultation_id) {
+        Some(consultation) if can_view_consultation(&consultation, &ic_cdk::caller()) => {
+            _get_request(&consultation_id).ok_or_else(|| Error::NotFound {
+                msg: format!(
+                    "Legal consultation with id={} was not requested through intake",
+                    consultation_id
+                ),
+            })
+        }
+        _ => Err(consultation_not_found(consultation_id)),
+    }
+}
+
+/// Pending requests waiting for the caller's answer.
+#[ic_cdk::query(composite = true)]
+async fn list_incoming_consultation_requests() -> Vec<IntakeRequest> {
+    sharding::or_trap(load_request_consultations().await);
+    let caller = ic_cdk::caller();
+    INTAKE_REQUESTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| {
+                is_pending(request) && advisor_principal(request.advisor_id) == Some(caller)
+            })
+            .collect()
+    })
+}
+
+/// Takes on a pending request addressed to the caller, opening the
+/// consultation.
+#[ic_cdk::update]
+async fn accept_consultation_request(consultation_id: u64) -> Result<(), Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let (mut consultation, mut request) = addressed_request(consultation_id)?;
+
+    push_response(&mut request, IntakeOutcome::Accepted, None);
+    do_insert_request(&request);
+    consultation.status = ConsultationStatus::Open;
+    do_insert_legal_consultation(&consultation);
+    Ok(())
+}
+
+/// Turns down a pending request addressed to the caller. The request moves
+/// on to the fallback advisor, or back to the client if there is none.
+#[ic_cdk::update]
+async fn decline_consultation_request(consultation_id: u64, reason: String) -> Result<(), Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    if reason.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Declining a request needs a reason".to_string(),
+        });
+    }
+    if reason.len() > MAX_REASON_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Decline reason exceeds {} bytes", MAX_REASON_BYTES),
+        });
+    }
+    let (consultation, mut request) = addressed_request(consultation_id)?;
+
+    push_response(&mut request, IntakeOutcome::Declined, Some(reason));
+    reroute(consultation, request, ic_cdk::caller());
+    Ok(())
+}
+
+/// Lets the client send a declined request to another advisor.
+#[ic_cdk::update]
+async fn redirect_consultation_request(consultation_id: u64, advisor_id: u64) -> Result<(), Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = scoped_legal_consultation(&consultation_id)
+        .filter(|consultation| consultation.client == Some(caller))
+        .ok_or_else(|| consultation_not_found(consultation_id))?;
+    if consultation.status != ConsultationStatus::Declined {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} has not been declined",
+                consultation_id
+            ),
+        });
+    }
+    if _get_legal_advisor(&advisor_id)
+        .is_none_or(|advisor| advisor.organization_id != consultation.organization_id)
+    {
+        return Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", advisor_id),
+        });
+    }
+
+    let mut request = _get_request(&consultation_id).unwrap_or_else(|| new_request(&consultation));
+    assign(consultation, &mut request, advisor_id, caller);
+    Ok(())
+}
+
+/// Hourly sweep that moves expired requests on.
+pub(crate) fn start_intake_timer() {
+    ic_cdk_timers::set_timer_interval(EXPIRY_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let _ = expire_requests().await;
+        })
+    });
+}
+
+/// Records the request for a consultation that was just created as
+/// pending.
+pub(crate) fn open_request(consultation: &LegalConsultation) {
+    do_insert_request(&new_request(consultation));
+}
+
+async fn expire_requests() -> Result<(), Error> {
+    load_request_consultations().await?;
+    let now = time();
+    let expired: Vec<IntakeRequest> = INTAKE_REQUESTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| request.respond_by <= now && is_pending(request))
+            .collect()
+    });
+
+    for mut request in expired {
+        if let Some(consultation) = _get_legal_consultation(&request.consultation_id) {
+            push_response(&mut request, IntakeOutcome::Expired, None);
+            reroute(consultation, request, ic_cdk::id());
+        }
+    }
+    Ok(())
+}
+
+/// Loads the consultations of every request from their buckets.
+async fn load_request_consultations() -> Result<(), Error> {
+    let consultation_ids: Vec<u64> = INTAKE_REQUESTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, request)| request.consultation_id)
+            .collect()
+    });
+    sharding::load_consultations(&consultation_ids).await
+}
+
+/// Hands a declined or expired request to the fallback advisor, unless they
+/// already had it, and otherwise returns it to the client.
+fn reroute(mut consultation: LegalConsultation, mut request: IntakeRequest, by: Principal) {
+    let fallback = fallback_advisor_id(consultation.organization_id).filter(|fallback| {
+        !request
+            .responses
+            .iter()
+            .any(|response| response.advisor_id == *fallback)
+    });
+
+    match fallback {
+        Some(fallback) => assign(consultation, &mut request, fallback, by),
+        None => {
+            do_insert_request(&request);
+            consultation.status = ConsultationStatus::Declined;
+            do_insert_legal_consultation(&consultation);
+        }
+    }
+}
+
+/// Asks `advisor_id` to take on the consultation, with a fresh deadline.
+fn assign(
+    mut consultation: LegalConsultation,
+    request: &mut IntakeRequest,
+    advisor_id: u64,
+    by: Principal,
+) {
+    if consultation.advisor_id != advisor_id {
+        participants::record_lead_transfer(&consultation, advisor_id, None, by);
+        consultation.advisor_id = advisor_id;
+    }
+    consultation.status = ConsultationStatus::Pending;
+    do_insert_legal_consultation(&consultation);
+
+    let now = time();
+    request.advisor_id = advisor_id;
+    request.requested_at = now;
+    request.respond_by = now.saturating_add(response_window(consultation.organization_id));
+    do_insert_request(request);
+}
+
+fn new_request(consultation: &LegalConsultation) -> IntakeRequest {
+    let now = time();
+    IntakeRequest {
+        consultation_id: consultation.id,
+        advisor_id: consultation.advisor_id,
+        requested_at: now,
+        respond_by: now.saturating_add(response_window(consultation.organization_id)),
+        responses: Vec::new(),
+    }
+}
+
+fn push_response(request: &mut IntakeRequest, outcome: IntakeOutcome, reason: Option<String>) {
+    request.responses.push(IntakeResponse {
+        advisor_id: request.advisor_id,
+        outcome,
+        reason,
+        at: time(),
+    });
+    if request.responses.len() > MAX_RESPONSES {
+        request.responses.remove(0);
+    }
+}
+
+/// A pending request the caller may answer as the advisor it is addressed
+/// to.
+fn addressed_request(consultation_id: u64) -> Result<(LegalConsultation, IntakeRequest), Error> {
+    let caller = ic_cdk::caller();
+    let consultation = scoped_legal_consultation(&consultation_id)
+        .ok_or_else(|| consultation_not_found(consultation_id))?;
+    let request = _get_request(&consultation_id)
+        .filter(|request| consultation.status == ConsultationStatus::Pending && is_pending(request))
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} is not waiting for an answer",
+                consultation_id
+            ),
+        })?;
+    if advisor_principal(request.advisor_id) != Some(caller) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "The request for legal consultation with id={} is addressed to another advisor",
+                consultation_id
+            ),
+        });
+    }
+    Ok((consultation, request))
+}
+
+fn is_pending(request: &IntakeRequest) -> bool {
+    _get_legal_consultation(&request.consultation_id).is_some_and(|consultation| {
+        consultation.status == ConsultationStatus::Pending
+            && consultation.advisor_id == request.advisor_id
+    })
+}
+
+fn response_window(organization_id: Option<u64>) -> u64 {
+    let hours = organizations::intake_window_hours(organization_id)
+        .unwrap_or_else(|| get_intake_config().response_window_hours);
+    hours as u64 * NANOS_PER_HOUR
+}
+
+/// Organizations name their own fallback advisor; the canister-wide one
+/// only serves consultations outside every organization.
+fn fallback_advisor_id(organization_id: Option<u64>) -> Option<u64> {
+    match organization_id {
+        Some(_) => organizations::fallback_advisor_id(organization_id),
+        None => get_intake_config().fallback_advisor_id,
+    }
+}
+
+fn advisor_principal(advisor_id: u64) -> Option<Principal> {
+    _get_legal_advisor(&advisor_id).and_then(|advisor| advisor.principal)
+}
+
+fn _get_request(consultation_id: &u64) -> Option<IntakeRequest> {
+    INTAKE_REQUESTS.with(|service| service.borrow().get(consultation_id))
+}
+
+fn do_insert_request(request: &IntakeRequest) {
+    INTAKE_REQUESTS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(request.consultation_id, request.clone())
+    });
+    changes::record_upsert(
+        ChangeEntity::IntakeRequest,
+        request.consultation_id,
+        request,
+    );
+}
+
+fn consultation_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Legal consultation with id={} not found", id),
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::cell_source("intake_config", &INTAKE_CONFIG),
+        backup::map_source("intake_requests", &INTAKE_REQUESTS),
+    ]
+}
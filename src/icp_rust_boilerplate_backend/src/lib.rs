@@ -1,11 +1,68 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Principal};
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
 use std::{borrow::Cow, cell::RefCell};
 
+mod archive;
+mod backup;
+mod billing;
+mod changes;
+mod custom_fields;
+mod directory;
+mod documents;
+mod forms;
+mod handoffs;
+mod http;
+mod intake;
+mod matters;
+mod names;
+mod notes;
+mod organizations;
+mod participants;
+mod payments;
+mod privacy;
+mod quotes;
+mod retention;
+mod search;
+mod sharding;
+mod tasks;
+mod trash;
+mod trust;
+mod workflows;
+
+use archive::{ArchiveConfig, ArchiveStub};
+use backup::{BackupChunk, BackupManifest};
+use billing::{BillingConfig, BillingEntry, Invoice, LedesValidation, TimekeeperClassification};
+use changes::{ChangeConsumer, ChangeEntity, ChangePage};
+use custom_fields::{CustomField, CustomFieldEntity, CustomFieldType, CustomFieldValue};
+use documents::Document;
+use forms::{FieldAnswer, FieldFilter, FieldValue, FormField, IntakeAnswers, IntakeForm};
+use handoffs::{Handoff, HandoffStatus};
+use http::{HttpRequest, HttpResponse};
+use intake::{IntakeConfig, IntakeRequest};
+use matters::{Matter, MatterEvent, MatterStatus};
+use names::{NameKind, NameMatch};
+use notes::{Note, NoteRevision, NoteVisibility};
+use organizations::{Organization, OrganizationSettings, StaffRole};
+use participants::{LeadTransfer, Participant, ParticipantRole};
+use payments::{Account, Escrow, PaymentConfig};
+use privacy::{PrivacyRequest, PrivacySubject, SubjectAccessExport};
+use quotes::{FeeAgreement, FeeTerms, Quote};
+use retention::{LegalHold, RetentionConfig};
+use search::SearchHit;
+use sharding::{Bucket, ShardingConfig};
+use tasks::{Task, TaskDetails, TaskStatus, TaskTemplate, TemplateTask};
+use trash::TrashEntry;
+use trust::{ClientTrustLedger, JournalEntry, ReconciliationReport, TrustTransactionKind};
+use workflows::{
+    AvailableTransition, ConsultationWorkflow, StageChange, Workflow, WorkflowNotice,
+    WorkflowStage, WorkflowTransition,
+};
+
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
@@ -17,15 +74,76 @@ struct LegalConsultation {
     created_at: u64,
     closed_at: Option<u64>,
     is_completed: bool,
+    status: ConsultationStatus,
+    client: Option<Principal>,
+    practice_area: Option<String>,
+    organization_id: Option<u64>,
+    // Organization-specific reference number, e.g. "ACME-000042".
+    reference: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum ConsultationStatus {
+    #[default]
+    Open,
+    Completed,
+    Cancelled,
+    // Waiting for the advisor to accept the request.
+    Pending,
+    // Declined or left unanswered by every advisor asked; back with the client.
+    Declined,
 }
 
 impl Storable for LegalConsultation {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), StoredLegalConsultation)
+            .unwrap()
+            .into()
+    }
+}
+
+/// A consultation as read from stable memory. Fields added after the first
+/// release are optional here, so records written before them still decode.
+#[derive(candid::CandidType, Deserialize)]
+struct StoredLegalConsultation {
+    id: u64,
+    advisor_id: u64,
+    details: String,
+    created_at: u64,
+    closed_at: Option<u64>,
+    is_completed: bool,
+    status: Option<ConsultationStatus>,
+    client: Option<Principal>,
+    practice_area: Option<String>,
+    organization_id: Option<u64>,
+    reference: Option<String>,
+}
+
+impl From<StoredLegalConsultation> for LegalConsultation {
+    fn from(stored: StoredLegalConsultation) -> Self {
+        // Before statuses, a consultation was either open or completed.
+        let status = stored.status.unwrap_or(if stored.is_completed {
+            ConsultationStatus::Completed
+        } else {
+            ConsultationStatus::Open
+        });
+        LegalConsultation {
+            id: stored.id,
+            advisor_id: stored.advisor_id,
+            details: stored.details,
+            created_at: stored.created_at,
+            closed_at: stored.closed_at,
+            is_completed: stored.is_completed,
+            status,
+            client: stored.client,
+            practice_area: stored.practice_area,
+            organization_id: stored.organization_id,
+            reference: stored.reference,
+        }
     }
 }
 
@@ -40,15 +158,49 @@ struct LegalAdvisor {
     name: String,
     credentials: String,
     rating: f32,
+    practice_areas: Vec<String>,
+    principal: Option<Principal>,
+    listed: bool,
+    organization_id: Option<u64>,
 }
 
 impl Storable for LegalAdvisor {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), StoredLegalAdvisor).unwrap().into()
+    }
+}
+
+/// An advisor as read from stable memory. Fields added after the first
+/// release are optional here, so records written before them still decode.
+#[derive(candid::CandidType, Deserialize)]
+struct StoredLegalAdvisor {
+    id: u64,
+    name: String,
+    credentials: String,
+    rating: f32,
+    practice_areas: Option<Vec<String>>,
+    principal: Option<Principal>,
+    listed: Option<bool>,
+    organization_id: Option<u64>,
+}
+
+impl From<StoredLegalAdvisor> for LegalAdvisor {
+    fn from(stored: StoredLegalAdvisor) -> Self {
+        LegalAdvisor {
+            id: stored.id,
+            name: stored.name,
+            credentials: stored.credentials,
+            rating: stored.rating,
+            practice_areas: stored.practice_areas.unwrap_or_default(),
+            principal: stored.principal,
+            // Advisors predating the directory stay listed until they opt out.
+            listed: stored.listed.unwrap_or(true),
+            organization_id: stored.organization_id,
+        }
     }
 }
 
@@ -57,6 +209,63 @@ impl BoundedStorable for LegalAdvisor {
     const IS_FIXED_SIZE: bool = false;  // Set to true if the size is fixed, otherwise false
 }
 
+// Free text is bounded so consultations and advisors stay within their
+// MAX_SIZE once encoded, with room for the other fields.
+const MAX_DETAILS_BYTES: usize = 512;
+const MAX_ADVISOR_NAME_BYTES: usize = 100;
+const MAX_CREDENTIALS_BYTES: usize = 200;
+const MAX_PRACTICE_AREAS: usize = 5;
+const MAX_PRACTICE_AREA_BYTES: usize = 48;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AdvisorRating {
+    id: u64,
+    advisor_id: u64,
+    rated_by: Principal,
+    score: u8,
+    created_at: u64,
+}
+
+impl Storable for AdvisorRating {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AdvisorRating {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegalClient {
+    id: u64,
+    name: String,
+    email: String,
+    principal: Option<Principal>,
+    created_at: u64,
+    organization_id: Option<u64>,
+}
+
+impl Storable for LegalClient {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegalClient {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -76,26 +285,108 @@ thread_local! {
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
     ));
+
+    static ADVISOR_RATINGS: RefCell<StableBTreeMap<u64, AdvisorRating, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static LEGAL_CLIENTS: RefCell<StableBTreeMap<u64, LegalClient, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
 }
 
-#[ic_cdk::query]
-fn get_legal_consultation(id: u64) -> Result<LegalConsultation, Error> {
-    match _get_legal_consultation(&id) {
-        Some(consultation) => Ok(consultation),
-        None => Err(Error::NotFound {
-            msg: format!("Legal consultation with id={} not found", id),
-        }),
-    }
+#[ic_cdk::init]
+fn init() {
+    http::certify_responses();
+    retention::start_retention_timer();
+    sharding::start_sharding_timer();
+    archive::start_archive_timer();
+    intake::start_intake_timer();
 }
 
-#[ic_cdk::update]
-fn initiate_legal_consultation(advisor_id: u64, details: String) -> Option<LegalConsultation> {
-    let id = ID_COUNTER
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    http::certify_responses();
+    trust::split_trust_account();
+    changes::index_change_log();
+    matters::index_consultations();
+    retention::start_retention_timer();
+    sharding::start_sharding_timer();
+    archive::start_archive_timer();
+    intake::start_intake_timer();
+}
+
+fn next_id() -> u64 {
+    ID_COUNTER
         .with(|counter| {
             let current_value = *counter.borrow().get();
             counter.borrow_mut().set(current_value + 1)
         })
-        .expect("Cannot increment id counter");
+        .expect("Cannot increment id counter")
+}
+
+fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::cell_source("id_counter", &ID_COUNTER),
+        backup::map_source("legal_consultations", &LEGAL_CONSULTATIONS),
+        backup::map_source("legal_advisors", &LEGAL_ADVISORS),
+        backup::map_source("advisor_ratings", &ADVISOR_RATINGS),
+        backup::map_source("legal_clients", &LEGAL_CLIENTS),
+    ]
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_legal_consultation(id: u64) -> Result<LegalConsultation, Error> {
+    sharding::load_consultations(&[id]).await?;
+    legal_consultation_for(id, &ic_cdk::caller())
+}
+
+/// The consultation as seen by `principal`; consultations of other
+/// organizations are reported as missing, archived ones point to their
+/// archive.
+fn legal_consultation_for(id: u64, principal: &Principal) -> Result<LegalConsultation, Error> {
+    match _get_legal_consultation(&id)
+        .filter(|consultation| can_reach_consultation(consultation, principal))
+    {
+        Some(consultation) => Ok(consultation),
+        None => match archive::archived_in(&id, principal) {
+            Some(archive) => Err(Error::NotFound {
+                msg: format!(
+                    "Legal consultation with id={} is archived in canister {}",
+                    id, archive
+                ),
+            }),
+            None => Err(Error::NotFound {
+                msg: format!("Legal consultation with id={} not found", id),
+            }),
+        },
+    }
+}
+
+#[ic_cdk::update]
+fn initiate_legal_consultation(advisor_id: u64, details: String) -> Option<LegalConsultation> {
+    open_legal_consultation(advisor_id, details, None)
+}
+
+/// Files a consultation for the caller, pending the advisor's acceptance.
+fn open_legal_consultation(
+    advisor_id: u64,
+    details: String,
+    practice_area: Option<String>,
+) -> Option<LegalConsultation> {
+    let caller = ic_cdk::caller();
+    // Anyone could act as the anonymous principal, so it owns nothing.
+    if caller == Principal::anonymous() || validate_details(&details).is_err() {
+        return None;
+    }
+    // The consultation belongs to the advisor's firm; clients engage it
+    // without becoming members.
+    let organization_id =
+        _get_legal_advisor(&advisor_id).and_then(|advisor| advisor.organization_id);
+
+    let id = next_id();
 
     let consultation = LegalConsultation {
         id,
@@ -104,19 +395,55 @@ fn initiate_legal_consultation(advisor_id: u64, details: String) -> Option<Legal
         created_at: time(),
         closed_at: None,
         is_completed: false,
+        status: ConsultationStatus::Pending,
+        client: Some(caller),
+        practice_area,
+        organization_id,
+        reference: organization_id.and_then(organizations::next_reference),
     };
 
     do_insert_legal_consultation(&consultation);
+    intake::open_request(&consultation);
     Some(consultation)
 }
 
 #[ic_cdk::update]
-fn update_legal_advisor(id: u64, name: String, credentials: String, rating: f32) -> Option<LegalAdvisor> {
+fn update_legal_advisor(
+    id: u64,
+    name: String,
+    credentials: String,
+    rating: f32,
+    practice_areas: Vec<String>,
+) -> Option<LegalAdvisor> {
+    validate_advisor_profile(&name, &credentials, &practice_areas).ok()?;
+    // Ownership, listing preferences and organization are not part of the
+    // profile update.
+    let caller = ic_cdk::caller();
+    let (principal, listed, organization_id) = match _get_legal_advisor(&id) {
+        Some(existing) => {
+            if !organizations::can_access(&caller, existing.organization_id)
+                || (existing.principal != Some(caller) && !ic_cdk::api::is_controller(&caller))
+            {
+                return None;
+            }
+            (
+                existing.principal,
+                existing.listed,
+                existing.organization_id,
+            )
+        }
+        None => (Some(caller), true, organizations::organization_of(&caller)),
+    };
+
     let advisor = LegalAdvisor {
         id,
         name,
         credentials,
         rating,
+        practice_areas,
+        principal,
+        listed,
+        organization_id,
     };
 
     do_update_legal_advisor(&advisor);
@@ -125,20 +452,58 @@ fn update_legal_advisor(id: u64, name: String, credentials: String, rating: f32)
 
 fn do_update_legal_advisor(advisor: &LegalAdvisor) {
     LEGAL_ADVISORS.with(|service| service.borrow_mut().insert(advisor.id, advisor.clone()));
+    changes::record_upsert(ChangeEntity::Advisor, advisor.id, advisor);
+    names::index_name(NameKind::Advisor, advisor.id, &advisor.name);
+    http::certify_advisor(advisor.id);
 }
 
 fn do_insert_legal_consultation(consultation: &LegalConsultation) {
-    LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().insert(consultation.id, consultation.clone()));
+    // With sharding enabled, the record goes to its bucket instead.
+    if sharding::store_consultation(consultation) {
+        LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().remove(&consultation.id));
+    } else {
+        LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().insert(consultation.id, consultation.clone()));
+    }
+    changes::record_upsert(ChangeEntity::Consultation, consultation.id, consultation);
+    search::index_consultation(consultation);
 }
 
+/// The consultation, from this canister or from its bucket. Records in
+/// buckets must have been loaded with `sharding::load_consultations`.
 fn _get_legal_consultation(id: &u64) -> Option<LegalConsultation> {
-    LEGAL_CONSULTATIONS.with(|service| service.borrow().get(id))
+    LEGAL_CONSULTATIONS
+        .with(|service| service.borrow().get(id))
+        .or_else(|| sharding::consultation(*id))
+}
+
+/// The consultation, unless the caller is neither its client nor in its
+/// organization; such records are treated as missing.
+fn scoped_legal_consultation(id: &u64) -> Option<LegalConsultation> {
+    _get_legal_consultation(id)
+        .filter(|consultation| can_reach_consultation(consultation, &ic_cdk::caller()))
+}
+
+/// The consultation, if it belongs to the caller's organization. Firm-internal
+/// records use this, as staff of one firm may be the client of another.
+fn firm_legal_consultation(id: &u64) -> Option<LegalConsultation> {
+    _get_legal_consultation(id).filter(|consultation| {
+        organizations::can_access(&ic_cdk::caller(), consultation.organization_id)
+    })
 }
 
+/// Moves the consultation to the trash, from where controllers and the
+/// organization's admins can restore it until it is purged.
 #[ic_cdk::update]
-fn delete_legal_consultation(id: u64) -> Result<(), Error> {
-    if let Some(_) = _get_legal_consultation(&id) {
-        LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().remove(&id));
+async fn delete_legal_consultation(id: u64) -> Result<(), Error> {
+    sharding::load_consultations(&[id]).await?;
+    if let Some(consultation) = scoped_legal_consultation(&id) {
+        retention::ensure_not_on_hold(id)?;
+        trash::move_to_trash(
+            id,
+            trash::TrashedRecord::Consultation(consultation),
+            ic_cdk::caller(),
+        )?;
+        do_remove_legal_consultation(id);
         Ok(())
     } else {
         Err(Error::NotFound {
@@ -147,20 +512,51 @@ fn delete_legal_consultation(id: u64) -> Result<(), Error> {
     }
 }
 
+fn do_remove_legal_consultation(id: u64) {
+    LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().remove(&id));
+    sharding::remove_consultation(id);
+    changes::record_delete(ChangeEntity::Consultation, id);
+    search::remove_document(search::SearchDocKind::Consultation, id);
+}
+
+/// Deletes what other stores keep about a consultation, advisor or client
+/// once the record itself is permanently gone.
+fn remove_dependent_records(id: u64) {
+    forms::remove_answers(id);
+    custom_fields::remove_values(id);
+    workflows::remove_consultation(id);
+    tasks::remove_consultation(id);
+    notes::remove_consultation(id);
+    documents::remove_consultation(id);
+}
+
+/// Drops the local copy of a consultation that now lives in the archive.
+fn evict_legal_consultation(id: u64) {
+    LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().remove(&id));
+    sharding::remove_consultation(id);
+    changes::record_archive(ChangeEntity::Consultation, id);
+}
+
 #[ic_cdk::update]
-fn add_legal_advisor(name: String, credentials: String, rating: f32) -> Option<LegalAdvisor> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment id counter");
+fn add_legal_advisor(
+    name: String,
+    credentials: String,
+    rating: f32,
+    practice_areas: Vec<String>,
+) -> Option<LegalAdvisor> {
+    validate_advisor_profile(&name, &credentials, &practice_areas).ok()?;
+    let id = next_id();
+    let caller = ic_cdk::caller();
 
     let advisor = LegalAdvisor {
         id,
         name,
         credentials,
         rating,
+        practice_areas,
+        principal: Some(caller),
+        listed: true,
+        organization_id: organizations::organization_of(&caller),
     };
 
     do_insert_legal_advisor(&advisor);
@@ -169,7 +565,11 @@ fn add_legal_advisor(name: String, credentials: String, rating: f32) -> Option<L
 
 #[ic_cdk::query]
 fn get_legal_advisor(id: u64) -> Result<LegalAdvisor, Error> {
-    match _get_legal_advisor(&id) {
+    legal_advisor_for(id, &ic_cdk::caller())
+}
+
+fn legal_advisor_for(id: u64, principal: &Principal) -> Result<LegalAdvisor, Error> {
+    match _get_legal_advisor(&id).filter(|advisor| can_see_advisor(advisor, principal)) {
         Some(advisor) => Ok(advisor),
         None => Err(Error::NotFound {
             msg: format!("Legal advisor with id={} not found", id),
@@ -179,18 +579,260 @@ fn get_legal_advisor(id: u64) -> Result<LegalAdvisor, Error> {
 
 fn do_insert_legal_advisor(advisor: &LegalAdvisor) {
     LEGAL_ADVISORS.with(|service| service.borrow_mut().insert(advisor.id, advisor.clone()));
+    changes::record_upsert(ChangeEntity::Advisor, advisor.id, advisor);
+    names::index_name(NameKind::Advisor, advisor.id, &advisor.name);
+    http::certify_advisor(advisor.id);
 }
 
 fn _get_legal_advisor(id: &u64) -> Option<LegalAdvisor> {
     LEGAL_ADVISORS.with(|service| service.borrow().get(id))
 }
 
+fn scoped_legal_advisor(id: &u64) -> Option<LegalAdvisor> {
+    _get_legal_advisor(id)
+        .filter(|advisor| organizations::can_access(&ic_cdk::caller(), advisor.organization_id))
+}
+
+#[ic_cdk::update]
+fn set_advisor_public_listing(id: u64, listed: bool) -> Result<(), Error> {
+    if let Some(mut advisor) = scoped_legal_advisor(&id) {
+        let caller = ic_cdk::caller();
+        if advisor.principal != Some(caller) && !ic_cdk::api::is_controller(&caller) {
+            return Err(Error::Unauthorized {
+                msg: format!("Caller cannot manage legal advisor with id={}", id),
+            });
+        }
+
+        advisor.listed = listed;
+        do_update_legal_advisor(&advisor);
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", id),
+        })
+    }
+}
+
+/// Moves the advisor to the trash, from where controllers and the
+/// organization's admins can restore it until it is purged.
+#[ic_cdk::update]
+fn delete_legal_advisor(id: u64) -> Result<(), Error> {
+    if let Some(advisor) = scoped_legal_advisor(&id) {
+        let caller = ic_cdk::caller();
+        if advisor.principal != Some(caller) && !ic_cdk::api::is_controller(&caller) {
+            return Err(Error::Unauthorized {
+                msg: format!("Caller cannot manage legal advisor with id={}", id),
+            });
+        }
+
+        trash::move_to_trash(id, trash::TrashedRecord::Advisor(advisor), caller)?;
+        LEGAL_ADVISORS.with(|service| service.borrow_mut().remove(&id));
+        changes::record_delete(ChangeEntity::Advisor, id);
+        names::remove_name(NameKind::Advisor, id);
+        http::certify_advisor(id);
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", id),
+        })
+    }
+}
+
+/// Rates an advisor as the client of one of their completed consultations.
+#[ic_cdk::update]
+async fn rate_legal_advisor(advisor_id: u64, score: u8) -> Result<AdvisorRating, Error> {
+    let consultations = all_legal_consultations().await?;
+    if scoped_legal_advisor(&advisor_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", advisor_id),
+        });
+    }
+    let rated_by = ic_cdk::caller();
+    let was_client = rated_by != Principal::anonymous()
+        && consultations.iter().any(|consultation| {
+            consultation.advisor_id == advisor_id
+                && consultation.client == Some(rated_by)
+                && consultation.status == ConsultationStatus::Completed
+        });
+    if !was_client {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Only clients of a completed consultation can rate legal advisor with id={}",
+                advisor_id
+            ),
+        });
+    }
+    if !(1..=5).contains(&score) {
+        return Err(Error::InvalidInput {
+            msg: format!("Rating score must be between 1 and 5, got {}", score),
+        });
+    }
+
+    // Each principal holds a single rating per advisor; rating again replaces it.
+    let existing_id = ADVISOR_RATINGS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .find(|(_, rating)| rating.advisor_id == advisor_id && rating.rated_by == rated_by)
+            .map(|(id, _)| id)
+    });
+
+    let rating = AdvisorRating {
+        id: existing_id.unwrap_or_else(next_id),
+        advisor_id,
+        rated_by,
+        score,
+        created_at: time(),
+    };
+
+    do_insert_advisor_rating(&rating);
+    Ok(rating)
+}
+
+#[ic_cdk::query]
+fn get_advisor_rating(advisor_id: u64) -> Result<f32, Error> {
+    match scoped_legal_advisor(&advisor_id) {
+        Some(advisor) => Ok(computed_rating(&advisor)),
+        None => Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", advisor_id),
+        }),
+    }
+}
+
+/// Average of the scores clients have submitted, falling back to the
+/// advisor's self-declared rating until the first score comes in.
+fn computed_rating(advisor: &LegalAdvisor) -> f32 {
+    let scores: Vec<u8> = ADVISOR_RATINGS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, rating)| rating.advisor_id == advisor.id)
+            .map(|(_, rating)| rating.score)
+            .collect()
+    });
+
+    if scores.is_empty() {
+        advisor.rating
+    } else {
+        scores.iter().map(|score| *score as f32).sum::<f32>() / scores.len() as f32
+    }
+}
+
+fn do_insert_advisor_rating(rating: &AdvisorRating) {
+    ADVISOR_RATINGS.with(|service| service.borrow_mut().insert(rating.id, rating.clone()));
+    changes::record_upsert(ChangeEntity::AdvisorRating, rating.id, rating);
+    http::certify_advisor(rating.advisor_id);
+}
+
+fn advisor_ratings_by(principal: &Principal) -> Vec<AdvisorRating> {
+    ADVISOR_RATINGS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, rating)| rating)
+            .filter(|rating| rating.rated_by == *principal)
+            .collect()
+    })
+}
+
+/// Clients, the lead and co-counsel, and canister controllers may act on a consultation.
+fn can_manage_consultation(consultation: &LegalConsultation, caller: &Principal) -> bool {
+    consultation.client.as_ref() == Some(caller)
+        || participants::role_of(consultation.id, consultation.advisor_id, caller)
+            .is_some_and(ParticipantRole::can_manage)
+        || ic_cdk::api::is_controller(caller)
+}
+
+/// Organization members reach their organization's consultations, clients
+/// only their own.
+fn can_reach_consultation(consultation: &LegalConsultation, principal: &Principal) -> bool {
+    (consultation.client.as_ref() == Some(principal) && *principal != Principal::anonymous())
+        || organizations::can_access(principal, consultation.organization_id)
+}
+
+/// Every participant may read a consultation, whatever their role.
+fn can_view_consultation(consultation: &LegalConsultation, caller: &Principal) -> bool {
+    (consultation.client.as_ref() == Some(caller) && *caller != Principal::anonymous())
+        || participants::role_of(consultation.id, consultation.advisor_id, caller).is_some()
+        || ic_cdk::api::is_controller(caller)
+}
+
+fn validate_details(details: &str) -> Result<(), Error> {
+    if details.len() > MAX_DETAILS_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Consultation details are at most {} bytes",
+                MAX_DETAILS_BYTES
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn validate_advisor_profile(
+    name: &str,
+    credentials: &str,
+    practice_areas: &[String],
+) -> Result<(), Error> {
+    if name.len() > MAX_ADVISOR_NAME_BYTES || credentials.len() > MAX_CREDENTIALS_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Advisor names are at most {} bytes and credentials at most {}",
+                MAX_ADVISOR_NAME_BYTES, MAX_CREDENTIALS_BYTES
+            ),
+        });
+    }
+    if practice_areas.len() > MAX_PRACTICE_AREAS
+        || practice_areas
+            .iter()
+            .any(|area| area.len() > MAX_PRACTICE_AREA_BYTES)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Advisors list at most {} practice areas of at most {} bytes",
+                MAX_PRACTICE_AREAS, MAX_PRACTICE_AREA_BYTES
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Work can only be recorded on consultations the advisor has accepted.
+fn ensure_accepted(consultation: &LegalConsultation) -> Result<(), Error> {
+    match consultation.status {
+        ConsultationStatus::Open | ConsultationStatus::Completed => Ok(()),
+        ConsultationStatus::Cancelled => Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} is cancelled",
+                consultation.id
+            ),
+        }),
+        ConsultationStatus::Pending | ConsultationStatus::Declined => Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} has not been accepted by an advisor",
+                consultation.id
+            ),
+        }),
+    }
+}
+
 #[ic_cdk::update]
-fn mark_consultation_as_completed(id: u64) -> Result<(), Error> {
-    if let Some(consultation) = _get_legal_consultation(&id) {
+async fn mark_consultation_as_completed(id: u64) -> Result<(), Error> {
+    sharding::load_consultations(&[id]).await?;
+    if let Some(consultation) = scoped_legal_consultation(&id) {
+        if !can_manage_consultation(&consultation, &ic_cdk::caller()) {
+            return Err(Error::Unauthorized {
+                msg: format!("Caller cannot complete legal consultation with id={}", id),
+            });
+        }
+        ensure_accepted(&consultation)?;
+        workflows::ensure_status_change_allowed(id)?;
+
         let mut updated_consultation = consultation.clone();
         updated_consultation.is_completed = true;
-        LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().insert(id, updated_consultation));
+        updated_consultation.status = ConsultationStatus::Completed;
+        do_insert_legal_consultation(&updated_consultation);
+        payments::schedule_settlement(id);
         Ok(())
     } else {
         Err(Error::NotFound {
@@ -199,11 +841,39 @@ fn mark_consultation_as_completed(id: u64) -> Result<(), Error> {
     }
 }
 
+/// Sets the practice area that decides the consultation's retention period;
+/// it must be one of the advisor's practice areas.
 #[ic_cdk::update]
-fn close_legal_consultation(id: u64, closed_at: u64) -> Result<(), Error> {
-    if let Some(mut consultation) = _get_legal_consultation(&id) {
-        consultation.closed_at = Some(closed_at);
-        LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().insert(id, consultation));
+async fn set_consultation_practice_area(
+    id: u64,
+    practice_area: Option<String>,
+) -> Result<(), Error> {
+    sharding::load_consultations(&[id]).await?;
+    if let Some(mut consultation) = scoped_legal_consultation(&id) {
+        if !can_manage_consultation(&consultation, &ic_cdk::caller()) {
+            return Err(Error::Unauthorized {
+                msg: format!("Caller cannot update legal consultation with id={}", id),
+            });
+        }
+        if let Some(area) = &practice_area {
+            let offered = _get_legal_advisor(&consultation.advisor_id).is_some_and(|advisor| {
+                advisor
+                    .practice_areas
+                    .iter()
+                    .any(|offered| offered.eq_ignore_ascii_case(area))
+            });
+            if !offered {
+                return Err(Error::InvalidInput {
+                    msg: format!(
+                        "Legal advisor with id={} does not practice {}",
+                        consultation.advisor_id, area
+                    ),
+                });
+            }
+        }
+
+        consultation.practice_area = practice_area;
+        do_insert_legal_consultation(&consultation);
         Ok(())
     } else {
         Err(Error::NotFound {
@@ -212,43 +882,150 @@ fn close_legal_consultation(id: u64, closed_at: u64) -> Result<(), Error> {
     }
 }
 
-#[ic_cdk::query]
-fn list_all_legal_consultations() -> Vec<LegalConsultation> {
-    LEGAL_CONSULTATIONS.with(|service| {
-        let map_ref = service.borrow();
-        map_ref.iter().map(|(_, v)| v.clone()).collect()
-    })
+/// Closes the consultation now; its retention period runs from this point.
+///
+/// `_closed_at` is the close time callers used to pass. It is still accepted,
+/// so existing callers keep working, but ignored: the canister's clock is
+/// what retention runs from.
+#[ic_cdk::update]
+async fn close_legal_consultation(id: u64, _closed_at: Option<u64>) -> Result<(), Error> {
+    sharding::load_consultations(&[id]).await?;
+    if let Some(mut consultation) = scoped_legal_consultation(&id) {
+        if !can_manage_consultation(&consultation, &ic_cdk::caller()) {
+            return Err(Error::Unauthorized {
+                msg: format!("Caller cannot close legal consultation with id={}", id),
+            });
+        }
+        if consultation.closed_at.is_some() {
+            return Err(Error::InvalidInput {
+                msg: format!("Legal consultation with id={} is already closed", id),
+            });
+        }
+
+        consultation.closed_at = Some(time());
+        do_insert_legal_consultation(&consultation);
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", id),
+        })
+    }
+}
+
+#[ic_cdk::update]
+async fn cancel_legal_consultation(id: u64) -> Result<(), Error> {
+    sharding::load_consultations(&[id]).await?;
+    if let Some(mut consultation) = scoped_legal_consultation(&id) {
+        if !can_manage_consultation(&consultation, &ic_cdk::caller()) {
+            return Err(Error::Unauthorized {
+                msg: format!("Caller cannot cancel legal consultation with id={}", id),
+            });
+        }
+        if consultation.status == ConsultationStatus::Completed {
+            return Err(Error::InvalidInput {
+                msg: format!("Legal consultation with id={} is already completed", id),
+            });
+        }
+
+        consultation.status = ConsultationStatus::Cancelled;
+        do_insert_legal_consultation(&consultation);
+        payments::schedule_settlement(id);
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", id),
+        })
+    }
+}
+
+#[ic_cdk::query(composite = true)]
+async fn list_all_legal_consultations() -> Vec<LegalConsultation> {
+    sharding::or_trap(consultations_visible_to(&ic_cdk::caller()).await)
 }
 
 #[ic_cdk::query]
 fn list_all_legal_advisors() -> Vec<LegalAdvisor> {
+    advisors_visible_to(&ic_cdk::caller())
+}
+
+/// Every consultation, including those in buckets.
+async fn all_legal_consultations() -> Result<Vec<LegalConsultation>, Error> {
+    sharding::all_consultations().await
+}
+
+fn all_legal_advisors() -> Vec<LegalAdvisor> {
     LEGAL_ADVISORS.with(|service| {
         let map_ref = service.borrow();
         map_ref.iter().map(|(_, v)| v.clone()).collect()
     })
 }
 
+async fn consultations_visible_to(principal: &Principal) -> Result<Vec<LegalConsultation>, Error> {
+    Ok(all_legal_consultations()
+        .await?
+        .into_iter()
+        .filter(|consultation| can_reach_consultation(consultation, principal))
+        .collect())
+}
+
+fn advisors_visible_to(principal: &Principal) -> Vec<LegalAdvisor> {
+    all_legal_advisors()
+        .into_iter()
+        .filter(|advisor| can_see_advisor(advisor, principal))
+        .collect()
+}
+
+/// Advisors outside every organization are public profiles, shown even to
+/// the anonymous principal; firm advisors only to their organization.
+fn can_see_advisor(advisor: &LegalAdvisor, principal: &Principal) -> bool {
+    (advisor.organization_id.is_none() && *principal == Principal::anonymous())
+        || organizations::can_access(principal, advisor.organization_id)
+}
+
 #[ic_cdk::update]
-fn update_legal_consultation(
+async fn update_legal_consultation(
     id: u64,
     advisor_id: Option<u64>,
     details: Option<String>,
     is_completed: Option<bool>,
 ) -> Result<(), Error> {
-    if let Some(mut consultation) = _get_legal_consultation(&id) {
-        // Update fields if provided
-        if let Some(advisor_id) = advisor_id {
-            consultation.advisor_id = advisor_id;
+    sharding::load_consultations(&[id]).await?;
+    if let Some(mut consultation) = scoped_legal_consultation(&id) {
+        if !can_manage_consultation(&consultation, &ic_cdk::caller()) {
+            return Err(Error::Unauthorized {
+                msg: format!("Caller cannot update legal consultation with id={}", id),
+            });
         }
+        // Reassignment needs the new advisor's consent, see `handoffs`.
+        if advisor_id.is_some_and(|advisor_id| advisor_id != consultation.advisor_id) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Propose a handoff to reassign legal consultation with id={}",
+                    id
+                ),
+            });
+        }
+        // Update fields if provided
         if let Some(details) = details {
+            validate_details(&details)?;
             consultation.details = details;
         }
         if let Some(is_completed) = is_completed {
+            ensure_accepted(&consultation)?;
+            workflows::ensure_status_change_allowed(id)?;
             consultation.is_completed = is_completed;
+            consultation.status = if is_completed {
+                ConsultationStatus::Completed
+            } else {
+                ConsultationStatus::Open
+            };
         }
 
         // Update the consultation in the map
-        LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().insert(id, consultation));
+        do_insert_legal_consultation(&consultation);
+        if is_completed.is_some() {
+            payments::schedule_settlement(id);
+        }
         Ok(())
     } else {
         Err(Error::NotFound {
@@ -257,9 +1034,142 @@ fn update_legal_consultation(
     }
 }
 
+#[ic_cdk::update]
+fn add_legal_client(
+    name: String,
+    email: String,
+    principal: Option<Principal>,
+) -> Option<LegalClient> {
+    let client = LegalClient {
+        id: next_id(),
+        name,
+        email,
+        principal,
+        created_at: time(),
+        organization_id: organizations::organization_of(&ic_cdk::caller()),
+    };
+
+    do_insert_legal_client(&client);
+    Some(client)
+}
+
+#[ic_cdk::update]
+fn update_legal_client(
+    id: u64,
+    name: Option<String>,
+    email: Option<String>,
+    principal: Option<Principal>,
+) -> Result<(), Error> {
+    if let Some(mut client) = firm_legal_client(&id) {
+        if let Some(name) = name {
+            client.name = name;
+        }
+        if let Some(email) = email {
+            client.email = email;
+        }
+        if principal.is_some() {
+            client.principal = principal;
+        }
+
+        do_insert_legal_client(&client);
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("Legal client with id={} not found", id),
+        })
+    }
+}
+
+#[ic_cdk::query]
+fn get_legal_client(id: u64) -> Result<LegalClient, Error> {
+    match scoped_legal_client(&id) {
+        Some(client) => Ok(client),
+        None => Err(Error::NotFound {
+            msg: format!("Legal client with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_all_legal_clients() -> Vec<LegalClient> {
+    let caller = ic_cdk::caller();
+    all_legal_clients()
+        .into_iter()
+        .filter(|client| can_reach_client(client, &caller))
+        .collect()
+}
+
+fn all_legal_clients() -> Vec<LegalClient> {
+    LEGAL_CLIENTS.with(|service| {
+        let map_ref = service.borrow();
+        map_ref.iter().map(|(_, v)| v.clone()).collect()
+    })
+}
+
+fn do_insert_legal_client(client: &LegalClient) {
+    LEGAL_CLIENTS.with(|service| service.borrow_mut().insert(client.id, client.clone()));
+    changes::record_upsert(ChangeEntity::Client, client.id, client);
+    names::index_name(NameKind::Client, client.id, &client.name);
+}
+
+fn _get_legal_client(id: &u64) -> Option<LegalClient> {
+    LEGAL_CLIENTS.with(|service| service.borrow().get(id))
+}
+
+fn scoped_legal_client(id: &u64) -> Option<LegalClient> {
+    _get_legal_client(id).filter(|client| can_reach_client(client, &ic_cdk::caller()))
+}
+
+/// The client record, if it belongs to the caller's organization.
+fn firm_legal_client(id: &u64) -> Option<LegalClient> {
+    _get_legal_client(id)
+        .filter(|client| organizations::can_access(&ic_cdk::caller(), client.organization_id))
+}
+
+/// Clients are not organization members, but can see their own record.
+fn can_reach_client(client: &LegalClient, principal: &Principal) -> bool {
+    client.principal.as_ref() == Some(principal)
+        || organizations::can_access(principal, client.organization_id)
+}
+
+/// Firm-internal records (billing, matters, tasks) are restricted to
+/// controllers, organization admins and principals granted a staff role.
+/// Callers must still be checked against the organization owning the record.
+fn ensure_firm_staff(caller: &Principal) -> Result<(), Error> {
+    if ic_cdk::api::is_controller(caller)
+        || organizations::is_organization_admin(caller)
+        || organizations::staff_role(caller).is_some()
+    {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only firm staff can access this record".to_string(),
+        })
+    }
+}
+
+/// Trust accounting is further restricted to controllers and bookkeepers.
+fn ensure_bookkeeper(caller: &Principal) -> Result<(), Error> {
+    if ic_cdk::api::is_controller(caller)
+        || organizations::staff_role(caller) == Some(StaffRole::Bookkeeper)
+    {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only bookkeepers can access the trust ledger".to_string(),
+        })
+    }
+}
+
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
     NotFound { msg: String },
+    Unauthorized { msg: String },
+    InvalidInput { msg: String },
+    PaymentFailed { msg: String },
+    InsufficientFunds { msg: String },
+    LegalHold { msg: String },
+    CallFailed { msg: String },
 }
 
 ic_cdk::export_candid!();
@@ -0,0 +1,636 @@
+use crate::{
+    _get_legal_client, _get_legal_consultation, backup, changes, ensure_firm_staff,
+    firm_legal_client, firm_legal_consultation, next_id, organizations, retention,
+    scoped_legal_advisor, sharding, ChangeEntity, ConsultationStatus, Error, Memory,
+    MAX_PRACTICE_AREA_BYTES, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Keep a matter within its storage bound.
+const MAX_MATTER_CONSULTATIONS: usize = 200;
+const MAX_TITLE_BYTES: usize = 256;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum MatterStatus {
+    Open,
+    Closed,
+}
+
+/// A legal matter grouping the consultations held about it, in order.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Matter {
+    id: u64,
+    title: String,
+    practice_area: Option<String>,
+    client_id: u64,
+    // The responsible advisor.
+    advisor_id: u64,
+    organization_id: Option<u64>,
+    status: MatterStatus,
+    opened_at: u64,
+    closed_at: Option<u64>,
+    consultation_ids: Vec<u64>,
+}
+
+impl Storable for Matter {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Matter {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum MatterEventKind {
+    Opened,
+    Closed,
+    Reopened,
+    ConsultationAdded,
+    ConsultationRemoved,
+    // Derived from the linked consultations when building a timeline.
+    ConsultationCreated,
+    ConsultationClosed,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct MatterEvent {
+    kind: MatterEventKind,
+    consultation_id: Option<u64>,
+    by: Option<Principal>,
+    at: u64,
+}
+
+/// A matter event as published in the change feed.
+#[derive(Serialize)]
+struct MatterEventChange<'a> {
+    matter_id: u64,
+    #[serde(flatten)]
+    event: &'a MatterEvent,
+}
+
+impl Storable for MatterEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MatterEvent {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Orders the events of a matter together, oldest first.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct MatterEventKey {
+    matter_id: u64,
+    id: u64,
+}
+
+impl Storable for MatterEventKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.matter_id.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        MatterEventKey {
+            matter_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for MatterEventKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static MATTERS: RefCell<StableBTreeMap<u64, Matter, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static MATTER_EVENTS: RefCell<StableBTreeMap<MatterEventKey, MatterEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    // The matter each linked consultation belongs to, keyed by consultation
+    // id. Mirrors the matters' consultation lists.
+    static CONSULTATION_MATTERS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+}
+
+/// Opens a matter for the client, optionally linking existing consultations
+/// in the given order.
+#[ic_cdk::update]
+async fn open_matter(
+    title: String,
+    practice_area: Option<String>,
+    client_id: u64,
+    advisor_id: u64,
+    consultation_ids: Vec<u64>,
+) -> Result<Matter, Error> {
+    sharding::load_consultations(&consultation_ids).await?;
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    if title.trim().is_empty() || title.len() > MAX_TITLE_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Matter titles are 1 to {} bytes", MAX_TITLE_BYTES),
+        });
+    }
+    if practice_area
+        .as_ref()
+        .is_some_and(|area| area.len() > MAX_PRACTICE_AREA_BYTES)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Practice areas are at most {} bytes",
+                MAX_PRACTICE_AREA_BYTES
+            ),
+        });
+    }
+    if firm_legal_client(&client_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal client with id={} not found", client_id),
+        });
+    }
+    if scoped_legal_advisor(&advisor_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", advisor_id),
+        });
+    }
+
+    let mut matter = Matter {
+        id: next_id(),
+        title,
+        practice_area,
+        client_id,
+        advisor_id,
+        organization_id: organizations::organization_of(&caller),
+        status: MatterStatus::Open,
+        opened_at: time(),
+        closed_at: None,
+        consultation_ids: Vec::new(),
+    };
+    for consultation_id in &consultation_ids {
+        ensure_linkable(&matter, *consultation_id)?;
+        ensure_movable(*consultation_id)?;
+    }
+    if consultation_ids.len() > MAX_MATTER_CONSULTATIONS {
+        return Err(too_many_consultations());
+    }
+
+    record_event(matter.id, MatterEventKind::Opened, None, caller);
+    for consultation_id in consultation_ids {
+        if matter.consultation_ids.contains(&consultation_id) {
+            continue;
+        }
+        unlink(consultation_id, caller);
+        matter.consultation_ids.push(consultation_id);
+        record_event(
+            matter.id,
+            MatterEventKind::ConsultationAdded,
+            Some(consultation_id),
+            caller,
+        );
+    }
+    do_insert_matter(&matter);
+    Ok(matter)
+}
+
+#[ic_cdk::query]
+fn get_matter(id: u64) -> Result<Matter, Error> {
+    visible_matter(id, &ic_cdk::caller())
+}
+
+/// Matters in the caller's organization, optionally limited to one client
+/// or status.
+#[ic_cdk::query]
+fn list_matters(
+    client_id: Option<u64>,
+    status: Option<MatterStatus>,
+) -> Result<Vec<Matter>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    Ok(MATTERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, matter)| matter)
+            .filter(|matter| {
+                organizations::can_access(&caller, matter.organization_id)
+                    && client_id.is_none_or(|client_id| matter.client_id == client_id)
+                    && status.is_none_or(|status| matter.status == status)
+            })
+            .collect()
+    }))
+}
+
+/// Closes the matter once none of its consultations is still open.
+#[ic_cdk::update]
+async fn close_matter(id: u64) -> Result<Matter, Error> {
+    load_matter_consultations(id).await?;
+    let caller = ic_cdk::caller();
+    let mut matter = managed_matter(id, &caller)?;
+    if matter.status == MatterStatus::Closed {
+        return Err(Error::InvalidInput {
+            msg: format!("Matter with id={} is already closed", id),
+        });
+    }
+    if let Some(open) = matter.consultation_ids.iter().find(|consultation_id| {
+        _get_legal_consultation(consultation_id).is_some_and(|consultation| {
+            matches!(
+                consultation.status,
+                ConsultationStatus::Open | ConsultationStatus::Pending
+            )
+        })
+    }) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Matter with id={} still has open legal consultation with id={}",
+                id, open
+            ),
+        });
+    }
+
+    matter.status = MatterStatus::Closed;
+    matter.closed_at = Some(time());
+    record_event(id, MatterEventKind::Closed, None, caller);
+    do_insert_matter(&matter);
+    Ok(matter)
+}
+
+#[ic_cdk::update]
+fn reopen_matter(id: u64) -> Result<Matter, Error> {
+    let caller = ic_cdk::caller();
+    let mut matter = managed_matter(id, &caller)?;
+    if matter.status == MatterStatus::Open {
+        return Err(Error::InvalidInput {
+            msg: format!("Matter with id={} is already open", id),
+        });
+    }
+
+    matter.status = MatterStatus::Open;
+    matter.closed_at = None;
+    record_event(id, MatterEventKind::Reopened, None, caller);
+    do_insert_matter(&matter);
+    Ok(matter)
+}
+
+/// Links the consultation to the matter at `position` (appended by
+/// default), taking it out of the matter it was linked to before. Also
+/// reorders a consultation already linked to the matter.
+#[ic_cdk::update]
+async fn move_consultation_to_matter(
+    consultation_id: u64,
+    matter_id: u64,
+    position: Option<u32>,
+) -> Result<Matter, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let mut matter = managed_matter(matter_id, &caller)?;
+    if matter.status == MatterStatus::Closed {
+        return Err(Error::InvalidInput {
+            msg: format!("Matter with id={} is closed", matter_id),
+        });
+    }
+    ensure_linkable(&matter, consultation_id)?;
+    let already_linked = matter.consultation_ids.contains(&consultation_id);
+    if !already_linked {
+        ensure_movable(consultation_id)?;
+    }
+
+    if !already_linked && matter.consultation_ids.len() >= MAX_MATTER_CONSULTATIONS {
+        return Err(too_many_consultations());
+    }
+    matter.consultation_ids.retain(|id| *id != consultation_id);
+    let position = position.map_or(matter.consultation_ids.len(), |position| {
+        (position as usize).min(matter.consultation_ids.len())
+    });
+    matter.consultation_ids.insert(position, consultation_id);
+
+    if !already_linked {
+        unlink(consultation_id, caller);
+        record_event(
+            matter_id,
+            MatterEventKind::ConsultationAdded,
+            Some(consultation_id),
+            caller,
+        );
+    }
+    do_insert_matter(&matter);
+    Ok(matter)
+}
+
+/// Takes the consultation out of its matter without linking it elsewhere.
+#[ic_cdk::update]
+fn remove_consultation_from_matter(consultation_id: u64) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    match matter_of(consultation_id) {
+        Some(matter) => {
+            managed_matter(matter.id, &caller)?;
+            ensure_movable(consultation_id)?;
+            unlink(consultation_id, caller);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Legal consultation with id={} is not part of a matter",
+                consultation_id
+            ),
+        }),
+    }
+}
+
+/// Everything that happened on the matter and its consultations, oldest
+/// first. Consultations moved elsewhere keep their earlier events.
+#[ic_cdk::query(composite = true)]
+async fn get_matter_timeline(id: u64) -> Result<Vec<MatterEvent>, Error> {
+    load_matter_consultations(id).await?;
+    let matter = visible_matter(id, &ic_cdk::caller())?;
+
+    let mut timeline: Vec<MatterEvent> = MATTER_EVENTS.with(|service| {
+        service
+            .borrow()
+            .range(
+                MatterEventKey {
+                    matter_id: id,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.matter_id == id)
+            .map(|(_, event)| event)
+            .collect()
+    });
+    for consultation_id in &matter.consultation_ids {
+        if let Some(consultation) = _get_legal_consultation(consultation_id) {
+            timeline.push(MatterEvent {
+                kind: MatterEventKind::ConsultationCreated,
+                consultation_id: Some(consultation.id),
+                by: consultation.client,
+                at: consultation.created_at,
+            });
+            if let Some(closed_at) = consultation.closed_at {
+                timeline.push(MatterEvent {
+                    kind: MatterEventKind::ConsultationClosed,
+                    consultation_id: Some(consultation.id),
+                    by: None,
+                    at: closed_at,
+                });
+            }
+        }
+    }
+    // Stable, so same-time events keep their recorded order.
+    timeline.sort_by_key(|event| event.at);
+    Ok(timeline)
+}
+
+/// The matter the consultation is linked to, if any.
+pub(crate) fn matter_of(consultation_id: u64) -> Option<Matter> {
+    matter_id_of(consultation_id).and_then(|id| _get_matter(&id))
+}
+
+/// The id of the matter the consultation is linked to, if any.
+pub(crate) fn matter_id_of(consultation_id: u64) -> Option<u64> {
+    CONSULTATION_MATTERS.with(|service| service.borrow().get(&consultation_id))
+}
+
+/// Indexes the consultations of matters linked before the index existed.
+pub(crate) fn index_consultations() {
+    if CONSULTATION_MATTERS.with(|service| !service.borrow().is_empty()) {
+        return;
+    }
+    MATTERS.with(|matters| {
+        CONSULTATION_MATTERS.with(|index| {
+            let mut index = index.borrow_mut();
+            for (id, matter) in matters.borrow().iter() {
+                for consultation_id in matter.consultation_ids {
+                    index.insert(consultation_id, id);
+                }
+            }
+        })
+    });
+}
+
+/// Whether the matter is the client's and within the staff member's scope.
+pub(crate) fn is_client_matter(matter_id: u64, client_id: u64, staff: &Principal) -> bool {
+    _get_matter(&matter_id).is_some_and(|matter| {
+        matter.client_id == client_id && organizations::can_access(staff, matter.organization_id)
+    })
+}
+
+/// The client's matters, limited to those `scope` may access when given.
+pub(crate) fn matters_of_client(client_id: u64, scope: Option<&Principal>) -> Vec<Matter> {
+    MATTERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, matter)| matter)
+            .filter(|matter| {
+                matter.client_id == client_id
+                    && scope.is_none_or(|staff| {
+                        organizations::can_access(staff, matter.organization_id)
+                    })
+            })
+            .collect()
+    })
+}
+
+/// Matters are visible to firm staff of their organization and to their
+/// client.
+fn visible_matter(id: u64, caller: &Principal) -> Result<Matter, Error> {
+    match _get_matter(&id) {
+        Some(matter)
+            if (organizations::can_access(caller, matter.organization_id)
+                && ensure_firm_staff(caller).is_ok())
+                || _get_legal_client(&matter.client_id)
+                    .and_then(|client| client.principal)
+                    .as_ref()
+                    == Some(caller) =>
+        {
+            Ok(matter)
+        }
+        _ => Err(matter_not_found(id)),
+    }
+}
+
+fn managed_matter(id: u64, caller: &Principal) -> Result<Matter, Error> {
+    ensure_firm_staff(caller)?;
+    _get_matter(&id)
+        .filter(|matter| organizations::can_access(caller, matter.organization_id))
+        .ok_or_else(|| matter_not_found(id))
+}
+
+/// Loads the consultations linked to a matter from their buckets.
+async fn load_matter_consultations(id: u64) -> Result<(), Error> {
+    let consultation_ids = _get_matter(&id)
+        .map(|matter| matter.consultation_ids)
+        .unwrap_or_default();
+    sharding::load_consultations(&consultation_ids).await
+}
+
+/// Consultations must belong to the matter's organization and client.
+fn ensure_linkable(matter: &Matter, consultation_id: u64) -> Result<(), Error> {
+    let consultation = firm_legal_consultation(&consultation_id)
+        .filter(|consultation| consultation.organization_id == matter.organization_id)
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", consultation_id),
+        })?;
+    let client = firm_legal_client(&matter.client_id).and_then(|client| client.principal);
+    if consultation.client.is_some() && client.is_some() && consultation.client != client {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} belongs to another client than matter {}",
+                consultation_id, matter.id
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Consultations of a closed matter stay put until it is reopened.
+fn ensure_movable(consultation_id: u64) -> Result<(), Error> {
+    match matter_of(consultation_id) {
+        Some(matter) if matter.status == MatterStatus::Closed => Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} belongs to closed matter {}",
+                consultation_id, matter.id
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Takes the consultation out of the matter it is linked to, if any.
+fn unlink(consultation_id: u64, by: Principal) {
+    if let Some(mut matter) = matter_of(consultation_id) {
+        matter.consultation_ids.retain(|id| *id != consultation_id);
+        record_event(
+            matter.id,
+            MatterEventKind::ConsultationRemoved,
+            Some(consultation_id),
+            by,
+        );
+        do_insert_matter(&matter);
+    }
+}
+
+fn record_event(
+    matter_id: u64,
+    kind: MatterEventKind,
+    consultation_id: Option<u64>,
+    by: Principal,
+) {
+    let key = MatterEventKey {
+        matter_id,
+        id: next_id(),
+    };
+    let event = MatterEvent {
+        kind,
+        consultation_id,
+        by: Some(by),
+        at: time(),
+    };
+    changes::record_upsert(
+        ChangeEntity::MatterEvent,
+        key.id,
+        &MatterEventChange {
+            matter_id,
+            event: &event,
+        },
+    );
+    MATTER_EVENTS.with(|service| service.borrow_mut().insert(key, event));
+}
+
+/// Replaces the titles of the client's matters within the staff member's
+/// scope, including in the change feed history. Matters holding a
+/// consultation under legal hold keep theirs.
+pub(crate) fn erase_client_matters(client_id: u64, staff: &Principal, erased: &str) -> usize {
+    let matters: Vec<Matter> = matters_of_client(client_id, Some(staff))
+        .into_iter()
+        .filter(|matter| {
+            !matter
+                .consultation_ids
+                .iter()
+                .any(|id| retention::is_on_hold(*id))
+        })
+        .collect();
+    for mut matter in matters.clone() {
+        matter.title = erased.to_string();
+        changes::redact_history(ChangeEntity::Matter, matter.id);
+        do_insert_matter(&matter);
+    }
+    matters.len()
+}
+
+fn do_insert_matter(matter: &Matter) {
+    let unlinked: Vec<u64> = _get_matter(&matter.id)
+        .map(|old| old.consultation_ids)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|id| !matter.consultation_ids.contains(id))
+        .collect();
+    CONSULTATION_MATTERS.with(|service| {
+        let mut index = service.borrow_mut();
+        for consultation_id in unlinked {
+            if index.get(&consultation_id) == Some(matter.id) {
+                index.remove(&consultation_id);
+            }
+        }
+        for consultation_id in &matter.consultation_ids {
+            index.insert(*consultation_id, matter.id);
+        }
+    });
+    MATTERS.with(|service| service.borrow_mut().insert(matter.id, matter.clone()));
+    changes::record_upsert(ChangeEntity::Matter, matter.id, matter);
+}
+
+fn _get_matter(id: &u64) -> Option<Matter> {
+    MATTERS.with(|service| service.borrow().get(id))
+}
+
+fn matter_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Matter with id={} not found", id),
+    }
+}
+
+fn too_many_consultations() -> Error {
+    Error::InvalidInput {
+        msg: format!(
+            "A matter can link at most {} consultations",
+            MAX_MATTER_CONSULTATIONS
+        ),
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("matters", &MATTERS),
+        backup::map_source("matter_events", &MATTER_EVENTS),
+        backup::map_source("consultation_matters", &CONSULTATION_MATTERS),
+    ]
+}
@@ -0,0 +1,474 @@
+use crate::{
+    all_legal_advisors, all_legal_clients, ensure_firm_staff, firm_legal_client,
+    scoped_legal_advisor, Error, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_CODE_LEN: usize = 4;
+const DEFAULT_MIN_SCORE: f32 = 0.7;
+const CONFLICT_MIN_SCORE: f32 = 0.8;
+const MAX_NAME_MATCHES: usize = 50;
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum NameKind {
+    Advisor,
+    Client,
+}
+
+impl NameKind {
+    fn code(self) -> u8 {
+        match self {
+            NameKind::Advisor => 0,
+            NameKind::Client => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            0 => NameKind::Advisor,
+            1 => NameKind::Client,
+            _ => panic!("Unknown name kind {}", code),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct NameKey {
+    kind: NameKind,
+    id: u64,
+}
+
+impl Storable for NameKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(9);
+        bytes.push(self.kind.code());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        NameKey {
+            kind: NameKind::from_code(bytes[0]),
+            id: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for NameKey {
+    const MAX_SIZE: u32 = 9;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// A name with its folded tokens and their phonetic keys precomputed.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IndexedName {
+    name: String,
+    tokens: Vec<NameToken>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NameToken {
+    text: String,
+    primary: String,
+    alternate: String,
+}
+
+impl Storable for IndexedName {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IndexedName {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct NameMatch {
+    kind: NameKind,
+    id: u64,
+    name: String,
+    score: f32,
+    phonetic_match: bool,
+}
+
+thread_local! {
+    static NAME_INDEX: RefCell<StableBTreeMap<NameKey, IndexedName, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+}
+
+/// Typo- and transliteration-tolerant search over advisor and client names.
+#[ic_cdk::query]
+fn search_names(
+    query: String,
+    kind: Option<NameKind>,
+    min_score: Option<f32>,
+) -> Result<Vec<NameMatch>, Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    Ok(match_names(
+        &query,
+        kind,
+        min_score.unwrap_or(DEFAULT_MIN_SCORE),
+    ))
+}
+
+/// Lists existing clients and advisors whose names are close enough to
+/// `name` to warrant a conflict-of-interest review.
+#[ic_cdk::query]
+fn conflict_check(name: String) -> Result<Vec<NameMatch>, Error> {
+    ensure_firm_staff(&ic_cdk::caller())?;
+
+    Ok(match_names(&name, None, CONFLICT_MIN_SCORE))
+}
+
+pub(crate) fn index_name(kind: NameKind, id: u64, name: &str) {
+    let indexed = IndexedName {
+        name: name.to_string(),
+        tokens: name_tokens(name),
+    };
+    NAME_INDEX.with(|service| service.borrow_mut().insert(NameKey { kind, id }, indexed));
+}
+
+pub(crate) fn remove_name(kind: NameKind, id: u64) {
+    NAME_INDEX.with(|service| service.borrow_mut().remove(&NameKey { kind, id }));
+}
+
+pub(crate) fn rebuild_name_index() -> u64 {
+    let advisors = all_legal_advisors();
+    let clients = all_legal_clients();
+    for advisor in &advisors {
+        index_name(NameKind::Advisor, advisor.id, &advisor.name);
+    }
+    for client in &clients {
+        index_name(NameKind::Client, client.id, &client.name);
+    }
+    (advisors.len() + clients.len()) as u64
+}
+
+fn match_names(query: &str, kind: Option<NameKind>, min_score: f32) -> Vec<NameMatch> {
+    let query_tokens = name_tokens(query);
+    if query_tokens.is_empty() {
+        return Vec::new();
+    }
+
+    let mut matches: Vec<NameMatch> = NAME_INDEX.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(key, _)| kind.is_none_or(|kind| key.kind == kind))
+            .filter_map(|(key, indexed)| {
+                let (score, phonetic_match) = name_similarity(&query_tokens, &indexed.tokens);
+                (score >= min_score).then_some(NameMatch {
+                    kind: key.kind,
+                    id: key.id,
+                    name: indexed.name,
+                    score,
+                    phonetic_match,
+                })
+            })
+            .filter(|name_match| match name_match.kind {
+                // Names of other organizations are neither matches nor conflicts.
+                NameKind::Advisor => scoped_legal_advisor(&name_match.id).is_some(),
+                NameKind::Client => firm_legal_client(&name_match.id).is_some(),
+            })
+            .collect()
+    });
+    matches.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+    matches.truncate(MAX_NAME_MATCHES);
+    matches
+}
+
+/// Scores how well every query token is matched by some token of the name,
+/// combining edit distance with agreement of the phonetic keys.
+fn name_similarity(query: &[NameToken], name: &[NameToken]) -> (f32, bool) {
+    let mut total = 0.0;
+    let mut phonetic_match = false;
+
+    for query_token in query {
+        let mut best = 0.0f32;
+        for name_token in name {
+            let edit = edit_similarity(&query_token.text, &name_token.text);
+            let phonetic = if query_token.primary == name_token.primary {
+                1.0
+            } else if query_token.primary == name_token.alternate
+                || query_token.alternate == name_token.primary
+                || query_token.alternate == name_token.alternate
+            {
+                0.8
+            } else {
+                0.0
+            };
+            if phonetic > 0.0 {
+                phonetic_match = true;
+            }
+            best = best.max(edit.max(0.6 * edit + 0.4 * phonetic));
+        }
+        total += best;
+    }
+
+    (total / query.len() as f32, phonetic_match)
+}
+
+fn name_tokens(name: &str) -> Vec<NameToken> {
+    fold(name)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            let (primary, alternate) = double_metaphone(token);
+            NameToken {
+                text: token.to_string(),
+                primary,
+                alternate,
+            }
+        })
+        .collect()
+}
+
+fn edit_similarity(a: &str, b: &str) -> f32 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(&a, &b) as f32 / longest as f32
+}
+
+/// Optimal string alignment distance: Levenshtein plus adjacent transpositions.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut rows = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Lowercases and strips diacritics so transliterated spellings compare equal.
+fn fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.to_lowercase().chars() {
+        match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => folded.push('a'),
+            'ç' | 'ć' | 'č' => folded.push('c'),
+            'ď' | 'đ' => folded.push('d'),
+            'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => folded.push('e'),
+            'ğ' => folded.push('g'),
+            'ì' | 'í' | 'î' | 'ï' | 'ī' | 'ı' => folded.push('i'),
+            'ł' => folded.push('l'),
+            'ñ' | 'ń' | 'ň' => folded.push('n'),
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => folded.push('o'),
+            'ř' => folded.push('r'),
+            'ś' | 'š' | 'ş' => folded.push('s'),
+            'ť' | 'ţ' => folded.push('t'),
+            'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' => folded.push('u'),
+            'ý' | 'ÿ' => folded.push('y'),
+            'ź' | 'ż' | 'ž' => folded.push('z'),
+            'ß' => folded.push_str("ss"),
+            'æ' => folded.push_str("ae"),
+            'œ' => folded.push_str("oe"),
+            _ => folded.push(c),
+        }
+    }
+    folded
+}
+
+/// Primary and alternate phonetic keys of a single word, following the main
+/// rules of Lawrence Philips' Double Metaphone.
+fn double_metaphone(word: &str) -> (String, String) {
+    let chars: Vec<char> = fold(word)
+        .to_ascii_uppercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .collect();
+    let at = |i: usize| chars.get(i).copied().unwrap_or('\0');
+    let is_vowel = |c: char| matches!(c, 'A' | 'E' | 'I' | 'O' | 'U' | 'Y');
+
+    let mut primary = String::new();
+    let mut alternate = String::new();
+    let mut i = 0;
+
+    // Silent leading letters.
+    if matches!(
+        (at(0), at(1)),
+        ('G', 'N') | ('K', 'N') | ('P', 'N') | ('P', 'S') | ('W', 'R')
+    ) {
+        i = 1;
+    }
+    if at(0) == 'X' {
+        primary.push('S');
+        alternate.push('S');
+        i = 1;
+    }
+
+    while i < chars.len() && (primary.len() < MAX_CODE_LEN || alternate.len() < MAX_CODE_LEN) {
+        let c = chars[i];
+        let next = at(i + 1);
+        let after_next = at(i + 2);
+        let previous = if i > 0 { chars[i - 1] } else { '\0' };
+        let doubled = if next == c { 2 } else { 1 };
+
+        let (main, alt, advance): (&str, &str, usize) = match c {
+            'A' | 'E' | 'I' | 'O' | 'U' | 'Y' => (if i == 0 { "A" } else { "" }, "", 1),
+            'B' => ("P", "P", doubled),
+            'C' => match next {
+                'H' => ("X", "K", 2),
+                'I' if matches!(after_next, 'A' | 'O') => ("X", "S", 3),
+                'I' | 'E' | 'Y' => ("S", "S", 2),
+                'K' | 'Q' | 'C' => ("K", "K", 2),
+                _ => ("K", "K", 1),
+            },
+            'D' => match next {
+                'G' if matches!(after_next, 'I' | 'E' | 'Y') => ("J", "J", 3),
+                'T' | 'D' => ("T", "T", 2),
+                _ => ("T", "T", 1),
+            },
+            'F' => ("F", "F", doubled),
+            'G' => match next {
+                'H' if i == 0 || !is_vowel(previous) => ("K", "K", 2),
+                'H' => ("", "", 2),
+                'N' => ("N", "KN", 2),
+                'I' | 'E' | 'Y' => ("J", "K", 2),
+                _ => ("K", "K", doubled),
+            },
+            'H' if (i == 0 || is_vowel(previous)) && is_vowel(next) => ("H", "H", 1),
+            'H' => ("", "", 1),
+            'J' => ("J", "H", doubled),
+            'K' => ("K", "K", doubled),
+            'L' => ("L", "L", doubled),
+            'M' => ("M", "M", doubled),
+            'N' => ("N", "N", doubled),
+            'P' if next == 'H' => ("F", "F", 2),
+            'P' => ("P", "P", if matches!(next, 'P' | 'B') { 2 } else { 1 }),
+            'Q' => ("K", "K", doubled),
+            'R' => ("R", "R", doubled),
+            'S' => match next {
+                'H' => ("X", "X", 2),
+                'C' if after_next == 'H' => ("SK", "SK", 3),
+                'I' if matches!(after_next, 'O' | 'A') => ("X", "S", 3),
+                'Z' => ("S", "X", 2),
+                _ => ("S", "S", doubled),
+            },
+            'T' => match next {
+                'H' => ("0", "T", 2),
+                'I' if matches!(after_next, 'O' | 'A') => ("X", "X", 3),
+                'C' if after_next == 'H' => ("X", "X", 3),
+                'T' | 'D' => ("T", "T", 2),
+                _ => ("T", "T", 1),
+            },
+            'V' => ("F", "F", doubled),
+            'W' if i == 0 && next == 'H' => ("A", "A", 2),
+            'W' if i == 0 && is_vowel(next) => ("A", "F", 1),
+            'W' => ("", "", 1),
+            'X' => ("KS", "KS", if matches!(next, 'C' | 'X') { 2 } else { 1 }),
+            'Z' => ("S", "TS", doubled),
+            _ => ("", "", 1),
+        };
+
+        primary.push_str(main);
+        // Vowels only contribute to the primary key at the start of a word.
+        alternate.push_str(if is_vowel(c) { main } else { alt });
+        i += advance;
+    }
+
+    primary.truncate(MAX_CODE_LEN);
+    alternate.truncate(MAX_CODE_LEN);
+    (primary, alternate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn similarity(query: &str, name: &str) -> (f32, bool) {
+        name_similarity(&name_tokens(query), &name_tokens(name))
+    }
+
+    #[test]
+    fn edit_distance_counts_adjacent_transpositions_once() {
+        let distance = |a: &str, b: &str| {
+            edit_distance(
+                &a.chars().collect::<Vec<_>>(),
+                &b.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(distance("martin", "martin"), 0);
+        assert_eq!(distance("martin", "matrin"), 1);
+        assert_eq!(distance("martin", "marten"), 1);
+        assert_eq!(distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn fold_strips_diacritics_and_expands_ligatures() {
+        assert_eq!(fold("Müller-Łukasz"), "muller-lukasz");
+        assert_eq!(fold("Straße Æsir"), "strasse aesir");
+    }
+
+    #[test]
+    fn double_metaphone_keys_agree_on_spelling_variants() {
+        assert_eq!(double_metaphone("Smith").0, double_metaphone("Smyth").0);
+        assert_eq!(
+            double_metaphone("Catherine").0,
+            double_metaphone("Kathryn").0
+        );
+        assert_eq!(double_metaphone("Philips").0, double_metaphone("Filips").0);
+        assert_ne!(double_metaphone("Smith").0, double_metaphone("Jones").0);
+    }
+
+    #[test]
+    fn double_metaphone_alternate_keys_cover_other_pronunciations() {
+        let (primary, alternate) = double_metaphone("Thomas");
+        assert_eq!(primary, "0MS");
+        assert_eq!(alternate, double_metaphone("Tomas").0);
+        assert_eq!(double_metaphone("Jose").1, double_metaphone("Hose").0);
+    }
+
+    #[test]
+    fn sound_alike_names_match_and_unrelated_ones_do_not() {
+        let (score, phonetic) = similarity("Kathryn Smyth", "Catherine Smith");
+        assert!(phonetic);
+        assert!(score >= CONFLICT_MIN_SCORE, "score {}", score);
+
+        let (score, _) = similarity("José Núñez", "Jose Nunez");
+        assert_eq!(score, 1.0);
+
+        let (score, _) = similarity("Robert Jones", "Catherine Smith");
+        assert!(score < DEFAULT_MIN_SCORE, "score {}", score);
+    }
+
+    #[test]
+    fn every_query_token_must_be_matched() {
+        let (full, _) = similarity("Anna Smith", "Anna Smith");
+        let (partial, _) = similarity("Anna Smith", "Anna Kowalski");
+        assert_eq!(full, 1.0);
+        assert!(partial < DEFAULT_MIN_SCORE, "score {}", partial);
+    }
+}
@@ -0,0 +1,429 @@
+use crate::{
+    backup, changes, next_id, organizations, participants, scoped_legal_consultation, sharding,
+    ChangeEntity, Error, LegalConsultation, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_NOTE_BYTES: usize = 8192;
+
+/// Who besides its author may read a note. Clients never can.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum NoteVisibility {
+    AuthorOnly,
+    // The consultation's current participants.
+    AdvisorTeam,
+    // The participants and admins of the consultation's organization.
+    FirmAdmins,
+}
+
+/// Privileged work product on a consultation: strategy, research and other
+/// internal notes. Notes live apart from the consultation so their text
+/// never reaches client-facing responses, exports or the change feed.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Note {
+    id: u64,
+    consultation_id: u64,
+    author: Principal,
+    visibility: NoteVisibility,
+    // The change feed only records that the text changed.
+    #[serde(skip)]
+    body: String,
+    revision: u32,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl Storable for Note {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Note {
+    const MAX_SIZE: u32 = 9216;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A note as it read after one of its edits.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct NoteRevision {
+    revision: u32,
+    visibility: NoteVisibility,
+    body: String,
+    edited_at: u64,
+}
+
+impl Storable for NoteRevision {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for NoteRevision {
+    const MAX_SIZE: u32 = 9216;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Orders the notes of a consultation together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct NoteKey {
+    consultation_id: u64,
+    id: u64,
+}
+
+impl Storable for NoteKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.consultation_id.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        NoteKey {
+            consultation_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for NoteKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Orders the revisions of a note together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct NoteRevisionKey {
+    note_id: u64,
+    revision: u64,
+}
+
+impl Storable for NoteRevisionKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.note_id.to_be_bytes());
+        bytes.extend_from_slice(&self.revision.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        NoteRevisionKey {
+            note_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            revision: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for NoteRevisionKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static NOTES: RefCell<StableBTreeMap<NoteKey, Note, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    // The consultation of each note, for lookups by note id.
+    static NOTE_CONSULTATIONS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    static NOTE_REVISIONS: RefCell<StableBTreeMap<NoteRevisionKey, NoteRevision, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+}
+
+/// Adds a note to a consultation the caller works on.
+#[ic_cdk::update]
+async fn add_consultation_note(
+    consultation_id: u64,
+    body: String,
+    visibility: NoteVisibility,
+) -> Result<Note, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = scoped_legal_consultation(&consultation_id)
+        .filter(|consultation| is_team_member(consultation, &caller))
+        .ok_or_else(|| consultation_not_found(consultation_id))?;
+    validate_body(&body)?;
+
+    let now = time();
+    let note = Note {
+        id: next_id(),
+        consultation_id: consultation.id,
+        author: caller,
+        visibility,
+        body,
+        revision: 1,
+        created_at: now,
+        updated_at: now,
+    };
+    save(&note);
+    Ok(note)
+}
+
+/// Replaces the text and visibility of one of the caller's notes, keeping
+/// the previous version in its history.
+#[ic_cdk::update]
+async fn edit_consultation_note(
+    id: u64,
+    body: String,
+    visibility: NoteVisibility,
+) -> Result<Note, Error> {
+    load_note_consultation(id).await?;
+    let mut note = authored_note(id)?;
+    validate_body(&body)?;
+
+    note.body = body;
+    note.visibility = visibility;
+    note.revision += 1;
+    note.updated_at = time();
+    save(&note);
+    Ok(note)
+}
+
+/// Deletes one of the caller's notes together with its history.
+#[ic_cdk::update]
+async fn delete_consultation_note(id: u64) -> Result<(), Error> {
+    load_note_consultation(id).await?;
+    let note = authored_note(id)?;
+    remove_note(&note);
+    Ok(())
+}
+
+/// The notes on a consultation the caller may read, oldest first.
+#[ic_cdk::query(composite = true)]
+async fn list_consultation_notes(consultation_id: u64) -> Result<Vec<Note>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = scoped_legal_consultation(&consultation_id)
+        .filter(|consultation| consultation.client.as_ref() != Some(&caller))
+        .ok_or_else(|| consultation_not_found(consultation_id))?;
+
+    Ok(notes_of(consultation_id)
+        .into_iter()
+        .filter(|note| can_read(note, &consultation, &caller))
+        .collect())
+}
+
+/// The versions of a note the caller may read, oldest first. Each version
+/// is shown according to the visibility it was written with, so widening a
+/// note does not reveal its earlier text.
+#[ic_cdk::query(composite = true)]
+async fn get_note_history(id: u64) -> Result<Vec<NoteRevision>, Error> {
+    load_note_consultation(id).await?;
+    let caller = ic_cdk::caller();
+    let (note, consultation) = readable_note(id)?;
+    Ok(NOTE_REVISIONS.with(|service| {
+        service
+            .borrow()
+            .range(
+                NoteRevisionKey {
+                    note_id: id,
+                    revision: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.note_id == id)
+            .map(|(_, revision)| revision)
+            .filter(|revision| can_see(&note.author, revision.visibility, &consultation, &caller))
+            .collect()
+    }))
+}
+
+/// Drops the notes of a consultation that is permanently deleted.
+pub(crate) fn remove_consultation(consultation_id: u64) {
+    for note in notes_of(consultation_id) {
+        remove_note(&note);
+    }
+}
+
+/// Stores the note and appends its current version to the history.
+fn save(note: &Note) {
+    NOTES.with(|service| service.borrow_mut().insert(key_of(note), note.clone()));
+    NOTE_CONSULTATIONS.with(|service| service.borrow_mut().insert(note.id, note.consultation_id));
+    let revision = NoteRevision {
+        revision: note.revision,
+        visibility: note.visibility,
+        body: note.body.clone(),
+        edited_at: note.updated_at,
+    };
+    NOTE_REVISIONS.with(|service| {
+        service.borrow_mut().insert(
+            NoteRevisionKey {
+                note_id: note.id,
+                revision: u64::from(note.revision),
+            },
+            revision,
+        )
+    });
+    changes::record_upsert(ChangeEntity::Note, note.id, note);
+}
+
+fn remove_note(note: &Note) {
+    let id = note.id;
+    NOTES.with(|service| service.borrow_mut().remove(&key_of(note)));
+    NOTE_CONSULTATIONS.with(|service| service.borrow_mut().remove(&id));
+    let keys: Vec<NoteRevisionKey> = NOTE_REVISIONS.with(|service| {
+        service
+            .borrow()
+            .range(
+                NoteRevisionKey {
+                    note_id: id,
+                    revision: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.note_id == id)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    NOTE_REVISIONS.with(|service| {
+        let mut revisions = service.borrow_mut();
+        for key in &keys {
+            revisions.remove(key);
+        }
+    });
+    changes::record_delete(ChangeEntity::Note, id);
+}
+
+fn notes_of(consultation_id: u64) -> Vec<Note> {
+    NOTES.with(|service| {
+        service
+            .borrow()
+            .range(
+                NoteKey {
+                    consultation_id,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.consultation_id == consultation_id)
+            .map(|(_, note)| note)
+            .collect()
+    })
+}
+
+fn key_of(note: &Note) -> NoteKey {
+    NoteKey {
+        consultation_id: note.consultation_id,
+        id: note.id,
+    }
+}
+
+/// Current participants of the consultation, excluding its client should
+/// they also be on the advisor roster.
+fn is_team_member(consultation: &LegalConsultation, caller: &Principal) -> bool {
+    consultation.client.as_ref() != Some(caller)
+        && participants::role_of(consultation.id, consultation.advisor_id, caller).is_some()
+}
+
+fn can_read(note: &Note, consultation: &LegalConsultation, caller: &Principal) -> bool {
+    can_see(&note.author, note.visibility, consultation, caller)
+}
+
+/// Whether the caller may read text written by `author` with `visibility`.
+fn can_see(
+    author: &Principal,
+    visibility: NoteVisibility,
+    consultation: &LegalConsultation,
+    caller: &Principal,
+) -> bool {
+    if consultation.client.as_ref() == Some(caller) {
+        return false;
+    }
+    if author == caller {
+        return true;
+    }
+    match visibility {
+        NoteVisibility::AuthorOnly => false,
+        NoteVisibility::AdvisorTeam => is_team_member(consultation, caller),
+        NoteVisibility::FirmAdmins => {
+            is_team_member(consultation, caller)
+                || ic_cdk::api::is_controller(caller)
+                || organizations::is_organization_admin(caller)
+        }
+    }
+}
+
+/// A note the caller may read, with its consultation.
+fn readable_note(id: u64) -> Result<(Note, LegalConsultation), Error> {
+    let caller = ic_cdk::caller();
+    NOTE_CONSULTATIONS
+        .with(|service| service.borrow().get(&id))
+        .and_then(|consultation_id| {
+            let note = NOTES.with(|service| {
+                service.borrow().get(&NoteKey {
+                    consultation_id,
+                    id,
+                })
+            })?;
+            scoped_legal_consultation(&consultation_id)
+                .filter(|consultation| can_read(&note, consultation, &caller))
+                .map(|consultation| (note, consultation))
+        })
+        .ok_or_else(|| note_not_found(id))
+}
+
+/// Loads the consultation a note belongs to from its bucket.
+async fn load_note_consultation(id: u64) -> Result<(), Error> {
+    let consultation_ids: Vec<u64> = NOTE_CONSULTATIONS
+        .with(|service| service.borrow().get(&id))
+        .into_iter()
+        .collect();
+    sharding::load_consultations(&consultation_ids).await
+}
+
+/// A note the caller wrote; only authors change their notes.
+fn authored_note(id: u64) -> Result<Note, Error> {
+    let (note, _) = readable_note(id)?;
+    if note.author != ic_cdk::caller() {
+        return Err(Error::Unauthorized {
+            msg: format!("Only the author can change note {}", id),
+        });
+    }
+    Ok(note)
+}
+
+fn validate_body(body: &str) -> Result<(), Error> {
+    if body.trim().is_empty() || body.len() > MAX_NOTE_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Notes are 1 to {} bytes", MAX_NOTE_BYTES),
+        });
+    }
+    Ok(())
+}
+
+fn note_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Note {} not found", id),
+    }
+}
+
+fn consultation_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Legal consultation with id={} not found", id),
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("notes", &NOTES),
+        backup::map_source("note_consultations", &NOTE_CONSULTATIONS),
+        backup::map_source("note_revisions", &NOTE_REVISIONS),
+    ]
+}
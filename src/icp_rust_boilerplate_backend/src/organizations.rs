@@ -0,0 +1,466 @@
+use crate::{
+    all_legal_advisors, backup, changes, next_id, scoped_legal_advisor, ChangeEntity, Error,
+    Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_REFERENCE_PREFIX_BYTES: usize = 16;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct OrganizationSettings {
+    // Prefix of the organization's consultation reference numbers.
+    reference_prefix: String,
+    // Overrides the canister-wide LEDES law firm id for this organization.
+    law_firm_id: Option<String>,
+    // Override the canister-wide intake settings for this organization.
+    intake_window_hours: Option<u32>,
+    fallback_advisor_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Organization {
+    id: u64,
+    name: String,
+    admins: Vec<Principal>,
+    settings: OrganizationSettings,
+    next_reference: u64,
+    created_at: u64,
+}
+
+impl Storable for Organization {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Organization {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Firm roles granted on top of organization membership. Registering as an
+/// advisor does not make a principal staff.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum StaffRole {
+    Staff,
+    // Staff who may also post to and reconcile the trust ledger.
+    Bookkeeper,
+}
+
+impl Storable for StaffRole {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StaffRole {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalKey(Principal);
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(&bytes))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ORGANIZATIONS: RefCell<StableBTreeMap<u64, Organization, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // Each principal belongs to at most one organization. Members are the
+    // firm's staff; clients reach their own records without joining.
+    static ORGANIZATION_MEMBERS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    static STAFF_ROLES: RefCell<StableBTreeMap<PrincipalKey, StaffRole, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+}
+
+#[ic_cdk::update]
+fn create_organization(
+    name: String,
+    admins: Vec<Principal>,
+    settings: OrganizationSettings,
+) -> Result<Organization, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can create organizations".to_string(),
+        });
+    }
+    if admins.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "An organization needs at least one admin".to_string(),
+        });
+    }
+    for admin in &admins {
+        ensure_unaffiliated(admin)?;
+    }
+    validate_settings(&settings, None)?;
+
+    let organization = Organization {
+        id: next_id(),
+        name,
+        admins,
+        settings,
+        next_reference: 1,
+        created_at: time(),
+    };
+    for admin in &organization.admins {
+        add_member(admin, organization.id);
+    }
+    do_insert_organization(&organization);
+    Ok(organization)
+}
+
+#[ic_cdk::query]
+fn get_organization(id: u64) -> Result<Organization, Error> {
+    let caller = ic_cdk::caller();
+    match _get_organization(&id) {
+        Some(organization) if can_access(&caller, Some(id)) => Ok(organization),
+        _ => Err(organization_not_found(id)),
+    }
+}
+
+/// The organization the caller belongs to, if any.
+#[ic_cdk::query]
+fn get_my_organization() -> Option<Organization> {
+    organization_of(&ic_cdk::caller()).and_then(|id| _get_organization(&id))
+}
+
+#[ic_cdk::update]
+fn update_organization_settings(
+    id: u64,
+    settings: OrganizationSettings,
+) -> Result<Organization, Error> {
+    let mut organization = administered_organization(id)?;
+    validate_settings(&settings, Some(id))?;
+    organization.settings = settings;
+    do_insert_organization(&organization);
+    Ok(organization)
+}
+
+/// Adds a principal to the organization, or changes whether an existing
+/// member is one of its admins.
+#[ic_cdk::update]
+fn add_organization_member(id: u64, principal: Principal, admin: bool) -> Result<(), Error> {
+    let mut organization = administered_organization(id)?;
+    if organization_of(&principal) != Some(id) {
+        ensure_unaffiliated(&principal)?;
+        add_member(&principal, id);
+    }
+
+    organization
+        .admins
+        .retain(|existing| *existing != principal);
+    if admin {
+        organization.admins.push(principal);
+    }
+    do_insert_organization(&organization);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_organization_member(id: u64, principal: Principal) -> Result<(), Error> {
+    let mut organization = administered_organization(id)?;
+    if organization_of(&principal) != Some(id) {
+        return Err(Error::NotFound {
+            msg: format!(
+                "Principal {} is not a member of organization {}",
+                principal, id
+            ),
+        });
+    }
+    if organization.admins == [principal] {
+        return Err(Error::InvalidInput {
+            msg: "Cannot remove the last admin of an organization".to_string(),
+        });
+    }
+
+    organization
+        .admins
+        .retain(|existing| *existing != principal);
+    ORGANIZATION_MEMBERS.with(|service| service.borrow_mut().remove(&PrincipalKey(principal)));
+    changes::record_principal_delete(ChangeEntity::OrganizationMember, principal);
+    if STAFF_ROLES
+        .with(|service| service.borrow_mut().remove(&PrincipalKey(principal)))
+        .is_some()
+    {
+        changes::record_principal_delete(ChangeEntity::StaffRole, principal);
+    }
+    do_insert_organization(&organization);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_organization_members(id: u64) -> Result<Vec<Principal>, Error> {
+    administered_organization(id)?;
+
+    Ok(ORGANIZATION_MEMBERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, organization_id)| *organization_id == id)
+            .map(|(key, _)| key.0)
+            .collect()
+    }))
+}
+
+/// Grants a firm role. Organization admins may only grant roles to members
+/// of their own organization.
+#[ic_cdk::update]
+fn grant_staff_role(principal: Principal, role: StaffRole) -> Result<(), Error> {
+    ensure_can_assign_roles(&principal)?;
+    STAFF_ROLES.with(|service| service.borrow_mut().insert(PrincipalKey(principal), role));
+    changes::record_principal_upsert(ChangeEntity::StaffRole, principal, &role);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn revoke_staff_role(principal: Principal) -> Result<(), Error> {
+    ensure_can_assign_roles(&principal)?;
+    match STAFF_ROLES.with(|service| service.borrow_mut().remove(&PrincipalKey(principal))) {
+        Some(_) => {
+            changes::record_principal_delete(ChangeEntity::StaffRole, principal);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Principal {} has no staff role", principal),
+        }),
+    }
+}
+
+/// The staff of the caller's organization, or every staff member for
+/// controllers.
+#[ic_cdk::query]
+fn list_staff_roles() -> Result<Vec<(Principal, StaffRole)>, Error> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) && !is_organization_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers and organization admins can list staff".to_string(),
+        });
+    }
+
+    Ok(STAFF_ROLES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(key, _)| can_access(&caller, organization_of(&key.0)))
+            .map(|(key, role)| (key.0, role))
+            .collect()
+    }))
+}
+
+pub(crate) fn staff_role(principal: &Principal) -> Option<StaffRole> {
+    STAFF_ROLES.with(|service| service.borrow().get(&PrincipalKey(*principal)))
+}
+
+pub(crate) fn organization_of(principal: &Principal) -> Option<u64> {
+    ORGANIZATION_MEMBERS.with(|service| service.borrow().get(&PrincipalKey(*principal)))
+}
+
+/// Whether the principal may see records owned by `organization_id`.
+/// Records without an organization are only visible to principals outside
+/// every organization, so tenants never see each other's data. The
+/// anonymous principal is nobody's and sees nothing.
+pub(crate) fn can_access(principal: &Principal, organization_id: Option<u64>) -> bool {
+    *principal != Principal::anonymous()
+        && (ic_cdk::api::is_controller(principal) || organization_of(principal) == organization_id)
+}
+
+pub(crate) fn is_organization_admin(principal: &Principal) -> bool {
+    organization_of(principal)
+        .and_then(|id| _get_organization(&id))
+        .is_some_and(|organization| organization.admins.contains(principal))
+}
+
+/// `organization_id` is `None` for an organization that is being created
+/// and so has no advisors yet.
+fn validate_settings(
+    settings: &OrganizationSettings,
+    organization_id: Option<u64>,
+) -> Result<(), Error> {
+    // The prefix is copied into every consultation's reference.
+    if settings.reference_prefix.len() > MAX_REFERENCE_PREFIX_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Reference prefixes are at most {} bytes",
+                MAX_REFERENCE_PREFIX_BYTES
+            ),
+        });
+    }
+    if let Some(advisor_id) = settings.fallback_advisor_id {
+        let is_member = organization_id.is_some()
+            && scoped_legal_advisor(&advisor_id)
+                .is_some_and(|advisor| advisor.organization_id == organization_id);
+        if !is_member {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Fallback advisor with id={} is not an advisor of the organization",
+                    advisor_id
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Hands out the organization's next consultation reference number.
+pub(crate) fn next_reference(organization_id: u64) -> Option<String> {
+    let mut organization = _get_organization(&organization_id)?;
+    let reference = format!(
+        "{}-{:06}",
+        organization.settings.reference_prefix, organization.next_reference
+    );
+    organization.next_reference += 1;
+    do_insert_organization(&organization);
+    Some(reference)
+}
+
+pub(crate) fn law_firm_id(organization_id: Option<u64>) -> Option<String> {
+    organization_id
+        .and_then(|id| _get_organization(&id))
+        .and_then(|organization| organization.settings.law_firm_id)
+}
+
+pub(crate) fn intake_window_hours(organization_id: Option<u64>) -> Option<u32> {
+    organization_id
+        .and_then(|id| _get_organization(&id))
+        .and_then(|organization| organization.settings.intake_window_hours)
+}
+
+pub(crate) fn fallback_advisor_id(organization_id: Option<u64>) -> Option<u64> {
+    organization_id
+        .and_then(|id| _get_organization(&id))
+        .and_then(|organization| organization.settings.fallback_advisor_id)
+}
+
+fn administered_organization(id: u64) -> Result<Organization, Error> {
+    let caller = ic_cdk::caller();
+    match _get_organization(&id) {
+        Some(organization) => {
+            if organization.admins.contains(&caller) || ic_cdk::api::is_controller(&caller) {
+                Ok(organization)
+            } else if organization_of(&caller) == Some(id) {
+                Err(Error::Unauthorized {
+                    msg: format!("Caller is not an admin of organization {}", id),
+                })
+            } else {
+                Err(organization_not_found(id))
+            }
+        }
+        None => Err(organization_not_found(id)),
+    }
+}
+
+fn ensure_can_assign_roles(principal: &Principal) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller)
+        || (is_organization_admin(&caller)
+            && organization_of(principal).is_some()
+            && organization_of(principal) == organization_of(&caller))
+    {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers and the principal's organization admins can assign staff roles"
+                .to_string(),
+        })
+    }
+}
+
+/// A principal joins at most one organization, and only while it has no
+/// advisor profile outside every organization: members no longer reach
+/// records without one, so the profile and its consultations would be cut
+/// off.
+fn ensure_unaffiliated(principal: &Principal) -> Result<(), Error> {
+    if let Some(id) = organization_of(principal) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Principal {} already belongs to organization {}",
+                principal, id
+            ),
+        });
+    }
+    if let Some(advisor) = all_legal_advisors().into_iter().find(|advisor| {
+        advisor.principal.as_ref() == Some(principal) && advisor.organization_id.is_none()
+    }) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Principal {} has the advisor profile with id={} outside any organization",
+                principal, advisor.id
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn add_member(principal: &Principal, organization_id: u64) {
+    ORGANIZATION_MEMBERS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(*principal), organization_id)
+    });
+    changes::record_principal_upsert(
+        ChangeEntity::OrganizationMember,
+        *principal,
+        &organization_id,
+    );
+}
+
+fn organization_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Organization with id={} not found", id),
+    }
+}
+
+fn do_insert_organization(organization: &Organization) {
+    ORGANIZATIONS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(organization.id, organization.clone())
+    });
+    changes::record_upsert(ChangeEntity::Organization, organization.id, organization);
+}
+
+fn _get_organization(id: &u64) -> Option<Organization> {
+    ORGANIZATIONS.with(|service| service.borrow().get(id))
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("organizations", &ORGANIZATIONS),
+        backup::map_source("organization_members", &ORGANIZATION_MEMBERS),
+        backup::map_source("staff_roles", &STAFF_ROLES),
+    ]
+}
@@ -0,0 +1,425 @@
+use crate::{
+    _get_legal_advisor, backup, can_view_consultation, changes, next_id, scoped_legal_consultation,
+    sharding, ChangeEntity, Error, LegalConsultation, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+/// What an advisor does on a consultation. Every participant can read the
+/// consultation; only the lead staffs it.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum ParticipantRole {
+    // Mirrors `LegalConsultation.advisor_id`; there is exactly one.
+    Lead,
+    CoCounsel,
+    Paralegal,
+    Reviewer,
+}
+
+impl ParticipantRole {
+    /// Whether the role may change the consultation like its client can.
+    pub(crate) fn can_manage(self) -> bool {
+        matches!(self, ParticipantRole::Lead | ParticipantRole::CoCounsel)
+    }
+}
+
+/// One stint of an advisor on a consultation. A role change ends the stint
+/// and starts a new one, so the records double as staffing history.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Participant {
+    advisor_id: u64,
+    role: ParticipantRole,
+    joined_at: u64,
+    left_at: Option<u64>,
+}
+
+/// A participant record as published in the change feed.
+#[derive(Serialize)]
+struct ParticipantChange<'a> {
+    consultation_id: u64,
+    #[serde(flatten)]
+    participant: &'a Participant,
+}
+
+impl Storable for Participant {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Participant {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LeadTransfer {
+    id: u64,
+    consultation_id: u64,
+    from_advisor_id: u64,
+    to_advisor_id: u64,
+    transferred_by: Principal,
+    transferred_at: u64,
+}
+
+impl Storable for LeadTransfer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LeadTransfer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Orders the participant records of a consultation together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct ParticipantKey {
+    consultation_id: u64,
+    id: u64,
+}
+
+impl Storable for ParticipantKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.consultation_id.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        ParticipantKey {
+            consultation_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ParticipantKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    // Consultations without records are staffed by their lead alone.
+    static PARTICIPANTS: RefCell<StableBTreeMap<ParticipantKey, Participant, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    static LEAD_TRANSFERS: RefCell<StableBTreeMap<u64, LeadTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+}
+
+/// The consultation's team, including former participants when asked.
+#[ic_cdk::query(composite = true)]
+async fn list_consultation_participants(
+    consultation_id: u64,
+    include_former: bool,
+) -> Result<Vec<Participant>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let consultation = viewable_consultation(consultation_id)?;
+    Ok(participants_of(&consultation)
+        .into_iter()
+        .map(|(_, participant)| participant)
+        .filter(|participant| include_former || participant.left_at.is_none())
+        .collect())
+}
+
+/// Adds an advisor to the team, or changes the role of a current member.
+/// The lead role only changes hands through an accepted handoff.
+#[ic_cdk::update]
+async fn set_consultation_participant(
+    consultation_id: u64,
+    advisor_id: u64,
+    role: ParticipantRole,
+) -> Result<Vec<Participant>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let consultation = staffed_consultation(consultation_id)?;
+    if role == ParticipantRole::Lead || advisor_id == consultation.advisor_id {
+        return Err(Error::InvalidInput {
+            msg: "The lead only changes through an accepted handoff".to_string(),
+        });
+    }
+    ensure_same_organization(&consultation, advisor_id)?;
+
+    seed(&consultation);
+    leave(consultation_id, advisor_id);
+    join(consultation_id, advisor_id, role);
+    Ok(active_participants(&consultation))
+}
+
+#[ic_cdk::update]
+async fn remove_consultation_participant(
+    consultation_id: u64,
+    advisor_id: u64,
+) -> Result<Vec<Participant>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let consultation = staffed_consultation(consultation_id)?;
+    if advisor_id == consultation.advisor_id {
+        return Err(Error::InvalidInput {
+            msg: "The lead cannot leave; hand off the consultation first".to_string(),
+        });
+    }
+
+    seed(&consultation);
+    if !leave(consultation_id, advisor_id) {
+        return Err(Error::NotFound {
+            msg: format!(
+                "Legal advisor with id={} is not on legal consultation with id={}",
+                advisor_id, consultation_id
+            ),
+        });
+    }
+    Ok(active_participants(&consultation))
+}
+
+#[ic_cdk::query(composite = true)]
+async fn list_lead_transfers(consultation_id: u64) -> Result<Vec<LeadTransfer>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    viewable_consultation(consultation_id)?;
+
+    Ok(LEAD_TRANSFERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, transfer)| transfer)
+            .filter(|transfer| transfer.consultation_id == consultation_id)
+            .collect()
+    }))
+}
+
+/// The caller's current role on the consultation, given its lead advisor.
+pub(crate) fn role_of(
+    consultation_id: u64,
+    lead_advisor_id: u64,
+    principal: &Principal,
+) -> Option<ParticipantRole> {
+    let is_principal = |advisor_id: &u64| {
+        _get_legal_advisor(advisor_id)
+            .and_then(|advisor| advisor.principal)
+            .as_ref()
+            == Some(principal)
+    };
+    let records = records_of(consultation_id);
+    if records.is_empty() {
+        return is_principal(&lead_advisor_id).then_some(ParticipantRole::Lead);
+    }
+    records
+        .into_iter()
+        .map(|(_, participant)| participant)
+        .find(|participant| participant.left_at.is_none() && is_principal(&participant.advisor_id))
+        .map(|participant| participant.role)
+}
+
+/// Whether an advisor currently holds the role on the consultation.
+pub(crate) fn has_role(consultation: &LegalConsultation, role: ParticipantRole) -> bool {
+    participants_of(consultation)
+        .into_iter()
+        .any(|(_, participant)| participant.left_at.is_none() && participant.role == role)
+}
+
+/// An advisor currently holding the role, for work addressed to the role.
+pub(crate) fn principal_with_role(
+    consultation: &LegalConsultation,
+    role: ParticipantRole,
+) -> Option<Principal> {
+    participants_of(consultation)
+        .into_iter()
+        .filter(|(_, participant)| participant.left_at.is_none() && participant.role == role)
+        .find_map(|(_, participant)| {
+            _get_legal_advisor(&participant.advisor_id).and_then(|advisor| advisor.principal)
+        })
+}
+
+/// Moves the lead role in the participant records and logs the transfer;
+/// the caller updates `advisor_id` on the consultation.
+pub(crate) fn record_lead_transfer(
+    consultation: &LegalConsultation,
+    to_advisor_id: u64,
+    keep_previous_as: Option<ParticipantRole>,
+    by: Principal,
+) -> LeadTransfer {
+    seed(consultation);
+    leave(consultation.id, consultation.advisor_id);
+    leave(consultation.id, to_advisor_id);
+    join(consultation.id, to_advisor_id, ParticipantRole::Lead);
+    if let Some(role) = keep_previous_as {
+        join(consultation.id, consultation.advisor_id, role);
+    }
+
+    let transfer = LeadTransfer {
+        id: next_id(),
+        consultation_id: consultation.id,
+        from_advisor_id: consultation.advisor_id,
+        to_advisor_id,
+        transferred_by: by,
+        transferred_at: time(),
+    };
+    LEAD_TRANSFERS.with(|service| service.borrow_mut().insert(transfer.id, transfer.clone()));
+    changes::record_upsert(ChangeEntity::LeadTransfer, transfer.id, &transfer);
+    transfer
+}
+
+fn participants_of(consultation: &LegalConsultation) -> Vec<(ParticipantKey, Participant)> {
+    let records = records_of(consultation.id);
+    if records.is_empty() {
+        vec![(
+            ParticipantKey {
+                consultation_id: consultation.id,
+                id: 0,
+            },
+            Participant {
+                advisor_id: consultation.advisor_id,
+                role: ParticipantRole::Lead,
+                joined_at: consultation.created_at,
+                left_at: None,
+            },
+        )]
+    } else {
+        records
+    }
+}
+
+fn active_participants(consultation: &LegalConsultation) -> Vec<Participant> {
+    participants_of(consultation)
+        .into_iter()
+        .map(|(_, participant)| participant)
+        .filter(|participant| participant.left_at.is_none())
+        .collect()
+}
+
+fn records_of(consultation_id: u64) -> Vec<(ParticipantKey, Participant)> {
+    PARTICIPANTS.with(|service| {
+        service
+            .borrow()
+            .range(
+                ParticipantKey {
+                    consultation_id,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.consultation_id == consultation_id)
+            .collect()
+    })
+}
+
+/// Stores the implicit lead of a consultation before its team changes.
+fn seed(consultation: &LegalConsultation) {
+    if records_of(consultation.id).is_empty() {
+        for (_, participant) in participants_of(consultation) {
+            insert(consultation.id, participant);
+        }
+    }
+}
+
+fn join(consultation_id: u64, advisor_id: u64, role: ParticipantRole) {
+    insert(
+        consultation_id,
+        Participant {
+            advisor_id,
+            role,
+            joined_at: time(),
+            left_at: None,
+        },
+    );
+}
+
+/// Ends the advisor's current stint; false if they are not on the team.
+fn leave(consultation_id: u64, advisor_id: u64) -> bool {
+    match records_of(consultation_id)
+        .into_iter()
+        .find(|(_, participant)| {
+            participant.advisor_id == advisor_id && participant.left_at.is_none()
+        }) {
+        Some((key, mut participant)) => {
+            participant.left_at = Some(time());
+            store(key, participant);
+            true
+        }
+        None => false,
+    }
+}
+
+fn insert(consultation_id: u64, participant: Participant) {
+    let key = ParticipantKey {
+        consultation_id,
+        id: next_id(),
+    };
+    store(key, participant);
+}
+
+fn store(key: ParticipantKey, participant: Participant) {
+    changes::record_upsert(
+        ChangeEntity::Participant,
+        key.id,
+        &ParticipantChange {
+            consultation_id: key.consultation_id,
+            participant: &participant,
+        },
+    );
+    PARTICIPANTS.with(|service| service.borrow_mut().insert(key, participant));
+}
+
+fn viewable_consultation(consultation_id: u64) -> Result<LegalConsultation, Error> {
+    scoped_legal_consultation(&consultation_id)
+        .filter(|consultation| can_view_consultation(consultation, &ic_cdk::caller()))
+        .ok_or_else(|| consultation_not_found(consultation_id))
+}
+
+/// Only the lead and controllers staff a consultation.
+fn staffed_consultation(consultation_id: u64) -> Result<LegalConsultation, Error> {
+    let caller = ic_cdk::caller();
+    let consultation = viewable_consultation(consultation_id)?;
+    if ic_cdk::api::is_controller(&caller)
+        || role_of(consultation.id, consultation.advisor_id, &caller) == Some(ParticipantRole::Lead)
+    {
+        Ok(consultation)
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "Only the lead can staff legal consultation with id={}",
+                consultation_id
+            ),
+        })
+    }
+}
+
+fn ensure_same_organization(
+    consultation: &LegalConsultation,
+    advisor_id: u64,
+) -> Result<(), Error> {
+    match _get_legal_advisor(&advisor_id) {
+        Some(advisor) if advisor.organization_id == consultation.organization_id => Ok(()),
+        _ => Err(Error::NotFound {
+            msg: format!("Legal advisor with id={} not found", advisor_id),
+        }),
+    }
+}
+
+fn consultation_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Legal consultation with id={} not found", id),
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("participants", &PARTICIPANTS),
+        backup::map_source("lead_transfers", &LEAD_TRANSFERS),
+    ]
+}
@@ -0,0 +1,532 @@
+use crate::{
+    _get_legal_advisor, _get_legal_consultation, backup, can_manage_consultation, changes,
+    scoped_legal_consultation, sharding, ChangeEntity, ConsultationStatus, Error, Memory,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+type Subaccount = [u8; 32];
+
+// Ledgers deduplicate transfers for 24 hours; retries after that get a new
+// timestamp rather than being rejected as too old.
+const DEDUP_WINDOW_NANOS: u64 = 23 * 60 * 60 * 1_000_000_000;
+
+/// ICRC-1 account.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct Account {
+    owner: Principal,
+    subaccount: Option<ByteBuf>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct PaymentConfig {
+    ledgers: Vec<Principal>,
+    commission_bps: u16,
+    treasury: Option<Account>,
+}
+
+impl Storable for PaymentConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+enum EscrowStatus {
+    AwaitingDeposit,
+    Funded,
+    Settling,
+    Released,
+    Refunded,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Escrow {
+    consultation_id: u64,
+    ledger: Principal,
+    amount: u64,
+    deposit_account: Account,
+    status: EscrowStatus,
+    created_at: u64,
+    settled_at: Option<u64>,
+    // Timestamp of the settlement transfers. Retries reuse it so the ledger
+    // deduplicates a transfer that went through before a failure.
+    settlement_created_at: Option<u64>,
+}
+
+impl Storable for Escrow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Escrow {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Serialize)]
+struct TransferArg {
+    from_subaccount: Option<ByteBuf>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+thread_local! {
+    static PAYMENT_CONFIG: RefCell<Cell<PaymentConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            PaymentConfig::default(),
+        )
+        .expect("Cannot create payment config")
+    );
+
+    static ESCROWS: RefCell<StableBTreeMap<u64, Escrow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+}
+
+#[ic_cdk::update]
+fn configure_payments(
+    ledgers: Vec<Principal>,
+    commission_bps: u16,
+    treasury: Option<Account>,
+) -> Result<PaymentConfig, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can configure payments".to_string(),
+        });
+    }
+    if commission_bps > 10_000 {
+        return Err(Error::InvalidInput {
+            msg: format!("Commission of {} bps exceeds 100%", commission_bps),
+        });
+    }
+    if commission_bps > 0 && treasury.is_none() {
+        return Err(Error::InvalidInput {
+            msg: "A treasury account is required to collect commission".to_string(),
+        });
+    }
+
+    let config = PaymentConfig {
+        ledgers,
+        commission_bps,
+        treasury,
+    };
+    PAYMENT_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("Cannot update payment config");
+    Ok(config)
+}
+
+#[ic_cdk::query]
+fn get_payment_config() -> PaymentConfig {
+    PAYMENT_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+#[ic_cdk::update]
+async fn open_escrow(
+    consultation_id: u64,
+    ledger: Principal,
+    amount: u64,
+) -> Result<Escrow, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let consultation = match scoped_legal_consultation(&consultation_id) {
+        Some(consultation) => consultation,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Legal consultation with id={} not found", consultation_id),
+            })
+        }
+    };
+    if !can_manage_consultation(&consultation, &ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Caller cannot open escrow for legal consultation with id={}",
+                consultation_id
+            ),
+        });
+    }
+    if consultation.status != ConsultationStatus::Open {
+        return Err(Error::InvalidInput {
+            msg: format!("Legal consultation with id={} is not open", consultation_id),
+        });
+    }
+    if !get_payment_config().ledgers.contains(&ledger) {
+        return Err(Error::InvalidInput {
+            msg: format!("Ledger {} is not accepted for payments", ledger),
+        });
+    }
+    if amount == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Escrow amount must be positive".to_string(),
+        });
+    }
+    if _get_escrow(&consultation_id).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Escrow for legal consultation with id={} already exists",
+                consultation_id
+            ),
+        });
+    }
+
+    let escrow = Escrow {
+        consultation_id,
+        ledger,
+        amount,
+        deposit_account: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(ByteBuf::from(escrow_subaccount(consultation_id).to_vec())),
+        },
+        status: EscrowStatus::AwaitingDeposit,
+        created_at: time(),
+        settled_at: None,
+        settlement_created_at: None,
+    };
+
+    do_insert_escrow(&escrow);
+    Ok(escrow)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_escrow(consultation_id: u64) -> Result<Escrow, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    match scoped_escrow(&consultation_id) {
+        Some(escrow) => Ok(escrow),
+        None => Err(escrow_not_found(consultation_id)),
+    }
+}
+
+/// Checks the deposit subaccount and marks the escrow as funded once the
+/// agreed amount has arrived.
+#[ic_cdk::update]
+async fn confirm_escrow_deposit(consultation_id: u64) -> Result<Escrow, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let mut escrow = match scoped_escrow(&consultation_id) {
+        Some(escrow) => escrow,
+        None => return Err(escrow_not_found(consultation_id)),
+    };
+    if escrow.status != EscrowStatus::AwaitingDeposit {
+        return Ok(escrow);
+    }
+
+    let balance = balance_of(escrow.ledger, escrow.deposit_account.clone()).await?;
+    if balance < escrow.amount {
+        return Err(Error::PaymentFailed {
+            msg: format!(
+                "Deposit of {} received, {} required",
+                balance, escrow.amount
+            ),
+        });
+    }
+
+    // Re-read after the await in case another call already confirmed it.
+    if let Some(current) = _get_escrow(&consultation_id) {
+        if current.status != EscrowStatus::AwaitingDeposit {
+            return Ok(current);
+        }
+    }
+    escrow.status = EscrowStatus::Funded;
+    do_insert_escrow(&escrow);
+    Ok(escrow)
+}
+
+/// Releases a funded escrow to the advisor once the consultation is
+/// completed, or refunds the client once it is cancelled.
+#[ic_cdk::update]
+async fn settle_escrow(consultation_id: u64) -> Result<Escrow, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let mut escrow = match scoped_escrow(&consultation_id) {
+        Some(escrow) => escrow,
+        None => return Err(escrow_not_found(consultation_id)),
+    };
+    if escrow.status != EscrowStatus::Funded {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Escrow for legal consultation with id={} is not funded",
+                consultation_id
+            ),
+        });
+    }
+    let consultation = match _get_legal_consultation(&consultation_id) {
+        Some(consultation) => consultation,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Legal consultation with id={} not found", consultation_id),
+            })
+        }
+    };
+
+    // Lock the escrow so concurrent calls cannot settle it twice while we
+    // wait on the ledger.
+    let now = time();
+    escrow.settlement_created_at = escrow
+        .settlement_created_at
+        .filter(|created_at| now.saturating_sub(*created_at) < DEDUP_WINDOW_NANOS)
+        .or(Some(now));
+    escrow.status = EscrowStatus::Settling;
+    do_insert_escrow(&escrow);
+
+    let outcome = match consultation.status {
+        ConsultationStatus::Completed => release(&escrow, consultation.advisor_id).await,
+        ConsultationStatus::Cancelled => match consultation.client {
+            Some(client) => refund(&escrow, client).await,
+            None => Err(Error::InvalidInput {
+                msg: format!(
+                    "Legal consultation with id={} has no client to refund",
+                    consultation_id
+                ),
+            }),
+        },
+        ConsultationStatus::Open | ConsultationStatus::Pending | ConsultationStatus::Declined => {
+            Err(Error::InvalidInput {
+                msg: format!(
+                    "Legal consultation with id={} is still open",
+                    consultation_id
+                ),
+            })
+        }
+    };
+
+    match outcome {
+        Ok(status) => {
+            escrow.status = status;
+            escrow.settled_at = Some(time());
+            do_insert_escrow(&escrow);
+            Ok(escrow)
+        }
+        Err(e) => {
+            escrow.status = EscrowStatus::Funded;
+            do_insert_escrow(&escrow);
+            Err(e)
+        }
+    }
+}
+
+/// Settles the consultation's escrow in the background if it is funded and
+/// the consultation has reached a final state.
+pub(crate) fn schedule_settlement(consultation_id: u64) {
+    let funded = _get_escrow(&consultation_id)
+        .map(|escrow| escrow.status == EscrowStatus::Funded)
+        .unwrap_or(false);
+    let settled = _get_legal_consultation(&consultation_id)
+        .map(|consultation| {
+            matches!(
+                consultation.status,
+                ConsultationStatus::Completed | ConsultationStatus::Cancelled
+            )
+        })
+        .unwrap_or(false);
+
+    if funded && settled {
+        ic_cdk::spawn(async move {
+            let _ = settle_escrow(consultation_id).await;
+        });
+    }
+}
+
+async fn release(escrow: &Escrow, advisor_id: u64) -> Result<EscrowStatus, Error> {
+    let advisor = match _get_legal_advisor(&advisor_id).and_then(|advisor| advisor.principal) {
+        Some(principal) => principal,
+        None => {
+            return Err(Error::InvalidInput {
+                msg: format!("Legal advisor with id={} has no payout account", advisor_id),
+            })
+        }
+    };
+    let config = get_payment_config();
+    let fee = to_u64(ledger_fee(escrow.ledger).await?)?;
+    let commission = (escrow.amount as u128 * config.commission_bps as u128 / 10_000) as u64;
+
+    let payout = escrow.amount.saturating_sub(commission).saturating_sub(fee);
+    if payout == 0 {
+        return Err(Error::PaymentFailed {
+            msg: "Escrow amount does not cover ledger fees".to_string(),
+        });
+    }
+    transfer(
+        escrow,
+        Account {
+            owner: advisor,
+            subaccount: None,
+        },
+        payout,
+        fee,
+    )
+    .await?;
+
+    // The advisor has been paid at this point; a failed commission transfer
+    // leaves the commission in the deposit subaccount rather than undoing it.
+    if let Some(treasury) = config.treasury {
+        if commission > fee {
+            let _ = transfer(escrow, treasury, commission - fee, fee).await;
+        }
+    }
+
+    Ok(EscrowStatus::Released)
+}
+
+async fn refund(escrow: &Escrow, client: Principal) -> Result<EscrowStatus, Error> {
+    let fee = to_u64(ledger_fee(escrow.ledger).await?)?;
+    let balance = to_u64(balance_of(escrow.ledger, escrow.deposit_account.clone()).await?)?;
+    if balance <= fee {
+        return Err(Error::PaymentFailed {
+            msg: "Escrow balance does not cover ledger fees".to_string(),
+        });
+    }
+
+    transfer(
+        escrow,
+        Account {
+            owner: client,
+            subaccount: None,
+        },
+        balance - fee,
+        fee,
+    )
+    .await?;
+    Ok(EscrowStatus::Refunded)
+}
+
+async fn balance_of(ledger: Principal, account: Account) -> Result<Nat, Error> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| ledger_call_failed("icrc1_balance_of", code, msg))?;
+    Ok(balance)
+}
+
+async fn ledger_fee(ledger: Principal) -> Result<Nat, Error> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| ledger_call_failed("icrc1_fee", code, msg))?;
+    Ok(fee)
+}
+
+async fn transfer(escrow: &Escrow, to: Account, amount: u64, fee: u64) -> Result<Nat, Error> {
+    let arg = TransferArg {
+        from_subaccount: Some(ByteBuf::from(
+            escrow_subaccount(escrow.consultation_id).to_vec(),
+        )),
+        to,
+        amount: Nat::from(amount),
+        fee: Some(Nat::from(fee)),
+        memo: Some(ByteBuf::from(escrow.consultation_id.to_be_bytes().to_vec())),
+        created_at_time: escrow.settlement_created_at,
+    };
+
+    let (result,): (Result<Nat, TransferError>,) =
+        ic_cdk::call(escrow.ledger, "icrc1_transfer", (arg,))
+            .await
+            .map_err(|(code, msg)| ledger_call_failed("icrc1_transfer", code, msg))?;
+    match result {
+        Ok(block_index) => Ok(block_index),
+        // An earlier attempt already made this transfer.
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        Err(e) => Err(Error::PaymentFailed {
+            msg: format!("Ledger rejected transfer: {:?}", e),
+        }),
+    }
+}
+
+/// Deposit subaccount of the canister that holds a consultation's escrow.
+fn escrow_subaccount(consultation_id: u64) -> Subaccount {
+    let mut hasher = Sha256::new();
+    hasher.update(b"consultation-escrow");
+    hasher.update(consultation_id.to_be_bytes());
+    hasher.finalize().into()
+}
+
+fn to_u64(amount: Nat) -> Result<u64, Error> {
+    u64::try_from(amount.0.clone()).map_err(|_| Error::PaymentFailed {
+        msg: format!("Amount {} does not fit into u64", amount),
+    })
+}
+
+fn ledger_call_failed(method: &str, code: ic_cdk::api::call::RejectionCode, msg: String) -> Error {
+    Error::PaymentFailed {
+        msg: format!("Call to {} failed ({:?}): {}", method, code, msg),
+    }
+}
+
+fn escrow_not_found(consultation_id: u64) -> Error {
+    Error::NotFound {
+        msg: format!(
+            "Escrow for legal consultation with id={} not found",
+            consultation_id
+        ),
+    }
+}
+
+fn do_insert_escrow(escrow: &Escrow) {
+    ESCROWS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(escrow.consultation_id, escrow.clone())
+    });
+    changes::record_upsert(ChangeEntity::Escrow, escrow.consultation_id, escrow);
+}
+
+/// Whether funds are still held in, or on their way out of, the
+/// consultation's escrow.
+pub(crate) fn has_unsettled_escrow(consultation_id: u64) -> bool {
+    _get_escrow(&consultation_id).is_some_and(|escrow| {
+        escrow.status != EscrowStatus::Released && escrow.status != EscrowStatus::Refunded
+    })
+}
+
+/// Whether the client has paid into the consultation's escrow.
+pub(crate) fn is_escrow_funded(consultation_id: u64) -> bool {
+    _get_escrow(&consultation_id).is_some_and(|escrow| {
+        matches!(
+            escrow.status,
+            EscrowStatus::Funded | EscrowStatus::Settling | EscrowStatus::Released
+        )
+    })
+}
+
+/// Escrows are only visible within the organization of their consultation.
+fn scoped_escrow(consultation_id: &u64) -> Option<Escrow> {
+    _get_escrow(consultation_id).filter(|_| scoped_legal_consultation(consultation_id).is_some())
+}
+
+pub(crate) fn _get_escrow(consultation_id: &u64) -> Option<Escrow> {
+    ESCROWS.with(|service| service.borrow().get(consultation_id))
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::cell_source("payment_config", &PAYMENT_CONFIG),
+        backup::map_source("escrows", &ESCROWS),
+    ]
+}
@@ -0,0 +1,473 @@
+use crate::{
+    _get_legal_client, advisor_ratings_by, all_legal_advisors, all_legal_clients,
+    all_legal_consultations, archive, backup, billing, changes, custom_fields,
+    do_insert_advisor_rating, do_insert_legal_client, do_insert_legal_consultation, documents,
+    ensure_firm_staff, forms, handoffs, matters, next_id, organizations, payments, quotes,
+    retention, tasks, trash, trust, ChangeEntity, Error, LegalClient, LegalConsultation, Memory,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+const ERASED_TEXT: &str = "[erased]";
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum PrivacySubject {
+    Client { client_id: u64 },
+    Principal { principal: Principal },
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum PrivacyRequestKind {
+    Access,
+    Erasure,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PrivacyRequest {
+    id: u64,
+    kind: PrivacyRequestKind,
+    subject: PrivacySubject,
+    legal_basis: String,
+    requested_by: Principal,
+    // Organization of the staff member handling the request, if any.
+    organization_id: Option<u64>,
+    completed_at: u64,
+    outcome: String,
+}
+
+impl Storable for PrivacyRequest {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PrivacyRequest {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SubjectAccessExport {
+    request: PrivacyRequest,
+    // JSON document with everything held about the subject.
+    data: String,
+}
+
+/// The data subject as found in the stores: the client record, if any, and
+/// the principal that links consultations and ratings to them.
+struct ResolvedSubject {
+    client: Option<LegalClient>,
+    principal: Option<Principal>,
+}
+
+thread_local! {
+    static PRIVACY_REQUESTS: RefCell<StableBTreeMap<u64, PrivacyRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+}
+
+/// Gathers every record linked to the subject across all stores. Subjects
+/// may request their own data; anyone else must be firm staff.
+#[ic_cdk::update]
+async fn request_subject_access(
+    subject: PrivacySubject,
+    legal_basis: String,
+) -> Result<SubjectAccessExport, Error> {
+    let caller = ic_cdk::caller();
+    let mut resolved = resolve_subject(&subject)?;
+    let scope = if resolved.principal == Some(caller) {
+        None
+    } else {
+        ensure_firm_staff(&caller)?;
+        restrict_to_scope(&caller, &subject, &mut resolved)?;
+        Some(caller)
+    };
+
+    let consultations = subject_consultations(&resolved, scope.as_ref()).await?;
+    let archived_consultations = archived_subject_consultations(&resolved, scope.as_ref()).await?;
+    let advisor_profiles: Vec<_> = match resolved.principal {
+        Some(principal) => all_legal_advisors()
+            .into_iter()
+            .filter(|advisor| advisor.principal == Some(principal))
+            .collect(),
+        None => Vec::new(),
+    };
+    let advisor_ratings = resolved
+        .principal
+        .map(|principal| advisor_ratings_by(&principal))
+        .unwrap_or_default();
+    let escrows: Vec<_> = consultations
+        .iter()
+        .filter_map(|consultation| payments::_get_escrow(&consultation.id))
+        .collect();
+    let billing_entries: Vec<_> = consultations
+        .iter()
+        .flat_map(|consultation| billing::entries_for_consultation(consultation.id))
+        .collect();
+    let fee_agreements: Vec<_> = consultations
+        .iter()
+        .chain(archived_consultations.iter())
+        .filter_map(|consultation| quotes::fee_agreement_of(consultation.id))
+        .collect();
+    let handoffs: Vec<_> = consultations
+        .iter()
+        .flat_map(|consultation| handoffs::handoffs_of(consultation.id))
+        .collect();
+    let tasks: Vec<_> = consultations
+        .iter()
+        .flat_map(|consultation| tasks::tasks_of(consultation.id))
+        .collect();
+    let documents: Vec<_> = consultations
+        .iter()
+        .chain(archived_consultations.iter())
+        .flat_map(|consultation| documents::documents_of(consultation.id))
+        .collect();
+    let client_id = resolved.client.as_ref().map(|client| client.id);
+    let matters = client_id
+        .map(|client_id| matters::matters_of_client(client_id, scope.as_ref()))
+        .unwrap_or_default();
+    let invoices = client_id
+        .map(billing::invoices_for_client)
+        .unwrap_or_default();
+    let trust_journal = client_id
+        .map(|client_id| trust::journal_entries(Some(client_id)))
+        .unwrap_or_default();
+    let intake_answers: Vec<_> = consultations
+        .iter()
+        .chain(archived_consultations.iter())
+        .filter_map(|consultation| forms::answers_for(consultation.id))
+        .collect();
+    // Keyed by the id of the client, consultation or advisor profile.
+    let custom_fields: BTreeMap<u64, _> = client_id
+        .into_iter()
+        .chain(consultations.iter().map(|consultation| consultation.id))
+        .chain(
+            archived_consultations
+                .iter()
+                .map(|consultation| consultation.id),
+        )
+        .chain(advisor_profiles.iter().map(|advisor| advisor.id))
+        .map(|id| (id, custom_fields::values_of(id)))
+        .filter(|(_, values)| !values.is_empty())
+        .collect();
+    let privacy_requests = requests_for(&subject);
+
+    let data = serde_json::json!({
+        "client": resolved.client,
+        "consultations": consultations,
+        "archived_consultations": archived_consultations,
+        "matters": matters,
+        "handoffs": handoffs,
+        "tasks": tasks,
+        "documents": documents,
+        "intake_answers": intake_answers,
+        "custom_fields": custom_fields,
+        "advisor_profiles": advisor_profiles,
+        "advisor_ratings": advisor_ratings,
+        "escrows": escrows,
+        "billing_entries": billing_entries,
+        "fee_agreements": fee_agreements,
+        "invoices": invoices,
+        "trust_journal": trust_journal,
+        "privacy_requests": privacy_requests,
+    });
+
+    let request = record_request(
+        PrivacyRequestKind::Access,
+        subject,
+        legal_basis,
+        caller,
+        format!(
+            "Exported {} consultations, {} matters, {} ratings, {} invoices and {} trust \
+             journal entries",
+            consultations.len() + archived_consultations.len(),
+            matters.len(),
+            advisor_ratings.len(),
+            invoices.len(),
+            trust_journal.len()
+        ),
+    );
+    Ok(SubjectAccessExport {
+        request,
+        data: data.to_string(),
+    })
+}
+
+/// Pseudonymizes the subject's personal data. Invoices, billing entries, fee
+/// agreements, escrows and trust journal entries are retained unchanged, as
+/// firms must keep them; the client, matter, consultation, handoff and task
+/// records are kept but stripped of anything identifying, including in the
+/// change feed history, and their intake answers, custom field values and
+/// documents are deleted. Consultations in the trash are pseudonymized too,
+/// so restoring them cannot bring the data back. Archived consultations are
+/// pseudonymized in their archive canister; if that fails the request can
+/// simply be repeated. Refused while an escrow of the subject is unsettled,
+/// since a refund needs the client's principal.
+#[ic_cdk::update]
+async fn request_erasure(
+    subject: PrivacySubject,
+    legal_basis: String,
+) -> Result<PrivacyRequest, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    let mut resolved = resolve_subject(&subject)?;
+    restrict_to_scope(&caller, &subject, &mut resolved)?;
+
+    // Consultations under legal hold, live or archived, are exempt.
+    let (held, consultations): (Vec<_>, Vec<_>) = subject_consultations(&resolved, Some(&caller))
+        .await?
+        .into_iter()
+        .partition(|consultation| retention::is_on_hold(consultation.id));
+    let trashed = trashed_subject_consultations(&resolved, &caller);
+    if let Some(unsettled) = consultations
+        .iter()
+        .chain(&trashed)
+        .find(|consultation| payments::has_unsettled_escrow(consultation.id))
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Escrow of consultation with id={} is unsettled; release or refund it first",
+                unsettled.id
+            ),
+        });
+    }
+
+    let (archived_held, mut archived): (Vec<_>, Vec<_>) =
+        archived_subject_consultations(&resolved, Some(&caller))
+            .await?
+            .into_iter()
+            .partition(|consultation| retention::is_on_hold(consultation.id));
+    for consultation in archived.iter_mut() {
+        pseudonymize(consultation);
+    }
+    let archived_count = archived.len();
+    archive::rewrite(archived).await?;
+
+    for mut consultation in consultations.clone() {
+        pseudonymize(&mut consultation);
+        do_insert_legal_consultation(&consultation);
+    }
+    for mut consultation in trashed.clone() {
+        pseudonymize(&mut consultation);
+        trash::update_consultation(consultation);
+    }
+
+    let ratings = resolved
+        .principal
+        .map(|principal| advisor_ratings_by(&principal))
+        .unwrap_or_default();
+    for mut rating in ratings.clone() {
+        rating.rated_by = Principal::anonymous();
+        changes::redact_history(ChangeEntity::AdvisorRating, rating.id);
+        do_insert_advisor_rating(&rating);
+    }
+
+    let mut retained = 0;
+    let mut erased_matters = 0;
+    if let Some(mut client) = resolved.client {
+        erased_matters = matters::erase_client_matters(client.id, &caller, ERASED_TEXT);
+        retained += billing::invoices_for_client(client.id).len()
+            + trust::journal_entries(Some(client.id)).len();
+        client.name = format!("Erased client {}", client.id);
+        client.email = String::new();
+        client.principal = None;
+        changes::redact_history(ChangeEntity::Client, client.id);
+        custom_fields::remove_values(client.id);
+        do_insert_legal_client(&client);
+    }
+    retained += consultations
+        .iter()
+        .map(|consultation| {
+            billing::entries_for_consultation(consultation.id).len()
+                + payments::_get_escrow(&consultation.id).map_or(0, |_| 1)
+        })
+        .sum::<usize>();
+
+    Ok(record_request(
+        PrivacyRequestKind::Erasure,
+        subject,
+        legal_basis,
+        caller,
+        format!(
+            "Pseudonymized {} consultations, {} matters and {} ratings; retained {} financial \
+             records and {} consultations under legal hold",
+            consultations.len() + archived_count + trashed.len(),
+            erased_matters,
+            ratings.len(),
+            retained,
+            held.len() + archived_held.len()
+        ),
+    ))
+}
+
+#[ic_cdk::query]
+fn list_privacy_requests(subject: Option<PrivacySubject>) -> Result<Vec<PrivacyRequest>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    let requests = match subject {
+        Some(subject) => requests_for(&subject),
+        None => PRIVACY_REQUESTS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, request)| request)
+                .collect()
+        }),
+    };
+    Ok(requests
+        .into_iter()
+        .filter(|request| organizations::can_access(&caller, request.organization_id))
+        .collect())
+}
+
+fn resolve_subject(subject: &PrivacySubject) -> Result<ResolvedSubject, Error> {
+    match *subject {
+        PrivacySubject::Client { client_id } => match _get_legal_client(&client_id) {
+            Some(client) => Ok(ResolvedSubject {
+                principal: client.principal,
+                client: Some(client),
+            }),
+            None => Err(Error::NotFound {
+                msg: format!("Legal client with id={} not found", client_id),
+            }),
+        },
+        PrivacySubject::Principal { principal } => Ok(ResolvedSubject {
+            client: all_legal_clients()
+                .into_iter()
+                .find(|client| client.principal == Some(principal)),
+            principal: Some(principal),
+        }),
+    }
+}
+
+/// Staff can only reach a client record of their own organization; a
+/// principal's client record elsewhere is left out of the request.
+fn restrict_to_scope(
+    caller: &Principal,
+    subject: &PrivacySubject,
+    resolved: &mut ResolvedSubject,
+) -> Result<(), Error> {
+    if resolved
+        .client
+        .as_ref()
+        .is_some_and(|client| !organizations::can_access(caller, client.organization_id))
+    {
+        if let PrivacySubject::Client { client_id } = subject {
+            return Err(Error::NotFound {
+                msg: format!("Legal client with id={} not found", client_id),
+            });
+        }
+        resolved.client = None;
+    }
+    Ok(())
+}
+
+/// The subject's consultations, limited to those `scope` may access when
+/// the request is handled by staff rather than the subject themselves.
+async fn subject_consultations(
+    subject: &ResolvedSubject,
+    scope: Option<&Principal>,
+) -> Result<Vec<LegalConsultation>, Error> {
+    Ok(match subject.principal {
+        Some(principal) => all_legal_consultations()
+            .await?
+            .into_iter()
+            .filter(|consultation| consultation.client == Some(principal))
+            .filter(|consultation| {
+                scope.is_none_or(|staff| {
+                    organizations::can_access(staff, consultation.organization_id)
+                })
+            })
+            .collect(),
+        None => Vec::new(),
+    })
+}
+
+/// The subject's trashed consultations within the staff member's scope,
+/// except those under legal hold.
+fn trashed_subject_consultations(
+    subject: &ResolvedSubject,
+    staff: &Principal,
+) -> Vec<LegalConsultation> {
+    match subject.principal {
+        Some(principal) => trash::consultations_of(&principal)
+            .into_iter()
+            .filter(|consultation| {
+                organizations::can_access(staff, consultation.organization_id)
+                    && !retention::is_on_hold(consultation.id)
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Strips a consultation of the client's data, including the values the
+/// change feed recorded for it.
+fn pseudonymize(consultation: &mut LegalConsultation) {
+    consultation.details = ERASED_TEXT.to_string();
+    consultation.client = None;
+    changes::redact_history(ChangeEntity::Consultation, consultation.id);
+    forms::remove_answers(consultation.id);
+    custom_fields::remove_values(consultation.id);
+    documents::remove_consultation(consultation.id);
+    handoffs::erase_notes(consultation.id, ERASED_TEXT);
+    tasks::erase_text(consultation.id, ERASED_TEXT);
+}
+
+async fn archived_subject_consultations(
+    subject: &ResolvedSubject,
+    scope: Option<&Principal>,
+) -> Result<Vec<LegalConsultation>, Error> {
+    match subject.principal {
+        Some(principal) => archive::client_consultations(&principal, scope).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+fn requests_for(subject: &PrivacySubject) -> Vec<PrivacyRequest> {
+    PRIVACY_REQUESTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| request.subject == *subject)
+            .collect()
+    })
+}
+
+fn record_request(
+    kind: PrivacyRequestKind,
+    subject: PrivacySubject,
+    legal_basis: String,
+    requested_by: Principal,
+    outcome: String,
+) -> PrivacyRequest {
+    let request = PrivacyRequest {
+        id: next_id(),
+        kind,
+        subject,
+        legal_basis,
+        requested_by,
+        organization_id: organizations::organization_of(&requested_by),
+        completed_at: time(),
+        outcome,
+    };
+    PRIVACY_REQUESTS.with(|service| service.borrow_mut().insert(request.id, request.clone()));
+    changes::record_upsert(ChangeEntity::PrivacyRequest, request.id, &request);
+    request
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![backup::map_source("privacy_requests", &PRIVACY_REQUESTS)]
+}
@@ -0,0 +1,453 @@
+use crate::{
+    backup, can_view_consultation, changes, next_id, participants, scoped_legal_consultation,
+    sharding, ChangeEntity, ConsultationStatus, Error, LegalConsultation, Memory, ParticipantRole,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell};
+
+const MAX_SCOPE_BYTES: usize = 4096;
+const MAX_REASON_BYTES: usize = 512;
+const FULL_PERCENTAGE_BPS: u32 = 10_000;
+
+/// Fee terms in minor currency units; hours are in hundredths, as on
+/// billing entries.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum FeeTerms {
+    Flat {
+        amount: u64,
+    },
+    Hourly {
+        rate: u64,
+        estimated_hours: u64,
+        cap: u64,
+    },
+    // Share of the recovery, in basis points.
+    Contingency {
+        percentage_bps: u32,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum QuoteStatus {
+    Issued,
+    Accepted,
+    Declined,
+    Withdrawn,
+    // Replaced by a newer quote on the same consultation.
+    Superseded,
+    Expired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Quote {
+    id: u64,
+    consultation_id: u64,
+    advisor_id: u64,
+    issued_by: Principal,
+    terms: FeeTerms,
+    // The work the fee covers.
+    scope: String,
+    // Hex SHA-256 of the quote's terms; the client echoes it on acceptance.
+    terms_hash: String,
+    status: QuoteStatus,
+    issued_at: u64,
+    valid_until: Option<u64>,
+    resolved_at: Option<u64>,
+    decline_reason: Option<String>,
+}
+
+impl Storable for Quote {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Quote {
+    const MAX_SIZE: u32 = 6144;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// The terms a client accepted for a consultation. It is written once, by
+/// the client's own update call, and never changed afterwards.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FeeAgreement {
+    consultation_id: u64,
+    quote_id: u64,
+    advisor_id: u64,
+    terms: FeeTerms,
+    scope: String,
+    terms_hash: String,
+    accepted_by: Principal,
+    accepted_at: u64,
+}
+
+impl Storable for FeeAgreement {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for FeeAgreement {
+    const MAX_SIZE: u32 = 6144;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static QUOTES: RefCell<StableBTreeMap<u64, Quote, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    static FEE_AGREEMENTS: RefCell<StableBTreeMap<u64, FeeAgreement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+}
+
+/// Quotes fee terms on a requested or open consultation. Only its lead
+/// advisor quotes, and a new quote supersedes any outstanding one.
+#[ic_cdk::update]
+async fn issue_quote(
+    consultation_id: u64,
+    terms: FeeTerms,
+    scope: String,
+    valid_until: Option<u64>,
+) -> Result<Quote, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = scoped_legal_consultation(&consultation_id)
+        .ok_or_else(|| consultation_not_found(consultation_id))?;
+    ensure_lead(&consultation, &caller)?;
+    if !matches!(
+        consultation.status,
+        ConsultationStatus::Pending | ConsultationStatus::Open
+    ) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} can no longer be quoted",
+                consultation_id
+            ),
+        });
+    }
+    if fee_agreement_of(consultation_id).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} already has accepted fee terms",
+                consultation_id
+            ),
+        });
+    }
+    validate_terms(&terms)?;
+    if scope.len() > MAX_SCOPE_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Quote scope exceeds {} bytes", MAX_SCOPE_BYTES),
+        });
+    }
+    let now = time();
+    if valid_until.is_some_and(|valid_until| valid_until <= now) {
+        return Err(Error::InvalidInput {
+            msg: "A quote must be valid for some time".to_string(),
+        });
+    }
+
+    for mut outstanding in quotes_of(consultation_id)
+        .into_iter()
+        .filter(|quote| quote.status == QuoteStatus::Issued)
+    {
+        resolve(&mut outstanding, QuoteStatus::Superseded);
+    }
+    let id = next_id();
+    let quote = Quote {
+        id,
+        consultation_id,
+        advisor_id: consultation.advisor_id,
+        issued_by: caller,
+        terms_hash: terms_hash(id, &terms, &scope),
+        terms,
+        scope,
+        status: QuoteStatus::Issued,
+        issued_at: now,
+        valid_until,
+        resolved_at: None,
+        decline_reason: None,
+    };
+    do_insert_quote(&quote);
+    Ok(quote)
+}
+
+/// Accepts a quote as the consultation's client. `terms_hash` must match
+/// the quote, so the client agrees to exactly the terms they were shown.
+#[ic_cdk::update]
+async fn accept_quote(id: u64, terms_hash: String) -> Result<FeeAgreement, Error> {
+    load_quote_consultation(id).await?;
+    let caller = ic_cdk::caller();
+    let (consultation, mut quote) = client_quote(id, &caller)?;
+    if !quote.terms_hash.eq_ignore_ascii_case(&terms_hash) {
+        return Err(Error::InvalidInput {
+            msg: format!("The terms of quote {} do not match the given hash", id),
+        });
+    }
+    if fee_agreement_of(consultation.id).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} already has accepted fee terms",
+                consultation.id
+            ),
+        });
+    }
+
+    resolve(&mut quote, QuoteStatus::Accepted);
+    let agreement = FeeAgreement {
+        consultation_id: consultation.id,
+        quote_id: quote.id,
+        advisor_id: quote.advisor_id,
+        terms: quote.terms,
+        scope: quote.scope,
+        terms_hash: quote.terms_hash,
+        accepted_by: caller,
+        accepted_at: time(),
+    };
+    FEE_AGREEMENTS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(consultation.id, agreement.clone())
+    });
+    changes::record_upsert(ChangeEntity::FeeAgreement, consultation.id, &agreement);
+    Ok(agreement)
+}
+
+#[ic_cdk::update]
+async fn decline_quote(id: u64, reason: Option<String>) -> Result<Quote, Error> {
+    load_quote_consultation(id).await?;
+    let (_, mut quote) = client_quote(id, &ic_cdk::caller())?;
+    if reason
+        .as_ref()
+        .is_some_and(|reason| reason.len() > MAX_REASON_BYTES)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("Decline reason exceeds {} bytes", MAX_REASON_BYTES),
+        });
+    }
+
+    quote.decline_reason = reason;
+    resolve(&mut quote, QuoteStatus::Declined);
+    Ok(quote)
+}
+
+/// Withdraws an outstanding quote; allowed for the consultation's lead.
+#[ic_cdk::update]
+async fn withdraw_quote(id: u64) -> Result<Quote, Error> {
+    load_quote_consultation(id).await?;
+    let caller = ic_cdk::caller();
+    let mut quote = outstanding_quote(id)?;
+    let consultation =
+        scoped_legal_consultation(&quote.consultation_id).ok_or_else(|| quote_not_found(id))?;
+    ensure_lead(&consultation, &caller)?;
+
+    resolve(&mut quote, QuoteStatus::Withdrawn);
+    Ok(quote)
+}
+
+/// Every quote issued on the consultation, oldest first.
+#[ic_cdk::query(composite = true)]
+async fn list_consultation_quotes(consultation_id: u64) -> Result<Vec<Quote>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    viewable_consultation(consultation_id)?;
+    Ok(quotes_of(consultation_id))
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_fee_agreement(consultation_id: u64) -> Result<FeeAgreement, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    viewable_consultation(consultation_id)?;
+    fee_agreement_of(consultation_id).ok_or_else(|| Error::NotFound {
+        msg: format!(
+            "Legal consultation with id={} has no accepted fee terms",
+            consultation_id
+        ),
+    })
+}
+
+pub(crate) fn fee_agreement_of(consultation_id: u64) -> Option<FeeAgreement> {
+    FEE_AGREEMENTS.with(|service| service.borrow().get(&consultation_id))
+}
+
+/// Billing waits for the client to accept fee terms.
+pub(crate) fn ensure_fee_agreement(consultation_id: u64) -> Result<(), Error> {
+    match fee_agreement_of(consultation_id) {
+        Some(_) => Ok(()),
+        None => Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} has no accepted fee terms; issue a quote first",
+                consultation_id
+            ),
+        }),
+    }
+}
+
+/// The most that may be billed in fees under the accepted terms, if they
+/// set a limit.
+pub(crate) fn fee_cap(consultation_id: u64) -> Option<u64> {
+    fee_agreement_of(consultation_id).and_then(|agreement| match agreement.terms {
+        FeeTerms::Flat { amount } => Some(amount),
+        FeeTerms::Hourly { cap, .. } => Some(cap),
+        FeeTerms::Contingency { .. } => None,
+    })
+}
+
+fn validate_terms(terms: &FeeTerms) -> Result<(), Error> {
+    let valid = match terms {
+        FeeTerms::Flat { amount } => *amount > 0,
+        FeeTerms::Hourly {
+            rate,
+            estimated_hours,
+            cap,
+        } => *rate > 0 && *estimated_hours > 0 && *cap > 0,
+        FeeTerms::Contingency { percentage_bps } => {
+            *percentage_bps > 0 && *percentage_bps <= FULL_PERCENTAGE_BPS
+        }
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: "Fee terms need positive amounts and a percentage of at most 100%".to_string(),
+        })
+    }
+}
+
+fn terms_hash(id: u64, terms: &FeeTerms, scope: &str) -> String {
+    Sha256::digest(Encode!(&id, terms, &scope).unwrap())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn quotes_of(consultation_id: u64) -> Vec<Quote> {
+    QUOTES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, quote)| quote)
+            .filter(|quote| quote.consultation_id == consultation_id)
+            .map(with_expiry)
+            .collect()
+    })
+}
+
+/// Reports lapsed quotes as expired; they are stored as such the next time
+/// they are touched.
+fn with_expiry(mut quote: Quote) -> Quote {
+    if quote.status == QuoteStatus::Issued
+        && quote
+            .valid_until
+            .is_some_and(|valid_until| valid_until <= time())
+    {
+        quote.status = QuoteStatus::Expired;
+        quote.resolved_at = quote.valid_until;
+    }
+    quote
+}
+
+async fn load_quote_consultation(id: u64) -> Result<(), Error> {
+    let consultation_ids: Vec<u64> = QUOTES
+        .with(|service| service.borrow().get(&id))
+        .map(|quote| quote.consultation_id)
+        .into_iter()
+        .collect();
+    sharding::load_consultations(&consultation_ids).await
+}
+
+fn outstanding_quote(id: u64) -> Result<Quote, Error> {
+    let quote = QUOTES
+        .with(|service| service.borrow().get(&id))
+        .filter(|quote| scoped_legal_consultation(&quote.consultation_id).is_some())
+        .ok_or_else(|| quote_not_found(id))?;
+    let current = with_expiry(quote.clone());
+    if current.status != quote.status {
+        do_insert_quote(&current);
+    }
+    if current.status != QuoteStatus::Issued {
+        return Err(Error::InvalidInput {
+            msg: format!("Quote {} is no longer outstanding", id),
+        });
+    }
+    Ok(current)
+}
+
+/// An outstanding quote on one of the caller's own consultations.
+fn client_quote(id: u64, caller: &Principal) -> Result<(LegalConsultation, Quote), Error> {
+    let quote = outstanding_quote(id)?;
+    match scoped_legal_consultation(&quote.consultation_id) {
+        Some(consultation) if consultation.client.as_ref() == Some(caller) => {
+            Ok((consultation, quote))
+        }
+        _ => Err(Error::Unauthorized {
+            msg: format!("Only the client can answer quote {}", id),
+        }),
+    }
+}
+
+fn ensure_lead(consultation: &LegalConsultation, caller: &Principal) -> Result<(), Error> {
+    if participants::role_of(consultation.id, consultation.advisor_id, caller)
+        == Some(ParticipantRole::Lead)
+    {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "Only the lead advisor can quote legal consultation with id={}",
+                consultation.id
+            ),
+        })
+    }
+}
+
+fn viewable_consultation(consultation_id: u64) -> Result<LegalConsultation, Error> {
+    scoped_legal_consultation(&consultation_id)
+        .filter(|consultation| can_view_consultation(consultation, &ic_cdk::caller()))
+        .ok_or_else(|| consultation_not_found(consultation_id))
+}
+
+fn resolve(quote: &mut Quote, status: QuoteStatus) {
+    quote.status = status;
+    quote.resolved_at = Some(time());
+    do_insert_quote(quote);
+}
+
+fn do_insert_quote(quote: &Quote) {
+    QUOTES.with(|service| service.borrow_mut().insert(quote.id, quote.clone()));
+    changes::record_upsert(ChangeEntity::Quote, quote.id, quote);
+}
+
+fn quote_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Quote {} not found", id),
+    }
+}
+
+fn consultation_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Legal consultation with id={} not found", id),
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("quotes", &QUOTES),
+        backup::map_source("fee_agreements", &FEE_AGREEMENTS),
+    ]
+}
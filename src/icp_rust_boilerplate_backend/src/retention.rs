@@ -0,0 +1,267 @@
+use crate::{
+    all_legal_consultations, archive, backup, billing, changes, do_remove_legal_consultation,
+    ensure_firm_staff, firm_legal_consultation, payments, remove_dependent_records, sharding,
+    trash, ChangeEntity, Error, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_YEAR: u64 = 31_557_600 * 1_000_000_000;
+const PURGE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// Keeps a single timer run well inside the instruction limit; anything
+// left over is purged by the next run.
+const PURGE_BATCH: usize = 100;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RetentionPolicy {
+    practice_area: String,
+    retention_years: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RetentionConfig {
+    policies: Vec<RetentionPolicy>,
+    // Applies to closed consultations without a practice area or policy;
+    // such consultations are kept indefinitely when unset.
+    default_retention_years: Option<u32>,
+}
+
+impl Storable for RetentionConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LegalHold {
+    consultation_id: u64,
+    reason: String,
+    placed_by: Principal,
+    placed_at: u64,
+}
+
+impl Storable for LegalHold {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegalHold {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static RETENTION_CONFIG: RefCell<Cell<RetentionConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+            RetentionConfig::default(),
+        )
+        .expect("Cannot create retention config")
+    );
+
+    // Active holds, keyed by the consultation they cover.
+    static LEGAL_HOLDS: RefCell<StableBTreeMap<u64, LegalHold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+}
+
+#[ic_cdk::update]
+fn configure_retention(config: RetentionConfig) -> Result<RetentionConfig, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can configure retention".to_string(),
+        });
+    }
+
+    RETENTION_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("Cannot update retention config");
+    Ok(config)
+}
+
+#[ic_cdk::query]
+fn get_retention_config() -> RetentionConfig {
+    RETENTION_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+/// Puts a consultation, live or archived, under litigation hold; it can
+/// then be neither deleted nor purged until the hold is released.
+#[ic_cdk::update]
+async fn place_legal_hold(consultation_id: u64, reason: String) -> Result<LegalHold, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    if !is_holdable(consultation_id, &caller) {
+        return Err(Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", consultation_id),
+        });
+    }
+
+    let hold = LegalHold {
+        consultation_id,
+        reason,
+        placed_by: caller,
+        placed_at: time(),
+    };
+    LEGAL_HOLDS.with(|service| service.borrow_mut().insert(consultation_id, hold.clone()));
+    changes::record_upsert(ChangeEntity::LegalHold, consultation_id, &hold);
+    Ok(hold)
+}
+
+#[ic_cdk::update]
+async fn release_legal_hold(consultation_id: u64) -> Result<(), Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    if is_holdable(consultation_id, &caller)
+        && LEGAL_HOLDS
+            .with(|service| service.borrow_mut().remove(&consultation_id))
+            .is_some()
+    {
+        changes::record_delete(ChangeEntity::LegalHold, consultation_id);
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!(
+                "No legal hold on legal consultation with id={}",
+                consultation_id
+            ),
+        })
+    }
+}
+
+#[ic_cdk::query(composite = true)]
+async fn list_legal_holds() -> Result<Vec<LegalHold>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    let held: Vec<u64> =
+        LEGAL_HOLDS.with(|service| service.borrow().iter().map(|(id, _)| id).collect());
+    sharding::load_consultations(&held).await?;
+
+    Ok(LEGAL_HOLDS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, hold)| hold)
+            .filter(|hold| is_holdable(hold.consultation_id, &caller))
+            .collect()
+    }))
+}
+
+/// Runs the retention purge now instead of waiting for the daily timer.
+/// Returns the number of purged consultations.
+#[ic_cdk::update]
+async fn run_retention_purge() -> Result<u64, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can run the retention purge".to_string(),
+        });
+    }
+
+    purge_expired().await
+}
+
+pub(crate) fn start_retention_timer() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, || {
+        trash::purge_expired();
+        ic_cdk::spawn(async {
+            let _ = purge_expired().await;
+        })
+    });
+}
+
+pub(crate) fn is_on_hold(consultation_id: u64) -> bool {
+    LEGAL_HOLDS.with(|service| service.borrow().contains_key(&consultation_id))
+}
+
+/// Live consultations and archive stubs in the staff member's scope.
+fn is_holdable(consultation_id: u64, staff: &Principal) -> bool {
+    firm_legal_consultation(&consultation_id).is_some()
+        || archive::is_archived_in_scope(&consultation_id, staff)
+}
+
+pub(crate) fn ensure_not_on_hold(consultation_id: u64) -> Result<(), Error> {
+    if is_on_hold(consultation_id) {
+        Err(Error::LegalHold {
+            msg: format!(
+                "Legal consultation with id={} is under legal hold",
+                consultation_id
+            ),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Deletes closed consultations whose retention period has ended, skipping
+/// any under legal hold or with money still to settle or bill, like
+/// archiving does.
+async fn purge_expired() -> Result<u64, Error> {
+    let expired: Vec<u64> = all_legal_consultations()
+        .await?
+        .into_iter()
+        .filter(|consultation| {
+            consultation
+                .closed_at
+                .is_some_and(|closed_at| is_expired(closed_at, consultation.practice_area.as_ref()))
+                && !is_on_hold(consultation.id)
+                && !payments::has_unsettled_escrow(consultation.id)
+                && !billing::has_unbilled_entries(consultation.id)
+        })
+        .map(|consultation| consultation.id)
+        .take(PURGE_BATCH)
+        .collect();
+
+    for id in &expired {
+        do_remove_legal_consultation(*id);
+        remove_dependent_records(*id);
+    }
+    Ok(expired.len() as u64)
+}
+
+/// Whether the retention period of a consultation closed at `closed_at` has
+/// ended.
+pub(crate) fn is_expired(closed_at: u64, practice_area: Option<&String>) -> bool {
+    retention_end(&get_retention_config(), closed_at, practice_area)
+        .is_some_and(|end| end <= time())
+}
+
+fn retention_end(
+    config: &RetentionConfig,
+    closed_at: u64,
+    practice_area: Option<&String>,
+) -> Option<u64> {
+    let years = practice_area
+        .and_then(|area| {
+            config
+                .policies
+                .iter()
+                .find(|policy| policy.practice_area.eq_ignore_ascii_case(area))
+        })
+        .map(|policy| policy.retention_years)
+        .or(config.default_retention_years)?;
+    Some(closed_at.saturating_add((years as u64).saturating_mul(NANOS_PER_YEAR)))
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::cell_source("retention_config", &RETENTION_CONFIG),
+        backup::map_source("legal_holds", &LEGAL_HOLDS),
+    ]
+}
@@ -0,0 +1,454 @@
+use crate::{
+    _get_legal_consultation, all_legal_consultations, archive, can_view_consultation, documents,
+    names, sharding, Error, LegalConsultation, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+// Longer terms are truncated so index keys stay bounded.
+const MAX_TERM_BYTES: usize = 32;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+const SNIPPET_CHARS: usize = 160;
+// Weight of a prefix match relative to an exact term match.
+const PREFIX_MATCH_WEIGHT: f64 = 0.5;
+
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "has", "have", "he",
+    "her", "his", "i", "in", "is", "it", "its", "my", "of", "on", "or", "our", "she", "so", "that",
+    "the", "their", "them", "they", "this", "to", "was", "we", "were", "will", "with", "you",
+    "your",
+];
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum SearchDocKind {
+    Consultation,
+    ArchivedConsultation,
+    // Indexed by name only; contents live in buckets.
+    Document,
+}
+
+impl SearchDocKind {
+    fn code(self) -> u8 {
+        match self {
+            SearchDocKind::Consultation => 0,
+            SearchDocKind::ArchivedConsultation => 1,
+            SearchDocKind::Document => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            0 => SearchDocKind::Consultation,
+            1 => SearchDocKind::ArchivedConsultation,
+            2 => SearchDocKind::Document,
+            _ => panic!("Unknown search document kind {}", code),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct DocKey {
+    kind: SearchDocKind,
+    id: u64,
+}
+
+impl Storable for DocKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(9);
+        bytes.push(self.kind.code());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        DocKey {
+            kind: SearchDocKind::from_code(bytes[0]),
+            id: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for DocKey {
+    const MAX_SIZE: u32 = 9;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Posting list entry: one term occurring in one document.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct TermKey {
+    term: String,
+    doc: DocKey,
+}
+
+impl Storable for TermKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(1 + self.term.len() + 9);
+        bytes.push(self.term.len() as u8);
+        bytes.extend_from_slice(self.term.as_bytes());
+        bytes.extend_from_slice(&self.doc.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let len = bytes[0] as usize;
+        TermKey {
+            term: String::from_utf8(bytes[1..1 + len].to_vec()).unwrap(),
+            doc: DocKey::from_bytes(Cow::Borrowed(&bytes[1 + len..])),
+        }
+    }
+}
+
+impl BoundedStorable for TermKey {
+    const MAX_SIZE: u32 = 1 + MAX_TERM_BYTES as u32 + DocKey::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Distinct terms of an indexed document, kept so the document can be
+/// unindexed without re-reading its previous text.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IndexedDocument {
+    terms: Vec<String>,
+    length: u32,
+}
+
+impl Storable for IndexedDocument {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IndexedDocument {
+    const MAX_SIZE: u32 = 16 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SearchHit {
+    kind: SearchDocKind,
+    id: u64,
+    score: f64,
+    snippet: String,
+}
+
+thread_local! {
+    static SEARCH_POSTINGS: RefCell<StableBTreeMap<TermKey, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    static SEARCH_DOCUMENTS: RefCell<StableBTreeMap<DocKey, IndexedDocument, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+}
+
+/// Ranked full-text search over the records visible to the caller:
+/// consultation details and document names. The canister keeps no
+/// messages, so there are none to search.
+///
+/// Every query term also matches indexed terms it is a prefix of, at a
+/// lower weight than an exact match. Archived consultations are only
+/// returned when `include_archived` is set.
+#[ic_cdk::query(composite = true)]
+async fn search(
+    query: String,
+    limit: Option<u32>,
+    include_archived: Option<bool>,
+) -> Vec<SearchHit> {
+    let include_archived = include_archived.unwrap_or(false);
+    let caller = ic_cdk::caller();
+    let limit = limit
+        .map(|limit| (limit as usize).min(MAX_SEARCH_LIMIT))
+        .unwrap_or(DEFAULT_SEARCH_LIMIT);
+    let ranked = rank(&query, include_archived);
+
+    // Hits the caller cannot see are skipped, so consultations are loaded
+    // from their buckets a page at a time until the limit is reached.
+    let mut hits = Vec::new();
+    for page in ranked.chunks(MAX_SEARCH_LIMIT) {
+        let consultation_ids: Vec<u64> = page
+            .iter()
+            .filter_map(|(doc, _)| match doc.kind {
+                SearchDocKind::Consultation => Some(doc.id),
+                SearchDocKind::Document => documents::consultation_of(doc.id),
+                SearchDocKind::ArchivedConsultation => None,
+            })
+            .collect();
+        sharding::or_trap(sharding::load_consultations(&consultation_ids).await);
+        hits.extend(
+            page.iter()
+                .filter_map(|(doc, score)| visible_hit(doc, *score, &caller)),
+        );
+        if hits.len() >= limit {
+            break;
+        }
+    }
+    hits.truncate(limit);
+    hits
+}
+
+/// Scores every indexed document matching the query by tf-idf, best first.
+fn rank(query: &str, include_archived: bool) -> Vec<(DocKey, f64)> {
+    let document_count = SEARCH_DOCUMENTS.with(|service| service.borrow().len()) as f64;
+
+    let mut scores: BTreeMap<DocKey, f64> = BTreeMap::new();
+    for query_term in tokenize(query) {
+        // Group postings by matched term so each term gets its own idf.
+        let mut postings: BTreeMap<String, Vec<(DocKey, u32)>> = BTreeMap::new();
+        SEARCH_POSTINGS.with(|service| {
+            let start = TermKey {
+                term: query_term.clone(),
+                doc: DocKey {
+                    kind: SearchDocKind::Consultation,
+                    id: 0,
+                },
+            };
+            for (key, frequency) in service
+                .borrow()
+                .range(start..)
+                .take_while(|(key, _)| key.term.starts_with(&query_term))
+            {
+                postings
+                    .entry(key.term)
+                    .or_default()
+                    .push((key.doc, frequency));
+            }
+        });
+
+        let mut term_scores: BTreeMap<DocKey, f64> = BTreeMap::new();
+        for (term, documents) in postings {
+            let idf = (1.0 + document_count / documents.len() as f64).ln();
+            let weight = if term == query_term {
+                1.0
+            } else {
+                PREFIX_MATCH_WEIGHT
+            };
+            for (doc, frequency) in documents {
+                let length = document_length(&doc).max(1) as f64;
+                let score = weight * idf * frequency as f64 / length.sqrt();
+                let best = term_scores.entry(doc).or_insert(0.0);
+                *best = best.max(score);
+            }
+        }
+        for (doc, score) in term_scores {
+            *scores.entry(doc).or_insert(0.0) += score;
+        }
+    }
+
+    let mut ranked: Vec<(DocKey, f64)> = scores
+        .into_iter()
+        .filter(|(doc, _)| include_archived || doc.kind != SearchDocKind::ArchivedConsultation)
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.id.cmp(&b.0.id)));
+    ranked
+}
+
+/// Indexes every existing record, including the name index; needed for
+/// data written before the indexes existed.
+#[ic_cdk::update]
+async fn rebuild_search_index() -> Result<u64, Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers can rebuild the search index".to_string(),
+        });
+    }
+
+    rebuild_search_indexes().await
+}
+
+pub(crate) async fn rebuild_search_indexes() -> Result<u64, Error> {
+    let consultations = all_legal_consultations().await?;
+    for consultation in &consultations {
+        index_consultation(consultation);
+    }
+    Ok(consultations.len() as u64
+        + archive::reindex_stubs()
+        + documents::reindex_documents()
+        + names::rebuild_name_index())
+}
+
+pub(crate) fn index_consultation(consultation: &LegalConsultation) {
+    index_document(
+        SearchDocKind::Consultation,
+        consultation.id,
+        &consultation.details,
+    );
+}
+
+pub(crate) fn index_document(kind: SearchDocKind, id: u64, text: &str) {
+    remove_document(kind, id);
+
+    let tokens = tokenize(text);
+    let mut frequencies: BTreeMap<String, u32> = BTreeMap::new();
+    for token in &tokens {
+        *frequencies.entry(token.clone()).or_insert(0) += 1;
+    }
+
+    let doc = DocKey { kind, id };
+    SEARCH_POSTINGS.with(|service| {
+        let mut postings = service.borrow_mut();
+        for (term, frequency) in &frequencies {
+            postings.insert(
+                TermKey {
+                    term: term.clone(),
+                    doc: doc.clone(),
+                },
+                *frequency,
+            );
+        }
+    });
+    SEARCH_DOCUMENTS.with(|service| {
+        service.borrow_mut().insert(
+            doc,
+            IndexedDocument {
+                terms: frequencies.into_keys().collect(),
+                length: tokens.len() as u32,
+            },
+        )
+    });
+}
+
+pub(crate) fn remove_document(kind: SearchDocKind, id: u64) {
+    let doc = DocKey { kind, id };
+    if let Some(indexed) = SEARCH_DOCUMENTS.with(|service| service.borrow_mut().remove(&doc)) {
+        SEARCH_POSTINGS.with(|service| {
+            let mut postings = service.borrow_mut();
+            for term in indexed.terms {
+                postings.remove(&TermKey {
+                    term,
+                    doc: doc.clone(),
+                });
+            }
+        });
+    }
+}
+
+/// Splits text into lowercase terms, dropping stop words and single letters.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|word| truncate_term(word.to_lowercase()))
+        .filter(|word| word.chars().count() > 1 && !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+fn truncate_term(mut term: String) -> String {
+    if term.len() > MAX_TERM_BYTES {
+        let mut end = MAX_TERM_BYTES;
+        while !term.is_char_boundary(end) {
+            end -= 1;
+        }
+        term.truncate(end);
+    }
+    term
+}
+
+fn document_length(doc: &DocKey) -> u32 {
+    SEARCH_DOCUMENTS.with(|service| {
+        service
+            .borrow()
+            .get(doc)
+            .map(|indexed| indexed.length)
+            .unwrap_or(0)
+    })
+}
+
+fn visible_hit(doc: &DocKey, score: f64, caller: &candid::Principal) -> Option<SearchHit> {
+    match doc.kind {
+        SearchDocKind::Consultation => {
+            let consultation = _get_legal_consultation(&doc.id)?;
+            if !can_view_consultation(&consultation, caller) {
+                return None;
+            }
+            Some(SearchHit {
+                kind: doc.kind,
+                id: doc.id,
+                score,
+                snippet: consultation.details.chars().take(SNIPPET_CHARS).collect(),
+            })
+        }
+        SearchDocKind::ArchivedConsultation => Some(SearchHit {
+            kind: doc.kind,
+            id: doc.id,
+            score,
+            snippet: archive::search_snippet(&doc.id, caller)?,
+        }),
+        SearchDocKind::Document => Some(SearchHit {
+            kind: doc.kind,
+            id: doc.id,
+            score,
+            snippet: documents::searchable_name(doc.id)?,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked_ids(query: &str, include_archived: bool) -> Vec<u64> {
+        rank(query, include_archived)
+            .into_iter()
+            .map(|(doc, _)| doc.id)
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_lowercases_and_drops_stop_words_and_single_letters() {
+        assert_eq!(
+            tokenize("The Tenant's LEASE, and a rent-review clause"),
+            vec!["tenant", "lease", "rent", "review", "clause"]
+        );
+    }
+
+    #[test]
+    fn tokenize_truncates_long_terms_on_a_char_boundary() {
+        let term = "é".repeat(40);
+        let tokens = tokenize(&term);
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].len() <= MAX_TERM_BYTES);
+        assert_eq!(tokens[0], "é".repeat(MAX_TERM_BYTES / 2));
+    }
+
+    #[test]
+    fn rarer_and_denser_matches_rank_first() {
+        index_document(SearchDocKind::Consultation, 1, "lease dispute");
+        index_document(SearchDocKind::Consultation, 2, "lease renewal of the lease");
+        index_document(SearchDocKind::Consultation, 3, "overtime dispute");
+        index_document(SearchDocKind::Consultation, 4, "lease");
+
+        // Occurrences count, scaled down by document length.
+        assert_eq!(ranked_ids("lease", false), vec![2, 4, 1]);
+        // A rare term outweighs a common one.
+        assert_eq!(ranked_ids("lease overtime", false)[0], 3);
+    }
+
+    #[test]
+    fn prefix_matches_score_below_exact_matches() {
+        index_document(SearchDocKind::Consultation, 1, "leasehold");
+        index_document(SearchDocKind::Consultation, 2, "lease");
+
+        let ranked = rank("lease", false);
+        assert_eq!(ranked[0].0.id, 2);
+        assert!(ranked[1].1 < ranked[0].1);
+    }
+
+    #[test]
+    fn archived_consultations_need_opting_in_and_removal_unindexes() {
+        index_document(SearchDocKind::ArchivedConsultation, 7, "probate");
+        assert!(ranked_ids("probate", false).is_empty());
+        assert_eq!(ranked_ids("probate", true), vec![7]);
+
+        remove_document(SearchDocKind::ArchivedConsultation, 7);
+        assert!(ranked_ids("probate", true).is_empty());
+    }
+}
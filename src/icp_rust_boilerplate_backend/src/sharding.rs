@@ -0,0 +1,694 @@
+use crate::{backup, Error, LegalConsultation, Memory, LEGAL_CONSULTATIONS, MEMORY_MANAGER};
+use candid::utils::ArgumentEncoder;
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use std::{borrow::Cow, cell::RefCell};
+
+// Width of the consultation id range owned by a single bucket.
+const IDS_PER_BUCKET: u64 = 1_000_000;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+// Records pushed to buckets per flush; the rest waits for the next one.
+const FLUSH_BATCH: usize = 200;
+// Consultations loaded from buckets and kept in memory between calls.
+const CACHE_CAPACITY: usize = 10_000;
+// Records read from a bucket per call.
+const READ_BATCH: usize = 500;
+
+// Records to upsert and ids to delete in one bucket.
+type BucketBatch = (Vec<(u64, ByteBuf)>, Vec<u64>);
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ShardingConfig {
+    enabled: bool,
+    // Cycles attached to the creation of each bucket canister.
+    bucket_cycles: u64,
+}
+
+impl Storable for ShardingConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Directory entry: the bucket canister owning consultation and document
+/// ids in `[range_start, range_end)`.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Bucket {
+    range_start: u64,
+    range_end: u64,
+    canister_id: Principal,
+    created_at: u64,
+}
+
+impl Storable for Bucket {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Bucket {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A consultation as stored, waiting to be pushed to its bucket.
+struct PendingRecord(Vec<u8>);
+
+impl Storable for PendingRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        PendingRecord(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for PendingRecord {
+    const MAX_SIZE: u32 = <LegalConsultation as BoundedStorable>::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Default)]
+struct BucketWasm(Vec<u8>);
+
+impl Storable for BucketWasm {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        BucketWasm(bytes.into_owned())
+    }
+}
+
+thread_local! {
+    static SHARDING_CONFIG: RefCell<Cell<ShardingConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+            ShardingConfig::default(),
+        )
+        .expect("Cannot create sharding config")
+    );
+
+    // Module installed into newly created buckets.
+    static BUCKET_WASM: RefCell<Cell<BucketWasm, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+            BucketWasm::default(),
+        )
+        .expect("Cannot create bucket wasm")
+    );
+
+    // Keyed by the start of the id range each bucket owns.
+    static BUCKETS: RefCell<StableBTreeMap<u64, Bucket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Ids whose consultation or document contents are to be deleted from
+    // their bucket, with the time they were queued.
+    static SHARD_OUTBOX: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // Consultations written while sharding is enabled, until they are pushed
+    // to their bucket. This is their only copy until then.
+    static PENDING_RECORDS: RefCell<StableBTreeMap<u64, PendingRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+
+    // Buckets created but not yet installed, keyed like `BUCKETS`.
+    static PENDING_BUCKETS: RefCell<StableBTreeMap<u64, Bucket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    // Consultations read from their bucket, `None` for ids it does not hold.
+    // Only this canister writes to buckets, so entries never go stale.
+    static CACHE: RefCell<BTreeMap<u64, Option<LegalConsultation>>> =
+        const { RefCell::new(BTreeMap::new()) };
+
+    static FLUSHING: RefCell<bool> = const { RefCell::new(false) };
+
+    // Id ranges whose bucket is being created.
+    static CREATING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Clears `FLUSHING` when the flush ends, even if it traps after an await.
+struct FlushGuard;
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        FLUSHING.with(|flushing| *flushing.borrow_mut() = false);
+    }
+}
+
+/// Releases an id range in `CREATING` when its creation ends, even if it
+/// traps after an await.
+struct CreatingGuard(u64);
+
+impl Drop for CreatingGuard {
+    fn drop(&mut self) {
+        CREATING.with(|creating| creating.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Uploads the `consultation_bucket` module used for new buckets. Existing
+/// buckets are not upgraded.
+#[ic_cdk::update]
+fn set_bucket_wasm(wasm: ByteBuf) -> Result<(), Error> {
+    ensure_controller()?;
+
+    if wasm.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Bucket wasm module is empty".to_string(),
+        });
+    }
+    BUCKET_WASM
+        .with(|cell| cell.borrow_mut().set(BucketWasm(wasm.into_vec())))
+        .expect("Cannot update bucket wasm");
+    Ok(())
+}
+
+/// While sharding is enabled, consultations are written to their bucket and
+/// the ones this canister still holds are moved there on each flush.
+/// Disabling it keeps new writes here; records already in buckets are still
+/// read from them.
+#[ic_cdk::update]
+fn configure_sharding(config: ShardingConfig) -> Result<ShardingConfig, Error> {
+    ensure_controller()?;
+
+    if config.enabled && BUCKET_WASM.with(|cell| cell.borrow().get().0.is_empty()) {
+        return Err(Error::InvalidInput {
+            msg: "Upload the bucket wasm before enabling sharding".to_string(),
+        });
+    }
+    SHARDING_CONFIG
+        .with(|cell| cell.borrow_mut().set(config.clone()))
+        .expect("Cannot update sharding config");
+    Ok(config)
+}
+
+#[ic_cdk::query]
+fn get_sharding_config() -> ShardingConfig {
+    SHARDING_CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+#[ic_cdk::query]
+fn list_buckets() -> Result<Vec<Bucket>, Error> {
+    ensure_controller()?;
+
+    Ok(BUCKETS.with(|service| service.borrow().iter().map(|(_, bucket)| bucket).collect()))
+}
+
+/// The bucket canister holding the consultation, if it has been created.
+#[ic_cdk::query]
+fn locate_legal_consultation(id: u64) -> Result<Option<Principal>, Error> {
+    ensure_controller()?;
+
+    Ok(locate(id))
+}
+
+/// Pushes queued consultations and deletions to their buckets now instead
+/// of waiting for the timer. Returns the number of records pushed.
+#[ic_cdk::update]
+async fn flush_shards() -> Result<u64, Error> {
+    ensure_controller()?;
+
+    flush_outbox().await
+}
+
+pub(crate) fn start_sharding_timer() {
+    ic_cdk_timers::set_timer_interval(FLUSH_INTERVAL, || {
+        ic_cdk::spawn(async {
+            let _ = flush_outbox().await;
+        })
+    });
+}
+
+/// Takes a consultation that was written while sharding is enabled: it is
+/// queued for its bucket, and read from the queue until it is pushed.
+/// Returns `false` if this canister is to keep the consultation itself.
+pub(crate) fn store_consultation(consultation: &LegalConsultation) -> bool {
+    let id = consultation.id;
+    if !is_enabled() {
+        // The local copy supersedes anything queued or cached.
+        PENDING_RECORDS.with(|service| service.borrow_mut().remove(&id));
+        CACHE.with(|cache| cache.borrow_mut().remove(&id));
+        return false;
+    }
+    PENDING_RECORDS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(id, PendingRecord(consultation.to_bytes().into_owned()))
+    });
+    SHARD_OUTBOX.with(|service| service.borrow_mut().remove(&id));
+    CACHE.with(|cache| cache.borrow_mut().insert(id, Some(consultation.clone())));
+    true
+}
+
+/// Forgets a consultation that was removed here and deletes its bucket copy.
+pub(crate) fn remove_consultation(id: u64) {
+    PENDING_RECORDS.with(|service| service.borrow_mut().remove(&id));
+    CACHE.with(|cache| cache.borrow_mut().insert(id, None));
+    mark_deleted(id);
+}
+
+/// Queues what the buckets hold under the id for deletion, even while
+/// sharding is disabled, so that nothing is left behind.
+pub(crate) fn mark_deleted(id: u64) {
+    if is_enabled() || locate(id).is_some() {
+        SHARD_OUTBOX.with(|service| service.borrow_mut().insert(id, time()));
+    }
+}
+
+/// A consultation held outside this canister's own store: queued for its
+/// bucket or loaded from it. Reading one that its bucket may hold but that
+/// was not loaded traps, as treating it as missing would be wrong.
+pub(crate) fn consultation(id: u64) -> Option<LegalConsultation> {
+    if let Some(record) = PENDING_RECORDS.with(|service| service.borrow().get(&id)) {
+        return Some(decode(&record.0));
+    }
+    match CACHE.with(|cache| cache.borrow().get(&id).cloned()) {
+        Some(cached) => cached,
+        None if locate(id).is_some() => ic_cdk::trap(&format!(
+            "Legal consultation with id={} was read before it was loaded from its bucket",
+            id
+        )),
+        None => None,
+    }
+}
+
+/// Loads the consultations that live in buckets, so that they can be read
+/// synchronously for the rest of the call. Calls load everything they read
+/// in a single call to this, before they change any state.
+pub(crate) async fn load_consultations(ids: &[u64]) -> Result<(), Error> {
+    // Buckets installed, or entries evicted, while the calls were in
+    // flight leave more to load.
+    loop {
+        let unloaded = unloaded(ids);
+        if unloaded.is_empty() {
+            return Ok(());
+        }
+        let mut loaded = Vec::new();
+        for (bucket, bucket_ids) in unloaded {
+            for batch in bucket_ids.chunks(READ_BATCH) {
+                let records = get_records(bucket, batch.to_vec()).await?;
+                loaded.extend(
+                    batch
+                        .iter()
+                        .map(|id| (*id, records.get(id).map(|bytes| decode(bytes)))),
+                );
+            }
+        }
+        cache(loaded, ids);
+    }
+}
+
+/// Every consultation: those this canister holds and those in buckets, in
+/// id order. Nothing is cached, so callers read the records they need from
+/// the result or load them.
+pub(crate) async fn all_consultations() -> Result<Vec<LegalConsultation>, Error> {
+    let buckets: Vec<Principal> = BUCKETS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, bucket)| bucket.canister_id)
+            .collect()
+    });
+    // Records a concurrent flush moves to a bucket already listed are taken
+    // from what was held here when the listing started.
+    let earlier = held_records();
+    let mut stored = BTreeMap::new();
+    for bucket in buckets {
+        let mut start_after = None;
+        loop {
+            let (result,): (Result<Vec<(u64, ByteBuf)>, Error>,) =
+                ic_cdk::call(bucket, "list_records", (start_after, READ_BATCH as u32))
+                    .await
+                    .map_err(|(code, msg)| bucket_call_failed("list_records", code, msg))?;
+            let page = result?;
+            let Some((last, _)) = page.last() else {
+                break;
+            };
+            start_after = Some(*last);
+            stored.extend(page.into_iter().map(|(id, bytes)| (id, decode(&bytes))));
+        }
+    }
+
+    for (id, consultation) in earlier {
+        stored.entry(id).or_insert(consultation);
+    }
+    // Bucket copies of removed records may still wait for deletion, and
+    // queued and local records are newer than theirs.
+    stored.retain(|id, _| {
+        !SHARD_OUTBOX.with(|service| service.borrow().contains_key(id))
+            && CACHE.with(|cache| !matches!(cache.borrow().get(id), Some(None)))
+    });
+    stored.extend(held_records());
+    Ok(stored.into_values().collect())
+}
+
+/// The consultations this canister holds itself or has queued.
+fn held_records() -> BTreeMap<u64, LegalConsultation> {
+    let mut held: BTreeMap<u64, LegalConsultation> = PENDING_RECORDS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(id, record)| (id, decode(&record.0)))
+            .collect()
+    });
+    LEGAL_CONSULTATIONS.with(|service| held.extend(service.borrow().iter()));
+    held
+}
+
+pub(crate) fn is_enabled() -> bool {
+    get_sharding_config().enabled
+}
+
+/// The bucket owning the id, if it has been created.
+pub(crate) fn locate(id: u64) -> Option<Principal> {
+    BUCKETS.with(|service| {
+        service
+            .borrow()
+            .get(&range_start(id))
+            .map(|bucket| bucket.canister_id)
+    })
+}
+
+/// The bucket owning the id, created on first use.
+pub(crate) async fn bucket_of(id: u64) -> Result<Principal, Error> {
+    bucket_for(range_start(id)).await
+}
+
+async fn flush_outbox() -> Result<u64, Error> {
+    if FLUSHING.with(|flushing| flushing.replace(true)) {
+        return Ok(0);
+    }
+    let _flushing = FlushGuard;
+    if is_enabled() {
+        migrate_local_records();
+    }
+    push_batch().await
+}
+
+/// Moves a batch of the consultations this canister still holds to the
+/// queue, from where they are pushed to their buckets.
+fn migrate_local_records() {
+    let local: Vec<LegalConsultation> = LEGAL_CONSULTATIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .take(FLUSH_BATCH)
+            .map(|(_, consultation)| consultation)
+            .collect()
+    });
+    for consultation in local {
+        LEGAL_CONSULTATIONS.with(|service| service.borrow_mut().remove(&consultation.id));
+        store_consultation(&consultation);
+    }
+}
+
+async fn push_batch() -> Result<u64, Error> {
+    let upserts: Vec<(u64, ByteBuf)> = PENDING_RECORDS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .take(FLUSH_BATCH)
+            .map(|(id, record)| (id, ByteBuf::from(record.0)))
+            .collect()
+    });
+    let deletes: Vec<u64> = SHARD_OUTBOX.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .take(FLUSH_BATCH)
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    let mut batches: BTreeMap<u64, BucketBatch> = BTreeMap::new();
+    for (id, record) in upserts {
+        batches
+            .entry(range_start(id))
+            .or_default()
+            .0
+            .push((id, record));
+    }
+    for id in deletes {
+        let start = range_start(id);
+        if locate(id).is_some() {
+            batches.entry(start).or_default().1.push(id);
+        } else if !is_being_created(start) {
+            // No bucket holds anything under the id.
+            SHARD_OUTBOX.with(|service| service.borrow_mut().remove(&id));
+        }
+    }
+
+    let mut pushed = 0;
+    for (start, (upserts, deletes)) in batches {
+        let bucket = bucket_for(start).await?;
+        if !upserts.is_empty() {
+            call_bucket(bucket, "put_records", (upserts.clone(),)).await?;
+            // Records written again while the call was in flight stay queued.
+            PENDING_RECORDS.with(|service| {
+                let mut pending = service.borrow_mut();
+                for (id, record) in &upserts {
+                    if pending.get(id).is_some_and(|current| current.0 == **record) {
+                        pending.remove(id);
+                    }
+                }
+            });
+        }
+        if !deletes.is_empty() {
+            call_bucket(bucket, "delete_records", (deletes.clone(),)).await?;
+            SHARD_OUTBOX.with(|service| {
+                let mut outbox = service.borrow_mut();
+                for id in &deletes {
+                    outbox.remove(id);
+                }
+            });
+        }
+        pushed += (upserts.len() + deletes.len()) as u64;
+    }
+    Ok(pushed)
+}
+
+/// Ids among `ids` whose bucket may hold them but that are not loaded,
+/// by bucket.
+fn unloaded(ids: &[u64]) -> BTreeMap<Principal, Vec<u64>> {
+    let mut unloaded: BTreeMap<Principal, Vec<u64>> = BTreeMap::new();
+    for id in ids.iter().copied().collect::<BTreeSet<u64>>() {
+        let held = LEGAL_CONSULTATIONS.with(|service| service.borrow().contains_key(&id))
+            || PENDING_RECORDS.with(|service| service.borrow().contains_key(&id))
+            || CACHE.with(|cache| cache.borrow().contains_key(&id));
+        if let (false, Some(bucket)) = (held, locate(id)) {
+            unloaded.entry(bucket).or_default().push(id);
+        }
+    }
+    unloaded
+}
+
+/// Caches loaded records, first evicting the lowest ids (the oldest
+/// consultations) other than those the caller is about to read.
+fn cache(loaded: Vec<(u64, Option<LegalConsultation>)>, reading: &[u64]) {
+    let reading: BTreeSet<u64> = reading.iter().copied().collect();
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let evictable: Vec<u64> = cache
+            .keys()
+            .copied()
+            .filter(|id| !reading.contains(id))
+            .take((cache.len() + loaded.len()).saturating_sub(CACHE_CAPACITY))
+            .collect();
+        for id in evictable {
+            cache.remove(&id);
+        }
+        cache.extend(loaded);
+    });
+}
+
+async fn get_records(bucket: Principal, ids: Vec<u64>) -> Result<BTreeMap<u64, ByteBuf>, Error> {
+    let (result,): (Result<Vec<(u64, ByteBuf)>, Error>,) =
+        ic_cdk::call(bucket, "get_records", (ids,))
+            .await
+            .map_err(|(code, msg)| bucket_call_failed("get_records", code, msg))?;
+    Ok(result?.into_iter().collect())
+}
+
+fn decode(bytes: &[u8]) -> LegalConsultation {
+    LegalConsultation::from_bytes(Cow::Borrowed(bytes))
+}
+
+fn is_being_created(range_start: u64) -> bool {
+    CREATING.with(|creating| creating.borrow().contains(&range_start))
+        || PENDING_BUCKETS.with(|service| service.borrow().contains_key(&range_start))
+}
+
+/// The bucket owning the id range, created and installed on first use.
+async fn bucket_for(range_start: u64) -> Result<Principal, Error> {
+    if let Some(bucket) = BUCKETS.with(|service| service.borrow().get(&range_start)) {
+        return Ok(bucket.canister_id);
+    }
+    if CREATING.with(|creating| !creating.borrow_mut().insert(range_start)) {
+        return Err(Error::CallFailed {
+            msg: format!(
+                "Bucket for ids from {} is being created, try again",
+                range_start
+            ),
+        });
+    }
+    let _creating = CreatingGuard(range_start);
+    create_bucket(range_start).await
+}
+
+/// Creates the bucket canister and installs it. The canister is recorded
+/// before installation, so a failed installation is retried on it rather
+/// than on yet another new canister.
+async fn create_bucket(range_start: u64) -> Result<Principal, Error> {
+    let bucket = match PENDING_BUCKETS.with(|service| service.borrow().get(&range_start)) {
+        Some(bucket) => bucket,
+        None => {
+            let settings = CanisterSettings {
+                controllers: Some(vec![ic_cdk::id()]),
+                ..Default::default()
+            };
+            let (record,) = create_canister(
+                CreateCanisterArgument {
+                    settings: Some(settings),
+                },
+                get_sharding_config().bucket_cycles as u128,
+            )
+            .await
+            .map_err(|(code, msg)| bucket_call_failed("create_canister", code, msg))?;
+            let bucket = Bucket {
+                range_start,
+                range_end: range_start.saturating_add(IDS_PER_BUCKET),
+                canister_id: record.canister_id,
+                created_at: time(),
+            };
+            PENDING_BUCKETS
+                .with(|service| service.borrow_mut().insert(range_start, bucket.clone()));
+            bucket
+        }
+    };
+
+    // A pending bucket holds nothing yet, so reinstalling it is safe even
+    // if an earlier attempt installed the module before failing.
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Reinstall,
+        canister_id: bucket.canister_id,
+        wasm_module: BUCKET_WASM.with(|cell| cell.borrow().get().0.clone()),
+        arg: Encode!(&ic_cdk::id()).unwrap(),
+    })
+    .await
+    .map_err(|(code, msg)| bucket_call_failed("install_code", code, msg))?;
+
+    PENDING_BUCKETS.with(|service| service.borrow_mut().remove(&range_start));
+    BUCKETS.with(|service| service.borrow_mut().insert(range_start, bucket.clone()));
+    Ok(bucket.canister_id)
+}
+
+/// For calls that have no error to return: failing to read a bucket
+/// rejects the whole call.
+pub(crate) fn or_trap<T>(result: Result<T, Error>) -> T {
+    result.unwrap_or_else(|error| match error {
+        Error::NotFound { msg }
+        | Error::Unauthorized { msg }
+        | Error::InvalidInput { msg }
+        | Error::PaymentFailed { msg }
+        | Error::InsufficientFunds { msg }
+        | Error::LegalHold { msg }
+        | Error::CallFailed { msg } => ic_cdk::trap(&msg),
+    })
+}
+
+pub(crate) async fn call_bucket<T: ArgumentEncoder>(
+    bucket: Principal,
+    method: &str,
+    args: T,
+) -> Result<(), Error> {
+    let (result,): (Result<(), Error>,) = ic_cdk::call(bucket, method, args)
+        .await
+        .map_err(|(code, msg)| bucket_call_failed(method, code, msg))?;
+    result
+}
+
+/// The canisters of every installed bucket.
+pub(crate) fn bucket_canisters() -> Vec<Principal> {
+    BUCKETS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, bucket)| bucket.canister_id)
+            .collect()
+    })
+}
+
+/// Checks that each bucket still answers to this canister, so that the
+/// consultations and documents it holds can be read after a restore.
+pub(crate) async fn ensure_buckets_owned(buckets: &[Principal]) -> Result<(), Error> {
+    for bucket in buckets {
+        if get_records(*bucket, Vec::new()).await.is_err() {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Bucket {} cannot be read by this canister, the records it holds would be lost",
+                    bucket
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::cell_source("sharding_config", &SHARDING_CONFIG),
+        backup::cell_source("bucket_wasm", &BUCKET_WASM),
+        backup::map_source("buckets", &BUCKETS),
+        backup::map_source("pending_buckets", &PENDING_BUCKETS),
+        backup::map_source("shard_outbox", &SHARD_OUTBOX),
+        backup::map_source("pending_records", &PENDING_RECORDS),
+    ]
+}
+
+fn range_start(id: u64) -> u64 {
+    id - id % IDS_PER_BUCKET
+}
+
+pub(crate) fn bucket_call_failed(method: &str, code: RejectionCode, msg: String) -> Error {
+    Error::CallFailed {
+        msg: format!("Call to {} failed ({:?}): {}", method, code, msg),
+    }
+}
+
+fn ensure_controller() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers can manage sharding".to_string(),
+        })
+    }
+}
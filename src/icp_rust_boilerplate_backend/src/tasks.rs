@@ -0,0 +1,692 @@
+use crate::{
+    backup, changes, ensure_firm_staff, firm_legal_consultation, next_id, organizations,
+    participants, sharding, ChangeEntity, Error, LegalConsultation, Memory, ParticipantRole,
+    MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Keep tasks and templates within their storage bounds.
+const MAX_TITLE_BYTES: usize = 256;
+const MAX_DESCRIPTION_BYTES: usize = 2048;
+const MAX_CHECKLIST_ITEMS: usize = 30;
+const MAX_TEMPLATE_TASKS: usize = 30;
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum TaskStatus {
+    Open,
+    InProgress,
+    Blocked,
+    Done,
+    Cancelled,
+}
+
+impl TaskStatus {
+    pub(crate) fn is_closed(self) -> bool {
+        matches!(self, TaskStatus::Done | TaskStatus::Cancelled)
+    }
+}
+
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum TaskPriority {
+    Low,
+    Normal,
+    High,
+    Urgent,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ChecklistItem {
+    text: String,
+    done: bool,
+    done_by: Option<Principal>,
+    done_at: Option<u64>,
+}
+
+/// The editable part of a task.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TaskDetails {
+    title: String,
+    description: String,
+    assignee: Option<Principal>,
+    due_at: Option<u64>,
+    priority: TaskPriority,
+}
+
+/// A work item on a consultation. Subtasks point at their parent task.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Task {
+    id: u64,
+    consultation_id: u64,
+    parent_id: Option<u64>,
+    details: TaskDetails,
+    status: TaskStatus,
+    checklist: Vec<ChecklistItem>,
+    created_by: Principal,
+    created_at: u64,
+    completed_at: Option<u64>,
+}
+
+impl Storable for Task {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Task {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TemplateTask {
+    title: String,
+    description: String,
+    priority: TaskPriority,
+    // Counted from the day the template is applied.
+    due_in_days: Option<u32>,
+    checklist: Vec<String>,
+    subtasks: Vec<String>,
+}
+
+/// A reusable set of tasks, such as the standard steps of an intake.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TaskTemplate {
+    id: u64,
+    name: String,
+    organization_id: Option<u64>,
+    tasks: Vec<TemplateTask>,
+    created_at: u64,
+}
+
+impl Storable for TaskTemplate {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TaskTemplate {
+    const MAX_SIZE: u32 = 131072;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TASKS: RefCell<StableBTreeMap<u64, Task, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    static TASK_TEMPLATES: RefCell<StableBTreeMap<u64, TaskTemplate, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+}
+
+#[ic_cdk::update]
+async fn create_task(
+    consultation_id: u64,
+    parent_id: Option<u64>,
+    details: TaskDetails,
+    checklist: Vec<String>,
+) -> Result<Task, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = staffed_consultation(consultation_id, &caller)?;
+    validate_details(&consultation, &details)?;
+    validate_checklist(&checklist)?;
+    if let Some(parent_id) = parent_id {
+        match _get_task(&parent_id) {
+            Some(parent) if parent.consultation_id == consultation_id => {}
+            _ => return Err(task_not_found(parent_id)),
+        }
+    }
+
+    Ok(insert_new_task(
+        consultation_id,
+        parent_id,
+        details,
+        checklist,
+        caller,
+    ))
+}
+
+#[ic_cdk::update]
+async fn update_task(id: u64, details: TaskDetails) -> Result<Task, Error> {
+    load_task_consultation(id).await?;
+    let (consultation, mut task) = staffed_task(id)?;
+    validate_details(&consultation, &details)?;
+
+    task.details = details;
+    do_insert_task(&task);
+    Ok(task)
+}
+
+/// Moves a task to a new status. A task is only done once all of its
+/// subtasks are closed.
+#[ic_cdk::update]
+async fn set_task_status(id: u64, status: TaskStatus) -> Result<Task, Error> {
+    load_task_consultation(id).await?;
+    let (_, mut task) = staffed_task(id)?;
+    if status == TaskStatus::Done
+        && subtasks_of(id)
+            .iter()
+            .any(|subtask| !subtask.status.is_closed())
+    {
+        return Err(Error::InvalidInput {
+            msg: format!("Task {} has open subtasks", id),
+        });
+    }
+
+    task.status = status;
+    task.completed_at = status.is_closed().then(time);
+    do_insert_task(&task);
+    Ok(task)
+}
+
+#[ic_cdk::update]
+async fn add_checklist_item(id: u64, text: String) -> Result<Task, Error> {
+    load_task_consultation(id).await?;
+    let (_, mut task) = staffed_task(id)?;
+    task.checklist.push(new_checklist_item(text));
+    validate_checklist_items(&task.checklist)?;
+
+    do_insert_task(&task);
+    Ok(task)
+}
+
+#[ic_cdk::update]
+async fn check_checklist_item(id: u64, index: u32, done: bool) -> Result<Task, Error> {
+    load_task_consultation(id).await?;
+    let (_, mut task) = staffed_task(id)?;
+    let item = task
+        .checklist
+        .get_mut(index as usize)
+        .ok_or_else(|| checklist_item_not_found(id, index))?;
+
+    item.done = done;
+    item.done_by = done.then(ic_cdk::caller);
+    item.done_at = done.then(time);
+    do_insert_task(&task);
+    Ok(task)
+}
+
+#[ic_cdk::update]
+async fn remove_checklist_item(id: u64, index: u32) -> Result<Task, Error> {
+    load_task_consultation(id).await?;
+    let (_, mut task) = staffed_task(id)?;
+    if index as usize >= task.checklist.len() {
+        return Err(checklist_item_not_found(id, index));
+    }
+
+    task.checklist.remove(index as usize);
+    do_insert_task(&task);
+    Ok(task)
+}
+
+/// Every task of the consultation, subtasks included, in creation order.
+#[ic_cdk::query(composite = true)]
+async fn list_consultation_tasks(consultation_id: u64) -> Result<Vec<Task>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    staffed_consultation(consultation_id, &ic_cdk::caller())?;
+    Ok(tasks_of(consultation_id))
+}
+
+/// Open tasks assigned to the caller, soonest due first.
+#[ic_cdk::query]
+fn list_my_open_tasks() -> Vec<Task> {
+    let caller = ic_cdk::caller();
+    let mut tasks: Vec<Task> = TASKS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, task)| task)
+            .filter(|task| task.details.assignee == Some(caller) && !task.status.is_closed())
+            .collect()
+    });
+    sort_by_due_date(&mut tasks);
+    tasks
+}
+
+/// Open tasks past their due date on consultations in the caller's scope,
+/// most overdue first.
+#[ic_cdk::query(composite = true)]
+async fn list_overdue_tasks() -> Result<Vec<Task>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    let now = time();
+
+    let overdue: Vec<Task> = TASKS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, task)| task)
+            .filter(|task| {
+                !task.status.is_closed() && task.details.due_at.is_some_and(|due_at| due_at < now)
+            })
+            .collect()
+    });
+    let consultation_ids: Vec<u64> = overdue.iter().map(|task| task.consultation_id).collect();
+    sharding::load_consultations(&consultation_ids).await?;
+    let mut tasks: Vec<Task> = overdue
+        .into_iter()
+        .filter(|task| firm_legal_consultation(&task.consultation_id).is_some())
+        .collect();
+    sort_by_due_date(&mut tasks);
+    Ok(tasks)
+}
+
+/// Saves a task template for the caller's organization. Controllers and
+/// organization admins only.
+#[ic_cdk::update]
+fn define_task_template(name: String, tasks: Vec<TemplateTask>) -> Result<TaskTemplate, Error> {
+    ensure_admin()?;
+    if name.trim().is_empty() || name.len() > MAX_TITLE_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Template names are 1 to {} bytes", MAX_TITLE_BYTES),
+        });
+    }
+    if tasks.is_empty() || tasks.len() > MAX_TEMPLATE_TASKS {
+        return Err(Error::InvalidInput {
+            msg: format!("A template has 1 to {} tasks", MAX_TEMPLATE_TASKS),
+        });
+    }
+    for task in &tasks {
+        validate_title(&task.title)?;
+        validate_description(&task.description)?;
+        validate_checklist(&task.checklist)?;
+        if task.subtasks.len() > MAX_CHECKLIST_ITEMS {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Template tasks have at most {} subtasks",
+                    MAX_CHECKLIST_ITEMS
+                ),
+            });
+        }
+        for subtask in &task.subtasks {
+            validate_title(subtask)?;
+        }
+    }
+
+    let template = TaskTemplate {
+        id: next_id(),
+        name,
+        organization_id: organizations::organization_of(&ic_cdk::caller()),
+        tasks,
+        created_at: time(),
+    };
+    TASK_TEMPLATES.with(|service| service.borrow_mut().insert(template.id, template.clone()));
+    changes::record_upsert(ChangeEntity::TaskTemplate, template.id, &template);
+    Ok(template)
+}
+
+#[ic_cdk::query]
+fn list_task_templates() -> Result<Vec<TaskTemplate>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    Ok(TASK_TEMPLATES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, template)| template)
+            .filter(|template| organizations::can_access(&caller, template.organization_id))
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn delete_task_template(id: u64) -> Result<(), Error> {
+    ensure_admin()?;
+    scoped_template(id)?;
+    TASK_TEMPLATES.with(|service| service.borrow_mut().remove(&id));
+    changes::record_delete(ChangeEntity::TaskTemplate, id);
+    Ok(())
+}
+
+/// Creates every task of the template on the consultation at once, all
+/// assigned to `assignee`.
+#[ic_cdk::update]
+async fn apply_task_template(
+    consultation_id: u64,
+    template_id: u64,
+    assignee: Option<Principal>,
+) -> Result<Vec<Task>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = staffed_consultation(consultation_id, &caller)?;
+    let template = scoped_template(template_id)?;
+    if template.organization_id != consultation.organization_id {
+        return Err(Error::NotFound {
+            msg: format!("Task template with id={} not found", template_id),
+        });
+    }
+    if let Some(assignee) = &assignee {
+        ensure_assignable(&consultation, assignee)?;
+    }
+
+    let now = time();
+    let mut created = Vec::new();
+    for item in template.tasks {
+        let due_at = item
+            .due_in_days
+            .map(|days| now.saturating_add(u64::from(days) * NANOS_PER_DAY));
+        let parent = insert_new_task(
+            consultation_id,
+            None,
+            TaskDetails {
+                title: item.title,
+                description: item.description,
+                assignee,
+                due_at,
+                priority: item.priority,
+            },
+            item.checklist,
+            caller,
+        );
+        for title in item.subtasks {
+            created.push(insert_new_task(
+                consultation_id,
+                Some(parent.id),
+                TaskDetails {
+                    title,
+                    description: String::new(),
+                    assignee,
+                    due_at,
+                    priority: item.priority,
+                },
+                Vec::new(),
+                caller,
+            ));
+        }
+        created.push(parent);
+    }
+    created.sort_by_key(|task| task.id);
+    Ok(created)
+}
+
+/// Creates a task on behalf of a workflow stage, assigned to the first
+/// advisor currently holding `role`.
+pub(crate) fn create_workflow_task(
+    consultation: &LegalConsultation,
+    title: String,
+    priority: TaskPriority,
+    due_in_days: Option<u32>,
+    role: Option<ParticipantRole>,
+    by: Principal,
+) -> Result<Task, Error> {
+    validate_title(&title)?;
+    let details = TaskDetails {
+        title,
+        description: String::new(),
+        assignee: role.and_then(|role| participants::principal_with_role(consultation, role)),
+        due_at: due_in_days.map(|days| time().saturating_add(u64::from(days) * NANOS_PER_DAY)),
+        priority,
+    };
+    Ok(insert_new_task(
+        consultation.id,
+        None,
+        details,
+        Vec::new(),
+        by,
+    ))
+}
+
+/// Whether every task of the consultation is done or cancelled.
+pub(crate) fn all_tasks_closed(consultation_id: u64) -> bool {
+    tasks_of(consultation_id)
+        .iter()
+        .all(|task| task.status.is_closed())
+}
+
+/// Drops the tasks of a consultation that is permanently deleted.
+pub(crate) fn remove_consultation(consultation_id: u64) {
+    let ids: Vec<u64> = tasks_of(consultation_id)
+        .iter()
+        .map(|task| task.id)
+        .collect();
+    TASKS.with(|service| {
+        let mut tasks = service.borrow_mut();
+        for id in &ids {
+            tasks.remove(id);
+        }
+    });
+    for id in ids {
+        changes::record_delete(ChangeEntity::Task, id);
+    }
+}
+
+fn insert_new_task(
+    consultation_id: u64,
+    parent_id: Option<u64>,
+    details: TaskDetails,
+    checklist: Vec<String>,
+    created_by: Principal,
+) -> Task {
+    let task = Task {
+        id: next_id(),
+        consultation_id,
+        parent_id,
+        details,
+        status: TaskStatus::Open,
+        checklist: checklist.into_iter().map(new_checklist_item).collect(),
+        created_by,
+        created_at: time(),
+        completed_at: None,
+    };
+    do_insert_task(&task);
+    task
+}
+
+fn new_checklist_item(text: String) -> ChecklistItem {
+    ChecklistItem {
+        text,
+        done: false,
+        done_by: None,
+        done_at: None,
+    }
+}
+
+pub(crate) fn tasks_of(consultation_id: u64) -> Vec<Task> {
+    TASKS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, task)| task)
+            .filter(|task| task.consultation_id == consultation_id)
+            .collect()
+    })
+}
+
+fn subtasks_of(id: u64) -> Vec<Task> {
+    TASKS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, task)| task)
+            .filter(|task| task.parent_id == Some(id))
+            .collect()
+    })
+}
+
+fn sort_by_due_date(tasks: &mut [Task]) {
+    // Undated tasks come last; ties go to the more urgent task.
+    tasks.sort_by_key(|task| {
+        (
+            task.details.due_at.is_none(),
+            task.details.due_at,
+            std::cmp::Reverse(task.details.priority),
+        )
+    });
+}
+
+fn validate_details(consultation: &LegalConsultation, details: &TaskDetails) -> Result<(), Error> {
+    validate_title(&details.title)?;
+    validate_description(&details.description)?;
+    match &details.assignee {
+        Some(assignee) => ensure_assignable(consultation, assignee),
+        None => Ok(()),
+    }
+}
+
+fn validate_title(title: &str) -> Result<(), Error> {
+    if title.trim().is_empty() || title.len() > MAX_TITLE_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Task titles are 1 to {} bytes", MAX_TITLE_BYTES),
+        });
+    }
+    Ok(())
+}
+
+fn validate_description(description: &str) -> Result<(), Error> {
+    if description.len() > MAX_DESCRIPTION_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Task descriptions exceed {} bytes", MAX_DESCRIPTION_BYTES),
+        });
+    }
+    Ok(())
+}
+
+fn validate_checklist(items: &[String]) -> Result<(), Error> {
+    if items.len() > MAX_CHECKLIST_ITEMS {
+        return Err(too_many_checklist_items());
+    }
+    items.iter().try_for_each(|item| validate_title(item))
+}
+
+fn validate_checklist_items(items: &[ChecklistItem]) -> Result<(), Error> {
+    if items.len() > MAX_CHECKLIST_ITEMS {
+        return Err(too_many_checklist_items());
+    }
+    items.iter().try_for_each(|item| validate_title(&item.text))
+}
+
+/// Tasks go to staff of the consultation's organization.
+fn ensure_assignable(consultation: &LegalConsultation, assignee: &Principal) -> Result<(), Error> {
+    if organizations::can_access(assignee, consultation.organization_id)
+        && ensure_firm_staff(assignee).is_ok()
+    {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!(
+                "Principal {} cannot be assigned tasks on legal consultation with id={}",
+                assignee, consultation.id
+            ),
+        })
+    }
+}
+
+/// A consultation whose tasks the caller may see and change: its advisors,
+/// admins of its organization and controllers.
+fn staffed_consultation(
+    consultation_id: u64,
+    caller: &Principal,
+) -> Result<LegalConsultation, Error> {
+    firm_legal_consultation(&consultation_id)
+        .filter(|consultation| {
+            ic_cdk::api::is_controller(caller)
+                || organizations::is_organization_admin(caller)
+                || participants::role_of(consultation.id, consultation.advisor_id, caller).is_some()
+        })
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Legal consultation with id={} not found", consultation_id),
+        })
+}
+
+/// Loads the consultation a task belongs to from its bucket.
+async fn load_task_consultation(id: u64) -> Result<(), Error> {
+    let consultation_ids: Vec<u64> = _get_task(&id)
+        .map(|task| task.consultation_id)
+        .into_iter()
+        .collect();
+    sharding::load_consultations(&consultation_ids).await
+}
+
+fn staffed_task(id: u64) -> Result<(LegalConsultation, Task), Error> {
+    let task = _get_task(&id).ok_or_else(|| task_not_found(id))?;
+    let consultation = staffed_consultation(task.consultation_id, &ic_cdk::caller())
+        .map_err(|_| task_not_found(id))?;
+    Ok((consultation, task))
+}
+
+fn scoped_template(id: u64) -> Result<TaskTemplate, Error> {
+    TASK_TEMPLATES
+        .with(|service| service.borrow().get(&id))
+        .filter(|template| organizations::can_access(&ic_cdk::caller(), template.organization_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Task template with id={} not found", id),
+        })
+}
+
+fn _get_task(id: &u64) -> Option<Task> {
+    TASKS.with(|service| service.borrow().get(id))
+}
+
+/// Replaces the titles, descriptions and checklist items of the
+/// consultation's tasks, including in the change feed history.
+pub(crate) fn erase_text(consultation_id: u64, erased: &str) {
+    for mut task in tasks_of(consultation_id) {
+        task.details.title = erased.to_string();
+        task.details.description = erased.to_string();
+        for item in task.checklist.iter_mut() {
+            item.text = erased.to_string();
+        }
+        changes::redact_history(ChangeEntity::Task, task.id);
+        do_insert_task(&task);
+    }
+}
+
+fn do_insert_task(task: &Task) {
+    TASKS.with(|service| service.borrow_mut().insert(task.id, task.clone()));
+    changes::record_upsert(ChangeEntity::Task, task.id, task);
+}
+
+fn task_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Task {} not found", id),
+    }
+}
+
+fn checklist_item_not_found(id: u64, index: u32) -> Error {
+    Error::NotFound {
+        msg: format!("Task {} has no checklist item {}", id, index),
+    }
+}
+
+fn too_many_checklist_items() -> Error {
+    Error::InvalidInput {
+        msg: format!("Tasks have at most {} checklist items", MAX_CHECKLIST_ITEMS),
+    }
+}
+
+fn ensure_admin() -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) || organizations::is_organization_admin(&caller) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers and organization admins can manage task templates".to_string(),
+        })
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("tasks", &TASKS),
+        backup::map_source("task_templates", &TASK_TEMPLATES),
+    ]
+}
@@ -0,0 +1,254 @@
+use crate::{
+    backup, changes, do_insert_legal_advisor, do_insert_legal_consultation, organizations,
+    payments, remove_dependent_records, ChangeEntity, Error, LegalAdvisor, LegalConsultation,
+    Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// How long deleted records stay restorable before they may be purged.
+const TRASH_GRACE_PERIOD: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum TrashedRecord {
+    Consultation(LegalConsultation),
+    Advisor(LegalAdvisor),
+}
+
+/// Tombstone of a deleted record, holding everything needed to restore it.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct TrashEntry {
+    id: u64,
+    record: TrashedRecord,
+    deleted_by: Principal,
+    deleted_at: u64,
+}
+
+/// A trash entry as published in the change feed. The record itself was
+/// published before it was deleted.
+#[derive(Serialize)]
+struct TrashEntryChange<'a> {
+    id: u64,
+    deleted_by: &'a Principal,
+    deleted_at: u64,
+}
+
+impl Storable for TrashEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TrashEntry {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static TRASH: RefCell<StableBTreeMap<u64, TrashEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+}
+
+/// The trashed records the caller manages: all of them for controllers,
+/// those of their organization for organization admins.
+#[ic_cdk::query]
+fn list_trash() -> Result<Vec<TrashEntry>, Error> {
+    let caller = ensure_admin()?;
+
+    Ok(TRASH.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| can_manage(entry, &caller))
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn restore_from_trash(id: u64) -> Result<(), Error> {
+    let caller = ensure_admin()?;
+
+    match managed_entry(id, &caller) {
+        Some(entry) => {
+            TRASH.with(|service| service.borrow_mut().remove(&id));
+            changes::record_delete(ChangeEntity::TrashEntry, id);
+            match entry.record {
+                TrashedRecord::Consultation(consultation) => {
+                    do_insert_legal_consultation(&consultation)
+                }
+                TrashedRecord::Advisor(advisor) => do_insert_legal_advisor(&advisor),
+            }
+            Ok(())
+        }
+        None => Err(trash_entry_not_found(id)),
+    }
+}
+
+/// Permanently removes a trashed record, or with no id every record whose
+/// grace period has ended. Returns the number of purged records.
+#[ic_cdk::update]
+fn purge_trash(id: Option<u64>) -> Result<u64, Error> {
+    let caller = ensure_admin()?;
+
+    match id {
+        Some(id) => match managed_entry(id, &caller) {
+            Some(entry) => {
+                if time() < entry.deleted_at.saturating_add(TRASH_GRACE_PERIOD) {
+                    return Err(Error::InvalidInput {
+                        msg: format!("Trashed record with id={} is still in its grace period", id),
+                    });
+                }
+                TRASH.with(|service| service.borrow_mut().remove(&id));
+                changes::record_delete(ChangeEntity::TrashEntry, id);
+                remove_dependent_records(id);
+                Ok(1)
+            }
+            None => Err(trash_entry_not_found(id)),
+        },
+        None => Ok(purge_expired_where(|entry| can_manage(entry, &caller))),
+    }
+}
+
+/// Consultations with money in escrow stay live until it is released or
+/// refunded, as nothing settles the escrow of a trashed consultation.
+pub(crate) fn move_to_trash(
+    id: u64,
+    record: TrashedRecord,
+    deleted_by: Principal,
+) -> Result<(), Error> {
+    if matches!(record, TrashedRecord::Consultation(_)) && payments::has_unsettled_escrow(id) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} has an unsettled escrow; release or refund it first",
+                id
+            ),
+        });
+    }
+
+    let entry = TrashEntry {
+        id,
+        record,
+        deleted_by,
+        deleted_at: time(),
+    };
+    changes::record_upsert(
+        ChangeEntity::TrashEntry,
+        id,
+        &TrashEntryChange {
+            id,
+            deleted_by: &entry.deleted_by,
+            deleted_at: entry.deleted_at,
+        },
+    );
+    TRASH.with(|service| service.borrow_mut().insert(id, entry));
+    Ok(())
+}
+
+/// The trashed consultations of the client.
+pub(crate) fn consultations_of(client: &Principal) -> Vec<LegalConsultation> {
+    TRASH.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter_map(|(_, entry)| match entry.record {
+                TrashedRecord::Consultation(consultation)
+                    if consultation.client.as_ref() == Some(client) =>
+                {
+                    Some(consultation)
+                }
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+/// Replaces the copy of a trashed consultation, keeping when and by whom
+/// it was deleted.
+pub(crate) fn update_consultation(consultation: LegalConsultation) {
+    TRASH.with(|service| {
+        let mut trash = service.borrow_mut();
+        if let Some(mut entry) = trash.get(&consultation.id) {
+            entry.record = TrashedRecord::Consultation(consultation);
+            trash.insert(entry.id, entry);
+        }
+    });
+}
+
+pub(crate) fn purge_expired() -> u64 {
+    purge_expired_where(|_| true)
+}
+
+fn purge_expired_where(selected: impl Fn(&TrashEntry) -> bool) -> u64 {
+    let now = time();
+    let expired: Vec<u64> = TRASH.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| {
+                now >= entry.deleted_at.saturating_add(TRASH_GRACE_PERIOD) && selected(entry)
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    TRASH.with(|service| {
+        let mut trash = service.borrow_mut();
+        for id in &expired {
+            trash.remove(id);
+            changes::record_delete(ChangeEntity::TrashEntry, *id);
+        }
+    });
+    // Records kept alongside a trashed one outlive it so it can be restored.
+    for id in &expired {
+        remove_dependent_records(*id);
+    }
+    expired.len() as u64
+}
+
+fn trash_entry_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Trashed record with id={} not found", id),
+    }
+}
+
+/// Controllers and organization admins manage the trash; returns the caller.
+fn ensure_admin() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) || organizations::is_organization_admin(&caller) {
+        Ok(caller)
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only controllers and organization admins can manage the trash".to_string(),
+        })
+    }
+}
+
+/// Organization admins only manage the records of their own organization.
+fn can_manage(entry: &TrashEntry, caller: &Principal) -> bool {
+    let organization_id = match &entry.record {
+        TrashedRecord::Consultation(consultation) => consultation.organization_id,
+        TrashedRecord::Advisor(advisor) => advisor.organization_id,
+    };
+    ic_cdk::api::is_controller(caller)
+        || organization_id.is_some_and(|id| organizations::organization_of(caller) == Some(id))
+}
+
+fn managed_entry(id: u64, caller: &Principal) -> Option<TrashEntry> {
+    TRASH
+        .with(|service| service.borrow().get(&id))
+        .filter(|entry| can_manage(entry, caller))
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![backup::map_source("trash", &TRASH)]
+}
@@ -0,0 +1,700 @@
+use crate::{
+    _get_legal_client, backup, changes, ensure_bookkeeper, firm_legal_client,
+    firm_legal_consultation, matters, next_id, organizations, scoped_legal_client, sharding,
+    ChangeEntity, Error, Memory, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
+use std::collections::BTreeMap;
+use std::{borrow::Cow, cell::RefCell};
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum TrustTransactionKind {
+    Deposit,
+    Disbursement { payee: String },
+    TransferToOperating,
+    Refund,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum LedgerAccount {
+    TrustBank,
+    // Money held for the client on a matter, or outside any matter.
+    ClientTrust {
+        client_id: u64,
+        matter_id: Option<u64>,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct JournalLine {
+    account: LedgerAccount,
+    debit: u64,
+    credit: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    id: u64,
+    kind: TrustTransactionKind,
+    client_id: u64,
+    consultation_id: Option<u64>,
+    matter_id: Option<u64>,
+    amount: u64,
+    description: String,
+    lines: Vec<JournalLine>,
+    recorded_by: Principal,
+    recorded_at: u64,
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for JournalEntry {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A client's trust balance on one matter, or outside any matter.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ClientTrustLedger {
+    matter_id: Option<u64>,
+    balance: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClientLedgerDiscrepancy {
+    client_id: u64,
+    matter_id: Option<u64>,
+    ledger_balance: u64,
+    journal_balance: i64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ReconciliationReport {
+    generated_at: u64,
+    organization_id: Option<u64>,
+    bank_statement_balance: Option<u64>,
+    trust_account_balance: u64,
+    client_ledger_total: u64,
+    journal_trust_balance: i64,
+    journal_client_total: i64,
+    journal_debits: u64,
+    journal_credits: u64,
+    client_discrepancies: Vec<ClientLedgerDiscrepancy>,
+    reconciled: bool,
+}
+
+/// Orders the matter ledgers of a client together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct MatterLedgerKey {
+    client_id: u64,
+    matter_id: u64,
+}
+
+impl Storable for MatterLedgerKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.client_id.to_be_bytes());
+        bytes.extend_from_slice(&self.matter_id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        MatterLedgerKey {
+            client_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            matter_id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for MatterLedgerKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static TRUST_JOURNAL: RefCell<StableBTreeMap<u64, JournalEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    // Per-client sub-ledger balances held outside any matter, keyed by
+    // client id.
+    static CLIENT_TRUST_LEDGERS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    // Per-client sub-ledger balances of each matter.
+    static MATTER_TRUST_LEDGERS: RefCell<StableBTreeMap<MatterLedgerKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    // Trust account of the firm's staff outside organizations.
+    static TRUST_ACCOUNT_BALANCE: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), 0)
+            .expect("Cannot create trust account balance")
+    );
+
+    // Trust account of each organization, keyed by organization id.
+    static ORGANIZATION_TRUST_BALANCES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+}
+
+/// Posts a balanced journal entry against a client's trust ledger for a
+/// matter, by default the consultation's, and the trust account of the
+/// client's organization.
+///
+/// Outflows (disbursements, transfers to operating and refunds) are
+/// rejected if they would overdraw the client's balance on that matter.
+#[ic_cdk::update]
+async fn record_trust_transaction(
+    kind: TrustTransactionKind,
+    client_id: u64,
+    consultation_id: Option<u64>,
+    amount: u64,
+    description: String,
+    matter_id: Option<u64>,
+) -> Result<JournalEntry, Error> {
+    let recorded_by = ic_cdk::caller();
+    ensure_bookkeeper(&recorded_by)?;
+
+    let organization_id = match firm_legal_client(&client_id) {
+        Some(client) => client.organization_id,
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Legal client with id={} not found", client_id),
+            })
+        }
+    };
+    if let Some(consultation_id) = consultation_id {
+        sharding::load_consultations(&[consultation_id]).await?;
+        if firm_legal_consultation(&consultation_id).is_none() {
+            return Err(Error::NotFound {
+                msg: format!("Legal consultation with id={} not found", consultation_id),
+            });
+        }
+    }
+    let consultation_matter = consultation_id.and_then(matters::matter_id_of);
+    if matter_id.is_some() && consultation_matter.is_some() && matter_id != consultation_matter {
+        return Err(Error::InvalidInput {
+            msg: "The consultation belongs to another matter".to_string(),
+        });
+    }
+    let matter_id = matter_id.or(consultation_matter);
+    if let Some(matter_id) = matter_id {
+        if !matters::is_client_matter(matter_id, client_id, &recorded_by) {
+            return Err(Error::NotFound {
+                msg: format!(
+                    "Matter with id={} of legal client with id={} not found",
+                    matter_id, client_id
+                ),
+            });
+        }
+    }
+    if amount == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Trust transaction amount must be positive".to_string(),
+        });
+    }
+
+    let client_balance = ledger_balance(client_id, matter_id);
+    let account_balance = trust_account_balance(organization_id);
+    let client_account = LedgerAccount::ClientTrust {
+        client_id,
+        matter_id,
+    };
+
+    let (client_balance, account_balance, lines) = match kind {
+        TrustTransactionKind::Deposit => {
+            match (
+                client_balance.checked_add(amount),
+                account_balance.checked_add(amount),
+            ) {
+                (Some(client_balance), Some(account_balance)) => (
+                    client_balance,
+                    account_balance,
+                    vec![
+                        debit(LedgerAccount::TrustBank, amount),
+                        credit(client_account, amount),
+                    ],
+                ),
+                _ => {
+                    return Err(Error::InvalidInput {
+                        msg: "Trust balance overflow".to_string(),
+                    })
+                }
+            }
+        }
+        TrustTransactionKind::Disbursement { .. }
+        | TrustTransactionKind::TransferToOperating
+        | TrustTransactionKind::Refund => {
+            if amount > client_balance || amount > account_balance {
+                return Err(Error::InsufficientFunds {
+                    msg: format!(
+                        "Client with id={} has {} in trust{}, {} requested",
+                        client_id,
+                        client_balance,
+                        matter_id.map_or(String::new(), |id| format!(" on matter {}", id)),
+                        amount
+                    ),
+                });
+            }
+            (
+                client_balance - amount,
+                account_balance - amount,
+                vec![
+                    debit(client_account, amount),
+                    credit(LedgerAccount::TrustBank, amount),
+                ],
+            )
+        }
+    };
+
+    let entry = JournalEntry {
+        id: next_id(),
+        kind,
+        client_id,
+        consultation_id,
+        matter_id,
+        amount,
+        description,
+        lines,
+        recorded_by,
+        recorded_at: time(),
+    };
+
+    TRUST_JOURNAL.with(|service| service.borrow_mut().insert(entry.id, entry.clone()));
+    changes::record_upsert(ChangeEntity::TrustJournalEntry, entry.id, &entry);
+    set_ledger_balance(client_id, matter_id, client_balance);
+    set_trust_account_balance(organization_id, account_balance);
+    Ok(entry)
+}
+
+/// The client's trust balance across all of their matters.
+#[ic_cdk::query]
+fn get_client_trust_balance(client_id: u64) -> Result<u64, Error> {
+    Ok(readable_ledgers(client_id)?
+        .iter()
+        .map(|ledger| ledger.balance)
+        .sum())
+}
+
+/// The client's trust balance on each matter, and outside any matter.
+#[ic_cdk::query]
+fn list_client_trust_ledgers(client_id: u64) -> Result<Vec<ClientTrustLedger>, Error> {
+    readable_ledgers(client_id)
+}
+
+#[ic_cdk::query]
+fn list_trust_journal(client_id: Option<u64>) -> Result<Vec<JournalEntry>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_bookkeeper(&caller)?;
+
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(journal_entries(client_id));
+    }
+    Ok(journal_entries(client_id)
+        .into_iter()
+        .filter(|entry| firm_legal_client(&entry.client_id).is_some())
+        .collect())
+}
+
+/// Three-way reconciliation of the trust account of the caller's
+/// organization (and, if given, its bank statement), the ledgers of the
+/// organization's clients and their journal entries.
+#[ic_cdk::query]
+fn trust_reconciliation_report(
+    bank_statement_balance: Option<u64>,
+) -> Result<ReconciliationReport, Error> {
+    let caller = ic_cdk::caller();
+    ensure_bookkeeper(&caller)?;
+
+    Ok(reconcile(
+        organizations::organization_of(&caller),
+        bank_statement_balance,
+        time(),
+    ))
+}
+
+/// Three-way reconciliation of an organization's trust account: the account
+/// balance against the client ledgers and both against the journal.
+fn reconcile(
+    organization_id: Option<u64>,
+    bank_statement_balance: Option<u64>,
+    generated_at: u64,
+) -> ReconciliationReport {
+    let mut organizations_of: BTreeMap<u64, Option<u64>> = BTreeMap::new();
+    let mut in_scope = |client_id: u64| {
+        *organizations_of
+            .entry(client_id)
+            .or_insert_with(|| client_organization(client_id))
+            == organization_id
+    };
+
+    let mut journal_debits: u128 = 0;
+    let mut journal_credits: u128 = 0;
+    let mut journal_trust_balance: i128 = 0;
+    let mut journal_client_balances: BTreeMap<(u64, Option<u64>), i128> = BTreeMap::new();
+    TRUST_JOURNAL.with(|service| {
+        for (_, entry) in service.borrow().iter() {
+            if !in_scope(entry.client_id) {
+                continue;
+            }
+            for line in entry.lines {
+                journal_debits += line.debit as u128;
+                journal_credits += line.credit as u128;
+                match line.account {
+                    LedgerAccount::TrustBank => {
+                        journal_trust_balance += line.debit as i128 - line.credit as i128;
+                    }
+                    LedgerAccount::ClientTrust {
+                        client_id,
+                        matter_id,
+                    } => {
+                        *journal_client_balances
+                            .entry((client_id, matter_id))
+                            .or_default() += line.credit as i128 - line.debit as i128;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut client_ledgers: BTreeMap<(u64, Option<u64>), u64> = BTreeMap::new();
+    CLIENT_TRUST_LEDGERS.with(|service| {
+        for (client_id, balance) in service.borrow().iter() {
+            if in_scope(client_id) {
+                client_ledgers.insert((client_id, None), balance);
+            }
+        }
+    });
+    MATTER_TRUST_LEDGERS.with(|service| {
+        for (key, balance) in service.borrow().iter() {
+            if in_scope(key.client_id) {
+                client_ledgers.insert((key.client_id, Some(key.matter_id)), balance);
+            }
+        }
+    });
+    let client_ledger_total: u64 = client_ledgers.values().sum();
+
+    let mut ledger_keys: Vec<(u64, Option<u64>)> = client_ledgers.keys().copied().collect();
+    ledger_keys.extend(journal_client_balances.keys().copied());
+    ledger_keys.sort_unstable();
+    ledger_keys.dedup();
+    let client_discrepancies: Vec<ClientLedgerDiscrepancy> = ledger_keys
+        .into_iter()
+        .filter_map(|key| {
+            let ledger_balance = client_ledgers.get(&key).copied().unwrap_or(0);
+            let journal_balance = journal_client_balances.get(&key).copied().unwrap_or(0);
+            (ledger_balance as i128 != journal_balance).then_some(ClientLedgerDiscrepancy {
+                client_id: key.0,
+                matter_id: key.1,
+                ledger_balance,
+                journal_balance: journal_balance as i64,
+            })
+        })
+        .collect();
+
+    let trust_account_balance = trust_account_balance(organization_id);
+    let journal_client_total: i128 = journal_client_balances.values().sum();
+    let reconciled = journal_debits == journal_credits
+        && client_discrepancies.is_empty()
+        && trust_account_balance == client_ledger_total
+        && journal_trust_balance == trust_account_balance as i128
+        && journal_client_total == client_ledger_total as i128
+        && bank_statement_balance.is_none_or(|balance| balance == trust_account_balance);
+
+    ReconciliationReport {
+        generated_at,
+        organization_id,
+        bank_statement_balance,
+        trust_account_balance,
+        client_ledger_total,
+        journal_trust_balance: journal_trust_balance as i64,
+        journal_client_total: journal_client_total as i64,
+        journal_debits: journal_debits as u64,
+        journal_credits: journal_credits as u64,
+        client_discrepancies,
+        reconciled,
+    }
+}
+
+/// Moves each organization's share of the trust account, as posted in the
+/// journal, out of the account that was shared by every organization
+/// before each kept its own. Does nothing once an organization has one.
+pub(crate) fn split_trust_account() {
+    let shared = trust_account_balance(None);
+    if shared == 0 || ORGANIZATION_TRUST_BALANCES.with(|service| !service.borrow().is_empty()) {
+        return;
+    }
+    let mut shares: BTreeMap<u64, i128> = BTreeMap::new();
+    TRUST_JOURNAL.with(|service| {
+        for (_, entry) in service.borrow().iter() {
+            if let Some(organization_id) = client_organization(entry.client_id) {
+                let share = shares.entry(organization_id).or_default();
+                for line in entry.lines {
+                    if line.account == LedgerAccount::TrustBank {
+                        *share += line.debit as i128 - line.credit as i128;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut remaining = shared;
+    for (organization_id, share) in shares {
+        let share = (share.max(0) as u64).min(remaining);
+        if share > 0 {
+            set_trust_account_balance(Some(organization_id), share);
+            remaining -= share;
+        }
+    }
+    set_trust_account_balance(None, remaining);
+}
+
+pub(crate) fn journal_entries(client_id: Option<u64>) -> Vec<JournalEntry> {
+    TRUST_JOURNAL.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| client_id.is_none_or(|client_id| entry.client_id == client_id))
+            .collect()
+    })
+}
+
+/// The client's ledgers, for the client themselves and bookkeepers.
+fn readable_ledgers(client_id: u64) -> Result<Vec<ClientTrustLedger>, Error> {
+    let caller = ic_cdk::caller();
+    match scoped_legal_client(&client_id) {
+        Some(client) => {
+            if client.principal != Some(caller) {
+                ensure_bookkeeper(&caller)?;
+            }
+            Ok(ledgers_of(client_id))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Legal client with id={} not found", client_id),
+        }),
+    }
+}
+
+fn ledgers_of(client_id: u64) -> Vec<ClientTrustLedger> {
+    let general = CLIENT_TRUST_LEDGERS
+        .with(|service| service.borrow().get(&client_id))
+        .map(|balance| ClientTrustLedger {
+            matter_id: None,
+            balance,
+        });
+    let matters: Vec<ClientTrustLedger> = MATTER_TRUST_LEDGERS.with(|service| {
+        service
+            .borrow()
+            .range(
+                MatterLedgerKey {
+                    client_id,
+                    matter_id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.client_id == client_id)
+            .map(|(key, balance)| ClientTrustLedger {
+                matter_id: Some(key.matter_id),
+                balance,
+            })
+            .collect()
+    });
+    general.into_iter().chain(matters).collect()
+}
+
+fn ledger_balance(client_id: u64, matter_id: Option<u64>) -> u64 {
+    match matter_id {
+        Some(matter_id) => MATTER_TRUST_LEDGERS.with(|service| {
+            service.borrow().get(&MatterLedgerKey {
+                client_id,
+                matter_id,
+            })
+        }),
+        None => CLIENT_TRUST_LEDGERS.with(|service| service.borrow().get(&client_id)),
+    }
+    .unwrap_or(0)
+}
+
+fn set_ledger_balance(client_id: u64, matter_id: Option<u64>, balance: u64) {
+    match matter_id {
+        Some(matter_id) => MATTER_TRUST_LEDGERS.with(|service| {
+            service.borrow_mut().insert(
+                MatterLedgerKey {
+                    client_id,
+                    matter_id,
+                },
+                balance,
+            )
+        }),
+        None => {
+            CLIENT_TRUST_LEDGERS.with(|service| service.borrow_mut().insert(client_id, balance))
+        }
+    };
+}
+
+fn trust_account_balance(organization_id: Option<u64>) -> u64 {
+    match organization_id {
+        Some(organization_id) => ORGANIZATION_TRUST_BALANCES
+            .with(|service| service.borrow().get(&organization_id))
+            .unwrap_or(0),
+        None => TRUST_ACCOUNT_BALANCE.with(|cell| *cell.borrow().get()),
+    }
+}
+
+fn set_trust_account_balance(organization_id: Option<u64>, balance: u64) {
+    match organization_id {
+        Some(organization_id) => {
+            ORGANIZATION_TRUST_BALANCES
+                .with(|service| service.borrow_mut().insert(organization_id, balance));
+        }
+        None => TRUST_ACCOUNT_BALANCE
+            .with(|cell| cell.borrow_mut().set(balance))
+            .map(|_| ())
+            .expect("Cannot update trust account balance"),
+    }
+}
+
+/// The organization of the client's trust money. Entries of clients that
+/// no longer exist count towards staff outside organizations.
+fn client_organization(client_id: u64) -> Option<u64> {
+    _get_legal_client(&client_id).and_then(|client| client.organization_id)
+}
+
+fn debit(account: LedgerAccount, amount: u64) -> JournalLine {
+    JournalLine {
+        account,
+        debit: amount,
+        credit: 0,
+    }
+}
+
+fn credit(account: LedgerAccount, amount: u64) -> JournalLine {
+    JournalLine {
+        account,
+        debit: 0,
+        credit: amount,
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("trust_journal", &TRUST_JOURNAL),
+        backup::map_source("client_trust_ledgers", &CLIENT_TRUST_LEDGERS),
+        backup::map_source("matter_trust_ledgers", &MATTER_TRUST_LEDGERS),
+        backup::cell_source("trust_account_balance", &TRUST_ACCOUNT_BALANCE),
+        backup::map_source("organization_trust_balances", &ORGANIZATION_TRUST_BALANCES),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: u64 = 1;
+    const MATTER: u64 = 10;
+
+    /// Journals a deposit into the client's general trust ledger and updates
+    /// the balances the way `record_trust_transaction` does.
+    fn deposit(id: u64, amount: u64) {
+        let client_account = LedgerAccount::ClientTrust {
+            client_id: CLIENT,
+            matter_id: None,
+        };
+        TRUST_JOURNAL.with(|service| {
+            service.borrow_mut().insert(
+                id,
+                JournalEntry {
+                    id,
+                    kind: TrustTransactionKind::Deposit,
+                    client_id: CLIENT,
+                    consultation_id: None,
+                    matter_id: None,
+                    amount,
+                    description: String::new(),
+                    lines: vec![
+                        debit(LedgerAccount::TrustBank, amount),
+                        credit(client_account, amount),
+                    ],
+                    recorded_by: Principal::anonymous(),
+                    recorded_at: 0,
+                },
+            )
+        });
+        set_ledger_balance(CLIENT, None, ledger_balance(CLIENT, None) + amount);
+        set_trust_account_balance(None, trust_account_balance(None) + amount);
+    }
+
+    #[test]
+    fn consistent_books_reconcile_against_the_bank_statement() {
+        deposit(1, 500);
+        deposit(2, 300);
+
+        let report = reconcile(None, Some(800), 0);
+        assert!(report.reconciled);
+        assert_eq!(report.trust_account_balance, 800);
+        assert_eq!(report.client_ledger_total, 800);
+        assert_eq!(report.journal_trust_balance, 800);
+        assert_eq!(report.journal_client_total, 800);
+        assert_eq!(report.journal_debits, report.journal_credits);
+
+        assert!(!reconcile(None, Some(799), 0).reconciled);
+    }
+
+    #[test]
+    fn a_drifted_client_ledger_is_reported_as_a_discrepancy() {
+        deposit(1, 500);
+        set_ledger_balance(CLIENT, None, 450);
+
+        let report = reconcile(None, None, 0);
+        assert!(!report.reconciled);
+        assert_eq!(report.client_discrepancies.len(), 1);
+        let discrepancy = &report.client_discrepancies[0];
+        assert_eq!(discrepancy.client_id, CLIENT);
+        assert_eq!(discrepancy.matter_id, None);
+        assert_eq!(discrepancy.ledger_balance, 450);
+        assert_eq!(discrepancy.journal_balance, 500);
+    }
+
+    #[test]
+    fn matter_ledgers_are_reconciled_separately() {
+        deposit(1, 500);
+        // Moved between ledgers without a journal entry.
+        set_ledger_balance(CLIENT, None, 200);
+        set_ledger_balance(CLIENT, Some(MATTER), 300);
+
+        let report = reconcile(None, Some(500), 0);
+        assert!(!report.reconciled);
+        assert_eq!(report.client_ledger_total, 500);
+        assert_eq!(report.client_discrepancies.len(), 2);
+        assert_eq!(report.client_discrepancies[1].matter_id, Some(MATTER));
+        assert_eq!(report.client_discrepancies[1].journal_balance, 0);
+    }
+
+    #[test]
+    fn an_account_balance_off_the_ledgers_does_not_reconcile() {
+        deposit(1, 300);
+        set_trust_account_balance(None, 400);
+
+        let report = reconcile(None, Some(400), 0);
+        assert!(!report.reconciled);
+        assert!(report.client_discrepancies.is_empty());
+        assert_eq!(report.client_ledger_total, 300);
+    }
+}
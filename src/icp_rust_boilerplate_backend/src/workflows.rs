@@ -0,0 +1,914 @@
+use crate::{
+    _get_legal_consultation, backup, billing, can_view_consultation, changes, custom_fields,
+    custom_fields::CustomFieldEntity, ensure_firm_staff, firm_legal_consultation, forms,
+    forms::FieldValue, next_id, organizations, participants, payments, scoped_legal_consultation,
+    sharding, tasks, tasks::TaskPriority, ChangeEntity, ConsultationStatus, Error,
+    LegalConsultation, Memory, ParticipantRole, MEMORY_MANAGER,
+};
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::{borrow::Cow, cell::RefCell};
+
+// Keep a workflow version within its storage bound.
+const MAX_STAGES: usize = 30;
+const MAX_TRANSITIONS: usize = 100;
+const MAX_STAGE_ACTIONS: usize = 10;
+const MAX_NAME_BYTES: usize = 128;
+const MAX_MESSAGE_BYTES: usize = 512;
+
+/// A condition the consultation must meet before a transition is taken.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum TransitionGuard {
+    ConsultationStatus(ConsultationStatus),
+    // The client has paid into escrow.
+    EscrowFunded,
+    // Every billing entry has made it onto an invoice. It says nothing about
+    // payment; combine it with `InvoicesPaid`.
+    EntriesInvoiced,
+    // Every invoice on the consultation has been paid.
+    InvoicesPaid,
+    IntakeAnswered { key: String },
+    CustomFieldSet { key: String },
+    RoleStaffed(ParticipantRole),
+    // Every task on the consultation is done or cancelled.
+    TasksClosed,
+}
+
+/// Run when a consultation enters a stage.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum StageAction {
+    // Posts a notice addressed to the role; every participant receives it.
+    Notify {
+        role: ParticipantRole,
+        message: String,
+    },
+    SetCustomField {
+        key: String,
+        value: FieldValue,
+    },
+    // Assigned to an advisor holding `role`, if any.
+    CreateTask {
+        title: String,
+        priority: TaskPriority,
+        due_in_days: Option<u32>,
+        role: Option<ParticipantRole>,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct WorkflowStage {
+    key: String,
+    label: String,
+    on_enter: Vec<StageAction>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct WorkflowTransition {
+    from: String,
+    to: String,
+    guards: Vec<TransitionGuard>,
+}
+
+/// One immutable version of a named workflow. Redefining a workflow adds a
+/// version; consultations stay on the version they started with.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Workflow {
+    id: u64,
+    name: String,
+    version: u32,
+    // Restricts the workflow to consultations in this practice area.
+    practice_area: Option<String>,
+    organization_id: Option<u64>,
+    stages: Vec<WorkflowStage>,
+    initial_stage: String,
+    transitions: Vec<WorkflowTransition>,
+    defined_at: u64,
+}
+
+impl Storable for Workflow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Workflow {
+    const MAX_SIZE: u32 = 131072;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// The workflow version a consultation is bound to and its current stage.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ConsultationWorkflow {
+    consultation_id: u64,
+    workflow_id: u64,
+    stage: String,
+    started_by: Principal,
+    started_at: u64,
+    entered_at: u64,
+}
+
+impl Storable for ConsultationWorkflow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ConsultationWorkflow {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StageChange {
+    from: Option<String>,
+    to: String,
+    by: Principal,
+    at: u64,
+    // On-enter actions that could not be carried out.
+    action_errors: Vec<String>,
+}
+
+/// A stage change as published in the change feed.
+#[derive(Serialize)]
+struct StageChangeEntry<'a> {
+    consultation_id: u64,
+    #[serde(flatten)]
+    change: &'a StageChange,
+}
+
+impl Storable for StageChange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StageChange {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct WorkflowNotice {
+    id: u64,
+    consultation_id: u64,
+    role: ParticipantRole,
+    stage: String,
+    message: String,
+    posted_at: u64,
+}
+
+impl Storable for WorkflowNotice {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WorkflowNotice {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A stage reachable from the current one and the guards still blocking it.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct AvailableTransition {
+    to: String,
+    unmet_guards: Vec<TransitionGuard>,
+}
+
+/// Orders the stage history of a consultation together.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct StageChangeKey {
+    consultation_id: u64,
+    id: u64,
+}
+
+impl Storable for StageChangeKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.consultation_id.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        StageChangeKey {
+            consultation_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for StageChangeKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+thread_local! {
+    static WORKFLOWS: RefCell<StableBTreeMap<u64, Workflow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    static CONSULTATION_WORKFLOWS: RefCell<StableBTreeMap<u64, ConsultationWorkflow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    static STAGE_CHANGES: RefCell<StableBTreeMap<StageChangeKey, StageChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    static WORKFLOW_NOTICES: RefCell<StableBTreeMap<u64, WorkflowNotice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+}
+
+/// Defines the next version of a workflow of the caller's organization.
+/// Controllers and organization admins only.
+#[ic_cdk::update]
+fn define_workflow(
+    name: String,
+    practice_area: Option<String>,
+    stages: Vec<WorkflowStage>,
+    initial_stage: String,
+    transitions: Vec<WorkflowTransition>,
+) -> Result<Workflow, Error> {
+    let caller = ic_cdk::caller();
+    if !ic_cdk::api::is_controller(&caller) && !organizations::is_organization_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Only controllers and organization admins can define workflows".to_string(),
+        });
+    }
+    if name.trim().is_empty() || name.len() > MAX_NAME_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!("Workflow names are 1 to {} bytes", MAX_NAME_BYTES),
+        });
+    }
+    validate_definition(&stages, &initial_stage, &transitions)?;
+
+    let organization_id = organizations::organization_of(&caller);
+    let version = latest_version(organization_id, &name).map_or(1, |workflow| workflow.version + 1);
+    let workflow = Workflow {
+        id: next_id(),
+        name,
+        version,
+        practice_area,
+        organization_id,
+        stages,
+        initial_stage,
+        transitions,
+        defined_at: time(),
+    };
+    WORKFLOWS.with(|service| service.borrow_mut().insert(workflow.id, workflow.clone()));
+    changes::record_upsert(ChangeEntity::Workflow, workflow.id, &workflow);
+    Ok(workflow)
+}
+
+#[ic_cdk::query]
+fn get_workflow(id: u64) -> Result<Workflow, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+    WORKFLOWS
+        .with(|service| service.borrow().get(&id))
+        .filter(|workflow| organizations::can_access(&caller, workflow.organization_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Workflow with id={} not found", id),
+        })
+}
+
+/// Every version of the caller's workflows, or of the one with `name`.
+#[ic_cdk::query]
+fn list_workflows(name: Option<String>) -> Result<Vec<Workflow>, Error> {
+    let caller = ic_cdk::caller();
+    ensure_firm_staff(&caller)?;
+
+    Ok(WORKFLOWS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, workflow)| workflow)
+            .filter(|workflow| {
+                organizations::can_access(&caller, workflow.organization_id)
+                    && name.as_ref().is_none_or(|name| workflow.name == *name)
+            })
+            .collect()
+    }))
+}
+
+/// Binds the consultation to the latest version of the workflow and enters
+/// its initial stage.
+#[ic_cdk::update]
+async fn start_consultation_workflow(
+    consultation_id: u64,
+    name: String,
+) -> Result<ConsultationWorkflow, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = staffed_consultation(consultation_id, &caller)?;
+    if binding_of(consultation_id).is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Legal consultation with id={} already follows a workflow",
+                consultation_id
+            ),
+        });
+    }
+    let workflow =
+        latest_version(consultation.organization_id, &name).ok_or_else(|| Error::NotFound {
+            msg: format!("Workflow {} not found", name),
+        })?;
+    if let Some(area) = &workflow.practice_area {
+        if !consultation
+            .practice_area
+            .as_ref()
+            .is_some_and(|practice_area| practice_area.eq_ignore_ascii_case(area))
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("Workflow {} only applies to {} consultations", name, area),
+            });
+        }
+    }
+
+    let now = time();
+    let binding = ConsultationWorkflow {
+        consultation_id,
+        workflow_id: workflow.id,
+        stage: workflow.initial_stage.clone(),
+        started_by: caller,
+        started_at: now,
+        entered_at: now,
+    };
+    enter_stage(&consultation, &workflow, None, &binding, caller);
+    Ok(binding)
+}
+
+#[ic_cdk::query(composite = true)]
+async fn get_consultation_workflow(consultation_id: u64) -> Result<ConsultationWorkflow, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    viewable_consultation(consultation_id)?;
+    binding_of(consultation_id).ok_or_else(|| not_bound(consultation_id))
+}
+
+/// The stages the consultation can move to next, with any guards that are
+/// not yet met.
+#[ic_cdk::query(composite = true)]
+async fn list_available_transitions(
+    consultation_id: u64,
+) -> Result<Vec<AvailableTransition>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let consultation = viewable_consultation(consultation_id)?;
+    let binding = binding_of(consultation_id).ok_or_else(|| not_bound(consultation_id))?;
+    let workflow = workflow_of(&binding);
+
+    Ok(workflow
+        .transitions
+        .iter()
+        .filter(|transition| transition.from == binding.stage)
+        .map(|transition| AvailableTransition {
+            to: transition.to.clone(),
+            unmet_guards: unmet_guards(&consultation, &transition.guards),
+        })
+        .collect())
+}
+
+/// Moves the consultation to `to_stage` along a transition of its workflow
+/// version whose guards are all met, then runs the stage's on-enter
+/// actions.
+#[ic_cdk::update]
+async fn advance_consultation_stage(
+    consultation_id: u64,
+    to_stage: String,
+) -> Result<ConsultationWorkflow, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    let caller = ic_cdk::caller();
+    let consultation = staffed_consultation(consultation_id, &caller)?;
+    let mut binding = binding_of(consultation_id).ok_or_else(|| not_bound(consultation_id))?;
+    let workflow = workflow_of(&binding);
+    let transition = workflow
+        .transitions
+        .iter()
+        .find(|transition| transition.from == binding.stage && transition.to == to_stage)
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!(
+                "Workflow {} has no transition from {} to {}",
+                workflow.name, binding.stage, to_stage
+            ),
+        })?;
+    let unmet = unmet_guards(&consultation, &transition.guards);
+    if !unmet.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} of the guards from {} to {} are not met",
+                unmet.len(),
+                binding.stage,
+                to_stage
+            ),
+        });
+    }
+
+    let from = std::mem::replace(&mut binding.stage, to_stage);
+    binding.entered_at = time();
+    enter_stage(&consultation, &workflow, Some(from), &binding, caller);
+    Ok(binding)
+}
+
+/// Every stage the consultation went through, oldest first.
+#[ic_cdk::query(composite = true)]
+async fn get_consultation_stage_history(consultation_id: u64) -> Result<Vec<StageChange>, Error> {
+    sharding::load_consultations(&[consultation_id]).await?;
+    viewable_consultation(consultation_id)?;
+    Ok(STAGE_CHANGES.with(|service| {
+        service
+            .borrow()
+            .range(
+                StageChangeKey {
+                    consultation_id,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.consultation_id == consultation_id)
+            .map(|(_, change)| change)
+            .collect()
+    }))
+}
+
+/// Notices posted by the workflows of consultations the caller currently
+/// participates in, whatever the role they are addressed to, so co-counsel
+/// and reviewers follow the matter too.
+#[ic_cdk::query(composite = true)]
+async fn list_my_workflow_notices() -> Vec<WorkflowNotice> {
+    let caller = ic_cdk::caller();
+    let notices: Vec<WorkflowNotice> = WORKFLOW_NOTICES
+        .with(|service| service.borrow().iter().map(|(_, notice)| notice).collect());
+    let consultation_ids: Vec<u64> = notices
+        .iter()
+        .map(|notice| notice.consultation_id)
+        .collect();
+    sharding::or_trap(sharding::load_consultations(&consultation_ids).await);
+    notices
+        .into_iter()
+        .filter(|notice| {
+            _get_legal_consultation(&notice.consultation_id).is_some_and(|consultation| {
+                participants::role_of(consultation.id, consultation.advisor_id, &caller).is_some()
+            })
+        })
+        .collect()
+}
+
+/// A consultation running a workflow only changes status once it has
+/// reached a final stage, so status changes cannot skip the workflow's
+/// guarded transitions.
+pub(crate) fn ensure_status_change_allowed(consultation_id: u64) -> Result<(), Error> {
+    match binding_of(consultation_id) {
+        Some(binding)
+            if workflow_of(&binding)
+                .transitions
+                .iter()
+                .any(|transition| transition.from == binding.stage) =>
+        {
+            Err(Error::InvalidInput {
+                msg: format!(
+                    "Legal consultation with id={} is at stage {} of its workflow; advance it to a final stage first",
+                    consultation_id, binding.stage
+                ),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Drops the workflow state of a consultation that is permanently deleted.
+pub(crate) fn remove_consultation(consultation_id: u64) {
+    if CONSULTATION_WORKFLOWS
+        .with(|service| service.borrow_mut().remove(&consultation_id))
+        .is_some()
+    {
+        changes::record_delete(ChangeEntity::ConsultationWorkflow, consultation_id);
+    }
+    STAGE_CHANGES.with(|service| {
+        let keys: Vec<StageChangeKey> = service
+            .borrow()
+            .range(
+                StageChangeKey {
+                    consultation_id,
+                    id: 0,
+                }..,
+            )
+            .take_while(|(key, _)| key.consultation_id == consultation_id)
+            .map(|(key, _)| key)
+            .collect();
+        let mut stage_changes = service.borrow_mut();
+        for key in &keys {
+            stage_changes.remove(key);
+            changes::record_delete(ChangeEntity::StageChange, key.id);
+        }
+    });
+    WORKFLOW_NOTICES.with(|service| {
+        let ids: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, notice)| notice.consultation_id == consultation_id)
+            .map(|(id, _)| id)
+            .collect();
+        let mut notices = service.borrow_mut();
+        for id in &ids {
+            notices.remove(id);
+            changes::record_delete(ChangeEntity::WorkflowNotice, *id);
+        }
+    });
+}
+
+/// Stores the binding at its new stage, runs the stage's actions and logs
+/// the change.
+fn enter_stage(
+    consultation: &LegalConsultation,
+    workflow: &Workflow,
+    from: Option<String>,
+    binding: &ConsultationWorkflow,
+    by: Principal,
+) {
+    CONSULTATION_WORKFLOWS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(binding.consultation_id, binding.clone())
+    });
+    changes::record_upsert(
+        ChangeEntity::ConsultationWorkflow,
+        binding.consultation_id,
+        binding,
+    );
+
+    let mut action_errors = Vec::new();
+    let stage = workflow
+        .stages
+        .iter()
+        .find(|stage| stage.key == binding.stage)
+        .expect("Transitions only lead to defined stages");
+    for action in &stage.on_enter {
+        match action {
+            StageAction::Notify { role, message } => {
+                let notice = WorkflowNotice {
+                    id: next_id(),
+                    consultation_id: consultation.id,
+                    role: *role,
+                    stage: stage.key.clone(),
+                    message: message.clone(),
+                    posted_at: binding.entered_at,
+                };
+                changes::record_upsert(ChangeEntity::WorkflowNotice, notice.id, &notice);
+                WORKFLOW_NOTICES.with(|service| service.borrow_mut().insert(notice.id, notice));
+            }
+            StageAction::SetCustomField { key, value } => {
+                if let Err(Error::NotFound { msg } | Error::InvalidInput { msg }) =
+                    custom_fields::set_value(
+                        CustomFieldEntity::Consultation,
+                        consultation.id,
+                        consultation.organization_id,
+                        key.clone(),
+                        value.clone(),
+                        by,
+                    )
+                {
+                    action_errors.push(msg);
+                }
+            }
+            StageAction::CreateTask {
+                title,
+                priority,
+                due_in_days,
+                role,
+            } => {
+                if let Err(Error::InvalidInput { msg }) = tasks::create_workflow_task(
+                    consultation,
+                    title.clone(),
+                    *priority,
+                    *due_in_days,
+                    *role,
+                    by,
+                ) {
+                    action_errors.push(msg);
+                }
+            }
+        }
+    }
+
+    let change = StageChange {
+        from,
+        to: binding.stage.clone(),
+        by,
+        at: binding.entered_at,
+        action_errors,
+    };
+    let key = StageChangeKey {
+        consultation_id: consultation.id,
+        id: next_id(),
+    };
+    changes::record_upsert(
+        ChangeEntity::StageChange,
+        key.id,
+        &StageChangeEntry {
+            consultation_id: consultation.id,
+            change: &change,
+        },
+    );
+    STAGE_CHANGES.with(|service| service.borrow_mut().insert(key, change));
+}
+
+fn unmet_guards(
+    consultation: &LegalConsultation,
+    guards: &[TransitionGuard],
+) -> Vec<TransitionGuard> {
+    guards
+        .iter()
+        .filter(|guard| !is_met(consultation, guard))
+        .cloned()
+        .collect()
+}
+
+fn is_met(consultation: &LegalConsultation, guard: &TransitionGuard) -> bool {
+    match guard {
+        TransitionGuard::ConsultationStatus(status) => consultation.status == *status,
+        TransitionGuard::EscrowFunded => payments::is_escrow_funded(consultation.id),
+        TransitionGuard::EntriesInvoiced => !billing::has_unbilled_entries(consultation.id),
+        TransitionGuard::InvoicesPaid => billing::all_invoices_paid(consultation.id),
+        TransitionGuard::IntakeAnswered { key } => forms::has_answer(consultation.id, key),
+        TransitionGuard::CustomFieldSet { key } => custom_fields::has_value(consultation.id, key),
+        TransitionGuard::RoleStaffed(role) => participants::has_role(consultation, *role),
+        TransitionGuard::TasksClosed => tasks::all_tasks_closed(consultation.id),
+    }
+}
+
+fn validate_definition(
+    stages: &[WorkflowStage],
+    initial_stage: &str,
+    transitions: &[WorkflowTransition],
+) -> Result<(), Error> {
+    if stages.is_empty() || stages.len() > MAX_STAGES || transitions.len() > MAX_TRANSITIONS {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A workflow has 1 to {} stages and at most {} transitions",
+                MAX_STAGES, MAX_TRANSITIONS
+            ),
+        });
+    }
+    for (index, stage) in stages.iter().enumerate() {
+        if stage.key.trim().is_empty() || stage.key.len() > MAX_NAME_BYTES {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Stage {} needs a key of at most {} bytes",
+                    index, MAX_NAME_BYTES
+                ),
+            });
+        }
+        if stages[..index].iter().any(|other| other.key == stage.key) {
+            return Err(Error::InvalidInput {
+                msg: format!("Stage {} is defined twice", stage.key),
+            });
+        }
+        if stage.on_enter.len() > MAX_STAGE_ACTIONS {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Stage {} has more than {} actions",
+                    stage.key, MAX_STAGE_ACTIONS
+                ),
+            });
+        }
+        let too_long = |action: &StageAction| match action {
+            StageAction::Notify { message, .. } => message.len() > MAX_MESSAGE_BYTES,
+            StageAction::CreateTask { title, .. } => {
+                title.trim().is_empty() || title.len() > MAX_NAME_BYTES
+            }
+            StageAction::SetCustomField { .. } => false,
+        };
+        if stage.on_enter.iter().any(too_long) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Notices are at most {} bytes and task titles 1 to {} bytes",
+                    MAX_MESSAGE_BYTES, MAX_NAME_BYTES
+                ),
+            });
+        }
+    }
+
+    let is_stage = |key: &str| stages.iter().any(|stage| stage.key == key);
+    if !is_stage(initial_stage) {
+        return Err(Error::InvalidInput {
+            msg: format!("Initial stage {} is not defined", initial_stage),
+        });
+    }
+    for (index, transition) in transitions.iter().enumerate() {
+        if !is_stage(&transition.from) || !is_stage(&transition.to) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Transition from {} to {} uses an undefined stage",
+                    transition.from, transition.to
+                ),
+            });
+        }
+        if transitions[..index]
+            .iter()
+            .any(|other| other.from == transition.from && other.to == transition.to)
+        {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Transition from {} to {} is defined twice",
+                    transition.from, transition.to
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn latest_version(organization_id: Option<u64>, name: &str) -> Option<Workflow> {
+    WORKFLOWS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, workflow)| workflow)
+            .filter(|workflow| workflow.organization_id == organization_id && workflow.name == name)
+            .max_by_key(|workflow| workflow.version)
+    })
+}
+
+fn binding_of(consultation_id: u64) -> Option<ConsultationWorkflow> {
+    CONSULTATION_WORKFLOWS.with(|service| service.borrow().get(&consultation_id))
+}
+
+fn workflow_of(binding: &ConsultationWorkflow) -> Workflow {
+    WORKFLOWS
+        .with(|service| service.borrow().get(&binding.workflow_id))
+        .expect("Workflow versions are never deleted")
+}
+
+/// A consultation the caller may move through its workflow: managing
+/// advisors, admins of its organization and controllers. Clients may not.
+fn staffed_consultation(
+    consultation_id: u64,
+    caller: &Principal,
+) -> Result<LegalConsultation, Error> {
+    let consultation = firm_legal_consultation(&consultation_id)
+        .ok_or_else(|| consultation_not_found(consultation_id))?;
+    let allowed = ic_cdk::api::is_controller(caller)
+        || organizations::is_organization_admin(caller)
+        || participants::role_of(consultation.id, consultation.advisor_id, caller)
+            .is_some_and(ParticipantRole::can_manage);
+    if !allowed {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "Caller cannot manage the workflow of legal consultation with id={}",
+                consultation_id
+            ),
+        });
+    }
+    Ok(consultation)
+}
+
+fn viewable_consultation(consultation_id: u64) -> Result<LegalConsultation, Error> {
+    scoped_legal_consultation(&consultation_id)
+        .filter(|consultation| can_view_consultation(consultation, &ic_cdk::caller()))
+        .ok_or_else(|| consultation_not_found(consultation_id))
+}
+
+fn not_bound(consultation_id: u64) -> Error {
+    Error::NotFound {
+        msg: format!(
+            "Legal consultation with id={} does not follow a workflow",
+            consultation_id
+        ),
+    }
+}
+
+fn consultation_not_found(id: u64) -> Error {
+    Error::NotFound {
+        msg: format!("Legal consultation with id={} not found", id),
+    }
+}
+
+pub(crate) fn backup_tables() -> Vec<backup::TableSource> {
+    vec![
+        backup::map_source("workflows", &WORKFLOWS),
+        backup::map_source("consultation_workflows", &CONSULTATION_WORKFLOWS),
+        backup::map_source("stage_changes", &STAGE_CHANGES),
+        backup::map_source("workflow_notices", &WORKFLOW_NOTICES),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consultation(status: ConsultationStatus) -> LegalConsultation {
+        LegalConsultation {
+            id: 1,
+            advisor_id: 2,
+            details: String::new(),
+            created_at: 0,
+            closed_at: None,
+            is_completed: false,
+            status,
+            client: None,
+            practice_area: None,
+            organization_id: None,
+            reference: None,
+        }
+    }
+
+    fn stage(key: &str) -> WorkflowStage {
+        WorkflowStage {
+            key: key.to_string(),
+            label: key.to_string(),
+            on_enter: Vec::new(),
+        }
+    }
+
+    fn transition(from: &str, to: &str) -> WorkflowTransition {
+        WorkflowTransition {
+            from: from.to_string(),
+            to: to.to_string(),
+            guards: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn status_guards_compare_the_consultation_status() {
+        let guard = TransitionGuard::ConsultationStatus(ConsultationStatus::Completed);
+        assert!(!is_met(&consultation(ConsultationStatus::Open), &guard));
+        assert!(is_met(&consultation(ConsultationStatus::Completed), &guard));
+    }
+
+    #[test]
+    fn guards_over_empty_collections_are_met() {
+        let consultation = consultation(ConsultationStatus::Open);
+        let guards = [
+            TransitionGuard::EntriesInvoiced,
+            TransitionGuard::InvoicesPaid,
+            TransitionGuard::TasksClosed,
+            // The lead mirrors the consultation's advisor.
+            TransitionGuard::RoleStaffed(ParticipantRole::Lead),
+        ];
+        assert!(unmet_guards(&consultation, &guards).is_empty());
+    }
+
+    #[test]
+    fn guards_needing_a_record_are_unmet_without_one() {
+        let consultation = consultation(ConsultationStatus::Open);
+        let guards = [
+            TransitionGuard::EscrowFunded,
+            TransitionGuard::IntakeAnswered {
+                key: "jurisdiction".to_string(),
+            },
+            TransitionGuard::CustomFieldSet {
+                key: "court".to_string(),
+            },
+            TransitionGuard::RoleStaffed(ParticipantRole::Reviewer),
+            TransitionGuard::ConsultationStatus(ConsultationStatus::Open),
+        ];
+        // Only the status guard holds.
+        assert_eq!(unmet_guards(&consultation, &guards).len(), guards.len() - 1);
+    }
+
+    #[test]
+    fn definitions_only_use_declared_stages_once() {
+        let stages = [stage("intake"), stage("review"), stage("closed")];
+        let transitions = [
+            transition("intake", "review"),
+            transition("review", "closed"),
+        ];
+        assert!(validate_definition(&stages, "intake", &transitions).is_ok());
+
+        assert!(validate_definition(&stages, "draft", &transitions).is_err());
+        assert!(validate_definition(&stages, "intake", &[transition("intake", "draft")]).is_err());
+        assert!(validate_definition(
+            &stages,
+            "intake",
+            &[
+                transition("intake", "review"),
+                transition("intake", "review")
+            ]
+        )
+        .is_err());
+        assert!(validate_definition(&[stage("intake"), stage("intake")], "intake", &[]).is_err());
+        assert!(validate_definition(&[], "intake", &[]).is_err());
+    }
+}
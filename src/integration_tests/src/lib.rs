@@ -0,0 +1,232 @@
+//! Shared setup for the PocketIC tests.
+//!
+//! The tests install the canisters' release wasm modules and, for payments,
+//! the ICRC-1 ledger published by DFINITY:
+//!
+//! * `BACKEND_WASM`, `BUCKET_WASM`: default to the workspace's
+//!   `target/wasm32-unknown-unknown/release` build;
+//! * `ICRC1_LEDGER_WASM`: path to `ic-icrc1-ledger.wasm.gz`;
+//! * `POCKET_IC_BIN`: path to the PocketIC server binary.
+//!
+//! The tests are ignored by default; `cargo test -p integration_tests --
+//! --ignored` runs them, and fails on a missing server or module.
+
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{CandidType, Deserialize, Encode, Nat, Principal};
+use pocket_ic::{query_candid_as, update_candid_as, PocketIc};
+use std::path::PathBuf;
+
+const INITIAL_CYCLES: u128 = 10_000_000_000_000;
+
+pub const LEDGER_FEE: u64 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    pub fn of(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+/// The backend's error type.
+#[derive(CandidType, Deserialize, Debug)]
+pub enum Error {
+    NotFound { msg: String },
+    Unauthorized { msg: String },
+    InvalidInput { msg: String },
+    PaymentFailed { msg: String },
+    InsufficientFunds { msg: String },
+    LegalHold { msg: String },
+    CallFailed { msg: String },
+}
+
+/// A PocketIC instance with the backend installed, controlled by `admin`.
+pub struct Env {
+    pub pic: PocketIc,
+    pub backend: Principal,
+    pub admin: Principal,
+}
+
+impl Env {
+    /// Installs the backend; panics if the PocketIC server or the backend's
+    /// wasm is missing.
+    pub fn start() -> Self {
+        assert!(
+            std::env::var_os("POCKET_IC_BIN").is_some(),
+            "POCKET_IC_BIN is not set"
+        );
+        let backend_wasm = wasm("BACKEND_WASM", Some("icp_rust_boilerplate_backend.wasm"));
+        let pic = PocketIc::new();
+        let admin = user(0);
+        let backend = install(&pic, admin, backend_wasm, Encode!().unwrap());
+        Env {
+            pic,
+            backend,
+            admin,
+        }
+    }
+
+    pub fn update<Input, Output>(&self, sender: Principal, method: &str, input: Input) -> Output
+    where
+        Input: ArgumentEncoder,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        update_candid_as(&self.pic, self.backend, sender, method, input)
+            .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e))
+    }
+
+    pub fn query<Input, Output>(&self, sender: Principal, method: &str, input: Input) -> Output
+    where
+        Input: ArgumentEncoder,
+        Output: for<'a> ArgumentDecoder<'a>,
+    {
+        query_candid_as(&self.pic, self.backend, sender, method, input)
+            .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e))
+    }
+
+    /// Runs a few rounds so background calls (timers, spawned futures) finish.
+    pub fn settle(&self) {
+        for _ in 0..10 {
+            self.pic.tick();
+        }
+    }
+}
+
+/// An ICRC-1 ledger installed next to the backend.
+pub struct Ledger {
+    pub canister_id: Principal,
+}
+
+#[derive(CandidType)]
+enum LedgerArg {
+    Init(LedgerInitArgs),
+}
+
+#[derive(CandidType)]
+struct LedgerInitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, MetadataValue)>,
+    initial_balances: Vec<(Account, Nat)>,
+    archive_options: ArchiveOptions,
+}
+
+#[derive(CandidType)]
+enum MetadataValue {
+    Text(String),
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+impl Ledger {
+    /// Installs a ledger that starts with the given balances.
+    pub fn install(env: &Env, ledger_wasm: Vec<u8>, initial_balances: &[(Principal, u64)]) -> Self {
+        let arg = LedgerArg::Init(LedgerInitArgs {
+            minting_account: Account::of(user(255)),
+            transfer_fee: Nat::from(LEDGER_FEE),
+            token_symbol: "TKN".to_string(),
+            token_name: "Test token".to_string(),
+            metadata: vec![("icrc1:logo".to_string(), MetadataValue::Text(String::new()))],
+            initial_balances: initial_balances
+                .iter()
+                .map(|(owner, amount)| (Account::of(*owner), Nat::from(*amount)))
+                .collect(),
+            archive_options: ArchiveOptions {
+                num_blocks_to_archive: 1_000,
+                trigger_threshold: 2_000,
+                controller_id: env.admin,
+            },
+        });
+        let canister_id = install(&env.pic, env.admin, ledger_wasm, Encode!(&arg).unwrap());
+        Ledger { canister_id }
+    }
+
+    pub fn balance_of(&self, env: &Env, account: Account) -> u64 {
+        let (balance,): (Nat,) = query_candid_as(
+            &env.pic,
+            self.canister_id,
+            Principal::anonymous(),
+            "icrc1_balance_of",
+            (account,),
+        )
+        .expect("icrc1_balance_of was rejected");
+        u64::try_from(balance.0).expect("balance fits into u64")
+    }
+
+    pub fn transfer(&self, env: &Env, from: Principal, to: Account, amount: u64) {
+        let arg = TransferArg {
+            from_subaccount: None,
+            to,
+            amount: Nat::from(amount),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Result<Nat, TransferError>,) =
+            update_candid_as(&env.pic, self.canister_id, from, "icrc1_transfer", (arg,))
+                .expect("icrc1_transfer was rejected");
+        result.expect("transfer failed");
+    }
+}
+
+/// A distinct test principal.
+pub fn user(n: u8) -> Principal {
+    Principal::from_slice(&[n, 0xAB, 0xCD, 0x01])
+}
+
+/// Reads a wasm module from `var`, or from the workspace's release build
+/// when a default file name is given.
+pub fn wasm(var: &str, default: Option<&str>) -> Vec<u8> {
+    let path = match (std::env::var_os(var), default) {
+        (Some(path), _) => PathBuf::from(path),
+        (None, Some(file)) => PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../target/wasm32-unknown-unknown/release")
+            .join(file),
+        (None, None) => panic!("{} is not set", var),
+    };
+    std::fs::read(&path)
+        .unwrap_or_else(|e| panic!("cannot read {} ({}): {}", path.display(), var, e))
+}
+
+fn install(pic: &PocketIc, controller: Principal, wasm: Vec<u8>, arg: Vec<u8>) -> Principal {
+    let canister_id = pic.create_canister_with_settings(Some(controller), None);
+    pic.add_cycles(canister_id, INITIAL_CYCLES);
+    pic.install_canister(canister_id, wasm, arg, Some(controller));
+    canister_id
+}
@@ -0,0 +1,206 @@
+//! Consultation escrow against a locally installed ICRC-1 ledger.
+
+use candid::{CandidType, Deserialize, Principal};
+use integration_tests::{user, wasm, Account, Env, Error, Ledger, LEDGER_FEE};
+
+const DEPOSIT: u64 = 100_000_000;
+const COMMISSION_BPS: u16 = 500;
+const CLIENT_FUNDS: u64 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize)]
+struct LegalAdvisor {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegalConsultation {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq)]
+enum EscrowStatus {
+    AwaitingDeposit,
+    Funded,
+    Settling,
+    Released,
+    Refunded,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Escrow {
+    status: EscrowStatus,
+    deposit_account: Account,
+}
+
+struct Setup {
+    env: Env,
+    ledger: Ledger,
+    client: Principal,
+    advisor: Principal,
+    treasury: Principal,
+    consultation_id: u64,
+}
+
+/// An accepted consultation whose client has funded its escrow.
+fn funded_consultation() -> Setup {
+    let ledger_wasm = wasm("ICRC1_LEDGER_WASM", None);
+    let env = Env::start();
+    let (client, advisor, treasury) = (user(1), user(2), user(3));
+    let ledger = Ledger::install(&env, ledger_wasm, &[(client, CLIENT_FUNDS)]);
+
+    let (configured,): (Result<candid::Reserved, Error>,) = env.update(
+        env.admin,
+        "configure_payments",
+        (
+            vec![ledger.canister_id],
+            COMMISSION_BPS,
+            Some(Account::of(treasury)),
+        ),
+    );
+    configured.expect("payments configured");
+
+    let (profile,): (Option<LegalAdvisor>,) = env.update(
+        advisor,
+        "add_legal_advisor",
+        (
+            "Ada Advocate".to_string(),
+            "Bar no. 1".to_string(),
+            5.0f32,
+            vec!["Tenancy".to_string()],
+        ),
+    );
+    let advisor_id = profile.expect("advisor added").id;
+    let (consultation,): (Option<LegalConsultation>,) = env.update(
+        client,
+        "initiate_legal_consultation",
+        (advisor_id, "Lease dispute".to_string()),
+    );
+    let consultation_id = consultation.expect("consultation requested").id;
+    let (accepted,): (Result<(), Error>,) =
+        env.update(advisor, "accept_consultation_request", (consultation_id,));
+    accepted.expect("request accepted");
+
+    let (escrow,): (Result<Escrow, Error>,) = env.update(
+        client,
+        "open_escrow",
+        (consultation_id, ledger.canister_id, DEPOSIT),
+    );
+    let escrow = escrow.expect("escrow opened");
+    assert_eq!(escrow.status, EscrowStatus::AwaitingDeposit);
+    ledger.transfer(&env, client, escrow.deposit_account, DEPOSIT);
+    let (escrow,): (Result<Escrow, Error>,) =
+        env.update(client, "confirm_escrow_deposit", (consultation_id,));
+    assert_eq!(
+        escrow.expect("deposit confirmed").status,
+        EscrowStatus::Funded
+    );
+
+    Setup {
+        env,
+        ledger,
+        client,
+        advisor,
+        treasury,
+        consultation_id,
+    }
+}
+
+fn escrow_status(setup: &Setup) -> EscrowStatus {
+    let (escrow,): (Result<Escrow, Error>,) =
+        setup
+            .env
+            .query(setup.client, "get_escrow", (setup.consultation_id,));
+    escrow.expect("escrow exists").status
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn completing_releases_the_escrow_minus_commission() {
+    let setup = funded_consultation();
+
+    let (completed,): (Result<(), Error>,) = setup.env.update(
+        setup.client,
+        "mark_consultation_as_completed",
+        (setup.consultation_id,),
+    );
+    completed.expect("consultation completed");
+    setup.env.settle();
+
+    assert_eq!(escrow_status(&setup), EscrowStatus::Released);
+    let commission = DEPOSIT * COMMISSION_BPS as u64 / 10_000;
+    assert_eq!(
+        setup
+            .ledger
+            .balance_of(&setup.env, Account::of(setup.advisor)),
+        DEPOSIT - commission - LEDGER_FEE
+    );
+    assert_eq!(
+        setup
+            .ledger
+            .balance_of(&setup.env, Account::of(setup.treasury)),
+        commission - LEDGER_FEE
+    );
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn cancelling_refunds_the_client() {
+    let setup = funded_consultation();
+
+    let (cancelled,): (Result<(), Error>,) = setup.env.update(
+        setup.client,
+        "cancel_legal_consultation",
+        (setup.consultation_id,),
+    );
+    cancelled.expect("consultation cancelled");
+    setup.env.settle();
+
+    assert_eq!(escrow_status(&setup), EscrowStatus::Refunded);
+    // One fee for the deposit, one for the refund.
+    assert_eq!(
+        setup
+            .ledger
+            .balance_of(&setup.env, Account::of(setup.client)),
+        CLIENT_FUNDS - 2 * LEDGER_FEE
+    );
+    assert_eq!(
+        setup
+            .ledger
+            .balance_of(&setup.env, Account::of(setup.advisor)),
+        0
+    );
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn strangers_cannot_complete_a_consultation() {
+    let setup = funded_consultation();
+    let stranger = user(4);
+
+    let (completed,): (Result<(), Error>,) = setup.env.update(
+        stranger,
+        "mark_consultation_as_completed",
+        (setup.consultation_id,),
+    );
+    assert!(matches!(completed, Err(Error::Unauthorized { .. })));
+    let (updated,): (Result<(), Error>,) = setup.env.update(
+        stranger,
+        "update_legal_consultation",
+        (
+            setup.consultation_id,
+            None::<u64>,
+            None::<String>,
+            Some(true),
+        ),
+    );
+    assert!(matches!(updated, Err(Error::Unauthorized { .. })));
+    setup.env.settle();
+
+    assert_eq!(escrow_status(&setup), EscrowStatus::Funded);
+    assert_eq!(
+        setup
+            .ledger
+            .balance_of(&setup.env, Account::of(setup.advisor)),
+        0
+    );
+}
@@ -0,0 +1,197 @@
+//! Outside clients engaging a firm's advisors.
+
+use candid::{CandidType, Deserialize, Principal};
+use integration_tests::{user, Env, Error};
+
+#[derive(CandidType)]
+struct OrganizationSettings {
+    reference_prefix: String,
+    law_firm_id: Option<String>,
+    intake_window_hours: Option<u32>,
+    fallback_advisor_id: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Organization {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegalAdvisor {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct LegalConsultation {
+    id: u64,
+    organization_id: Option<u64>,
+    reference: Option<String>,
+}
+
+struct Firm {
+    env: Env,
+    organization_id: u64,
+    firm_admin: Principal,
+    advisor_id: u64,
+}
+
+/// An organization with one admin and one advisor on its staff.
+fn firm() -> Firm {
+    let env = Env::start();
+    let (firm_admin, advisor) = (user(10), user(11));
+
+    let (organization,): (Result<Organization, Error>,) = env.update(
+        env.admin,
+        "create_organization",
+        (
+            "Acme Legal".to_string(),
+            vec![firm_admin],
+            OrganizationSettings {
+                reference_prefix: "ACME".to_string(),
+                law_firm_id: None,
+                intake_window_hours: None,
+                fallback_advisor_id: None,
+            },
+        ),
+    );
+    let organization_id = organization.expect("organization created").id;
+    let (added,): (Result<(), Error>,) = env.update(
+        firm_admin,
+        "add_organization_member",
+        (organization_id, advisor, false),
+    );
+    added.expect("advisor joined");
+
+    let (profile,): (Option<LegalAdvisor>,) = env.update(
+        advisor,
+        "add_legal_advisor",
+        (
+            "Ada Advocate".to_string(),
+            "Bar no. 1".to_string(),
+            5.0f32,
+            vec!["Tenancy".to_string()],
+        ),
+    );
+    Firm {
+        env,
+        organization_id,
+        firm_admin,
+        advisor_id: profile.expect("advisor added").id,
+    }
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn outside_clients_can_engage_a_firm_advisor() {
+    let firm = firm();
+    let client = user(12);
+
+    let (consultation,): (Option<LegalConsultation>,) = firm.env.update(
+        client,
+        "initiate_legal_consultation",
+        (firm.advisor_id, "Lease dispute".to_string()),
+    );
+    let consultation = consultation.expect("consultation requested");
+    assert_eq!(consultation.organization_id, Some(firm.organization_id));
+    assert_eq!(consultation.reference.as_deref(), Some("ACME-000001"));
+
+    for principal in [client, firm.firm_admin] {
+        let (found,): (Result<LegalConsultation, Error>,) =
+            firm.env
+                .query(principal, "get_legal_consultation", (consultation.id,));
+        assert_eq!(found.expect("consultation visible").id, consultation.id);
+    }
+    let (membership,): (Option<Organization>,) = firm.env.query(client, "get_my_organization", ());
+    assert!(membership.is_none());
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn clients_only_see_their_own_consultations() {
+    let firm = firm();
+    let (client, other_client) = (user(12), user(13));
+
+    let mut ids = Vec::new();
+    for principal in [client, other_client] {
+        let (consultation,): (Option<LegalConsultation>,) = firm.env.update(
+            principal,
+            "initiate_legal_consultation",
+            (firm.advisor_id, "Lease dispute".to_string()),
+        );
+        ids.push(consultation.expect("consultation requested").id);
+    }
+
+    let (visible,): (Vec<LegalConsultation>,) =
+        firm.env.query(client, "list_all_legal_consultations", ());
+    assert_eq!(
+        visible
+            .iter()
+            .map(|consultation| consultation.id)
+            .collect::<Vec<_>>(),
+        vec![ids[0]]
+    );
+    let (found,): (Result<LegalConsultation, Error>,) =
+        firm.env.query(client, "get_legal_consultation", (ids[1],));
+    assert!(matches!(found, Err(Error::NotFound { .. })));
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn the_anonymous_principal_owns_and_sees_no_consultations() {
+    let firm = firm();
+    // An advisor outside every organization, whose consultations have none.
+    let (profile,): (Option<LegalAdvisor>,) = firm.env.update(
+        user(14),
+        "add_legal_advisor",
+        (
+            "Ian Independent".to_string(),
+            "Bar no. 2".to_string(),
+            5.0f32,
+            Vec::<String>::new(),
+        ),
+    );
+    let advisor_id = profile.expect("advisor added").id;
+
+    let (anonymous,): (Option<LegalConsultation>,) = firm.env.update(
+        Principal::anonymous(),
+        "initiate_legal_consultation",
+        (advisor_id, "Lease dispute".to_string()),
+    );
+    assert!(anonymous.is_none());
+
+    let (consultation,): (Option<LegalConsultation>,) = firm.env.update(
+        user(12),
+        "initiate_legal_consultation",
+        (advisor_id, "Lease dispute".to_string()),
+    );
+    let id = consultation.expect("consultation requested").id;
+    let (found,): (Result<LegalConsultation, Error>,) =
+        firm.env
+            .query(Principal::anonymous(), "get_legal_consultation", (id,));
+    assert!(matches!(found, Err(Error::NotFound { .. })));
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn advisors_with_a_profile_outside_organizations_cannot_join_one() {
+    let firm = firm();
+    let independent = user(15);
+    let (profile,): (Option<LegalAdvisor>,) = firm.env.update(
+        independent,
+        "add_legal_advisor",
+        (
+            "Ivy Independent".to_string(),
+            "Bar no. 3".to_string(),
+            5.0f32,
+            Vec::<String>::new(),
+        ),
+    );
+    profile.expect("advisor added");
+
+    let (added,): (Result<(), Error>,) = firm.env.update(
+        firm.firm_admin,
+        "add_organization_member",
+        (firm.organization_id, independent, false),
+    );
+    assert!(matches!(added, Err(Error::InvalidInput { .. })));
+}
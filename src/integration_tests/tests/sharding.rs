@@ -0,0 +1,246 @@
+//! Bucket canisters created on demand for consultations and documents.
+
+use candid::{CandidType, Deserialize, Principal};
+use integration_tests::{user, wasm, Env, Error};
+use pocket_ic::query_candid_as;
+
+const BUCKET_CYCLES: u64 = 1_000_000_000_000;
+// A document spanning a full chunk and a partial one.
+const CHUNK_BYTES: usize = 256 * 1024;
+const DOCUMENT_BYTES: usize = CHUNK_BYTES + 10;
+
+#[derive(CandidType)]
+struct ShardingConfig {
+    enabled: bool,
+    bucket_cycles: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Bucket {
+    range_start: u64,
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegalAdvisor {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegalConsultation {
+    id: u64,
+    details: String,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct Document {
+    id: u64,
+    chunk_count: u32,
+    uploaded_chunks: u32,
+    completed_at: Option<u64>,
+}
+
+/// The backend and the bucket module, or `None` if either is missing.
+fn env() -> (Env, Vec<u8>) {
+    let bucket_wasm = wasm("BUCKET_WASM", Some("consultation_bucket.wasm"));
+    let env = Env::start();
+    (env, bucket_wasm)
+}
+
+fn set_bucket_wasm(env: &Env, module: Vec<u8>) {
+    let (uploaded,): (Result<(), Error>,) = env.update(env.admin, "set_bucket_wasm", (module,));
+    uploaded.expect("bucket wasm uploaded");
+}
+
+fn enable_sharding(env: &Env) {
+    let (configured,): (Result<candid::Reserved, Error>,) = env.update(
+        env.admin,
+        "configure_sharding",
+        (ShardingConfig {
+            enabled: true,
+            bucket_cycles: BUCKET_CYCLES,
+        },),
+    );
+    configured.expect("sharding enabled");
+}
+
+fn flush(env: &Env) -> Result<u64, Error> {
+    let (flushed,): (Result<u64, Error>,) = env.update(env.admin, "flush_shards", ());
+    flushed
+}
+
+fn buckets(env: &Env) -> Vec<Bucket> {
+    let (buckets,): (Result<Vec<Bucket>, Error>,) = env.query(env.admin, "list_buckets", ());
+    buckets.expect("buckets listed")
+}
+
+/// A consultation `client` opened with a freshly registered advisor.
+fn consultation(env: &Env, client: Principal) -> u64 {
+    let (advisor,): (Option<LegalAdvisor>,) = env.update(
+        user(20),
+        "add_legal_advisor",
+        (
+            "Ada Advocate".to_string(),
+            "Bar no. 1".to_string(),
+            5.0f32,
+            vec!["Tenancy".to_string()],
+        ),
+    );
+    let (consultation,): (Option<LegalConsultation>,) = env.update(
+        client,
+        "initiate_legal_consultation",
+        (
+            advisor.expect("advisor added").id,
+            "Lease dispute".to_string(),
+        ),
+    );
+    consultation.expect("consultation requested").id
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn consultations_move_to_a_bucket_created_on_demand() {
+    let (env, bucket_wasm) = env();
+    set_bucket_wasm(&env, bucket_wasm);
+    enable_sharding(&env);
+    assert!(buckets(&env).is_empty());
+
+    let id = consultation(&env, user(1));
+    assert!(flush(&env).expect("flushed") >= 1);
+
+    let buckets = buckets(&env);
+    assert_eq!(buckets.len(), 1);
+    assert_eq!(buckets[0].range_start, 0);
+    let (located,): (Result<Option<Principal>, Error>,) =
+        env.query(env.admin, "locate_legal_consultation", (id,));
+    assert_eq!(located.expect("located"), Some(buckets[0].canister_id));
+
+    // Buckets only answer the backend.
+    let (record,): (Result<Option<Vec<u8>>, Error>,) = query_candid_as(
+        &env.pic,
+        buckets[0].canister_id,
+        env.backend,
+        "get_record",
+        (id,),
+    )
+    .expect("get_record was rejected");
+    assert!(record.expect("record read").is_some());
+
+    // The backend keeps only the directory and reads through to the bucket.
+    let client = user(1);
+    let (updated,): (Result<(), Error>,) = env.update(
+        client,
+        "update_legal_consultation",
+        (
+            id,
+            None::<u64>,
+            Some("Lease renewal".to_string()),
+            None::<bool>,
+        ),
+    );
+    updated.expect("consultation updated");
+    flush(&env).expect("flushed");
+    let (read,): (Result<LegalConsultation, Error>,) =
+        env.query(client, "get_legal_consultation", (id,));
+    assert_eq!(read.expect("consultation read").details, "Lease renewal");
+    let (listed,): (Vec<LegalConsultation>,) =
+        env.query(client, "list_all_legal_consultations", ());
+    assert_eq!(listed.len(), 1);
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn a_failed_bucket_installation_is_retried_on_the_same_canister() {
+    let (env, bucket_wasm) = env();
+    set_bucket_wasm(&env, b"not a wasm module".to_vec());
+    enable_sharding(&env);
+    consultation(&env, user(1));
+
+    let before = env.pic.cycle_balance(env.backend);
+    assert!(matches!(flush(&env), Err(Error::CallFailed { .. })));
+    assert!(buckets(&env).is_empty());
+
+    set_bucket_wasm(&env, bucket_wasm);
+    assert!(flush(&env).expect("flushed") >= 1);
+    assert_eq!(buckets(&env).len(), 1);
+    // Only one canister was paid for.
+    let spent = before - env.pic.cycle_balance(env.backend);
+    assert!(spent < u128::from(BUCKET_CYCLES) * 3 / 2, "spent {}", spent);
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn documents_are_stored_in_and_read_from_their_bucket() {
+    let (env, bucket_wasm) = env();
+    set_bucket_wasm(&env, bucket_wasm);
+    enable_sharding(&env);
+    let client = user(1);
+    let consultation_id = consultation(&env, client);
+
+    let (document,): (Result<Document, Error>,) = env.update(
+        client,
+        "create_document",
+        (
+            consultation_id,
+            "lease.pdf".to_string(),
+            "application/pdf".to_string(),
+            DOCUMENT_BYTES as u64,
+        ),
+    );
+    let document = document.expect("document created");
+    assert_eq!(document.chunk_count, 2);
+
+    let chunks = [
+        vec![1u8; CHUNK_BYTES],
+        vec![2u8; DOCUMENT_BYTES - CHUNK_BYTES],
+    ];
+    let (out_of_order,): (Result<Document, Error>,) = env.update(
+        client,
+        "upload_document_chunk",
+        (document.id, 1u32, chunks[1].clone()),
+    );
+    assert!(matches!(out_of_order, Err(Error::InvalidInput { .. })));
+    let (by_someone_else,): (Result<Document, Error>,) = env.update(
+        user(2),
+        "upload_document_chunk",
+        (document.id, 0u32, chunks[0].clone()),
+    );
+    assert!(by_someone_else.is_err());
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let (uploaded,): (Result<Document, Error>,) = env.update(
+            client,
+            "upload_document_chunk",
+            (document.id, index as u32, chunk.clone()),
+        );
+        let uploaded = uploaded.expect("chunk uploaded");
+        assert_eq!(uploaded.uploaded_chunks, index as u32 + 1);
+    }
+    let (completed,): (Result<Document, Error>,) =
+        env.query(client, "get_document", (document.id,));
+    assert!(completed.expect("document read").completed_at.is_some());
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let (read,): (Result<Vec<u8>, Error>,) =
+            env.query(client, "get_document_chunk", (document.id, index as u32));
+        assert_eq!(&read.expect("chunk read"), chunk);
+    }
+    let (stranger,): (Result<Vec<u8>, Error>,) =
+        env.query(user(2), "get_document_chunk", (document.id, 0u32));
+    assert!(matches!(stranger, Err(Error::NotFound { .. })));
+
+    // Deleting drops the contents from the bucket on the next flush.
+    let bucket = buckets(&env)[0].canister_id;
+    let (deleted,): (Result<(), Error>,) = env.update(client, "delete_document", (document.id,));
+    deleted.expect("document deleted");
+    flush(&env).expect("flushed");
+    let (chunk,): (Result<Option<Vec<u8>>, Error>,) = query_candid_as(
+        &env.pic,
+        bucket,
+        env.backend,
+        "get_chunk",
+        (document.id, 0u32),
+    )
+    .expect("get_chunk was rejected");
+    assert!(chunk.expect("chunk looked up").is_none());
+}
@@ -0,0 +1,117 @@
+//! Staff roles gating the trust ledger.
+
+use candid::{CandidType, Deserialize, Principal};
+use integration_tests::{user, Env, Error};
+
+#[derive(CandidType)]
+enum StaffRole {
+    Staff,
+    Bookkeeper,
+}
+
+#[derive(CandidType)]
+enum TrustTransactionKind {
+    Deposit,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegalAdvisor {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegalClient {
+    id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct JournalEntry {
+    amount: u64,
+}
+
+fn deposit(env: &Env, caller: Principal, client_id: u64) -> Result<JournalEntry, Error> {
+    let (entry,): (Result<JournalEntry, Error>,) = env.update(
+        caller,
+        "record_trust_transaction",
+        (
+            TrustTransactionKind::Deposit,
+            client_id,
+            None::<u64>,
+            5_000u64,
+            "Retainer".to_string(),
+        ),
+    );
+    entry
+}
+
+fn grant(env: &Env, principal: Principal, role: StaffRole) {
+    let (granted,): (Result<(), Error>,) =
+        env.update(env.admin, "grant_staff_role", (principal, role));
+    granted.expect("role granted");
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn only_bookkeepers_post_to_the_trust_ledger() {
+    let env = Env::start();
+    let (client,): (Option<LegalClient>,) = env.update(
+        env.admin,
+        "add_legal_client",
+        (
+            "Carol Client".to_string(),
+            "carol@example.com".to_string(),
+            None::<Principal>,
+        ),
+    );
+    let client_id = client.expect("client added").id;
+
+    // Anyone can list themselves as an advisor; that grants no firm access.
+    let advisor = user(5);
+    let (profile,): (Option<LegalAdvisor>,) = env.update(
+        advisor,
+        "add_legal_advisor",
+        (
+            "Sam Self-Registered".to_string(),
+            "None".to_string(),
+            5.0f32,
+            Vec::<String>::new(),
+        ),
+    );
+    profile.expect("advisor added");
+    assert!(matches!(
+        deposit(&env, advisor, client_id),
+        Err(Error::Unauthorized { .. })
+    ));
+    let (journal,): (Result<Vec<JournalEntry>, Error>,) =
+        env.query(advisor, "list_trust_journal", (Some(client_id),));
+    assert!(matches!(journal, Err(Error::Unauthorized { .. })));
+
+    grant(&env, advisor, StaffRole::Staff);
+    assert!(matches!(
+        deposit(&env, advisor, client_id),
+        Err(Error::Unauthorized { .. })
+    ));
+
+    grant(&env, advisor, StaffRole::Bookkeeper);
+    assert_eq!(
+        deposit(&env, advisor, client_id)
+            .expect("deposit recorded")
+            .amount,
+        5_000
+    );
+    let (journal,): (Result<Vec<JournalEntry>, Error>,) =
+        env.query(advisor, "list_trust_journal", (Some(client_id),));
+    assert_eq!(journal.expect("journal listed").len(), 1);
+}
+
+#[test]
+#[ignore = "needs PocketIC; run with --ignored"]
+fn only_controllers_and_organization_admins_grant_roles() {
+    let env = Env::start();
+    let (granted,): (Result<(), Error>,) = env.update(
+        user(5),
+        "grant_staff_role",
+        (user(5), StaffRole::Bookkeeper),
+    );
+    assert!(matches!(granted, Err(Error::Unauthorized { .. })));
+}